@@ -185,6 +185,11 @@ pub enum SubCmd {
         about = "receive sozu events about the status of backends"
     )]
     Events,
+    #[clap(
+        name = "sessions",
+        about = "list the sessions of the workers, with the progress of their requests"
+    )]
+    Sessions,
 }
 
 #[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
@@ -16,9 +16,9 @@ use sozu_command_lib::{
     proto::command::{
         request::RequestType, response_content::ContentType, AggregatedMetrics, AvailableMetrics,
         CertificatesWithFingerprints, ClusterHashes, ClusterInformations, FrontendFilters,
-        HardStop, QueryCertificatesFilters, QueryMetricsOptions, Request, ResponseContent,
-        ResponseStatus, RunState, SoftStop, Status, WorkerInfo, WorkerInfos, WorkerRequest,
-        WorkerResponses,
+        HardStop, QueryCertificatesFilters, QueryMetricsOptions, QuerySessions, Request,
+        ResponseContent, ResponseStatus, RunState, SoftStop, Status, WorkerInfo, WorkerInfos,
+        WorkerRequest, WorkerResponses,
     },
 };
 use sozu_lib::metrics::METRICS;
@@ -83,6 +83,7 @@ impl Server {
                 query_clusters(self, client, request_type);
             }
             RequestType::QueryMetrics(inner) => query_metrics(self, client, inner),
+            RequestType::QuerySessions(_) => query_sessions(self, client),
             RequestType::SoftStop(_) => stop(self, client, false),
             RequestType::HardStop(_) => stop(self, client, true),
             RequestType::Logging(logging_filter) => set_logging_level(self, client, logging_filter),
@@ -321,6 +322,65 @@ impl GatheringTask for QueryClustersTask {
     }
 }
 
+//===============================================
+// Query sessions
+
+#[derive(Debug)]
+struct QuerySessionsTask {
+    client_token: Token,
+    gatherer: DefaultGatherer,
+}
+
+fn query_sessions(server: &mut Server, client: &mut ClientSession) {
+    client.return_processing("Querying sessions...");
+
+    server.scatter(
+        RequestType::QuerySessions(QuerySessions {}).into(),
+        Box::new(QuerySessionsTask {
+            client_token: client.token,
+            gatherer: DefaultGatherer::default(),
+        }),
+        Timeout::Default,
+        None,
+    )
+}
+
+impl GatheringTask for QuerySessionsTask {
+    fn client_token(&self) -> Option<Token> {
+        Some(self.client_token)
+    }
+
+    fn get_gatherer(&mut self) -> &mut dyn Gatherer {
+        &mut self.gatherer
+    }
+
+    fn on_finish(
+        self: Box<Self>,
+        _server: &mut Server,
+        client: &mut OptionalClient,
+        _timed_out: bool,
+    ) {
+        let worker_responses = self
+            .gatherer
+            .responses
+            .into_iter()
+            .filter_map(|(worker_id, proxy_response)| {
+                proxy_response
+                    .content
+                    .map(|response_content| (worker_id.to_string(), response_content))
+            })
+            .collect();
+
+        client.finish_ok_with_content(
+            ContentType::WorkerResponses(WorkerResponses {
+                map: worker_responses,
+            })
+            .into(),
+            "Successfully queried sessions",
+        );
+    }
+}
+
 //===============================================
 // Load static configuration
 
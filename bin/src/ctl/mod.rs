@@ -207,6 +207,7 @@ impl CommandManager {
             },
             SubCmd::Config { cmd: _ } => Ok(()), // noop, handled at the beginning of the method
             SubCmd::Events => self.events(),
+            SubCmd::Sessions => self.query_sessions(),
             rest => {
                 panic!("that command should have been handled earlier: {rest:x?}")
             }
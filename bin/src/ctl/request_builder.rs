@@ -9,8 +9,8 @@ use sozu_command_lib::{
         request::RequestType, ActivateListener, AddBackend, AddCertificate, Cluster, CountRequests,
         DeactivateListener, FrontendFilters, HardStop, ListListeners, ListenerType,
         LoadBalancingParams, MetricsConfiguration, PathRule, ProxyProtocolConfig,
-        QueryCertificatesFilters, QueryClusterByDomain, QueryClustersHashes, RemoveBackend,
-        RemoveCertificate, RemoveListener, ReplaceCertificate, RequestHttpFrontend,
+        QueryCertificatesFilters, QueryClusterByDomain, QueryClustersHashes, QuerySessions,
+        RemoveBackend, RemoveCertificate, RemoveListener, ReplaceCertificate, RequestHttpFrontend,
         RequestTcpFrontend, RulePosition, SocketAddress, SoftStop, Status, SubscribeEvents,
         TlsVersion,
     },
@@ -107,6 +107,10 @@ impl CommandManager {
         self.send_request_no_timeout(RequestType::SubscribeEvents(SubscribeEvents {}).into())
     }
 
+    pub fn query_sessions(&mut self) -> Result<(), CtlError> {
+        self.send_request(RequestType::QuerySessions(QuerySessions {}).into())
+    }
+
     pub fn backend_command(&mut self, cmd: BackendCmd) -> Result<(), CtlError> {
         match cmd {
             BackendCmd::Add {
@@ -618,3 +622,44 @@ impl CommandManager {
         self.send_request(RequestType::UpgradeWorker(worker_id).into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use sozu_command_lib::{
+        channel::Channel,
+        config::Config,
+        proto::command::{Request, Response, ResponseStatus},
+    };
+
+    use super::*;
+
+    #[test]
+    fn query_sessions_sends_a_query_sessions_request() {
+        let (channel, mut sozu): (Channel<Request, Response>, Channel<Response, Request>) =
+            Channel::generate(1000, 10000).expect("could not generate channels");
+        sozu.blocking().expect("could not block channel");
+        sozu.write_message(&Response {
+            status: ResponseStatus::Ok.into(),
+            ..Default::default()
+        })
+        .expect("could not write the response");
+
+        let mut command_manager = CommandManager {
+            channel,
+            timeout: Duration::from_secs(1),
+            config: Config::default(),
+            json: true,
+        };
+        command_manager
+            .query_sessions()
+            .expect("could not query the sessions");
+
+        let request = sozu.read_message().expect("could not read the request");
+        assert_eq!(
+            request,
+            Request::from(RequestType::QuerySessions(QuerySessions {}))
+        );
+    }
+}
@@ -61,12 +61,13 @@ use crate::{
     certificate::split_certificate_chain,
     logging::AccessLogFormat,
     proto::command::{
-        request::RequestType, ActivateListener, AddBackend, AddCertificate, CertificateAndKey,
-        Cluster, CustomHttpAnswers, HttpListenerConfig, HttpsListenerConfig, ListenerType,
-        LoadBalancingAlgorithms, LoadBalancingParams, LoadMetric, MetricsConfiguration, PathRule,
-        ProtobufAccessLogFormat, ProxyProtocolConfig, Request, RequestHttpFrontend,
-        RequestTcpFrontend, RulePosition, ServerConfig, ServerMetricsConfig, SocketAddress,
-        TcpListenerConfig, TlsVersion, WorkerRequest,
+        request::RequestType, ActivateListener, AddBackend, AddCertificate, BackendProtocol,
+        CertificateAndKey, Cluster, Compression, CustomHttpAnswers, ForwardedHeaders,
+        HttpListenerConfig, HttpsListenerConfig, ListenerType, LoadBalancingAlgorithms,
+        LoadBalancingParams, LoadMetric, MetricsConfiguration, PathRule, ProtobufAccessLogFormat,
+        ProxyProtocolConfig, Request, RequestHttpFrontend, RequestTcpFrontend, ResponseBuffering,
+        ResponseRewrite, RulePosition, ServerConfig, ServerMetricsConfig, SocketAddress,
+        TcpKeepalive, TcpListenerConfig, TlsVersion, WorkerRequest,
     },
     ObjectKind,
 };
@@ -246,6 +247,10 @@ pub struct ListenerBuilder {
     pub answer_404: Option<String>,
     pub answer_408: Option<String>,
     pub answer_413: Option<String>,
+    pub answer_414: Option<String>,
+    pub answer_421: Option<String>,
+    pub answer_431: Option<String>,
+    pub answer_501: Option<String>,
     pub answer_502: Option<String>,
     pub answer_503: Option<String>,
     pub answer_504: Option<String>,
@@ -267,6 +272,60 @@ pub struct ListenerBuilder {
     pub connect_timeout: Option<u32>,
     /// maximum time to receive a request since the connection started
     pub request_timeout: Option<u32>,
+    /// maximum time of inactivity for an upgraded connection (WebSocket), defaults to front_timeout
+    pub tunnel_idle_timeout: Option<u32>,
+    /// maximum lifetime of an upgraded connection (WebSocket), unlimited if absent or 0
+    pub tunnel_max_lifetime: Option<u32>,
+    /// reject with a 421 the requests whose host does not match the TLS SNI (HTTPS only)
+    pub strict_sni_binding: Option<bool>,
+    /// maximum number of backend connections of an HTTP/2 connection, unlimited if 0 (HTTPS only)
+    pub max_backend_connections_per_session: Option<u32>,
+    /// accept requests in absolute-form (forward proxy), rejected with a 400 otherwise
+    pub forward_proxy: Option<bool>,
+    /// TCP keepalive probes sent on the client sockets, disabled if absent
+    pub tcp_keepalive: Option<TcpKeepalive>,
+    /// value of an Alt-Svc header added to the responses, none if absent
+    pub alt_svc: Option<String>,
+    /// replace the Alt-Svc headers of the backends instead of adding to them
+    pub replace_alt_svc: Option<bool>,
+    /// largest request header section, in bytes as counted for SETTINGS_MAX_HEADER_LIST_SIZE
+    pub max_request_header_size: Option<u32>,
+    /// largest response header section, in bytes as counted for SETTINGS_MAX_HEADER_LIST_SIZE
+    pub max_response_header_size: Option<u32>,
+    /// HTTP/2 streams waiting for a slot over the concurrency limit, refused at once if 0 (HTTPS only)
+    pub h2_stream_queue_depth: Option<u32>,
+    /// time a queued HTTP/2 stream waits before being refused, in milliseconds (HTTPS only)
+    pub h2_stream_queue_timeout: Option<u32>,
+    /// HTTP/2 frames handled in one pass before yielding to the other sessions, no limit if 0 (HTTPS only)
+    pub h2_max_frames_per_read: Option<u32>,
+    /// time to receive the HTTP/2 client preface and SETTINGS, in seconds (HTTPS only)
+    pub h2_handshake_timeout: Option<u32>,
+    /// new HTTP/2 streams a connection may open per second, no limit if 0 (HTTPS only)
+    pub h2_max_new_streams_per_second: Option<u32>,
+    /// consumed share of the HTTP/2 receive windows given back, in percent (HTTPS only)
+    pub h2_window_update_threshold: Option<u32>,
+    /// cluster of the requests without Host header nor :authority, answered with a 400 if absent
+    pub no_host_cluster_id: Option<String>,
+    /// add X-Forwarded-TLS-* headers describing the TLS handshake of the client (HTTPS only)
+    pub forward_tls_headers: Option<bool>,
+    /// unforwarded request body bytes over which reading from the client pauses, in bytes
+    pub read_buffer_high_water_mark: Option<u32>,
+    /// longest request target, answered with a 414 above it, no limit if 0
+    pub max_uri_length: Option<u32>,
+    /// header carrying the id of each request to the backend and back, not sent if absent
+    pub request_id_header: Option<String>,
+    /// keep the id sent by the client in the request id header
+    pub trust_client_request_id: Option<bool>,
+    /// answer with a 400 the requests whose path has an encoded slash or backslash
+    pub reject_encoded_slashes: Option<bool>,
+    /// send the backends the normalized path used for routing instead of the client's
+    pub forward_normalized_path: Option<bool>,
+    /// serve without ALPN the clients offering only unsupported protocols, instead of
+    /// failing their TLS handshake
+    pub alpn_mismatch_fallback: Option<bool>,
+    /// seconds an HTTP/2 stream may keep response data unread before it is reset,
+    /// 0 disables it (HTTPS only)
+    pub h2_slow_read_timeout: Option<u32>,
     /// A [Config] to pull defaults from
     pub config: Option<Config>,
     /// Number of TLS 1.3 tickets to send to a client when establishing a connection.
@@ -308,6 +367,10 @@ impl ListenerBuilder {
             answer_404: None,
             answer_408: None,
             answer_413: None,
+            answer_414: None,
+            answer_421: None,
+            answer_431: None,
+            answer_501: None,
             answer_502: None,
             answer_503: None,
             answer_504: None,
@@ -328,6 +391,32 @@ impl ListenerBuilder {
             send_tls13_tickets: None,
             sticky_name: DEFAULT_STICKY_NAME.to_string(),
             tls_versions: None,
+            tunnel_idle_timeout: None,
+            tunnel_max_lifetime: None,
+            strict_sni_binding: None,
+            max_backend_connections_per_session: None,
+            forward_proxy: None,
+            tcp_keepalive: None,
+            alt_svc: None,
+            replace_alt_svc: None,
+            max_request_header_size: None,
+            max_response_header_size: None,
+            h2_stream_queue_depth: None,
+            h2_stream_queue_timeout: None,
+            h2_max_frames_per_read: None,
+            h2_handshake_timeout: None,
+            h2_max_new_streams_per_second: None,
+            h2_window_update_threshold: None,
+            no_host_cluster_id: None,
+            forward_tls_headers: None,
+            read_buffer_high_water_mark: None,
+            max_uri_length: None,
+            request_id_header: None,
+            trust_client_request_id: None,
+            reject_encoded_slashes: None,
+            forward_normalized_path: None,
+            alpn_mismatch_fallback: None,
+            h2_slow_read_timeout: None,
         }
     }
 
@@ -429,6 +518,168 @@ impl ListenerBuilder {
         self
     }
 
+    pub fn with_tunnel_idle_timeout(&mut self, tunnel_idle_timeout: Option<u32>) -> &mut Self {
+        self.tunnel_idle_timeout = tunnel_idle_timeout;
+        self
+    }
+
+    pub fn with_tunnel_max_lifetime(&mut self, tunnel_max_lifetime: Option<u32>) -> &mut Self {
+        self.tunnel_max_lifetime = tunnel_max_lifetime;
+        self
+    }
+
+    pub fn with_strict_sni_binding(&mut self, strict_sni_binding: Option<bool>) -> &mut Self {
+        self.strict_sni_binding = strict_sni_binding;
+        self
+    }
+
+    pub fn with_forward_proxy(&mut self, forward_proxy: Option<bool>) -> &mut Self {
+        self.forward_proxy = forward_proxy;
+        self
+    }
+
+    pub fn with_tcp_keepalive(&mut self, tcp_keepalive: Option<TcpKeepalive>) -> &mut Self {
+        self.tcp_keepalive = tcp_keepalive;
+        self
+    }
+
+    pub fn with_alt_svc<S>(&mut self, alt_svc: Option<S>) -> &mut Self
+    where
+        S: ToString,
+    {
+        self.alt_svc = alt_svc.map(|alt_svc| alt_svc.to_string());
+        self
+    }
+
+    pub fn with_replace_alt_svc(&mut self, replace_alt_svc: Option<bool>) -> &mut Self {
+        self.replace_alt_svc = replace_alt_svc;
+        self
+    }
+
+    pub fn with_max_header_sizes(
+        &mut self,
+        max_request_header_size: Option<u32>,
+        max_response_header_size: Option<u32>,
+    ) -> &mut Self {
+        self.max_request_header_size = max_request_header_size;
+        self.max_response_header_size = max_response_header_size;
+        self
+    }
+
+    pub fn with_h2_stream_queue(&mut self, depth: Option<u32>, timeout: Option<u32>) -> &mut Self {
+        self.h2_stream_queue_depth = depth;
+        self.h2_stream_queue_timeout = timeout;
+        self
+    }
+
+    pub fn with_h2_max_frames_per_read(
+        &mut self,
+        h2_max_frames_per_read: Option<u32>,
+    ) -> &mut Self {
+        self.h2_max_frames_per_read = h2_max_frames_per_read;
+        self
+    }
+
+    pub fn with_h2_handshake_timeout(&mut self, h2_handshake_timeout: Option<u32>) -> &mut Self {
+        self.h2_handshake_timeout = h2_handshake_timeout;
+        self
+    }
+
+    pub fn with_h2_max_new_streams_per_second(
+        &mut self,
+        h2_max_new_streams_per_second: Option<u32>,
+    ) -> &mut Self {
+        self.h2_max_new_streams_per_second = h2_max_new_streams_per_second;
+        self
+    }
+
+    pub fn with_h2_window_update_threshold(
+        &mut self,
+        h2_window_update_threshold: Option<u32>,
+    ) -> &mut Self {
+        self.h2_window_update_threshold = h2_window_update_threshold;
+        self
+    }
+
+    pub fn with_no_host_cluster_id<S>(&mut self, no_host_cluster_id: Option<S>) -> &mut Self
+    where
+        S: ToString,
+    {
+        self.no_host_cluster_id = no_host_cluster_id.map(|cluster_id| cluster_id.to_string());
+        self
+    }
+
+    pub fn with_forward_tls_headers(&mut self, forward_tls_headers: Option<bool>) -> &mut Self {
+        self.forward_tls_headers = forward_tls_headers;
+        self
+    }
+
+    pub fn with_read_buffer_high_water_mark(
+        &mut self,
+        read_buffer_high_water_mark: Option<u32>,
+    ) -> &mut Self {
+        self.read_buffer_high_water_mark = read_buffer_high_water_mark;
+        self
+    }
+
+    pub fn with_max_uri_length(&mut self, max_uri_length: Option<u32>) -> &mut Self {
+        self.max_uri_length = max_uri_length;
+        self
+    }
+
+    pub fn with_request_id_header<S>(&mut self, request_id_header: Option<S>) -> &mut Self
+    where
+        S: ToString,
+    {
+        self.request_id_header = request_id_header.map(|header| header.to_string());
+        self
+    }
+
+    pub fn with_trust_client_request_id(
+        &mut self,
+        trust_client_request_id: Option<bool>,
+    ) -> &mut Self {
+        self.trust_client_request_id = trust_client_request_id;
+        self
+    }
+
+    pub fn with_reject_encoded_slashes(
+        &mut self,
+        reject_encoded_slashes: Option<bool>,
+    ) -> &mut Self {
+        self.reject_encoded_slashes = reject_encoded_slashes;
+        self
+    }
+
+    pub fn with_forward_normalized_path(
+        &mut self,
+        forward_normalized_path: Option<bool>,
+    ) -> &mut Self {
+        self.forward_normalized_path = forward_normalized_path;
+        self
+    }
+
+    pub fn with_alpn_mismatch_fallback(
+        &mut self,
+        alpn_mismatch_fallback: Option<bool>,
+    ) -> &mut Self {
+        self.alpn_mismatch_fallback = alpn_mismatch_fallback;
+        self
+    }
+
+    pub fn with_h2_slow_read_timeout(&mut self, h2_slow_read_timeout: Option<u32>) -> &mut Self {
+        self.h2_slow_read_timeout = h2_slow_read_timeout;
+        self
+    }
+
+    pub fn with_max_backend_connections_per_session(
+        &mut self,
+        max_backend_connections_per_session: Option<u32>,
+    ) -> &mut Self {
+        self.max_backend_connections_per_session = max_backend_connections_per_session;
+        self
+    }
+
     /// Get the custom HTTP answers from the file system using the provided paths
     fn get_http_answers(&self) -> Result<Option<CustomHttpAnswers>, ConfigError> {
         let http_answers = CustomHttpAnswers {
@@ -438,6 +689,10 @@ impl ListenerBuilder {
             answer_404: read_http_answer_file(&self.answer_404)?,
             answer_408: read_http_answer_file(&self.answer_408)?,
             answer_413: read_http_answer_file(&self.answer_413)?,
+            answer_414: read_http_answer_file(&self.answer_414)?,
+            answer_421: read_http_answer_file(&self.answer_421)?,
+            answer_431: read_http_answer_file(&self.answer_431)?,
+            answer_501: read_http_answer_file(&self.answer_501)?,
             answer_502: read_http_answer_file(&self.answer_502)?,
             answer_503: read_http_answer_file(&self.answer_503)?,
             answer_504: read_http_answer_file(&self.answer_504)?,
@@ -479,6 +734,21 @@ impl ListenerBuilder {
             connect_timeout: self.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT),
             request_timeout: self.request_timeout.unwrap_or(DEFAULT_REQUEST_TIMEOUT),
             http_answers,
+            tunnel_idle_timeout: self.tunnel_idle_timeout,
+            tunnel_max_lifetime: self.tunnel_max_lifetime,
+            forward_proxy: self.forward_proxy,
+            tcp_keepalive: self.tcp_keepalive,
+            alt_svc: self.alt_svc.clone(),
+            replace_alt_svc: self.replace_alt_svc,
+            max_request_header_size: self.max_request_header_size,
+            max_response_header_size: self.max_response_header_size,
+            no_host_cluster_id: self.no_host_cluster_id.clone(),
+            read_buffer_high_water_mark: self.read_buffer_high_water_mark,
+            max_uri_length: self.max_uri_length,
+            request_id_header: self.request_id_header.clone(),
+            trust_client_request_id: self.trust_client_request_id,
+            reject_encoded_slashes: self.reject_encoded_slashes,
+            forward_normalized_path: self.forward_normalized_path,
             ..Default::default()
         };
 
@@ -578,6 +848,32 @@ impl ListenerBuilder {
                 .send_tls13_tickets
                 .unwrap_or(DEFAULT_SEND_TLS_13_TICKETS),
             http_answers,
+            tunnel_idle_timeout: self.tunnel_idle_timeout,
+            tunnel_max_lifetime: self.tunnel_max_lifetime,
+            strict_sni_binding: self.strict_sni_binding,
+            max_backend_connections_per_session: self.max_backend_connections_per_session,
+            forward_proxy: self.forward_proxy,
+            tcp_keepalive: self.tcp_keepalive,
+            alt_svc: self.alt_svc.clone(),
+            replace_alt_svc: self.replace_alt_svc,
+            max_request_header_size: self.max_request_header_size,
+            max_response_header_size: self.max_response_header_size,
+            h2_stream_queue_depth: self.h2_stream_queue_depth,
+            h2_stream_queue_timeout: self.h2_stream_queue_timeout,
+            h2_max_frames_per_read: self.h2_max_frames_per_read,
+            h2_handshake_timeout: self.h2_handshake_timeout,
+            h2_max_new_streams_per_second: self.h2_max_new_streams_per_second,
+            h2_window_update_threshold: self.h2_window_update_threshold,
+            no_host_cluster_id: self.no_host_cluster_id.clone(),
+            forward_tls_headers: self.forward_tls_headers,
+            read_buffer_high_water_mark: self.read_buffer_high_water_mark,
+            max_uri_length: self.max_uri_length,
+            request_id_header: self.request_id_header.clone(),
+            trust_client_request_id: self.trust_client_request_id,
+            reject_encoded_slashes: self.reject_encoded_slashes,
+            forward_normalized_path: self.forward_normalized_path,
+            alpn_mismatch_fallback: self.alpn_mismatch_fallback,
+            h2_slow_read_timeout: self.h2_slow_read_timeout,
         };
 
         Ok(https_listener_config)
@@ -786,6 +1082,53 @@ pub struct FileClusterConfig {
     pub answer_503: Option<String>,
     #[serde(default)]
     pub load_metric: Option<LoadMetric>,
+    #[serde(default)]
+    pub tcp_nodelay: Option<bool>,
+    /// id of a cluster receiving a copy of the requests, for HTTP clusters
+    #[serde(default)]
+    pub mirror_cluster: Option<String>,
+    /// percentage of the requests copied to the mirror cluster, defaults to 100
+    #[serde(default)]
+    pub mirror_percentage: Option<u32>,
+    /// add a Server-Timing header to the responses of HTTP clusters
+    #[serde(default)]
+    pub server_timing: Option<bool>,
+    /// keep the case of the Host and Cookie header names written by the client
+    #[serde(default)]
+    pub preserve_header_case: Option<bool>,
+    /// names of the request trailers forwarded to the backends
+    #[serde(default)]
+    pub allowed_request_trailers: Option<Vec<String>>,
+    /// TCP keepalive probes sent on the backend sockets, disabled if absent
+    #[serde(default)]
+    pub tcp_keepalive: Option<TcpKeepalive>,
+    /// compress the responses of HTTP clusters when the client accepts it, disabled if absent
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    /// protocol of the connections to the backends of HTTP clusters, H1 if absent
+    #[serde(default)]
+    pub backend_protocol: Option<BackendProtocol>,
+    /// rewrite the Location and Set-Cookie headers of HTTP clusters, disabled if absent
+    #[serde(default)]
+    pub response_rewrite: Option<ResponseRewrite>,
+    /// forwarding headers added to the requests of HTTP clusters, all of them if absent
+    #[serde(default)]
+    pub forwarded_headers: Option<ForwardedHeaders>,
+    /// whether the responses of HTTP clusters are streamed or sent once complete, streamed if absent
+    #[serde(default)]
+    pub response_buffering: Option<ResponseBuffering>,
+    /// largest response body kept until complete, the buffer size if absent
+    #[serde(default)]
+    pub response_buffering_max_size: Option<u32>,
+    /// requests waiting for a backend while all are down, none if absent
+    #[serde(default)]
+    pub backend_queue_depth: Option<u32>,
+    /// time a request waits for a backend before a 503, in milliseconds
+    #[serde(default)]
+    pub backend_queue_timeout: Option<u32>,
+    /// Retry-After header of the 503 answers, in seconds or as an HTTP-date
+    #[serde(default)]
+    pub retry_after: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -854,6 +1197,8 @@ impl FileClusterConfig {
                     proxy_protocol,
                     load_balancing: self.load_balancing,
                     load_metric: self.load_metric,
+                    tcp_nodelay: self.tcp_nodelay,
+                    tcp_keepalive: self.tcp_keepalive,
                 }))
             }
             FileClusterProtocolConfig::Http => {
@@ -872,15 +1217,37 @@ impl FileClusterConfig {
                         .ok()
                 });
 
+                let proxy_protocol = match self.send_proxy {
+                    Some(true) => Some(ProxyProtocolConfig::SendHeader),
+                    _ => None,
+                };
+
                 Ok(ClusterConfig::Http(HttpClusterConfig {
                     cluster_id: cluster_id.to_string(),
                     frontends,
                     backends: self.backends,
                     sticky_session: self.sticky_session.unwrap_or(false),
                     https_redirect: self.https_redirect.unwrap_or(false),
+                    proxy_protocol,
                     load_balancing: self.load_balancing,
                     load_metric: self.load_metric,
                     answer_503,
+                    tcp_nodelay: self.tcp_nodelay,
+                    mirror_cluster: self.mirror_cluster,
+                    mirror_percentage: self.mirror_percentage,
+                    server_timing: self.server_timing,
+                    preserve_header_case: self.preserve_header_case,
+                    allowed_request_trailers: self.allowed_request_trailers.unwrap_or_default(),
+                    tcp_keepalive: self.tcp_keepalive,
+                    compression: self.compression,
+                    backend_protocol: self.backend_protocol,
+                    response_rewrite: self.response_rewrite,
+                    forwarded_headers: self.forwarded_headers,
+                    response_buffering: self.response_buffering,
+                    response_buffering_max_size: self.response_buffering_max_size,
+                    backend_queue_depth: self.backend_queue_depth,
+                    backend_queue_timeout: self.backend_queue_timeout,
+                    retry_after: self.retry_after,
                 }))
             }
         }
@@ -970,9 +1337,42 @@ pub struct HttpClusterConfig {
     pub backends: Vec<BackendConfig>,
     pub sticky_session: bool,
     pub https_redirect: bool,
+    #[serde(default)]
+    pub proxy_protocol: Option<ProxyProtocolConfig>,
     pub load_balancing: LoadBalancingAlgorithms,
     pub load_metric: Option<LoadMetric>,
     pub answer_503: Option<String>,
+    pub tcp_nodelay: Option<bool>,
+    #[serde(default)]
+    pub mirror_cluster: Option<String>,
+    #[serde(default)]
+    pub mirror_percentage: Option<u32>,
+    #[serde(default)]
+    pub server_timing: Option<bool>,
+    #[serde(default)]
+    pub preserve_header_case: Option<bool>,
+    #[serde(default)]
+    pub allowed_request_trailers: Vec<String>,
+    #[serde(default)]
+    pub tcp_keepalive: Option<TcpKeepalive>,
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    #[serde(default)]
+    pub backend_protocol: Option<BackendProtocol>,
+    #[serde(default)]
+    pub response_rewrite: Option<ResponseRewrite>,
+    #[serde(default)]
+    pub forwarded_headers: Option<ForwardedHeaders>,
+    #[serde(default)]
+    pub response_buffering: Option<ResponseBuffering>,
+    #[serde(default)]
+    pub response_buffering_max_size: Option<u32>,
+    #[serde(default)]
+    pub backend_queue_depth: Option<u32>,
+    #[serde(default)]
+    pub backend_queue_timeout: Option<u32>,
+    #[serde(default)]
+    pub retry_after: Option<String>,
 }
 
 impl HttpClusterConfig {
@@ -981,10 +1381,26 @@ impl HttpClusterConfig {
             cluster_id: self.cluster_id.clone(),
             sticky_session: self.sticky_session,
             https_redirect: self.https_redirect,
-            proxy_protocol: None,
+            proxy_protocol: self.proxy_protocol.map(|s| s as i32),
             load_balancing: self.load_balancing as i32,
             answer_503: self.answer_503.clone(),
             load_metric: self.load_metric.map(|s| s as i32),
+            tcp_nodelay: self.tcp_nodelay,
+            mirror_cluster: self.mirror_cluster.clone(),
+            mirror_percentage: self.mirror_percentage,
+            server_timing: self.server_timing,
+            preserve_header_case: self.preserve_header_case,
+            allowed_request_trailers: self.allowed_request_trailers.clone(),
+            tcp_keepalive: self.tcp_keepalive,
+            compression: self.compression.clone(),
+            backend_protocol: self.backend_protocol.map(|p| p as i32),
+            response_rewrite: self.response_rewrite.clone(),
+            forwarded_headers: self.forwarded_headers.map(|h| h as i32),
+            response_buffering: self.response_buffering.map(|b| b as i32),
+            response_buffering_max_size: self.response_buffering_max_size,
+            backend_queue_depth: self.backend_queue_depth,
+            backend_queue_timeout: self.backend_queue_timeout,
+            retry_after: self.retry_after.clone(),
         })
         .into()];
 
@@ -1032,6 +1448,10 @@ pub struct TcpClusterConfig {
     pub proxy_protocol: Option<ProxyProtocolConfig>,
     pub load_balancing: LoadBalancingAlgorithms,
     pub load_metric: Option<LoadMetric>,
+    #[serde(default)]
+    pub tcp_nodelay: Option<bool>,
+    #[serde(default)]
+    pub tcp_keepalive: Option<TcpKeepalive>,
 }
 
 impl TcpClusterConfig {
@@ -1044,6 +1464,22 @@ impl TcpClusterConfig {
             load_balancing: self.load_balancing as i32,
             load_metric: self.load_metric.map(|s| s as i32),
             answer_503: None,
+            tcp_nodelay: self.tcp_nodelay,
+            mirror_cluster: None,
+            mirror_percentage: None,
+            server_timing: None,
+            preserve_header_case: None,
+            allowed_request_trailers: Vec::new(),
+            tcp_keepalive: self.tcp_keepalive,
+            compression: None,
+            backend_protocol: None,
+            response_rewrite: None,
+            forwarded_headers: None,
+            response_buffering: None,
+            response_buffering_max_size: None,
+            backend_queue_depth: None,
+            backend_queue_timeout: None,
+            retry_after: None,
         })
         .into()];
 
@@ -1912,4 +2348,27 @@ mod tests {
         println!("config: {config:#?}");
         //panic!();
     }
+
+    #[test]
+    fn cluster_tcp_nodelay() {
+        for protocol in ["http", "tcp"] {
+            for (option, expected) in [("", true), ("tcp_nodelay = false", false)] {
+                let cluster: FileClusterConfig = toml::from_str(&format!(
+                    "protocol = \"{protocol}\"\nfrontends = []\nbackends = []\n{option}"
+                ))
+                .unwrap();
+                let requests = cluster
+                    .to_cluster_config("cluster_0", &HashSet::new())
+                    .unwrap()
+                    .generate_requests()
+                    .unwrap();
+                match &requests[0].request_type {
+                    Some(RequestType::AddCluster(cluster)) => {
+                        assert_eq!(cluster.tcp_nodelay(), expected, "{protocol} {option:?}")
+                    }
+                    other => panic!("expected AddCluster, got {other:?}"),
+                }
+            }
+        }
+    }
 }
@@ -96,6 +96,19 @@ impl CachedTags {
 pub struct FullTags<'a> {
     pub concatenated: Option<&'a str>,
     pub user_agent: Option<&'a str>,
+    pub tls_cipher: Option<&'a str>,
+    pub tls_server_name: Option<&'a str>,
+    pub request_id: Option<&'a str>,
+}
+
+/// What the TLS handshake of the client negotiated
+#[derive(Debug)]
+pub struct TlsRecord<'a> {
+    /// for instance "TLSv1.3"
+    pub version: &'a str,
+    pub cipher: Option<&'a str>,
+    /// server name sent by the client (SNI)
+    pub server_name: Option<&'a str>,
 }
 
 /// Intermediate representation of an access log agnostic of the final format.
@@ -111,6 +124,10 @@ pub struct RequestRecord<'a> {
     pub client_rtt: Option<Duration>,
     pub server_rtt: Option<Duration>,
     pub user_agent: Option<&'a str>,
+    /// id of the request sent to the backend in the request id header of the listener
+    pub request_id: Option<&'a str>,
+    /// none on plain text connections
+    pub tls: Option<TlsRecord<'a>>,
     pub service_time: Duration,
     /// time from connecting to the backend until the end of the response
     pub response_time: Option<Duration>,
@@ -132,6 +149,9 @@ impl RequestRecord<'_> {
         FullTags {
             concatenated: self.tags.as_ref().map(|t| t.concatenated.as_str()),
             user_agent: self.user_agent,
+            tls_cipher: self.tls.as_ref().and_then(|tls| tls.cipher),
+            tls_server_name: self.tls.as_ref().and_then(|tls| tls.server_name),
+            request_id: self.request_id,
         }
     }
 
@@ -182,6 +202,14 @@ impl RequestRecord<'_> {
                 tag: self.tag.duplicate(),
                 time: self.precise_time.into(),
                 request_time: Some(self.request_time.as_micros() as u64),
+                tls_version: self.tls.as_ref().map(|tls| tls.version).duplicate(),
+                tls_cipher: self.tls.as_ref().and_then(|tls| tls.cipher).duplicate(),
+                tls_server_name: self
+                    .tls
+                    .as_ref()
+                    .and_then(|tls| tls.server_name)
+                    .duplicate(),
+                forwarded_request_id: self.request_id.duplicate(),
             })
         }
     }
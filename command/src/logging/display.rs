@@ -148,14 +148,27 @@ fn display_status(status: Option<u16>, pretty: bool) -> String {
 
 impl<'a> fmt::Display for FullTags<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match (self.concatenated, self.user_agent) {
-            (None, None) => Ok(()),
-            (Some(tags), None) => write!(f, "{tags}"),
-            (Some(tags), Some(ua)) if !tags.is_empty() => {
-                write!(f, "{tags}, user-agent={}", prepare_user_agent(ua))
-            }
-            (_, Some(ua)) => write!(f, "user-agent={}", prepare_user_agent(ua)),
+        let mut separator = "";
+        if let Some(tags) = self.concatenated.filter(|tags| !tags.is_empty()) {
+            write!(f, "{tags}")?;
+            separator = ", ";
+        }
+        if let Some(ua) = self.user_agent {
+            write!(f, "{separator}user-agent={}", prepare_user_agent(ua))?;
+            separator = ", ";
+        }
+        if let Some(cipher) = self.tls_cipher {
+            write!(f, "{separator}tls-cipher={cipher}")?;
+            separator = ", ";
+        }
+        if let Some(server_name) = self.tls_server_name {
+            write!(f, "{separator}sni={server_name}")?;
+            separator = ", ";
+        }
+        if let Some(request_id) = self.request_id {
+            write!(f, "{separator}request-id={request_id}")?;
         }
+        Ok(())
     }
 }
 
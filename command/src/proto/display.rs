@@ -17,8 +17,8 @@ use crate::{
             Event, EventKind, FilteredMetrics, HttpEndpoint, HttpListenerConfig,
             HttpsListenerConfig, ListOfCertificatesByAddress, ListedFrontends, ListenersList,
             ProtobufEndpoint, QueryCertificatesFilters, RequestCounts, Response, ResponseContent,
-            ResponseStatus, RunState, SocketAddress, TlsVersion, WorkerInfos, WorkerMetrics,
-            WorkerResponses,
+            ResponseStatus, RunState, SessionSnapshots, SocketAddress, TcpKeepalive, TlsVersion,
+            WorkerInfos, WorkerMetrics, WorkerResponses,
         },
         DisplayError,
     },
@@ -111,6 +111,7 @@ pub fn format_request_type(request_type: &RequestType) -> &str {
         RequestType::ReturnListenSockets(_) => "ReturnListenSockets",
         RequestType::QueryCertificatesFromTheState(_) => "QueryCertificatesFromTheState",
         RequestType::QueryCertificatesFromWorkers(_) => "QueryCertificatesFromWorkers",
+        RequestType::QuerySessions(_) => "QuerySessions",
     }
 }
 
@@ -170,6 +171,7 @@ impl ResponseContent {
             ContentType::WorkerMetrics(worker_metrics) => print_worker_metrics(worker_metrics),
             ContentType::AvailableMetrics(list) => print_available_metrics(list),
             ContentType::RequestCounts(request_counts) => print_request_counts(request_counts),
+            ContentType::SessionSnapshots(snapshots) => print_session_snapshots(snapshots),
             ContentType::CertificatesWithFingerprints(certs) => {
                 print_certificates_with_validity(certs)
             }
@@ -925,6 +927,57 @@ fn print_request_counts(request_counts: &RequestCounts) -> Result<(), DisplayErr
     Ok(())
 }
 
+fn print_session_snapshots(snapshots: &SessionSnapshots) -> Result<(), DisplayError> {
+    if snapshots.sessions.is_empty() {
+        println!("No sessions");
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
+    table.add_row(row![
+        "protocol",
+        "token",
+        "request id",
+        "cluster",
+        "backend",
+        "bytes in",
+        "bytes out",
+        "streams",
+    ]);
+
+    for session in &snapshots.sessions {
+        let token = session
+            .frontend_token
+            .map(|token| token.to_string())
+            .unwrap_or_default();
+        let streams: Vec<String> = session
+            .streams
+            .iter()
+            .map(|stream| {
+                format!(
+                    "{} {} {}",
+                    stream.id,
+                    stream.state,
+                    stream.path.as_deref().unwrap_or_default()
+                )
+            })
+            .collect();
+        table.add_row(row!(
+            session.protocol,
+            token,
+            session.request_id.as_deref().unwrap_or_default(),
+            session.cluster_id.as_deref().unwrap_or_default(),
+            session.backend_id.as_deref().unwrap_or_default(),
+            session.bytes_in.unwrap_or_default(),
+            session.bytes_out.unwrap_or_default(),
+            list_string_vec(&streams),
+        ));
+    }
+    table.printstd();
+    Ok(())
+}
+
 fn format_tags_to_string(tags: &BTreeMap<String, String>) -> String {
     tags.iter()
         .map(|(k, v)| format!("{k}={v}"))
@@ -1005,6 +1058,18 @@ impl Display for ProtobufEndpoint {
     }
 }
 
+fn display_keepalive(keepalive: &Option<TcpKeepalive>) -> String {
+    match keepalive {
+        Some(keepalive) => format!(
+            "idle {}s, interval {}s, {} probes",
+            keepalive.idle(),
+            keepalive.interval(),
+            keepalive.count()
+        ),
+        None => "disabled".to_owned(),
+    }
+}
+
 impl Display for HttpListenerConfig {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let mut table = Table::new();
@@ -1020,6 +1085,51 @@ impl Display for HttpListenerConfig {
         table.add_row(row!["back timeout", self.back_timeout]);
         table.add_row(row!["connect timeout", self.connect_timeout]);
         table.add_row(row!["request timeout", self.request_timeout]);
+        table.add_row(row![
+            "tunnel idle timeout",
+            format!("{:?}", self.tunnel_idle_timeout)
+        ]);
+        table.add_row(row!["tunnel max lifetime", self.tunnel_max_lifetime()]);
+        table.add_row(row!["forward proxy", self.forward_proxy()]);
+        table.add_row(row![
+            "TCP keepalive",
+            display_keepalive(&self.tcp_keepalive)
+        ]);
+        table.add_row(row!["Alt-Svc", format!("{:?}", self.alt_svc)]);
+        table.add_row(row!["replace Alt-Svc", self.replace_alt_svc()]);
+        table.add_row(row![
+            "max request header size",
+            format!("{:?}", self.max_request_header_size)
+        ]);
+        table.add_row(row![
+            "max response header size",
+            format!("{:?}", self.max_response_header_size)
+        ]);
+        table.add_row(row![
+            "cluster of requests without host",
+            format!("{:?}", self.no_host_cluster_id)
+        ]);
+        table.add_row(row![
+            "read buffer high-water mark",
+            format!("{:?}", self.read_buffer_high_water_mark)
+        ]);
+        table.add_row(row!["max URI length", self.max_uri_length()]);
+        table.add_row(row![
+            "request id header",
+            format!("{:?}", self.request_id_header)
+        ]);
+        table.add_row(row![
+            "trust client request id",
+            self.trust_client_request_id()
+        ]);
+        table.add_row(row![
+            "reject encoded slashes",
+            self.reject_encoded_slashes()
+        ]);
+        table.add_row(row![
+            "forward normalized path",
+            self.forward_normalized_path()
+        ]);
         table.add_row(row!["activated", self.active]);
         write!(f, "{}", table)
     }
@@ -1054,6 +1164,89 @@ impl Display for HttpsListenerConfig {
         table.add_row(row!["back timeout", self.back_timeout]);
         table.add_row(row!["connect timeout", self.connect_timeout]);
         table.add_row(row!["request timeout", self.request_timeout]);
+        table.add_row(row![
+            "tunnel idle timeout",
+            format!("{:?}", self.tunnel_idle_timeout)
+        ]);
+        table.add_row(row!["tunnel max lifetime", self.tunnel_max_lifetime()]);
+        table.add_row(row!["strict SNI binding", self.strict_sni_binding()]);
+        table.add_row(row!["forward proxy", self.forward_proxy()]);
+        table.add_row(row![
+            "TCP keepalive",
+            display_keepalive(&self.tcp_keepalive)
+        ]);
+        table.add_row(row!["Alt-Svc", format!("{:?}", self.alt_svc)]);
+        table.add_row(row!["replace Alt-Svc", self.replace_alt_svc()]);
+        table.add_row(row![
+            "max request header size",
+            format!("{:?}", self.max_request_header_size)
+        ]);
+        table.add_row(row![
+            "max response header size",
+            format!("{:?}", self.max_response_header_size)
+        ]);
+        table.add_row(row![
+            "cluster of requests without host",
+            format!("{:?}", self.no_host_cluster_id)
+        ]);
+        table.add_row(row![
+            "read buffer high-water mark",
+            format!("{:?}", self.read_buffer_high_water_mark)
+        ]);
+        table.add_row(row!["forward TLS headers", self.forward_tls_headers()]);
+        table.add_row(row![
+            "ALPN mismatch fallback",
+            self.alpn_mismatch_fallback()
+        ]);
+        table.add_row(row![
+            "max backend connections per session",
+            self.max_backend_connections_per_session()
+        ]);
+        table.add_row(row![
+            "HTTP/2 stream queue depth",
+            self.h2_stream_queue_depth()
+        ]);
+        table.add_row(row![
+            "HTTP/2 stream queue timeout (ms)",
+            self.h2_stream_queue_timeout()
+        ]);
+        table.add_row(row![
+            "HTTP/2 max frames per read",
+            self.h2_max_frames_per_read()
+        ]);
+        table.add_row(row![
+            "HTTP/2 handshake timeout",
+            self.h2_handshake_timeout()
+        ]);
+        table.add_row(row![
+            "HTTP/2 max new streams per second",
+            self.h2_max_new_streams_per_second()
+        ]);
+        table.add_row(row![
+            "HTTP/2 window update threshold",
+            self.h2_window_update_threshold()
+        ]);
+        table.add_row(row![
+            "HTTP/2 slow read timeout",
+            self.h2_slow_read_timeout()
+        ]);
+        table.add_row(row!["max URI length", self.max_uri_length()]);
+        table.add_row(row![
+            "request id header",
+            format!("{:?}", self.request_id_header)
+        ]);
+        table.add_row(row![
+            "trust client request id",
+            self.trust_client_request_id()
+        ]);
+        table.add_row(row![
+            "reject encoded slashes",
+            self.reject_encoded_slashes()
+        ]);
+        table.add_row(row![
+            "forward normalized path",
+            self.forward_normalized_path()
+        ]);
         table.add_row(row!["activated", self.active]);
         write!(f, "{}", table)
     }
@@ -1078,6 +1271,18 @@ impl CustomHttpAnswers {
             if let Some(a) = &answers.answer_413 {
                 rows.push(row!("413", a));
             }
+            if let Some(a) = &answers.answer_414 {
+                rows.push(row!("414", a));
+            }
+            if let Some(a) = &answers.answer_421 {
+                rows.push(row!("421", a));
+            }
+            if let Some(a) = &answers.answer_431 {
+                rows.push(row!("431", a));
+            }
+            if let Some(a) = &answers.answer_501 {
+                rows.push(row!("501", a));
+            }
             if let Some(a) = &answers.answer_502 {
                 rows.push(row!("502", a));
             }
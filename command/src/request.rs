@@ -79,7 +79,8 @@ impl Request {
             | RequestType::Logging(_)
             | RequestType::QueryClustersHashes(_)
             | RequestType::QueryClusterById(_)
-            | RequestType::QueryClustersByDomain(_) => {}
+            | RequestType::QueryClustersByDomain(_)
+            | RequestType::QuerySessions(_) => {}
 
             // the Add***Listener and other Listener orders will be handled separately
             // by the notify_proxys function, so we don't give them destinations
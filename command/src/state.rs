@@ -133,6 +133,7 @@ impl ConfigState {
             | RequestType::QueryClustersByDomain(_)
             | RequestType::QueryMetrics(_)
             | RequestType::QueryClustersHashes(_)
+            | RequestType::QuerySessions(_)
             | RequestType::ConfigureMetrics(_)
             | RequestType::ReturnListenSockets(_)
             | RequestType::HardStop(_) => Ok(()),
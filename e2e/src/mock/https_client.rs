@@ -1,4 +1,9 @@
-use std::{sync::Arc, time::SystemTime};
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use hyper::{
     client::{connect::dns::GaiResolver, HttpConnector, ResponseFuture},
@@ -7,7 +12,7 @@ use hyper::{
 use hyper_rustls::HttpsConnector;
 use rustls::{
     client::{ClientConfig, ServerCertVerified, ServerCertVerifier},
-    Certificate, ServerName,
+    Certificate, ClientConnection, ServerName, StreamOwned,
 };
 
 // We implement our own verifier to allow self-signed certificates
@@ -45,6 +50,56 @@ pub fn build_https_client() -> hyper::Client<HttpsConnector<HttpConnector<GaiRes
     hyper::Client::builder().build::<_, hyper::Body>(https)
 }
 
+/// Sends a raw HTTP/1.1 request over TLS, offering the given ALPN protocols, and
+/// returns what the server answered before closing, None if the handshake failed
+pub fn tls_request_with_alpn(
+    address: SocketAddr,
+    server_name: &str,
+    alpn_protocols: &[&[u8]],
+    request: &str,
+) -> Option<String> {
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(Verifier))
+        .with_no_client_auth();
+    config.alpn_protocols = alpn_protocols
+        .iter()
+        .map(|protocol| protocol.to_vec())
+        .collect();
+
+    let connection = ClientConnection::new(
+        Arc::new(config),
+        ServerName::try_from(server_name).expect("invalid server name"),
+    )
+    .expect("Could not create TLS client");
+    let socket = TcpStream::connect(address).expect("Could not connect");
+    socket
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .expect("Could not set read timeout");
+    let mut stream = StreamOwned::new(connection, socket);
+
+    if let Err(error) = stream.write_all(request.as_bytes()) {
+        println!("Could not send request: {error}");
+        return None;
+    }
+    let mut response = Vec::new();
+    let mut buffer = [0; 4096];
+    loop {
+        match stream.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(size) => response.extend_from_slice(&buffer[..size]),
+            Err(error) => {
+                println!("Could not read response: {error}");
+                break;
+            }
+        }
+    }
+    if response.is_empty() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&response).into_owned())
+}
+
 /// Sends the request, awaits the response,
 /// returns the status code and body in case of success
 pub fn resolve_request(request: ResponseFuture) -> Option<(StatusCode, String)> {
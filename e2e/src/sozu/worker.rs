@@ -15,9 +15,10 @@ use sozu_command::{
     config::{ConfigBuilder, FileConfig},
     logging::setup_default_logging,
     proto::command::{
-        request::RequestType, AddBackend, Cluster, HardStop, LoadBalancingParams, PathRule,
-        Request, RequestHttpFrontend, RequestTcpFrontend, ReturnListenSockets, RulePosition,
-        ServerConfig, SoftStop, WorkerRequest, WorkerResponse,
+        request::RequestType, response_content::ContentType, AddBackend, Cluster, HardStop,
+        LoadBalancingParams, PathRule, QueryMetricsOptions, Request, RequestHttpFrontend,
+        RequestTcpFrontend, ReturnListenSockets, RulePosition, ServerConfig, SoftStop,
+        WorkerMetrics, WorkerRequest, WorkerResponse,
     },
     scm_socket::{Listeners, ScmSocket},
     state::ConfigState,
@@ -228,6 +229,24 @@ impl Worker {
         }
     }
 
+    /// Send a query to the worker, reading the responses up to the one to that query
+    pub fn query(&mut self, request: RequestType) -> Option<ContentType> {
+        self.send_proxy_request_type(request);
+        loop {
+            let response = self.read_proxy_response()?;
+            if response.id == self.command_id.last {
+                return response.content?.content_type;
+            }
+        }
+    }
+
+    pub fn query_metrics(&mut self, options: QueryMetricsOptions) -> Option<WorkerMetrics> {
+        match self.query(RequestType::QueryMetrics(options))? {
+            ContentType::WorkerMetrics(metrics) => Some(metrics),
+            _ => None,
+        }
+    }
+
     pub fn hard_stop(&mut self) {
         self.send_proxy_request_type(RequestType::HardStop(HardStop {}));
     }
@@ -1,5 +1,6 @@
 use std::{
-    net::SocketAddr,
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
     thread,
     time::{Duration, Instant},
 };
@@ -9,8 +10,11 @@ use sozu_command_lib::{
     info,
     logging::setup_default_logging,
     proto::command::{
-        request::RequestType, ActivateListener, AddCertificate, CertificateAndKey, Cluster,
-        CustomHttpAnswers, ListenerType, RemoveBackend, RequestHttpFrontend, SocketAddress,
+        filtered_metrics::Inner, request::RequestType, response_content::ContentType,
+        ActivateListener, AddCertificate, CertificateAndKey, Cluster, Compression,
+        CustomHttpAnswers, ForwardedHeaders, ListenerType, PathRule, ProxyProtocolConfig,
+        QueryMetricsOptions, QuerySessions, RemoveBackend, RequestHttpFrontend, ResponseBuffering,
+        SocketAddress,
     },
     scm_socket::Listeners,
     state::ConfigState,
@@ -22,11 +26,13 @@ use crate::{
         aggregator::SimpleAggregator,
         async_backend::BackendHandle as AsyncBackend,
         client::Client,
-        https_client::{build_https_client, resolve_request},
+        https_client::{build_https_client, resolve_request, tls_request_with_alpn},
         sync_backend::Backend as SyncBackend,
     },
     sozu::worker::Worker,
-    tests::{provide_port, repeat_until_error_or, setup_async_test, setup_sync_test, State},
+    tests::{
+        provide_port, repeat_until_error_or, setup_async_test, setup_sync_test, setup_test, State,
+    },
 };
 
 pub fn create_local_address() -> SocketAddr {
@@ -727,6 +733,21 @@ fn try_http_behaviors() -> State {
     assert_eq!(response, Some(immutable_answer(502)));
     assert_eq!(client.receive(), None);
 
+    info!("backend only speaks HTTP/2, expecting 502");
+    // empty SETTINGS frame
+    backend.set_response("\0\0\0\x04\0\0\0\0\0");
+    client.connect();
+    client.send();
+    backend.accept(0);
+    let request = backend.receive(0);
+    backend.send(0);
+
+    let response = client.receive();
+    println!("request: {request:?}");
+    println!("response: {response:?}");
+    assert_eq!(response, Some(immutable_answer(502)));
+    assert_eq!(client.receive(), None);
+
     info!("expecting 200");
     worker.send_proxy_request_type(RequestType::RemoveBackend(RemoveBackend {
         cluster_id: String::from("cluster_0"),
@@ -1451,195 +1472,2410 @@ pub fn try_head() -> State {
     State::Success
 }
 
-pub fn try_status_header_split() -> State {
+pub fn try_mirror() -> State {
     let front_address = create_local_address();
 
     let (config, listeners, state) = Worker::empty_config();
     let (mut worker, mut backends) =
-        setup_sync_test("SPLIT", config, listeners, state, front_address, 1, false);
+        setup_sync_test("MIRROR", config, listeners, state, front_address, 1, false);
     let mut backend = backends.pop().unwrap();
 
-    backend.connect();
+    let mirror_address = create_local_address();
+    worker.send_proxy_request_type(RequestType::AddCluster(Worker::default_cluster(
+        "cluster_1",
+    )));
+    worker.send_proxy_request_type(RequestType::AddBackend(Worker::default_backend(
+        "cluster_1",
+        "cluster_1-0",
+        mirror_address,
+        None,
+    )));
+    worker.send_proxy_request_type(RequestType::AddCluster(Cluster {
+        mirror_cluster: Some(String::from("cluster_1")),
+        ..Worker::default_cluster("cluster_0")
+    }));
+    worker.read_to_last();
 
-    let mut client = Client::new("client", front_address, "");
+    let mut mirror = SyncBackend::new("mirror", mirror_address, http_ok_response("mirror"));
+    backend.connect();
+    backend.set_response(http_ok_response("pong"));
+    mirror.connect();
 
+    let mut client = Client::new(
+        "client",
+        front_address,
+        http_request("POST", "/api", "ping".to_string(), "localhost"),
+    );
     client.connect();
+    client.send();
 
-    let mut accepted = false;
-    for (i, chunk) in [
-        "POST /api HTTP/1.",
-        "1\r\n",
-        "Host: localhost\r\n",
-        "Content-Length",
-        ":",
-        " 1",
-        "0",
-        "\r",
-        "\n\r",
-        "\n012",
-        "34567",
-        "89",
-    ]
-    .iter()
-    .enumerate()
-    {
-        println!("{accepted} {i} {chunk:?}");
-        client.set_request(*chunk);
-        client.send();
-        if !accepted {
-            println!("accepting");
-            accepted = backend.accept(0);
-            if accepted {
-                assert_eq!(i, 9);
-            }
-            backend.send(0);
-        }
-        if accepted {
-            println!("receiving");
-            let request = backend.receive(0);
-            println!("request: {request:?}");
-        }
-    }
+    backend.accept(0);
+    mirror.accept(0);
+    let request = backend.receive(0);
+    let mirrored = mirror.receive(0);
+    println!("request: {request:?}");
+    println!("mirrored: {mirrored:?}");
+    assert_eq!(request, mirrored);
+
+    mirror.send(0);
     backend.send(0);
     let response = client.receive();
     println!("response: {response:?}");
-    assert!(response.is_some());
+    assert!(response.unwrap().ends_with("pong"));
 
-    worker.hard_stop();
+    worker.soft_stop();
     worker.wait_for_server_stop();
     State::Success
 }
 
-fn try_wildcard() -> State {
-    use sozu_command_lib::proto::command::{PathRule, RulePosition};
+pub fn try_connect_tunnel() -> State {
     let front_address = create_local_address();
+    let proxy_address = create_local_address();
 
     let (config, listeners, state) = Worker::empty_config();
-    let mut worker = Worker::start_new_worker("WLD_CRD", config, &listeners, state);
-    worker.send_proxy_request(
-        RequestType::AddHttpListener(
-            ListenerBuilder::new_http(front_address.into())
-                .to_http(None)
-                .unwrap(),
-        )
-        .into(),
-    );
-    worker.send_proxy_request(
-        RequestType::ActivateListener(ActivateListener {
-            address: front_address.into(),
-            proxy: ListenerType::Http.into(),
-            from_scm: false,
-        })
-        .into(),
-    );
+    let (mut worker, mut backends) =
+        setup_sync_test("CONNECT", config, listeners, state, front_address, 1, false);
+    let mut backend = backends.pop().unwrap();
+    backend.connect();
 
-    worker.send_proxy_request(RequestType::AddCluster(Worker::default_cluster("cluster_0")).into());
-    worker.send_proxy_request(RequestType::AddCluster(Worker::default_cluster("cluster_1")).into());
+    let http_config = ListenerBuilder::new_http(proxy_address.into())
+        .with_forward_proxy(Some(true))
+        .to_http(None)
+        .unwrap();
+    worker.send_proxy_request_type(RequestType::AddHttpListener(http_config));
+    worker.send_proxy_request_type(RequestType::ActivateListener(ActivateListener {
+        address: proxy_address.into(),
+        proxy: ListenerType::Http.into(),
+        from_scm: false,
+    }));
+    worker.send_proxy_request_type(RequestType::AddHttpFrontend(Worker::default_http_frontend(
+        "cluster_0",
+        proxy_address,
+    )));
+    worker.read_to_last();
 
-    worker.send_proxy_request(
-        RequestType::AddHttpFrontend(RequestHttpFrontend {
-            cluster_id: Some("cluster_0".to_string()),
-            address: front_address.into(),
-            hostname: String::from("*.sozu.io"),
-            path: PathRule::prefix(String::from("")),
-            position: RulePosition::Tree.into(),
-            ..Default::default()
-        })
-        .into(),
+    // reverse proxy listeners refuse CONNECT
+    let mut client = Client::new(
+        "client",
+        front_address,
+        "CONNECT localhost:443 HTTP/1.1\r\nHost: localhost\r\n\r\n",
     );
+    client.connect();
+    client.send();
+    let response = client.receive();
+    println!("response on the reverse proxy: {response:?}");
+    match response {
+        Some(response) if response.starts_with("HTTP/1.1 400 Bad Request") => {}
+        _ => return State::Fail,
+    }
 
-    let back_address: SocketAddr = create_local_address();
-    worker.send_proxy_request(
-        RequestType::AddBackend(Worker::default_backend(
-            "cluster_0",
-            "cluster_0-0",
-            back_address,
-            None,
-        ))
-        .into(),
+    // the forward proxy answers itself once connected, the request is not forwarded
+    let mut client = Client::new(
+        "client",
+        proxy_address,
+        "CONNECT localhost:443 HTTP/1.1\r\nHost: localhost:443\r\n\r\n",
     );
+    client.connect();
+    client.send();
+    backend.accept(0);
+    let response = client.receive();
+    println!("response: {response:?}");
+    assert!(response
+        .unwrap()
+        .starts_with("HTTP/1.1 200 Connection Established\r\n"));
+
+    // then the bytes are relayed as is in both directions
+    client.set_request("not HTTP at all");
+    client.send();
+    let tunneled = backend.receive(0);
+    println!("tunneled: {tunneled:?}");
+    assert_eq!(tunneled.as_deref(), Some("not HTTP at all"));
+
+    backend.set_response("neither is this");
+    backend.send(0);
+    let tunneled = client.receive();
+    println!("tunneled: {tunneled:?}");
+    assert_eq!(tunneled.as_deref(), Some("neither is this"));
+
+    worker.soft_stop();
+    worker.wait_for_server_stop();
+    State::Success
+}
+
+pub fn try_server_timing() -> State {
+    let front_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, mut backends) =
+        setup_sync_test("TIMING", config, listeners, state, front_address, 1, false);
+    let mut backend = backends.pop().unwrap();
+
+    worker.send_proxy_request_type(RequestType::AddCluster(Cluster {
+        server_timing: Some(true),
+        ..Worker::default_cluster("cluster_0")
+    }));
     worker.read_to_last();
 
-    let mut backend0 = SyncBackend::new(
-        "BACKEND_0",
-        back_address,
-        http_ok_response("pong0".to_string()),
-    );
+    backend.connect();
+    backend.set_response(http_ok_response("pong"));
 
     let mut client = Client::new(
         "client",
         front_address,
-        http_request("POST", "/api", "ping".to_string(), "www.sozu.io"),
+        http_request("GET", "/api", "ping".to_string(), "localhost"),
     );
-
-    backend0.connect();
     client.connect();
     client.send();
-    let accepted = backend0.accept(0);
-    assert!(accepted);
-    let request = backend0.receive(0);
+
+    backend.accept(0);
+    let request = backend.receive(0);
     println!("request: {request:?}");
-    backend0.send(0);
+    assert!(!request.unwrap().contains("Server-Timing"));
+    backend.send(0);
     let response = client.receive();
     println!("response: {response:?}");
+    let response = response.unwrap();
+    assert!(response.contains("Server-Timing: sozu-queue;dur="));
+    assert!(response.contains(", sozu-backend;dur="));
 
-    worker.send_proxy_request(
-        RequestType::AddHttpFrontend(RequestHttpFrontend {
-            cluster_id: Some("cluster_1".to_string()),
-            address: front_address.into(),
-            hostname: String::from("*.sozu.io"),
-            path: PathRule::prefix(String::from("/api")),
-            position: RulePosition::Tree.into(),
-            ..Default::default()
-        })
-        .into(),
-    );
-    let back_address: SocketAddr = create_local_address();
-    worker.send_proxy_request(
-        RequestType::AddBackend(Worker::default_backend(
-            "cluster_1",
-            "cluster_1-0",
-            back_address,
-            None,
-        ))
-        .into(),
-    );
+    worker.soft_stop();
+    worker.wait_for_server_stop();
+    State::Success
+}
 
-    let mut backend1 = SyncBackend::new(
-        "BACKEND_1",
-        back_address,
-        http_ok_response("pong1".to_string()),
-    );
+pub fn try_slow_loris() -> State {
+    let front_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let mut worker = Worker::start_new_worker("SLOW-LORIS", config, &listeners, state);
 
+    let http_config = ListenerBuilder::new_http(front_address.into())
+        .with_request_timeout(Some(1))
+        .to_http(None)
+        .unwrap();
+    worker.send_proxy_request_type(RequestType::AddHttpListener(http_config));
+    worker.send_proxy_request_type(RequestType::ActivateListener(ActivateListener {
+        address: front_address.into(),
+        proxy: ListenerType::Http.into(),
+        from_scm: false,
+    }));
     worker.read_to_last();
 
-    backend1.connect();
+    // trickle the headers one byte at a time, each read must not push the timeout back
+    let mut client = Client::new(
+        "client",
+        front_address,
+        "GET / HTTP/1.1\r\nHost: localhost\r\nX-Slow: ",
+    );
+    client.connect();
+    client.send();
+    client.set_request("a");
+
+    let start = Instant::now();
+    let mut response = None;
+    while response.is_none() && start.elapsed() < Duration::from_secs(5) {
+        client.send();
+        response = client.receive();
+    }
+    println!("response after {:?}: {response:?}", start.elapsed());
+    assert!(response.unwrap().starts_with("HTTP/1.1 408"));
+    assert!(start.elapsed() < Duration::from_secs(3));
+    assert_eq!(client.receive(), None);
 
+    // send endless headers, they are bounded by the buffer size
+    let mut client = Client::new(
+        "client",
+        front_address,
+        "GET / HTTP/1.1\r\nHost: localhost\r\n",
+    );
+    client.connect();
     client.send();
-    let accepted = backend1.accept(0);
-    assert!(accepted);
-    let request = backend1.receive(0);
-    println!("request: {request:?}");
-    backend1.send(0);
+    client.set_request(format!("X-Endless: {}\r\n", "a".repeat(1000)));
+
+    for _ in 0..30 {
+        client.send();
+    }
     let response = client.receive();
     println!("response: {response:?}");
+    assert!(response.unwrap().starts_with("HTTP/1.1 413"));
 
+    worker.soft_stop();
+    worker.wait_for_server_stop();
     State::Success
 }
 
-#[test]
-fn test_sync() {
-    assert_eq!(try_sync(10, 100), State::Success);
-}
-
-#[test]
-fn test_async() {
-    assert_eq!(try_async(3, 10, 100), State::Success);
-}
+pub fn try_preserve_header_case() -> State {
+    let front_address = create_local_address();
 
-#[test]
-fn test_hard_stop() {
-    assert_eq!(
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, mut backends) = setup_sync_test(
+        "HEADER-CASE",
+        config,
+        listeners,
+        state,
+        front_address,
+        1,
+        false,
+    );
+    let mut backend = backends.pop().unwrap();
+    backend.connect();
+    backend.set_response(http_ok_response("pong"));
+
+    let request = "GET /api HTTP/1.1\r\nHOST: localhost\r\ncookie: a=b\r\nX-Custom: c\r\n\r\n";
+
+    let mut client = Client::new("client", front_address, request);
+    client.connect();
+    client.send();
+    backend.accept(0);
+    let forwarded = backend.receive(0).unwrap();
+    println!("request: {forwarded:?}");
+    assert!(forwarded.contains("\r\nHost: localhost\r\n"));
+    assert!(forwarded.contains("\r\nCookie: a=b\r\n"));
+    assert!(forwarded.contains("\r\nX-Custom: c\r\n"));
+    backend.send(0);
+    assert!(client.receive().is_some());
+
+    worker.send_proxy_request_type(RequestType::AddCluster(Cluster {
+        preserve_header_case: Some(true),
+        ..Worker::default_cluster("cluster_0")
+    }));
+    worker.read_to_last();
+
+    let mut client = Client::new("client", front_address, request);
+    client.connect();
+    client.send();
+    backend.accept(1);
+    let forwarded = backend.receive(1).unwrap();
+    println!("request: {forwarded:?}");
+    assert!(forwarded.contains("\r\nHOST: localhost\r\n"));
+    assert!(forwarded.contains("\r\ncookie: a=b\r\n"));
+    assert!(forwarded.contains("\r\nX-Custom: c\r\n"));
+    backend.send(1);
+    assert!(client.receive().is_some());
+
+    worker.soft_stop();
+    worker.wait_for_server_stop();
+    State::Success
+}
+
+pub fn try_request_trailers() -> State {
+    let front_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, mut backends) = setup_sync_test(
+        "TRAILERS",
+        config,
+        listeners,
+        state,
+        front_address,
+        1,
+        false,
+    );
+    let mut backend = backends.pop().unwrap();
+    backend.connect();
+    backend.set_response(http_ok_response("pong"));
+
+    let request = "POST /api HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+        4\r\nping\r\n0\r\nX-Checksum: abc\r\nX-Other: 1\r\nContent-Length: 4\r\n\r\n";
+
+    let mut client = Client::new("client", front_address, request);
+    client.connect();
+    client.send();
+    backend.accept(0);
+    let forwarded = backend.receive(0).unwrap();
+    println!("request: {forwarded:?}");
+    assert!(forwarded.ends_with("0\r\nX-Checksum: abc\r\nX-Other: 1\r\n\r\n"));
+    backend.send(0);
+    assert!(client.receive().is_some());
+
+    worker.send_proxy_request_type(RequestType::AddCluster(Cluster {
+        allowed_request_trailers: vec!["x-checksum".to_owned()],
+        ..Worker::default_cluster("cluster_0")
+    }));
+    worker.read_to_last();
+
+    let mut client = Client::new("client", front_address, request);
+    client.connect();
+    client.send();
+    backend.accept(1);
+    let forwarded = backend.receive(1).unwrap();
+    println!("request: {forwarded:?}");
+    assert!(forwarded.ends_with("0\r\nX-Checksum: abc\r\n\r\n"));
+    backend.send(1);
+    assert!(client.receive().is_some());
+
+    worker.soft_stop();
+    worker.wait_for_server_stop();
+    State::Success
+}
+
+pub fn try_unsupported_transfer_encoding() -> State {
+    let front_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, mut backends) =
+        setup_sync_test("TE", config, listeners, state, front_address, 1, false);
+    let mut backend = backends.pop().unwrap();
+    backend.connect();
+    backend.set_response(http_ok_response("pong"));
+
+    for coding in ["gzip", "gzip, chunked", "chunked, chunked"] {
+        let mut client = Client::new(
+            "client",
+            front_address,
+            format!(
+                "POST /api HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: {coding}\r\n\r\n\
+                4\r\nping\r\n0\r\n\r\n"
+            ),
+        );
+        client.connect();
+        client.send();
+        let response = client.receive();
+        println!("response to {coding}: {response:?}");
+        match response {
+            Some(response) if response.starts_with("HTTP/1.1 501 Not Implemented") => {}
+            _ => return State::Fail,
+        }
+    }
+
+    let mut client = Client::new(
+        "client",
+        front_address,
+        "POST /api HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: Chunked\r\n\r\n\
+        4\r\nping\r\n0\r\n\r\n",
+    );
+    client.connect();
+    client.send();
+    backend.accept(0);
+    let forwarded = backend.receive(0);
+    println!("request: {forwarded:?}");
+    assert!(forwarded.is_some());
+    backend.send(0);
+    let response = client.receive();
+    println!("response: {response:?}");
+    assert!(response.unwrap().starts_with("HTTP/1.1 200"));
+
+    worker.soft_stop();
+    worker.wait_for_server_stop();
+    State::Success
+}
+
+pub fn try_conflicting_framing() -> State {
+    let front_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, mut backends) =
+        setup_sync_test("FRAMING", config, listeners, state, front_address, 1, false);
+    let mut backend = backends.pop().unwrap();
+    backend.connect();
+    backend.set_response(http_ok_response("pong"));
+
+    for headers in [
+        "Content-Length: 4\r\nTransfer-Encoding: chunked",
+        "Transfer-Encoding: chunked\r\nContent-Length: 4",
+        "Content-Length: 4\r\nContent-Length: 5",
+    ] {
+        let mut client = Client::new(
+            "client",
+            front_address,
+            format!(
+                "POST /api HTTP/1.1\r\nHost: localhost\r\n{headers}\r\n\r\n\
+                4\r\nping\r\n0\r\n\r\n"
+            ),
+        );
+        client.connect();
+        client.send();
+        let response = client.receive();
+        println!("response to {headers:?}: {response:?}");
+        match response {
+            Some(response) if response.starts_with("HTTP/1.1 400 Bad Request") => {}
+            _ => return State::Fail,
+        }
+    }
+
+    let mut client = Client::new(
+        "client",
+        front_address,
+        "POST /api HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\nContent-Length: 4\r\n\r\nping",
+    );
+    client.connect();
+    client.send();
+    backend.accept(0);
+    let forwarded = backend.receive(0);
+    println!("request: {forwarded:?}");
+    assert!(forwarded.is_some());
+    backend.send(0);
+    let response = client.receive();
+    println!("response: {response:?}");
+    assert!(response.unwrap().starts_with("HTTP/1.1 200"));
+
+    worker.soft_stop();
+    worker.wait_for_server_stop();
+    State::Success
+}
+
+pub fn try_absolute_form() -> State {
+    let front_address = create_local_address();
+    let proxy_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, mut backends) = setup_sync_test(
+        "ABSOLUTE",
+        config,
+        listeners,
+        state,
+        front_address,
+        1,
+        false,
+    );
+    let mut backend = backends.pop().unwrap();
+    backend.connect();
+
+    let http_config = ListenerBuilder::new_http(proxy_address.into())
+        .with_forward_proxy(Some(true))
+        .to_http(None)
+        .unwrap();
+    worker.send_proxy_request_type(RequestType::AddHttpListener(http_config));
+    worker.send_proxy_request_type(RequestType::ActivateListener(ActivateListener {
+        address: proxy_address.into(),
+        proxy: ListenerType::Http.into(),
+        from_scm: false,
+    }));
+    worker.send_proxy_request_type(RequestType::AddHttpFrontend(Worker::default_http_frontend(
+        "cluster_0",
+        proxy_address,
+    )));
+    worker.read_to_last();
+
+    // reverse proxy listeners and unknown schemes refuse absolute-form
+    for (address, target) in [
+        (front_address, "http://localhost/api"),
+        (proxy_address, "ftp://localhost/api"),
+    ] {
+        let mut client = Client::new(
+            "client",
+            address,
+            format!("GET {target} HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+        );
+        client.connect();
+        client.send();
+        let response = client.receive();
+        println!("response to {target}: {response:?}");
+        match response {
+            Some(response) if response.starts_with("HTTP/1.1 400 Bad Request") => {}
+            _ => return State::Fail,
+        }
+    }
+
+    // the forward proxy routes by the host of the target and sends its path only
+    let mut client = Client::new(
+        "client",
+        proxy_address,
+        "GET http://localhost/api?q=1 HTTP/1.1\r\nHost: example.com\r\n\r\n",
+    );
+    client.connect();
+    client.send();
+    backend.accept(0);
+    let forwarded = backend.receive(0);
+    println!("request: {forwarded:?}");
+    assert!(forwarded
+        .unwrap()
+        .starts_with("GET /api?q=1 HTTP/1.1\r\nHost: localhost\r\n"));
+    backend.send(0);
+    let response = client.receive();
+    println!("response: {response:?}");
+    assert!(response.unwrap().starts_with("HTTP/1.1 200"));
+
+    worker.soft_stop();
+    worker.wait_for_server_stop();
+    State::Success
+}
+
+pub fn try_pipelining() -> State {
+    let front_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, mut backends) = setup_sync_test(
+        "PIPELINING",
+        config,
+        listeners,
+        state,
+        front_address,
+        1,
+        false,
+    );
+    let mut backend = backends.pop().unwrap();
+    backend.connect();
+
+    // the three requests are sent at once, before any response
+    let mut client = Client::new(
+        "client",
+        front_address,
+        "GET /first HTTP/1.1\r\nHost: localhost\r\n\r\n\
+        POST /second HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\nping\
+        GET /third HTTP/1.1\r\nHost: localhost\r\n\r\n",
+    );
+    client.connect();
+    client.send();
+    backend.accept(0);
+
+    let mut responses = String::new();
+    for (path, body) in [("/first", "one"), ("/second", "two"), ("/third", "three")] {
+        let request = backend.receive(0);
+        println!("request: {request:?}");
+        match request {
+            Some(request)
+                if request.starts_with(&format!(
+                    "{} {path} ",
+                    if path == "/second" { "POST" } else { "GET" }
+                )) => {}
+            _ => return State::Fail,
+        }
+        backend.set_response(http_ok_response(body));
+        backend.send(0);
+        // each response is sent before the next request is forwarded
+        while !responses.ends_with(body) {
+            match client.receive() {
+                Some(response) => responses.push_str(&response),
+                None => return State::Fail,
+            }
+        }
+    }
+    println!("responses: {responses:?}");
+    let bodies: Vec<&str> = responses
+        .split("HTTP/1.1 200 OK")
+        .skip(1)
+        .map(|response| response.rsplit("\r\n\r\n").next().unwrap())
+        .collect();
+    assert_eq!(bodies, vec!["one", "two", "three"]);
+
+    worker.soft_stop();
+    worker.wait_for_server_stop();
+    State::Success
+}
+
+pub fn try_compression() -> State {
+    let front_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, mut backends) = setup_sync_test(
+        "COMPRESSION",
+        config,
+        listeners,
+        state,
+        front_address,
+        1,
+        false,
+    );
+    let mut backend = backends.pop().unwrap();
+
+    worker.send_proxy_request_type(RequestType::AddCluster(Cluster {
+        compression: Some(Compression {
+            algorithms: vec!["gzip".to_owned()],
+            min_size: Some(100),
+            content_types: Vec::new(),
+        }),
+        ..Worker::default_cluster("cluster_0")
+    }));
+    worker.read_to_last();
+
+    let content = "compress me please ".repeat(200);
+    backend.connect();
+    backend.set_response(format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nETag: \"v1\"\r\nContent-Length: {}\r\n\r\n{content}",
+        content.len()
+    ));
+
+    let mut client = TcpStream::connect(front_address).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+
+    // the client accepts gzip, the body is compressed and sent in chunks
+    client
+        .write_all(b"GET /api HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: br;q=0, gzip\r\n\r\n")
+        .unwrap();
+    backend.accept(0);
+    backend.receive(0);
+    backend.send(0);
+    let (head, body) = read_raw_response(&mut client);
+    println!("response: {head:?}");
+    assert!(head.contains("Content-Encoding: gzip"));
+    assert!(head.contains("Transfer-Encoding: chunked"));
+    assert!(head.contains("Vary: Accept-Encoding"));
+    assert!(head.contains("ETag: W/\"v1\""));
+    assert!(!head.contains("Content-Length"));
+    assert!(body.len() < content.len());
+    let mut decoded = String::new();
+    flate2::read::GzDecoder::new(&body[..])
+        .read_to_string(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, content);
+
+    // without Accept-Encoding, the response is forwarded as is
+    client
+        .write_all(b"GET /api HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    backend.receive(0);
+    backend.send(0);
+    let (head, body) = read_raw_response(&mut client);
+    println!("response: {head:?}");
+    assert!(!head.contains("Content-Encoding"));
+    assert_eq!(body, content.as_bytes());
+
+    // an encoded response is not compressed twice
+    let encoded = "already encoded ".repeat(20);
+    backend.set_response(format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Encoding: br\r\nContent-Length: {}\r\n\r\n{encoded}",
+        encoded.len()
+    ));
+    client
+        .write_all(b"GET /api HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip\r\n\r\n")
+        .unwrap();
+    backend.receive(0);
+    backend.send(0);
+    let (head, body) = read_raw_response(&mut client);
+    println!("response: {head:?}");
+    assert!(head.contains("Content-Encoding: br"));
+    assert!(!head.contains("gzip"));
+    assert_eq!(body, encoded.as_bytes());
+
+    worker.soft_stop();
+    worker.wait_for_server_stop();
+    State::Success
+}
+
+pub fn try_server_sent_events() -> State {
+    let front_address = create_local_address();
+    let stream_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, mut backends) =
+        setup_sync_test("SSE", config, listeners, state, front_address, 1, false);
+    let mut backend = backends.pop().unwrap();
+    backend.connect();
+
+    let http_config = ListenerBuilder::new_http(stream_address.into())
+        .with_back_timeout(Some(1))
+        .to_http(None)
+        .unwrap();
+    worker.send_proxy_request_type(RequestType::AddHttpListener(http_config));
+    worker.send_proxy_request_type(RequestType::ActivateListener(ActivateListener {
+        address: stream_address.into(),
+        proxy: ListenerType::Http.into(),
+        from_scm: false,
+    }));
+    worker.send_proxy_request_type(RequestType::AddHttpFrontend(Worker::default_http_frontend(
+        "cluster_0",
+        stream_address,
+    )));
+    worker.read_to_last();
+
+    let mut client = Client::new(
+        "client",
+        stream_address,
+        "GET /events HTTP/1.1\r\nHost: localhost\r\nAccept: text/event-stream\r\n\r\n",
+    );
+    client.connect();
+    client.send();
+    backend.accept(0);
+    backend.receive(0);
+
+    // each event reaches the client as soon as the backend sends it, the stream
+    // lasts longer than the backend timeout as long as events keep coming
+    backend.set_response(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nTransfer-Encoding: chunked\r\n\r\n",
+    );
+    backend.send(0);
+    for event in 0..4 {
+        let data = format!("data: {event}\n\n");
+        backend.set_response(format!("{:x}\r\n{data}\r\n", data.len()));
+        backend.send(0);
+        let response = client.receive();
+        println!("event {event}: {response:?}");
+        match response {
+            // the Sozu-Id header may end with a 0 too, only the last chunk ends the body
+            Some(response) if response.contains(&data) && !response.ends_with("0\r\n\r\n") => {}
+            _ => return State::Fail,
+        }
+        thread::sleep(Duration::from_millis(600));
+    }
+
+    backend.set_response("0\r\n\r\n");
+    backend.send(0);
+    let response = client.receive();
+    println!("end of stream: {response:?}");
+    if response.as_deref() != Some("0\r\n\r\n") {
+        return State::Fail;
+    }
+
+    worker.soft_stop();
+    worker.wait_for_server_stop();
+    State::Success
+}
+
+pub fn try_alt_svc() -> State {
+    let front_address = create_local_address();
+    let append_address = create_local_address();
+    let replace_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, mut backends) =
+        setup_sync_test("ALT-SVC", config, listeners, state, front_address, 1, false);
+    let mut backend = backends.pop().unwrap();
+    backend.connect();
+
+    for (address, replace) in [(append_address, false), (replace_address, true)] {
+        let http_config = ListenerBuilder::new_http(address.into())
+            .with_alt_svc(Some("h3=\":443\"; ma=3600"))
+            .with_replace_alt_svc(Some(replace))
+            .to_http(None)
+            .unwrap();
+        worker.send_proxy_request_type(RequestType::AddHttpListener(http_config));
+        worker.send_proxy_request_type(RequestType::ActivateListener(ActivateListener {
+            address: address.into(),
+            proxy: ListenerType::Http.into(),
+            from_scm: false,
+        }));
+        worker.send_proxy_request_type(RequestType::AddHttpFrontend(
+            Worker::default_http_frontend("cluster_0", address),
+        ));
+    }
+    worker.read_to_last();
+
+    let mut exchange = |address, backend_alt_svc: &str, connection: usize| {
+        backend.set_response(format!(
+            "HTTP/1.1 200 OK\r\n{backend_alt_svc}Content-Length: 4\r\n\r\npong"
+        ));
+        let mut client = Client::new(
+            "client",
+            address,
+            http_request("GET", "/api", "ping".to_string(), "localhost"),
+        );
+        client.connect();
+        client.send();
+        backend.accept(connection);
+        backend.receive(connection);
+        backend.send(connection);
+        let response = client.receive();
+        println!("response: {response:?}");
+        response.unwrap_or_default()
+    };
+
+    // no header on listeners without the option
+    let response = exchange(front_address, "", 0);
+    assert!(!response.contains("Alt-Svc"));
+
+    // added after the backend's own alternative services
+    let response = exchange(append_address, "Alt-Svc: h2=\":8443\"\r\n", 1);
+    assert!(response.contains("Alt-Svc: h2=\":8443\"\r\n"));
+    assert!(response.contains("Alt-Svc: h3=\":443\"; ma=3600\r\n"));
+
+    // a backend clearing its alternative services is respected
+    let response = exchange(append_address, "Alt-Svc: clear\r\n", 2);
+    assert!(response.contains("Alt-Svc: clear\r\n"));
+    assert!(!response.contains("h3="));
+
+    // or replaced
+    let response = exchange(replace_address, "Alt-Svc: h2=\":8443\"\r\n", 3);
+    assert!(!response.contains("h2="));
+    assert!(response.contains("Alt-Svc: h3=\":443\"; ma=3600\r\n"));
+
+    worker.soft_stop();
+    worker.wait_for_server_stop();
+    State::Success
+}
+
+pub fn try_max_header_sizes() -> State {
+    let front_address = create_local_address();
+    let limited_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, mut backends) =
+        setup_sync_test("HEADERS", config, listeners, state, front_address, 1, false);
+    let mut backend = backends.pop().unwrap();
+    backend.connect();
+
+    let http_config = ListenerBuilder::new_http(limited_address.into())
+        .with_max_header_sizes(Some(512), Some(512))
+        .to_http(None)
+        .unwrap();
+    worker.send_proxy_request_type(RequestType::AddHttpListener(http_config));
+    worker.send_proxy_request_type(RequestType::ActivateListener(ActivateListener {
+        address: limited_address.into(),
+        proxy: ListenerType::Http.into(),
+        from_scm: false,
+    }));
+    worker.send_proxy_request_type(RequestType::AddHttpFrontend(Worker::default_http_frontend(
+        "cluster_0",
+        limited_address,
+    )));
+    worker.read_to_last();
+
+    let large = "a".repeat(600);
+    let request = |address, header: &str| {
+        let mut client = Client::new(
+            "client",
+            address,
+            format!("GET /api HTTP/1.1\r\nHost: localhost\r\n{header}\r\n"),
+        );
+        client.connect();
+        client.send();
+        client
+    };
+
+    // the limits only apply to the listener configured with them
+    backend.set_response(format!(
+        "HTTP/1.1 200 OK\r\nX-Large: {large}\r\nContent-Length: 4\r\n\r\npong"
+    ));
+    let mut client = request(front_address, &format!("X-Large: {large}\r\n"));
+    backend.accept(0);
+    backend.receive(0);
+    backend.send(0);
+    let response = client.receive();
+    println!("response: {response:?}");
+    assert!(response.unwrap().starts_with("HTTP/1.1 200"));
+
+    // request headers over the limit never reach the backend
+    let mut client = request(limited_address, &format!("X-Large: {large}\r\n"));
+    let response = client.receive();
+    println!("response: {response:?}");
+    match response {
+        Some(response) if response.starts_with("HTTP/1.1 431 Request Header Fields Too Large") => {}
+        _ => return State::Fail,
+    }
+
+    // response headers over the limit are replaced by a 502
+    let mut client = request(limited_address, "");
+    backend.accept(1);
+    backend.receive(1);
+    backend.send(1);
+    let response = client.receive();
+    println!("response: {response:?}");
+    match response {
+        Some(response) if response.starts_with("HTTP/1.1 502") => {}
+        _ => return State::Fail,
+    }
+
+    worker.soft_stop();
+    worker.wait_for_server_stop();
+    State::Success
+}
+
+pub fn try_forwarded_headers() -> State {
+    let front_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, mut backends) = setup_sync_test(
+        "FORWARDED",
+        config,
+        listeners,
+        state,
+        front_address,
+        1,
+        false,
+    );
+    let mut backend = backends.pop().unwrap();
+    backend.connect();
+    backend.set_response(http_ok_response("pong"));
+
+    let request = "GET /api HTTP/1.1\r\nHost: localhost\r\nForwarded: for=192.0.2.43\r\n\r\n";
+
+    // both families by default, the client is appended to the existing chain
+    let mut client = Client::new("client", front_address, request);
+    client.connect();
+    client.send();
+    backend.accept(0);
+    let forwarded = backend.receive(0).unwrap();
+    println!("request: {forwarded:?}");
+    assert!(forwarded.contains("\r\nX-Forwarded-For: 127.0.0.1\r\n"));
+    assert!(forwarded.contains("\r\nX-Forwarded-Proto: http\r\n"));
+    assert!(forwarded.contains("\r\nForwarded: for=192.0.2.43, proto=http;for=\"127.0.0.1:"));
+    assert!(forwarded.contains(";by=127.0.0.1;host=localhost\r\n"));
+    backend.send(0);
+    assert!(client.receive().is_some());
+
+    worker.send_proxy_request_type(RequestType::AddCluster(Cluster {
+        forwarded_headers: Some(ForwardedHeaders::Forwarded.into()),
+        ..Worker::default_cluster("cluster_0")
+    }));
+    worker.read_to_last();
+
+    let mut client = Client::new("client", front_address, request);
+    client.connect();
+    client.send();
+    backend.accept(1);
+    let forwarded = backend.receive(1).unwrap();
+    println!("request: {forwarded:?}");
+    assert!(!forwarded.contains("X-Forwarded-"));
+    assert!(forwarded.contains("\r\nForwarded: for=192.0.2.43, proto=http;for=\"127.0.0.1:"));
+    backend.send(1);
+    assert!(client.receive().is_some());
+
+    worker.soft_stop();
+    worker.wait_for_server_stop();
+    State::Success
+}
+
+pub fn try_requests_without_host() -> State {
+    let front_address = create_local_address();
+    let fallback_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, mut backends) =
+        setup_sync_test("NO-HOST", config, listeners, state, front_address, 1, false);
+    let mut backend = backends.pop().unwrap();
+    backend.connect();
+    backend.set_response(http_ok_response("pong"));
+
+    let http_config = ListenerBuilder::new_http(fallback_address.into())
+        .with_no_host_cluster_id(Some("cluster_0"))
+        .to_http(None)
+        .unwrap();
+    worker.send_proxy_request_type(RequestType::AddHttpListener(http_config));
+    worker.send_proxy_request_type(RequestType::ActivateListener(ActivateListener {
+        address: fallback_address.into(),
+        proxy: ListenerType::Http.into(),
+        from_scm: false,
+    }));
+    worker.read_to_last();
+
+    // rejected by default, without or with an empty Host header
+    for request in [
+        "GET /api HTTP/1.1\r\n\r\n",
+        "GET /api HTTP/1.1\r\nHost: \r\n\r\n",
+    ] {
+        let mut client = Client::new("client", front_address, request);
+        client.connect();
+        client.send();
+        let response = client.receive();
+        println!("response: {response:?}");
+        assert!(response.unwrap_or_default().starts_with("HTTP/1.1 400"));
+    }
+
+    // routed to the cluster of the listener, which has no frontend at all
+    let mut client = Client::new("client", fallback_address, "GET /api HTTP/1.0\r\n\r\n");
+    client.connect();
+    client.send();
+    backend.accept(0);
+    backend.receive(0);
+    backend.send(0);
+    let response = client.receive();
+    println!("response: {response:?}");
+    assert!(response.unwrap_or_default().starts_with("HTTP/1.1 200"));
+
+    worker.soft_stop();
+    worker.wait_for_server_stop();
+    State::Success
+}
+
+pub fn try_response_buffering() -> State {
+    let front_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, mut backends) = setup_sync_test(
+        "RESPONSE-BUFFERING",
+        config,
+        listeners,
+        state,
+        front_address,
+        1,
+        false,
+    );
+    let mut backend = backends.pop().unwrap();
+
+    worker.send_proxy_request_type(RequestType::AddCluster(Cluster {
+        response_buffering: Some(ResponseBuffering::BufferComplete.into()),
+        ..Worker::default_cluster("cluster_0")
+    }));
+    worker.read_to_last();
+
+    backend.connect();
+    backend.set_response(
+        "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+    );
+
+    let mut client = TcpStream::connect(front_address).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+
+    // the complete chunked response is sent with a Content-Length
+    client
+        .write_all(b"GET /api HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    backend.accept(0);
+    backend.receive(0);
+    backend.send(0);
+    let (head, body) = read_raw_response(&mut client);
+    println!("response: {head:?}");
+    assert!(head.contains("Content-Length: 11"));
+    assert!(!head.contains("Transfer-Encoding"));
+    assert_eq!(body, b"hello world");
+
+    // a response over the limit is streamed in chunks
+    worker.send_proxy_request_type(RequestType::AddCluster(Cluster {
+        response_buffering: Some(ResponseBuffering::BufferComplete.into()),
+        response_buffering_max_size: Some(4),
+        ..Worker::default_cluster("cluster_0")
+    }));
+    worker.read_to_last();
+
+    let mut client = TcpStream::connect(front_address).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    client
+        .write_all(b"GET /api HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    backend.accept(1);
+    backend.receive(1);
+    backend.send(1);
+    let (head, body) = read_raw_response(&mut client);
+    println!("response: {head:?}");
+    assert!(head.contains("Transfer-Encoding: chunked"));
+    assert_eq!(body, b"hello world");
+
+    worker.soft_stop();
+    worker.wait_for_server_stop();
+    State::Success
+}
+
+pub fn try_read_high_water_mark() -> State {
+    let front_address = create_local_address();
+    let limited_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, mut backends) = setup_sync_test(
+        "HIGH-WATER-MARK",
+        config,
+        listeners,
+        state,
+        front_address,
+        1,
+        false,
+    );
+    let mut backend = backends.pop().unwrap();
+    backend.connect();
+
+    let http_config = ListenerBuilder::new_http(limited_address.into())
+        .with_read_buffer_high_water_mark(Some(1024))
+        .to_http(None)
+        .unwrap();
+    worker.send_proxy_request_type(RequestType::AddHttpListener(http_config));
+    worker.send_proxy_request_type(RequestType::ActivateListener(ActivateListener {
+        address: limited_address.into(),
+        proxy: ListenerType::Http.into(),
+        from_scm: false,
+    }));
+    worker.send_proxy_request_type(RequestType::AddHttpFrontend(Worker::default_http_frontend(
+        "cluster_0",
+        limited_address,
+    )));
+    worker.read_to_last();
+
+    // the body goes through in parts, reading pauses and resumes as the backend takes it
+    let body = "a".repeat(20000);
+    let mut client = Client::new(
+        "client",
+        limited_address,
+        format!(
+            "POST /api HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        ),
+    );
+    client.connect();
+    client.send();
+    backend.accept(0);
+    let mut received = String::new();
+    for _ in 0..100 {
+        if received.ends_with(&body) {
+            break;
+        }
+        if let Some(part) = backend.receive(0) {
+            received.push_str(&part);
+        }
+    }
+    if !received.ends_with(&body) {
+        println!("backend received {} bytes", received.len());
+        return State::Fail;
+    }
+    backend.send(0);
+    let response = client.receive();
+    println!("response: {response:?}");
+    match response {
+        Some(response) if response.starts_with("HTTP/1.1 200") => {}
+        _ => return State::Fail,
+    }
+
+    worker.soft_stop();
+    worker.wait_for_server_stop();
+    State::Success
+}
+
+pub fn try_max_uri_length() -> State {
+    let front_address = create_local_address();
+    let limited_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, mut backends) = setup_sync_test(
+        "MAX-URI-LENGTH",
+        config,
+        listeners,
+        state,
+        front_address,
+        1,
+        false,
+    );
+    let mut backend = backends.pop().unwrap();
+    backend.connect();
+
+    let http_config = ListenerBuilder::new_http(limited_address.into())
+        .with_max_uri_length(Some(64))
+        .to_http(None)
+        .unwrap();
+    worker.send_proxy_request_type(RequestType::AddHttpListener(http_config));
+    worker.send_proxy_request_type(RequestType::ActivateListener(ActivateListener {
+        address: limited_address.into(),
+        proxy: ListenerType::Http.into(),
+        from_scm: false,
+    }));
+    worker.send_proxy_request_type(RequestType::AddHttpFrontend(Worker::default_http_frontend(
+        "cluster_0",
+        limited_address,
+    )));
+    worker.read_to_last();
+
+    // refused before the end of the request line
+    let mut client = Client::new(
+        "client",
+        limited_address,
+        format!("GET /{}", "a".repeat(200)),
+    );
+    client.connect();
+    client.send();
+    let response = client.receive();
+    println!("response: {response:?}");
+    match response {
+        Some(response) if response.starts_with("HTTP/1.1 414") => {}
+        _ => return State::Fail,
+    }
+
+    let mut client = Client::new(
+        "client",
+        limited_address,
+        http_request("GET", "/api", "", "localhost"),
+    );
+    client.connect();
+    client.send();
+    backend.accept(0);
+    backend.receive(0);
+    backend.send(0);
+    let response = client.receive();
+    println!("response: {response:?}");
+    match response {
+        Some(response) if response.starts_with("HTTP/1.1 200") => {}
+        _ => return State::Fail,
+    }
+
+    worker.soft_stop();
+    worker.wait_for_server_stop();
+    State::Success
+}
+
+pub fn try_request_id_header() -> State {
+    let front_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let mut worker = Worker::start_new_worker("REQUEST-ID", config, &listeners, state);
+    let http_config = ListenerBuilder::new_http(front_address.into())
+        .with_request_id_header(Some("X-Request-Id"))
+        .with_trust_client_request_id(Some(true))
+        .to_http(None)
+        .unwrap();
+    worker.send_proxy_request_type(RequestType::AddHttpListener(http_config));
+    worker.send_proxy_request_type(RequestType::ActivateListener(ActivateListener {
+        address: front_address.into(),
+        proxy: ListenerType::Http.into(),
+        from_scm: false,
+    }));
+    worker.send_proxy_request_type(RequestType::AddCluster(Worker::default_cluster(
+        "cluster_0",
+    )));
+    worker.send_proxy_request_type(RequestType::AddHttpFrontend(Worker::default_http_frontend(
+        "cluster_0",
+        front_address,
+    )));
+    let back_address = create_local_address();
+    worker.send_proxy_request_type(RequestType::AddBackend(Worker::default_backend(
+        "cluster_0",
+        "cluster_0-0",
+        back_address,
+        None,
+    )));
+    worker.read_to_last();
+    let mut backend = SyncBackend::new("backend", back_address, http_ok_response("pong"));
+    backend.connect();
+
+    // the id of a trusted client is forwarded and echoed
+    let mut client = Client::new(
+        "client",
+        front_address,
+        "GET /api HTTP/1.1\r\nHost: localhost\r\nX-Request-Id: trace-42\r\n\r\n",
+    );
+    client.connect();
+    client.send();
+    backend.accept(0);
+    let request = backend.receive(0).unwrap();
+    println!("request: {request:?}");
+    assert_eq!(request.matches("X-Request-Id: ").count(), 1);
+    assert!(request.contains("X-Request-Id: trace-42\r\n"));
+    backend.send(0);
+    let response = client.receive().unwrap();
+    println!("response: {response:?}");
+    assert!(response.contains("X-Request-Id: trace-42\r\n"));
+
+    // an invalid one is replaced by the id of Sōzu
+    client.set_request("GET /api HTTP/1.1\r\nHost: localhost\r\nX-Request-Id: trace 42\r\n\r\n");
+    client.send();
+    let request = backend.receive(0).unwrap();
+    println!("request: {request:?}");
+    let sozu_id = request
+        .split("\r\n")
+        .find_map(|line| line.strip_prefix("Sozu-Id: "))
+        .unwrap();
+    assert!(request.contains(&format!("X-Request-Id: {sozu_id}\r\n")));
+    assert!(!request.contains("trace 42"));
+    backend.send(0);
+    let response = client.receive().unwrap();
+    println!("response: {response:?}");
+    assert!(response.contains(&format!("X-Request-Id: {sozu_id}\r\n")));
+
+    worker.soft_stop();
+    worker.wait_for_server_stop();
+    State::Success
+}
+
+pub fn try_chunked_upload() -> State {
+    let front_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, mut backends) = setup_sync_test(
+        "CHUNKED-UPLOAD",
+        config,
+        listeners,
+        state,
+        front_address,
+        1,
+        false,
+    );
+    let mut backend = backends.pop().unwrap();
+    backend.connect();
+
+    // the body is several times the size of the buffer, it goes through as it arrives
+    let chunks: Vec<String> = (0..64)
+        .map(|index| char::from(b'a' + index % 26).to_string().repeat(1024))
+        .collect();
+    let mut request =
+        String::from("POST /api HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n");
+    for chunk in &chunks {
+        request.push_str(&format!("{:x}\r\n{chunk}\r\n", chunk.len()));
+    }
+    request.push_str("0\r\n\r\n");
+    let mut client = Client::new("client", front_address, request.clone());
+    client.connect();
+    if client.send() != Some(request.len()) {
+        return State::Fail;
+    }
+    backend.accept(0);
+    let mut received = String::new();
+    for _ in 0..500 {
+        if received.ends_with("0\r\n\r\n") {
+            break;
+        }
+        if let Some(part) = backend.receive(0) {
+            received.push_str(&part);
+        }
+    }
+    let body: String = received
+        .split("\r\n")
+        .filter(|line| line.len() == 1024)
+        .collect();
+    if body != chunks.concat() {
+        println!("backend received {} bytes", received.len());
+        return State::Fail;
+    }
+    backend.send(0);
+    let response = client.receive();
+    println!("response: {response:?}");
+    match response {
+        Some(response) if response.starts_with("HTTP/1.1 200") => {}
+        _ => return State::Fail,
+    }
+
+    worker.soft_stop();
+    worker.wait_for_server_stop();
+    State::Success
+}
+
+pub fn try_alpn_mismatch_fallback() -> State {
+    let strict_address = SocketAddress::new_v4(127, 0, 0, 1, provide_port());
+    let fallback_address = SocketAddress::new_v4(127, 0, 0, 1, provide_port());
+    let back_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let mut worker = Worker::start_new_worker("ALPN-FALLBACK", config, &listeners, state);
+
+    worker.send_proxy_request_type(RequestType::AddCluster(Worker::default_cluster(
+        "cluster_0",
+    )));
+    for (address, fallback) in [(&strict_address, false), (&fallback_address, true)] {
+        worker.send_proxy_request_type(RequestType::AddHttpsListener(
+            ListenerBuilder::new_https(*address)
+                .with_alpn_mismatch_fallback(Some(fallback))
+                .to_tls(None)
+                .unwrap(),
+        ));
+        worker.send_proxy_request_type(RequestType::ActivateListener(ActivateListener {
+            address: *address,
+            proxy: ListenerType::Https.into(),
+            from_scm: false,
+        }));
+        worker.send_proxy_request_type(RequestType::AddHttpsFrontend(RequestHttpFrontend {
+            hostname: "localhost".to_owned(),
+            ..Worker::default_http_frontend("cluster_0", (*address).into())
+        }));
+        worker.send_proxy_request_type(RequestType::AddCertificate(AddCertificate {
+            address: *address,
+            certificate: CertificateAndKey {
+                certificate: String::from(include_str!(
+                    "../../../lib/assets/local-certificate.pem"
+                )),
+                key: String::from(include_str!("../../../lib/assets/local-key.pem")),
+                certificate_chain: vec![],
+                versions: vec![],
+                names: vec![],
+            },
+            expired_at: None,
+        }));
+    }
+    worker.send_proxy_request_type(RequestType::AddBackend(Worker::default_backend(
+        "cluster_0",
+        "cluster_0-0",
+        back_address,
+        None,
+    )));
+    worker.read_to_last();
+
+    let mut backend = AsyncBackend::spawn_detached_backend(
+        "BACKEND",
+        back_address,
+        SimpleAggregator::default(),
+        AsyncBackend::http_handler("pong"),
+    );
+
+    let request = "GET /api HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+    // no protocol in common: the handshake fails with no_application_protocol
+    let rejected = tls_request_with_alpn(strict_address.into(), "localhost", &[b"spdy/3"], request);
+    println!("strict listener answered: {rejected:?}");
+    // the same client is served in HTTP/1.1 by the listener falling back
+    let served = tls_request_with_alpn(fallback_address.into(), "localhost", &[b"spdy/3"], request);
+    println!("fallback listener answered: {served:?}");
+    // clients with a supported protocol are not affected
+    let negotiated = tls_request_with_alpn(
+        fallback_address.into(),
+        "localhost",
+        &[b"spdy/3", b"http/1.1"],
+        request,
+    );
+    println!("fallback listener answered with ALPN: {negotiated:?}");
+
+    worker.soft_stop();
+    let success = worker.wait_for_server_stop();
+
+    let aggregator = backend
+        .stop_and_get_aggregator()
+        .expect("Could not get aggregator");
+    println!(
+        "{} sent: {}, received: {}",
+        backend.name, aggregator.responses_sent, aggregator.requests_received
+    );
+
+    let is_ok = |response: &Option<String>| {
+        response
+            .as_ref()
+            .is_some_and(|response| response.starts_with("HTTP/1.1 200"))
+    };
+    if success
+        && rejected.is_none()
+        && is_ok(&served)
+        && is_ok(&negotiated)
+        && aggregator.responses_sent == 2
+    {
+        State::Success
+    } else {
+        State::Fail
+    }
+}
+
+pub fn try_backend_queue() -> State {
+    let front_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, mut backends) = setup_sync_test(
+        "BACKEND-QUEUE",
+        config,
+        listeners,
+        state,
+        front_address,
+        1,
+        false,
+    );
+    let mut backend = backends.pop().unwrap();
+
+    worker.send_proxy_request_type(RequestType::AddCluster(Cluster {
+        backend_queue_depth: Some(1),
+        backend_queue_timeout: Some(5000),
+        ..Worker::default_cluster("cluster_0")
+    }));
+    worker.read_to_last();
+
+    // the backend is down when the request arrives, it waits for it to come back
+    let mut client = TcpStream::connect(front_address).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(6)))
+        .unwrap();
+    client
+        .write_all(b"GET /api HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    thread::sleep(Duration::from_millis(300));
+    backend.connect();
+    if !(0..50).any(|_| backend.accept(0)) {
+        println!("the queued request never reached the backend");
+        return State::Fail;
+    }
+    backend.receive(0);
+    backend.send(0);
+    let (head, body) = read_raw_response(&mut client);
+    println!("response: {head:?}");
+    if !head.starts_with("HTTP/1.1 200") || body != b"pong0" {
+        return State::Fail;
+    }
+
+    // a request queued for longer than backend_queue_timeout gets a 503
+    backend.disconnect();
+    worker.send_proxy_request_type(RequestType::AddCluster(Cluster {
+        backend_queue_depth: Some(1),
+        backend_queue_timeout: Some(200),
+        ..Worker::default_cluster("cluster_0")
+    }));
+    worker.read_to_last();
+
+    let mut client = TcpStream::connect(front_address).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(6)))
+        .unwrap();
+    client
+        .write_all(b"GET /api HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let (head, _) = read_raw_response(&mut client);
+    println!("response: {head:?}");
+    if !head.starts_with("HTTP/1.1 503") {
+        return State::Fail;
+    }
+
+    worker.soft_stop();
+    if worker.wait_for_server_stop() {
+        State::Success
+    } else {
+        State::Fail
+    }
+}
+
+pub fn try_retry_after() -> State {
+    let front_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, _backends) = setup_sync_test(
+        "RETRY-AFTER",
+        config,
+        listeners,
+        state,
+        front_address,
+        1,
+        false,
+    );
+
+    // the backend never listens, the request gets a 503
+    worker.send_proxy_request_type(RequestType::AddCluster(Cluster {
+        retry_after: Some("30".to_owned()),
+        ..Worker::default_cluster("cluster_0")
+    }));
+    worker.read_to_last();
+
+    let mut client = TcpStream::connect(front_address).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(6)))
+        .unwrap();
+    client
+        .write_all(b"GET /api HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let (head, _) = read_raw_response(&mut client);
+    println!("response: {head:?}");
+    if !head.starts_with("HTTP/1.1 503") || !head.contains("\r\nRetry-After: 30\r\n") {
+        return State::Fail;
+    }
+
+    worker.soft_stop();
+    if worker.wait_for_server_stop() {
+        State::Success
+    } else {
+        State::Fail
+    }
+}
+
+pub fn try_interim_responses_to_http10_clients() -> State {
+    let front_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, mut backends) =
+        setup_sync_test("INTERIM", config, listeners, state, front_address, 1, false);
+    let mut backend = backends.pop().unwrap();
+
+    backend.connect();
+
+    let mut client = Client::new(
+        "client",
+        front_address,
+        "GET /hints HTTP/1.0\r\nHost: localhost\r\n\r\n",
+    );
+    client.connect();
+    client.send();
+    backend.accept(0);
+    let request = backend.receive(0);
+    println!("request: {request:?}");
+
+    // HTTP/1.0 has no interim responses, the client only gets the final one
+    backend.set_response("HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload\r\n\r\n");
+    backend.send(0);
+    thread::sleep(Duration::from_millis(100));
+    backend.set_response("HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\npong");
+    backend.send(0);
+
+    let response = client.receive();
+    println!("response: {response:?}");
+    let success = response.is_some_and(|response| {
+        response.starts_with("HTTP/1.1 200 OK\r\n") && response.ends_with("\r\n\r\npong")
+    });
+
+    worker.hard_stop();
+    worker.wait_for_server_stop();
+    if success {
+        State::Success
+    } else {
+        State::Fail
+    }
+}
+
+pub fn try_tls_headers() -> State {
+    let forwarding_address = SocketAddress::new_v4(127, 0, 0, 1, provide_port());
+    let plain_address = SocketAddress::new_v4(127, 0, 0, 1, provide_port());
+    let back_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let mut worker = Worker::start_new_worker("TLS-HEADERS", config, &listeners, state);
+
+    worker.send_proxy_request_type(RequestType::AddCluster(Worker::default_cluster(
+        "cluster_0",
+    )));
+    for (address, forward) in [(&forwarding_address, true), (&plain_address, false)] {
+        worker.send_proxy_request_type(RequestType::AddHttpsListener(
+            ListenerBuilder::new_https(*address)
+                .with_forward_tls_headers(Some(forward))
+                .to_tls(None)
+                .unwrap(),
+        ));
+        worker.send_proxy_request_type(RequestType::ActivateListener(ActivateListener {
+            address: *address,
+            proxy: ListenerType::Https.into(),
+            from_scm: false,
+        }));
+        worker.send_proxy_request_type(RequestType::AddHttpsFrontend(RequestHttpFrontend {
+            hostname: "localhost".to_owned(),
+            ..Worker::default_http_frontend("cluster_0", (*address).into())
+        }));
+        worker.send_proxy_request_type(RequestType::AddCertificate(AddCertificate {
+            address: *address,
+            certificate: CertificateAndKey {
+                certificate: String::from(include_str!(
+                    "../../../lib/assets/local-certificate.pem"
+                )),
+                key: String::from(include_str!("../../../lib/assets/local-key.pem")),
+                certificate_chain: vec![],
+                versions: vec![],
+                names: vec![],
+            },
+            expired_at: None,
+        }));
+    }
+    worker.send_proxy_request_type(RequestType::AddBackend(Worker::default_backend(
+        "cluster_0",
+        "cluster_0-0",
+        back_address,
+        None,
+    )));
+    worker.read_to_last();
+
+    let mut backend = SyncBackend::new("BACKEND", back_address, http_ok_response("pong"));
+    backend.connect();
+
+    // a client pretends to know better than Sōzu what its handshake was
+    let mut requests = Vec::new();
+    for (client_id, address) in [forwarding_address, plain_address].into_iter().enumerate() {
+        let client = thread::spawn(move || {
+            tls_request_with_alpn(
+                address.into(),
+                "localhost",
+                &[b"http/1.1"],
+                "GET /api HTTP/1.1\r\nHost: localhost\r\nX-Forwarded-TLS-SNI: spoofed\r\nConnection: close\r\n\r\n",
+            )
+        });
+        if !(0..50).any(|_| backend.accept(client_id)) {
+            println!("the request never reached the backend");
+            return State::Fail;
+        }
+        let request = backend.receive(client_id).unwrap_or_default();
+        println!("request: {request:?}");
+        backend.send(client_id);
+        let response = client.join().unwrap();
+        println!("response: {response:?}");
+        if !response.is_some_and(|response| response.starts_with("HTTP/1.1 200")) {
+            return State::Fail;
+        }
+        requests.push(request);
+    }
+
+    worker.hard_stop();
+    worker.wait_for_server_stop();
+
+    let forwarded = &requests[0];
+    let plain = &requests[1];
+    if forwarded.contains("X-Forwarded-TLS-Version: TLSv1.")
+        && forwarded.contains("X-Forwarded-TLS-Cipher: TLS")
+        && forwarded.contains("X-Forwarded-TLS-SNI: localhost\r\n")
+        && !forwarded.contains("spoofed")
+        && !plain.contains("X-Forwarded-TLS-")
+    {
+        State::Success
+    } else {
+        State::Fail
+    }
+}
+
+pub fn try_status_header_split() -> State {
+    let front_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, mut backends) =
+        setup_sync_test("SPLIT", config, listeners, state, front_address, 1, false);
+    let mut backend = backends.pop().unwrap();
+
+    backend.connect();
+
+    let mut client = Client::new("client", front_address, "");
+
+    client.connect();
+
+    let mut accepted = false;
+    for (i, chunk) in [
+        "POST /api HTTP/1.",
+        "1\r\n",
+        "Host: localhost\r\n",
+        "Content-Length",
+        ":",
+        " 1",
+        "0",
+        "\r",
+        "\n\r",
+        "\n012",
+        "34567",
+        "89",
+    ]
+    .iter()
+    .enumerate()
+    {
+        println!("{accepted} {i} {chunk:?}");
+        client.set_request(*chunk);
+        client.send();
+        if !accepted {
+            println!("accepting");
+            accepted = backend.accept(0);
+            if accepted {
+                assert_eq!(i, 9);
+            }
+            backend.send(0);
+        }
+        if accepted {
+            println!("receiving");
+            let request = backend.receive(0);
+            println!("request: {request:?}");
+        }
+    }
+    backend.send(0);
+    let response = client.receive();
+    println!("response: {response:?}");
+    assert!(response.is_some());
+
+    worker.hard_stop();
+    worker.wait_for_server_stop();
+    State::Success
+}
+
+fn try_wildcard() -> State {
+    use sozu_command_lib::proto::command::{PathRule, RulePosition};
+    let front_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let mut worker = Worker::start_new_worker("WLD_CRD", config, &listeners, state);
+    worker.send_proxy_request(
+        RequestType::AddHttpListener(
+            ListenerBuilder::new_http(front_address.into())
+                .to_http(None)
+                .unwrap(),
+        )
+        .into(),
+    );
+    worker.send_proxy_request(
+        RequestType::ActivateListener(ActivateListener {
+            address: front_address.into(),
+            proxy: ListenerType::Http.into(),
+            from_scm: false,
+        })
+        .into(),
+    );
+
+    worker.send_proxy_request(RequestType::AddCluster(Worker::default_cluster("cluster_0")).into());
+    worker.send_proxy_request(RequestType::AddCluster(Worker::default_cluster("cluster_1")).into());
+
+    worker.send_proxy_request(
+        RequestType::AddHttpFrontend(RequestHttpFrontend {
+            cluster_id: Some("cluster_0".to_string()),
+            address: front_address.into(),
+            hostname: String::from("*.sozu.io"),
+            path: PathRule::prefix(String::from("")),
+            position: RulePosition::Tree.into(),
+            ..Default::default()
+        })
+        .into(),
+    );
+
+    let back_address: SocketAddr = create_local_address();
+    worker.send_proxy_request(
+        RequestType::AddBackend(Worker::default_backend(
+            "cluster_0",
+            "cluster_0-0",
+            back_address,
+            None,
+        ))
+        .into(),
+    );
+    worker.read_to_last();
+
+    let mut backend0 = SyncBackend::new(
+        "BACKEND_0",
+        back_address,
+        http_ok_response("pong0".to_string()),
+    );
+
+    let mut client = Client::new(
+        "client",
+        front_address,
+        http_request("POST", "/api", "ping".to_string(), "www.sozu.io"),
+    );
+
+    backend0.connect();
+    client.connect();
+    client.send();
+    let accepted = backend0.accept(0);
+    assert!(accepted);
+    let request = backend0.receive(0);
+    println!("request: {request:?}");
+    backend0.send(0);
+    let response = client.receive();
+    println!("response: {response:?}");
+
+    worker.send_proxy_request(
+        RequestType::AddHttpFrontend(RequestHttpFrontend {
+            cluster_id: Some("cluster_1".to_string()),
+            address: front_address.into(),
+            hostname: String::from("*.sozu.io"),
+            path: PathRule::prefix(String::from("/api")),
+            position: RulePosition::Tree.into(),
+            ..Default::default()
+        })
+        .into(),
+    );
+    let back_address: SocketAddr = create_local_address();
+    worker.send_proxy_request(
+        RequestType::AddBackend(Worker::default_backend(
+            "cluster_1",
+            "cluster_1-0",
+            back_address,
+            None,
+        ))
+        .into(),
+    );
+
+    let mut backend1 = SyncBackend::new(
+        "BACKEND_1",
+        back_address,
+        http_ok_response("pong1".to_string()),
+    );
+
+    worker.read_to_last();
+
+    backend1.connect();
+
+    client.send();
+    let accepted = backend1.accept(0);
+    assert!(accepted);
+    let request = backend1.receive(0);
+    println!("request: {request:?}");
+    backend1.send(0);
+    let response = client.receive();
+    println!("response: {response:?}");
+
+    State::Success
+}
+
+/// read a response until the end of its body, known from its Content-Length or last chunk
+fn read_raw_response(stream: &mut TcpStream) -> (String, Vec<u8>) {
+    let mut response = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        if let Some(end) = response.windows(4).position(|w| w == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&response[..end]).to_string();
+            let body = &response[end + 4..];
+            let length = head
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length: "))
+                .and_then(|length| length.parse::<usize>().ok());
+            match length {
+                Some(length) if body.len() >= length => return (head, body.to_vec()),
+                None if body.ends_with(b"0\r\n\r\n") => {
+                    // remove the chunk framing
+                    let mut data = Vec::new();
+                    let mut rest = body;
+                    while let Some(eol) = rest.windows(2).position(|w| w == b"\r\n") {
+                        let size =
+                            usize::from_str_radix(&String::from_utf8_lossy(&rest[..eol]), 16)
+                                .unwrap();
+                        if size == 0 {
+                            break;
+                        }
+                        data.extend_from_slice(&rest[eol + 2..eol + 2 + size]);
+                        rest = &rest[eol + 2 + size + 2..];
+                    }
+                    return (head, data);
+                }
+                _ => {}
+            }
+        }
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => panic!("incomplete response: {response:?}"),
+            Ok(n) => response.extend_from_slice(&buf[..n]),
+        }
+    }
+}
+
+pub fn try_send_proxy_protocol() -> State {
+    let front_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, back_addresses) = setup_test(
+        "SEND-PROXY",
+        config,
+        listeners,
+        state,
+        front_address,
+        1,
+        false,
+    );
+    worker.send_proxy_request_type(RequestType::AddCluster(Cluster {
+        proxy_protocol: Some(ProxyProtocolConfig::SendHeader as i32),
+        ..Worker::default_cluster("cluster_0")
+    }));
+    worker.read_to_last();
+    let backend = TcpListener::bind(back_addresses[0]).unwrap();
+
+    let mut client = TcpStream::connect(front_address).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let client_address = client.local_addr().unwrap();
+    let request = b"GET /api HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    client.write_all(request).unwrap();
+
+    let (mut server, _) = backend.accept().unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let mut received = Vec::new();
+    while !received.ends_with(b"\r\n\r\n") {
+        let mut buf = [0u8; 4096];
+        match server.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => received.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    // signature, PROXY command over TCP/IPv4, then the source and destination addresses
+    let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+    header.extend_from_slice(&[127, 0, 0, 1, 127, 0, 0, 1]);
+    header.extend_from_slice(&client_address.port().to_be_bytes());
+    header.extend_from_slice(&front_address.port().to_be_bytes());
+    if !received.starts_with(&header) || !received[header.len()..].starts_with(b"GET /api ") {
+        println!("first request: {received:?}");
+        return State::Fail;
+    }
+    server
+        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\npong0")
+        .unwrap();
+    let (head, _) = read_raw_response(&mut client);
+    println!("response: {head:?}");
+
+    // the backend connection is kept alive, no header is sent on it again
+    client.write_all(request).unwrap();
+    let mut buf = [0u8; 4096];
+    let received = match server.read(&mut buf) {
+        Ok(n) => buf[..n].to_vec(),
+        Err(_) => Vec::new(),
+    };
+    if !received.starts_with(b"GET /api ") {
+        println!("second request: {received:?}");
+        return State::Fail;
+    }
+    server
+        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\npong1")
+        .unwrap();
+    let (head, body) = read_raw_response(&mut client);
+    println!("response: {head:?}");
+
+    worker.hard_stop();
+    worker.wait_for_server_stop();
+    if body == b"pong1" {
+        State::Success
+    } else {
+        State::Fail
+    }
+}
+
+pub fn try_keep_alive_after_default_answer() -> State {
+    let front_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, mut backends) = setup_sync_test(
+        "KEEP-ALIVE-DEFAULT-ANSWER",
+        config,
+        listeners,
+        state,
+        front_address,
+        1,
+        false,
+    );
+    let mut backend = backends.pop().unwrap();
+
+    // requests for this host are denied with a 401
+    worker.send_proxy_request_type(RequestType::AddHttpFrontend(RequestHttpFrontend {
+        cluster_id: None,
+        hostname: "private".to_owned(),
+        ..Worker::default_http_frontend("cluster_0", front_address)
+    }));
+    worker.read_to_last();
+    backend.connect();
+
+    let mut client = TcpStream::connect(front_address).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+
+    // the answer is written before the body is completely sent
+    client
+        .write_all(b"POST /admin HTTP/1.1\r\nHost: private\r\nContent-Length: 10\r\n\r\nhello")
+        .unwrap();
+    let (head, _) = read_raw_response(&mut client);
+    println!("response: {head:?}");
+    assert!(head.starts_with("HTTP/1.1 401 Unauthorized"));
+    assert!(!head.contains("Connection: close"));
+
+    // the rest of the body is drained, then a chunked request to an unknown host
+    // and a pipelined one are read from the same connection
+    client
+        .write_all(
+            b"world\
+            POST /api HTTP/1.1\r\nHost: unknown\r\nTransfer-Encoding: chunked\r\n\r\n\
+            4\r\nping\r\n0\r\n\r\n\
+            GET /api HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        )
+        .unwrap();
+    let (head, _) = read_raw_response(&mut client);
+    println!("response: {head:?}");
+    assert!(head.starts_with("HTTP/1.1 404 Not Found"));
+
+    backend.accept(0);
+    let request = backend.receive(0);
+    println!("request: {request:?}");
+    match request {
+        Some(request) if request.starts_with("GET /api HTTP/1.1") => {}
+        _ => return State::Fail,
+    }
+    backend.send(0);
+    let (head, body) = read_raw_response(&mut client);
+    println!("response: {head:?}");
+    assert!(head.starts_with("HTTP/1.1 200 OK"));
+    assert_eq!(body, b"pong0");
+
+    worker.soft_stop();
+    worker.wait_for_server_stop();
+    State::Success
+}
+
+pub fn try_strict_sni_binding() -> State {
+    let front_address = SocketAddress::new_v4(127, 0, 0, 1, provide_port());
+    let back_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let mut worker = Worker::start_new_worker("STRICT-SNI", config, &listeners, state);
+
+    worker.send_proxy_request_type(RequestType::AddCluster(Worker::default_cluster(
+        "cluster_0",
+    )));
+    worker.send_proxy_request_type(RequestType::AddHttpsListener(
+        ListenerBuilder::new_https(front_address)
+            .with_strict_sni_binding(Some(true))
+            .to_tls(None)
+            .unwrap(),
+    ));
+    worker.send_proxy_request_type(RequestType::ActivateListener(ActivateListener {
+        address: front_address,
+        proxy: ListenerType::Https.into(),
+        from_scm: false,
+    }));
+    // both hosts are served by the listener, only the one of the SNI may be requested
+    for hostname in ["localhost", "example.com"] {
+        worker.send_proxy_request_type(RequestType::AddHttpsFrontend(RequestHttpFrontend {
+            hostname: hostname.to_owned(),
+            ..Worker::default_http_frontend("cluster_0", front_address.into())
+        }));
+    }
+    worker.send_proxy_request_type(RequestType::AddCertificate(AddCertificate {
+        address: front_address,
+        certificate: CertificateAndKey {
+            certificate: String::from(include_str!("../../../lib/assets/local-certificate.pem")),
+            key: String::from(include_str!("../../../lib/assets/local-key.pem")),
+            certificate_chain: vec![],
+            versions: vec![],
+            names: vec![],
+        },
+        expired_at: None,
+    }));
+    worker.send_proxy_request_type(RequestType::AddBackend(Worker::default_backend(
+        "cluster_0",
+        "cluster_0-0",
+        back_address,
+        None,
+    )));
+    worker.read_to_last();
+
+    let mut backend = AsyncBackend::spawn_detached_backend(
+        "BACKEND",
+        back_address,
+        SimpleAggregator::default(),
+        AsyncBackend::http_handler("pong"),
+    );
+
+    let request = |host: &str| {
+        tls_request_with_alpn(
+            front_address.into(),
+            "localhost",
+            &[b"http/1.1"],
+            &format!("GET /api HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\n\r\n"),
+        )
+    };
+    let fronted = request("example.com");
+    println!("request for another host than the SNI: {fronted:?}");
+    let matching = request("localhost");
+    println!("request for the host of the SNI: {matching:?}");
+
+    worker.soft_stop();
+    let success = worker.wait_for_server_stop();
+
+    let aggregator = backend
+        .stop_and_get_aggregator()
+        .expect("Could not get aggregator");
+    let starts_with = |response: &Option<String>, status: &str| {
+        response
+            .as_ref()
+            .is_some_and(|response| response.starts_with(status))
+    };
+    if success
+        && starts_with(&fronted, "HTTP/1.1 421")
+        && starts_with(&matching, "HTTP/1.1 200")
+        && aggregator.responses_sent == 1
+    {
+        State::Success
+    } else {
+        State::Fail
+    }
+}
+
+pub fn try_body_size_metrics() -> State {
+    let front_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, mut backends) = setup_sync_test(
+        "BODY-SIZE-METRICS",
+        config,
+        listeners,
+        state,
+        front_address,
+        1,
+        false,
+    );
+    let mut backend = backends.pop().unwrap();
+    backend.connect();
+    backend.set_response("HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\npong");
+
+    let mut client = Client::new(
+        "client",
+        front_address,
+        "POST /api HTTP/1.1\r\nHost: localhost\r\nContent-Length: 11\r\n\r\nhello world",
+    );
+    client.connect();
+    client.send();
+    backend.accept(0);
+    backend.receive(0);
+    backend.send(0);
+    let response = client.receive();
+    println!("response: {response:?}");
+    match response {
+        Some(response) if response.starts_with("HTTP/1.1 200") => {}
+        _ => return State::Fail,
+    }
+
+    let metrics = worker.query_metrics(QueryMetricsOptions {
+        cluster_ids: vec![String::from("cluster_0")],
+        metric_names: vec![
+            String::from("http.request.body_size"),
+            String::from("http.response.body_size"),
+        ],
+        ..Default::default()
+    });
+    println!("metrics: {metrics:?}");
+    let body_size = |name: &str| {
+        let cluster = &metrics.as_ref()?.clusters.get("cluster_0")?.cluster;
+        match &cluster.get(name)?.inner {
+            Some(Inner::Percentiles(percentiles)) => Some((percentiles.samples, percentiles.p_100)),
+            _ => None,
+        }
+    };
+    if body_size("http.request.body_size") != Some((1, 11))
+        || body_size("http.response.body_size") != Some((1, 4))
+    {
+        return State::Fail;
+    }
+
+    worker.soft_stop();
+    worker.wait_for_server_stop();
+    State::Success
+}
+
+pub fn try_query_sessions() -> State {
+    let front_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, mut backends) = setup_sync_test(
+        "QUERY-SESSIONS",
+        config,
+        listeners,
+        state,
+        front_address,
+        1,
+        false,
+    );
+    let mut backend = backends.pop().unwrap();
+    backend.connect();
+
+    let mut client = Client::new(
+        "client",
+        front_address,
+        "GET /api HTTP/1.1\r\nHost: localhost\r\n\r\n",
+    );
+    client.connect();
+    client.send();
+    backend.accept(0);
+    backend.receive(0);
+
+    // the request waits for its response, the session is listed with its cluster
+    let sessions = match worker.query(RequestType::QuerySessions(QuerySessions {})) {
+        Some(ContentType::SessionSnapshots(snapshots)) => snapshots.sessions,
+        other => {
+            println!("unexpected response: {other:?}");
+            return State::Fail;
+        }
+    };
+    println!("sessions: {sessions:?}");
+    match sessions.as_slice() {
+        [session]
+            if session.protocol == "HTTP" && session.cluster_id.as_deref() == Some("cluster_0") => {
+        }
+        _ => return State::Fail,
+    }
+
+    backend.send(0);
+    let response = client.receive();
+    println!("response: {response:?}");
+    match response {
+        Some(response) if response.starts_with("HTTP/1.1 200") => {}
+        _ => return State::Fail,
+    }
+
+    worker.soft_stop();
+    worker.wait_for_server_stop();
+    State::Success
+}
+
+pub fn try_path_normalization() -> State {
+    let front_address = create_local_address();
+    let forwarding_address = create_local_address();
+    let admin_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, _backends) = setup_sync_test(
+        "PATH-NORMALIZATION",
+        config,
+        listeners,
+        state,
+        front_address,
+        1,
+        false,
+    );
+
+    // the second listener sends the normalized path to the backends
+    worker.send_proxy_request_type(RequestType::AddHttpListener(
+        ListenerBuilder::new_http(forwarding_address.into())
+            .with_forward_normalized_path(Some(true))
+            .to_http(None)
+            .unwrap(),
+    ));
+    worker.send_proxy_request_type(RequestType::ActivateListener(ActivateListener {
+        address: forwarding_address.into(),
+        proxy: ListenerType::Http.into(),
+        from_scm: false,
+    }));
+    worker.send_proxy_request_type(RequestType::AddCluster(Worker::default_cluster("admin")));
+    for address in [front_address, forwarding_address] {
+        worker.send_proxy_request_type(RequestType::AddHttpFrontend(RequestHttpFrontend {
+            path: PathRule::prefix(String::from("/admin")),
+            ..Worker::default_http_frontend("admin", address)
+        }));
+    }
+    worker.send_proxy_request_type(RequestType::AddBackend(Worker::default_backend(
+        "admin",
+        "admin-0",
+        admin_address,
+        None,
+    )));
+    worker.read_to_last();
+
+    let mut admin = SyncBackend::new("admin", admin_address, http_ok_response("admin"));
+    admin.connect();
+
+    let request = |address, path: &str| {
+        let mut client = Client::new(
+            "client",
+            address,
+            format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+        );
+        client.connect();
+        client.send();
+        client
+    };
+
+    // paths that resolve to /admin reach its cluster, with the path of the client
+    for (index, path) in ["/static/../admin", "/%61dmin"].into_iter().enumerate() {
+        let mut client = request(front_address, path);
+        admin.accept(index);
+        let received = admin.receive(index);
+        println!("admin received: {received:?}");
+        if !received.is_some_and(|received| received.starts_with(&format!("GET {path} HTTP/1.1"))) {
+            return State::Fail;
+        }
+        admin.send(index);
+        let response = client.receive();
+        println!("response: {response:?}");
+        if !response.is_some_and(|response| response.ends_with("admin")) {
+            return State::Fail;
+        }
+    }
+
+    // or with the normalized path, if the listener forwards it
+    let mut client = request(forwarding_address, "/static/../admin");
+    admin.accept(2);
+    let received = admin.receive(2);
+    println!("admin received: {received:?}");
+    if !received.is_some_and(|received| received.starts_with("GET /admin HTTP/1.1")) {
+        return State::Fail;
+    }
+    admin.send(2);
+    if !client
+        .receive()
+        .is_some_and(|response| response.ends_with("admin"))
+    {
+        return State::Fail;
+    }
+
+    // paths going above the root or badly encoded are refused
+    for path in ["/../admin", "/%zzadmin"] {
+        let mut client = request(front_address, path);
+        let response = client.receive();
+        println!("response: {response:?}");
+        if !response.is_some_and(|response| response.starts_with("HTTP/1.1 400")) {
+            return State::Fail;
+        }
+    }
+    let metrics = worker.query_metrics(QueryMetricsOptions {
+        metric_names: vec![String::from("http.invalid_paths")],
+        no_clusters: true,
+        ..Default::default()
+    });
+    println!("metrics: {metrics:?}");
+    let invalid_paths = metrics
+        .as_ref()
+        .and_then(|metrics| metrics.proxy.get("http.invalid_paths"))
+        .and_then(|metric| metric.inner.as_ref());
+    if invalid_paths != Some(&Inner::Count(2)) {
+        return State::Fail;
+    }
+
+    worker.soft_stop();
+    worker.wait_for_server_stop();
+    State::Success
+}
+
+#[test]
+fn test_sync() {
+    assert_eq!(try_sync(10, 100), State::Success);
+}
+
+#[test]
+fn test_async() {
+    assert_eq!(try_async(3, 10, 100), State::Success);
+}
+
+#[test]
+fn test_hard_stop() {
+    assert_eq!(
         repeat_until_error_or(
             10,
             "Hard Stop: Test that the worker shuts down even if backends are not done",
@@ -1814,6 +4050,162 @@ fn test_wildcard() {
     );
 }
 
+#[test]
+fn test_mirror() {
+    assert_eq!(
+        repeat_until_error_or(2, "Mirror requests to a shadow cluster", try_mirror),
+        State::Success
+    );
+}
+
+#[test]
+fn test_connect_tunnel() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "CONNECT opens a tunnel on forward proxies",
+            try_connect_tunnel
+        ),
+        State::Success
+    );
+}
+
+#[test]
+fn test_server_timing() {
+    assert_eq!(
+        repeat_until_error_or(2, "Server-Timing header on responses", try_server_timing),
+        State::Success
+    );
+}
+
+#[test]
+fn test_slow_loris() {
+    assert_eq!(
+        repeat_until_error_or(2, "Slow header attacks are bounded", try_slow_loris),
+        State::Success
+    );
+}
+
+#[test]
+fn test_preserve_header_case() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "Host and Cookie header names keep the client's case",
+            try_preserve_header_case
+        ),
+        State::Success
+    );
+}
+
+#[test]
+fn test_request_trailers() {
+    assert_eq!(
+        repeat_until_error_or(2, "Request trailers are filtered", try_request_trailers),
+        State::Success
+    );
+}
+
+#[test]
+fn test_unsupported_transfer_encoding() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "Transfer codings other than a single chunked are answered with a 501",
+            try_unsupported_transfer_encoding
+        ),
+        State::Success
+    );
+}
+
+#[test]
+fn test_conflicting_framing() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "Requests with an ambiguous length are answered with a 400",
+            try_conflicting_framing
+        ),
+        State::Success
+    );
+}
+
+#[test]
+fn test_absolute_form() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "Forward proxy listeners accept absolute-form requests and send them in origin-form",
+            try_absolute_form
+        ),
+        State::Success
+    );
+}
+
+#[test]
+fn test_pipelining() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "Pipelined requests are answered in order",
+            try_pipelining
+        ),
+        State::Success
+    );
+}
+
+#[test]
+fn test_compression() {
+    assert_eq!(
+        repeat_until_error_or(2, "Compression of the responses", try_compression),
+        State::Success
+    );
+}
+
+#[test]
+fn test_server_sent_events() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "Server-Sent Events stream past the backend timeout",
+            try_server_sent_events
+        ),
+        State::Success
+    );
+}
+
+#[test]
+fn test_alt_svc() {
+    assert_eq!(
+        repeat_until_error_or(2, "Alt-Svc header added by the listener", try_alt_svc),
+        State::Success
+    );
+}
+
+#[test]
+fn test_max_header_sizes() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "Request and response headers over the listener limits",
+            try_max_header_sizes
+        ),
+        State::Success
+    );
+}
+
+#[test]
+fn test_forwarded_headers() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "Forwarded and X-Forwarded-* headers chosen by the cluster",
+            try_forwarded_headers
+        ),
+        State::Success
+    );
+}
+
 #[test]
 fn test_status_header_split() {
     assert_eq!(
@@ -1821,3 +4213,207 @@ fn test_status_header_split() {
         State::Success
     );
 }
+
+#[test]
+fn test_requests_without_host() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "Requests without Host rejected or routed to the listener cluster",
+            try_requests_without_host
+        ),
+        State::Success
+    );
+}
+
+#[test]
+fn test_response_buffering() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "Responses sent once complete, with a Content-Length",
+            try_response_buffering
+        ),
+        State::Success
+    );
+}
+
+#[test]
+fn test_read_high_water_mark() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "Request bodies go through a listener with a read high-water mark",
+            try_read_high_water_mark
+        ),
+        State::Success
+    );
+}
+
+#[test]
+fn test_max_uri_length() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "Request targets over max_uri_length get a 414",
+            try_max_uri_length
+        ),
+        State::Success
+    );
+}
+
+#[test]
+fn test_request_id_header() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "The request id is forwarded in a configurable header",
+            try_request_id_header
+        ),
+        State::Success
+    );
+}
+
+#[test]
+fn test_chunked_upload() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "Chunked request bodies larger than the buffer reach the backend",
+            try_chunked_upload
+        ),
+        State::Success
+    );
+}
+
+#[test]
+fn test_alpn_mismatch_fallback() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "Clients without a supported ALPN protocol are rejected or served in HTTP/1.1",
+            try_alpn_mismatch_fallback
+        ),
+        State::Success
+    );
+}
+
+#[test]
+fn test_backend_queue() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "Requests wait for a backend of their cluster to come back, up to the queue timeout",
+            try_backend_queue
+        ),
+        State::Success
+    );
+}
+
+#[test]
+fn test_retry_after() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "The 503 answers of a cluster carry its Retry-After",
+            try_retry_after
+        ),
+        State::Success
+    );
+}
+
+#[test]
+fn test_interim_responses_to_http10_clients() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "HTTP/1.0 clients only get the final response, not the interim ones",
+            try_interim_responses_to_http10_clients
+        ),
+        State::Success
+    );
+}
+
+#[test]
+fn test_tls_headers() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "The TLS details are forwarded by Sōzu only, never by clients",
+            try_tls_headers
+        ),
+        State::Success
+    );
+}
+
+#[test]
+fn test_send_proxy_protocol() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "A PROXY protocol v2 header starts each new backend connection",
+            try_send_proxy_protocol
+        ),
+        State::Success
+    );
+}
+
+#[test]
+fn test_keep_alive_after_default_answer() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "The request of a default answer is drained and the connection kept",
+            try_keep_alive_after_default_answer
+        ),
+        State::Success
+    );
+}
+
+#[test]
+fn test_strict_sni_binding() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "A request for another host than the SNI gets a 421 on a strict listener",
+            try_strict_sni_binding
+        ),
+        State::Success
+    );
+}
+
+#[test]
+fn test_body_size_metrics() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "Request and response body sizes are recorded in the cluster histograms",
+            try_body_size_metrics
+        ),
+        State::Success
+    );
+}
+
+#[test]
+fn test_query_sessions() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "A request waiting for its response is listed in the sessions of the worker",
+            try_query_sessions
+        ),
+        State::Success
+    );
+}
+
+#[test]
+fn test_path_normalization() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "Requests are routed on their normalized path, unsafe paths get a 400",
+            try_path_normalization
+        ),
+        State::Success
+    );
+}
@@ -300,6 +300,28 @@ impl BackendMap {
         Ok((next_backend.clone(), tcp_stream))
     }
 
+    /// Take a place among the requests waiting for a backend of the cluster while
+    /// none is available. Only clusters that have backends queue requests, false if
+    /// the cluster has none or `queue_depth` requests are already waiting
+    pub fn enqueue_request(&mut self, cluster_id: &str, queue_depth: usize) -> bool {
+        match self.backends.get_mut(cluster_id) {
+            Some(cluster_backends)
+                if !cluster_backends.backends.is_empty()
+                    && cluster_backends.queued_requests < queue_depth =>
+            {
+                cluster_backends.queued_requests += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn dequeue_request(&mut self, cluster_id: &str) {
+        if let Some(cluster_backends) = self.backends.get_mut(cluster_id) {
+            cluster_backends.queued_requests = cluster_backends.queued_requests.saturating_sub(1);
+        }
+    }
+
     pub fn backend_from_sticky_session(
         &mut self,
         cluster_id: &str,
@@ -347,6 +369,17 @@ impl BackendMap {
         cluster_backends.set_load_balancing_policy(lb_algo, metric);
     }
 
+    /// Plug a custom backend selection for a cluster. It is replaced if the cluster
+    /// configuration is updated with a new load balancing policy.
+    pub fn set_load_balancing_algorithm_for_cluster(
+        &mut self,
+        cluster_id: &str,
+        algorithm: Box<dyn LoadBalancingAlgorithm>,
+    ) {
+        let cluster_backends = self.get_or_create_backend_list_for_cluster(cluster_id);
+        cluster_backends.set_load_balancing_algorithm(algorithm);
+    }
+
     pub fn get_or_create_backend_list_for_cluster(&mut self, cluster_id: &str) -> &mut BackendList {
         self.backends.entry(cluster_id.to_string()).or_default()
     }
@@ -357,6 +390,8 @@ pub struct BackendList {
     pub backends: Vec<Rc<RefCell<Backend>>>,
     pub next_id: u32,
     pub load_balancing: Box<dyn LoadBalancingAlgorithm>,
+    /// requests waiting for one of the backends to come back
+    pub queued_requests: usize,
 }
 
 impl Default for BackendList {
@@ -371,6 +406,7 @@ impl BackendList {
             backends: Vec::new(),
             next_id: 0,
             load_balancing: Box::new(Random),
+            queued_requests: 0,
         }
     }
 
@@ -470,22 +506,22 @@ impl BackendList {
         load_balancing_policy: LoadBalancingAlgorithms,
         metric: Option<LoadMetric>,
     ) {
-        match load_balancing_policy {
-            LoadBalancingAlgorithms::RoundRobin => {
-                self.load_balancing = Box::new(RoundRobin::new())
-            }
-            LoadBalancingAlgorithms::Random => self.load_balancing = Box::new(Random {}),
-            LoadBalancingAlgorithms::LeastLoaded => {
-                self.load_balancing = Box::new(LeastLoaded {
-                    metric: metric.unwrap_or(LoadMetric::Connections),
-                })
-            }
-            LoadBalancingAlgorithms::PowerOfTwo => {
-                self.load_balancing = Box::new(PowerOfTwo {
-                    metric: metric.unwrap_or(LoadMetric::Connections),
-                })
-            }
-        }
+        let algorithm: Box<dyn LoadBalancingAlgorithm> = match load_balancing_policy {
+            LoadBalancingAlgorithms::RoundRobin => Box::new(RoundRobin::new()),
+            LoadBalancingAlgorithms::Random => Box::new(Random {}),
+            LoadBalancingAlgorithms::LeastLoaded => Box::new(LeastLoaded {
+                metric: metric.unwrap_or(LoadMetric::Connections),
+            }),
+            LoadBalancingAlgorithms::PowerOfTwo => Box::new(PowerOfTwo {
+                metric: metric.unwrap_or(LoadMetric::Connections),
+            }),
+        };
+        self.set_load_balancing_algorithm(algorithm);
+    }
+
+    /// Use a custom backend selection, instead of one of the [LoadBalancingAlgorithms]
+    pub fn set_load_balancing_algorithm(&mut self, algorithm: Box<dyn LoadBalancingAlgorithm>) {
+        self.load_balancing = algorithm;
     }
 }
 
@@ -665,4 +701,70 @@ mod backends_test {
 
         assert_eq!(1, backends_list.backends.len());
     }
+
+    #[test]
+    fn it_should_queue_requests_up_to_the_depth_of_clusters_with_backends() {
+        let mut backend_map = BackendMap::new();
+        backend_map.add_backend(
+            "mycluster",
+            Backend::new(
+                "mycluster-1",
+                "127.0.0.1:80".parse().unwrap(),
+                None,
+                None,
+                None,
+            ),
+        );
+        backend_map.get_or_create_backend_list_for_cluster("empty");
+
+        assert!(backend_map.enqueue_request("mycluster", 2));
+        assert!(backend_map.enqueue_request("mycluster", 2));
+        assert!(!backend_map.enqueue_request("mycluster", 2));
+        backend_map.dequeue_request("mycluster");
+        assert!(backend_map.enqueue_request("mycluster", 2));
+
+        assert!(!backend_map.enqueue_request("empty", 2));
+        assert!(!backend_map.enqueue_request("unknown", 2));
+    }
+
+    #[derive(Debug)]
+    struct LastBackend;
+
+    impl LoadBalancingAlgorithm for LastBackend {
+        fn next_available_backend(
+            &mut self,
+            backends: &mut Vec<Rc<RefCell<Backend>>>,
+        ) -> Option<Rc<RefCell<Backend>>> {
+            backends.last().cloned()
+        }
+    }
+
+    #[test]
+    fn it_should_select_backends_with_a_custom_load_balancing_algorithm() {
+        let mut backend_map = BackendMap::new();
+        let cluster_id = "mycluster";
+
+        for (index, address) in ["127.0.0.1:9001", "127.0.0.1:9002", "127.0.0.1:9003"]
+            .iter()
+            .enumerate()
+        {
+            backend_map.add_backend(
+                cluster_id,
+                Backend::new(
+                    &format!("{cluster_id}-{index}"),
+                    address.parse().unwrap(),
+                    None,
+                    None,
+                    None,
+                ),
+            );
+        }
+        backend_map.set_load_balancing_algorithm_for_cluster(cluster_id, Box::new(LastBackend));
+
+        let backend = backend_map
+            .get_or_create_backend_list_for_cluster(cluster_id)
+            .next_available_backend()
+            .expect("should select a backend");
+        assert_eq!(backend.borrow().backend_id, "mycluster-2");
+    }
 }
@@ -20,7 +20,7 @@ use sozu_command::{
     logging::CachedTags,
     proto::command::{
         request::RequestType, Cluster, HttpListenerConfig, ListenerType, RemoveListener,
-        RequestHttpFrontend, WorkerRequest, WorkerResponse,
+        RequestHttpFrontend, SessionSnapshot, WorkerRequest, WorkerResponse,
     },
     ready::Ready,
     response::HttpFrontend,
@@ -32,7 +32,7 @@ use crate::{
     pool::Pool,
     protocol::{
         http::{
-            answers::HttpAnswers,
+            answers::{is_valid_retry_after, HttpAnswers},
             parser::{hostname_and_port, Method},
             ResponseStream,
         },
@@ -41,7 +41,7 @@ use crate::{
     },
     router::{Route, Router},
     server::{ListenToken, SessionManager},
-    socket::server_bind,
+    socket::{server_bind, set_tcp_keepalive},
     timer::TimeoutContainer,
     AcceptError, FrontendFromRequestError, L7ListenerHandler, L7Proxy, ListenerError,
     ListenerHandler, Protocol, ProxyConfiguration, ProxyError, ProxySession, SessionIsToBeClosed,
@@ -214,7 +214,10 @@ impl HttpSession {
         }
     }
 
-    fn upgrade_http(&mut self, http: Http<TcpStream, HttpListener>) -> Option<HttpStateMachine> {
+    fn upgrade_http(
+        &mut self,
+        mut http: Http<TcpStream, HttpListener>,
+    ) -> Option<HttpStateMachine> {
         debug!("http switching to ws");
         let front_token = self.frontend_token;
         let back_token = match http.backend_token {
@@ -228,6 +231,9 @@ impl HttpSession {
             }
         };
 
+        // a tunnel has no request to mirror
+        http.close_mirror(self.proxy.clone());
+
         let ws_context = http.websocket_context();
         let mut container_frontend_timeout = http.container_frontend_timeout;
         let mut container_backend_timeout = http.container_backend_timeout;
@@ -262,6 +268,17 @@ impl HttpSession {
         pipe.backend_readiness.event = http.backend_readiness.event;
         pipe.set_back_token(back_token);
 
+        let (tunnel_idle_timeout, tunnel_max_lifetime) = {
+            let config = &self.listener.borrow().config;
+            (
+                config.tunnel_idle_timeout.unwrap_or(config.front_timeout),
+                config.tunnel_max_lifetime(),
+            )
+        };
+        pipe.set_tunnel_timeouts(
+            Duration::from_secs(tunnel_idle_timeout as u64),
+            (tunnel_max_lifetime > 0).then(|| Duration::from_secs(tunnel_max_lifetime as u64)),
+        );
         gauge_add!("protocol.http", -1);
         gauge_add!("protocol.ws", 1);
         gauge_add!("http.active_requests", -1);
@@ -386,6 +403,12 @@ impl ProxySession for HttpSession {
         error!("Metrics: {:?}", self.metrics);
     }
 
+    fn snapshot(&self) -> SessionSnapshot {
+        let mut snapshot = self.state.snapshot();
+        self.metrics.fill_snapshot(&mut snapshot);
+        snapshot
+    }
+
     fn frontend_token(&self) -> Token {
         self.frontend_token
     }
@@ -430,6 +453,62 @@ impl L7ListenerHandler for HttpListener {
         self.config.connect_timeout
     }
 
+    fn strict_sni_binding(&self) -> bool {
+        false
+    }
+
+    fn forward_proxy(&self) -> bool {
+        self.config.forward_proxy()
+    }
+
+    fn alt_svc(&self) -> Option<&str> {
+        self.config.alt_svc.as_deref()
+    }
+
+    fn replace_alt_svc(&self) -> bool {
+        self.config.replace_alt_svc()
+    }
+
+    fn max_request_header_size(&self) -> Option<u32> {
+        self.config.max_request_header_size
+    }
+
+    fn max_response_header_size(&self) -> Option<u32> {
+        self.config.max_response_header_size
+    }
+
+    fn no_host_cluster_id(&self) -> Option<&str> {
+        self.config.no_host_cluster_id.as_deref()
+    }
+
+    fn forward_tls_headers(&self) -> bool {
+        false
+    }
+
+    fn read_buffer_high_water_mark(&self) -> Option<u32> {
+        self.config.read_buffer_high_water_mark
+    }
+
+    fn max_uri_length(&self) -> u32 {
+        self.config.max_uri_length()
+    }
+
+    fn request_id_header(&self) -> Option<&str> {
+        self.config.request_id_header.as_deref()
+    }
+
+    fn trust_client_request_id(&self) -> bool {
+        self.config.trust_client_request_id()
+    }
+
+    fn reject_encoded_slashes(&self) -> bool {
+        self.config.reject_encoded_slashes()
+    }
+
+    fn forward_normalized_path(&self) -> bool {
+        self.config.forward_normalized_path()
+    }
+
     // redundant, already called once in extract_route
     fn frontend_from_request(
         &self,
@@ -605,6 +684,20 @@ impl HttpProxy {
                     })?;
             }
         }
+        if let Some(retry_after) = &cluster.retry_after {
+            if !is_valid_retry_after(retry_after) {
+                return Err(ProxyError::AddCluster(ListenerError::InvalidRetryAfter(
+                    retry_after.to_owned(),
+                )));
+            }
+        }
+        for listener in self.listeners.values() {
+            listener
+                .borrow()
+                .answers
+                .borrow_mut()
+                .set_retry_after(&cluster.cluster_id, cluster.retry_after.clone());
+        }
         self.clusters.insert(cluster.cluster_id.clone(), cluster);
         Ok(())
     }
@@ -613,11 +706,10 @@ impl HttpProxy {
         self.clusters.remove(cluster_id);
 
         for listener in self.listeners.values() {
-            listener
-                .borrow()
-                .answers
-                .borrow_mut()
-                .remove_custom_answer(cluster_id);
+            let listener = listener.borrow();
+            let mut answers = listener.answers.borrow_mut();
+            answers.remove_custom_answer(cluster_id);
+            answers.set_retry_after(cluster_id, None);
         }
         Ok(())
     }
@@ -893,6 +985,14 @@ impl ProxyConfiguration for HttpProxy {
                 frontend_sock, e
             );
         }
+        if let Some(keepalive) = &listener.borrow().config.tcp_keepalive {
+            if let Err(e) = set_tcp_keepalive(&frontend_sock, keepalive) {
+                error!(
+                    "error setting keepalive on front socket({:?}): {:?}",
+                    frontend_sock, e
+                );
+            }
+        }
         let mut session_manager = self.sessions.borrow_mut();
         let session_entry = session_manager.slab.vacant_entry();
         let session_token = Token(session_entry.key());
@@ -41,7 +41,7 @@ use sozu_command::{
         request::RequestType, response_content::ContentType, AddCertificate, CertificateSummary,
         CertificatesByAddress, Cluster, HttpsListenerConfig, ListOfCertificatesByAddress,
         ListenerType, RemoveCertificate, RemoveListener, ReplaceCertificate, RequestHttpFrontend,
-        ResponseContent, TlsVersion, WorkerRequest, WorkerResponse,
+        ResponseContent, SessionSnapshot, TlsVersion, WorkerRequest, WorkerResponse,
     },
     ready::Ready,
     response::HttpFrontend,
@@ -54,7 +54,7 @@ use crate::{
     protocol::{
         h2::Http2,
         http::{
-            answers::HttpAnswers,
+            answers::{is_valid_retry_after, HttpAnswers},
             parser::{hostname_and_port, Method},
             ResponseStream,
         },
@@ -64,7 +64,7 @@ use crate::{
     },
     router::{Route, Router},
     server::{ListenToken, SessionManager},
-    socket::{server_bind, FrontRustls},
+    socket::{server_bind, set_tcp_keepalive, FrontRustls},
     timer::TimeoutContainer,
     tls::MutexCertificateResolver,
     util::UnwrapLog,
@@ -153,14 +153,16 @@ impl HttpsSession {
             )
         } else {
             gauge_add!("protocol.tls.handshake", 1);
-            HttpsStateMachine::Handshake(TlsHandshake::new(
+            let mut handshake = TlsHandshake::new(
                 container_frontend_timeout,
                 rustls_details,
                 sock,
                 token,
                 request_id,
                 peer_address,
-            ))
+            );
+            handshake.set_alpn_fallback(listener.borrow().rustls_alpn_fallback.clone());
+            HttpsStateMachine::Handshake(handshake)
         };
 
         let metrics = SessionMetrics::new(Some(wait_time));
@@ -232,6 +234,7 @@ impl HttpsSession {
                     request_id,
                     self.peer_address,
                 );
+                handshake.set_alpn_fallback(self.listener.borrow().rustls_alpn_fallback.clone());
                 handshake.frontend_readiness.event = readiness.event;
                 // Can we remove this? If not why?
                 // Add e2e test for proto-proxy upgrades
@@ -316,16 +319,58 @@ impl HttpsSession {
                 Some(HttpsStateMachine::Http(http))
             }
             AlpnProtocols::H2 => {
-                let mut http = Http2::new(
+                let http = Http2::new(
                     front_stream,
                     self.frontend_token,
                     self.pool.clone(),
                     Some(self.public_address),
                     None,
                     self.sticky_name.clone(),
+                    self.configured_connect_timeout,
+                    self.listener
+                        .borrow()
+                        .config
+                        .max_backend_connections_per_session(),
                 );
+                let mut http = match http {
+                    Ok(http) => http,
+                    // the proxy is shutting down, the session closes with it
+                    Err(AcceptError::PoolDropped) => {
+                        info!("buffer pool dropped, closing the HTTP/2 session");
+                        return None;
+                    }
+                    Err(error) => {
+                        error!("could not create the HTTP/2 session: {:?}", error);
+                        return None;
+                    }
+                };
 
                 http.frontend.readiness.event = handshake.frontend_readiness.event;
+                {
+                    let listener = self.listener.borrow();
+                    http.set_stream_queue(
+                        listener.config.h2_stream_queue_depth(),
+                        Duration::from_millis(listener.config.h2_stream_queue_timeout() as u64),
+                    );
+                    http.set_max_frames_per_read(listener.config.h2_max_frames_per_read());
+                    http.set_max_new_streams_per_second(
+                        listener.config.h2_max_new_streams_per_second(),
+                    );
+                    http.set_window_update_threshold(listener.config.h2_window_update_threshold());
+                    http.set_slow_read_timeout(Duration::from_secs(
+                        listener.config.h2_slow_read_timeout() as u64,
+                    ));
+                    http.set_handshake_timeout(
+                        handshake.container_frontend_timeout,
+                        Duration::from_secs(listener.config.h2_handshake_timeout() as u64),
+                    );
+                    http.set_max_header_sizes(
+                        listener.config.max_request_header_size,
+                        listener.config.max_response_header_size,
+                    );
+                    http.set_no_host_cluster_id(listener.config.no_host_cluster_id.clone());
+                }
+                http.set_answers(self.answers.clone());
 
                 gauge_add!("protocol.http2", 1);
                 Some(HttpsStateMachine::Http2(http))
@@ -333,7 +378,10 @@ impl HttpsSession {
         }
     }
 
-    fn upgrade_http(&self, http: Http<FrontRustls, HttpsListener>) -> Option<HttpsStateMachine> {
+    fn upgrade_http(
+        &self,
+        mut http: Http<FrontRustls, HttpsListener>,
+    ) -> Option<HttpsStateMachine> {
         debug!("https switching to wss");
         let front_token = self.frontend_token;
         let back_token = match http.backend_token {
@@ -347,6 +395,9 @@ impl HttpsSession {
             }
         };
 
+        // a tunnel has no request to mirror
+        http.close_mirror(self.proxy.clone());
+
         let ws_context = http.websocket_context();
         let mut container_frontend_timeout = http.container_frontend_timeout;
         let mut container_backend_timeout = http.container_backend_timeout;
@@ -381,6 +432,17 @@ impl HttpsSession {
         pipe.backend_readiness.event = http.backend_readiness.event;
         pipe.set_back_token(back_token);
 
+        let (tunnel_idle_timeout, tunnel_max_lifetime) = {
+            let config = &self.listener.borrow().config;
+            (
+                config.tunnel_idle_timeout.unwrap_or(config.front_timeout),
+                config.tunnel_max_lifetime(),
+            )
+        };
+        pipe.set_tunnel_timeouts(
+            Duration::from_secs(tunnel_idle_timeout as u64),
+            (tunnel_max_lifetime > 0).then(|| Duration::from_secs(tunnel_max_lifetime as u64)),
+        );
         gauge_add!("protocol.https", -1);
         gauge_add!("protocol.wss", 1);
         gauge_add!("http.active_requests", -1);
@@ -517,6 +579,12 @@ impl ProxySession for HttpsSession {
         error!("Metrics: {:?}", self.metrics);
     }
 
+    fn snapshot(&self) -> SessionSnapshot {
+        let mut snapshot = self.state.snapshot();
+        self.metrics.fill_snapshot(&mut snapshot);
+        snapshot
+    }
+
     fn frontend_token(&self) -> Token {
         self.frontend_token
     }
@@ -534,6 +602,9 @@ pub struct HttpsListener {
     listener: Option<MioTcpListener>,
     resolver: Arc<MutexCertificateResolver>,
     rustls_details: Arc<RustlsServerConfig>,
+    /// same as rustls_details without ALPN, for the clients offering only protocols
+    /// we do not support, when the listener falls back to HTTP/1.1 for them
+    rustls_alpn_fallback: Option<Arc<RustlsServerConfig>>,
     tags: BTreeMap<String, CachedTags>,
     token: Token,
 }
@@ -564,6 +635,62 @@ impl L7ListenerHandler for HttpsListener {
         self.config.connect_timeout
     }
 
+    fn strict_sni_binding(&self) -> bool {
+        self.config.strict_sni_binding()
+    }
+
+    fn forward_proxy(&self) -> bool {
+        self.config.forward_proxy()
+    }
+
+    fn alt_svc(&self) -> Option<&str> {
+        self.config.alt_svc.as_deref()
+    }
+
+    fn replace_alt_svc(&self) -> bool {
+        self.config.replace_alt_svc()
+    }
+
+    fn max_request_header_size(&self) -> Option<u32> {
+        self.config.max_request_header_size
+    }
+
+    fn max_response_header_size(&self) -> Option<u32> {
+        self.config.max_response_header_size
+    }
+
+    fn no_host_cluster_id(&self) -> Option<&str> {
+        self.config.no_host_cluster_id.as_deref()
+    }
+
+    fn forward_tls_headers(&self) -> bool {
+        self.config.forward_tls_headers()
+    }
+
+    fn read_buffer_high_water_mark(&self) -> Option<u32> {
+        self.config.read_buffer_high_water_mark
+    }
+
+    fn max_uri_length(&self) -> u32 {
+        self.config.max_uri_length()
+    }
+
+    fn request_id_header(&self) -> Option<&str> {
+        self.config.request_id_header.as_deref()
+    }
+
+    fn trust_client_request_id(&self) -> bool {
+        self.config.trust_client_request_id()
+    }
+
+    fn reject_encoded_slashes(&self) -> bool {
+        self.config.reject_encoded_slashes()
+    }
+
+    fn forward_normalized_path(&self) -> bool {
+        self.config.forward_normalized_path()
+    }
+
     fn frontend_from_request(
         &self,
         host: &str,
@@ -616,12 +743,18 @@ impl HttpsListener {
         let resolver = Arc::new(MutexCertificateResolver::default());
 
         let server_config = Arc::new(Self::create_rustls_context(&config, resolver.to_owned())?);
+        let alpn_fallback = config.alpn_mismatch_fallback().then(|| {
+            let mut fallback_config = (*server_config).clone();
+            fallback_config.alpn_protocols.clear();
+            Arc::new(fallback_config)
+        });
 
         Ok(HttpsListener {
             listener: None,
             address: config.address.into(),
             resolver,
             rustls_details: server_config,
+            rustls_alpn_fallback: alpn_fallback,
             active: false,
             fronts: Router::new(),
             answers: Rc::new(RefCell::new(
@@ -1009,6 +1142,20 @@ impl HttpsProxy {
                     })?;
             }
         }
+        if let Some(retry_after) = &cluster.retry_after {
+            if !is_valid_retry_after(retry_after) {
+                return Err(ProxyError::AddCluster(ListenerError::InvalidRetryAfter(
+                    retry_after.to_owned(),
+                )));
+            }
+        }
+        for listener in self.listeners.values() {
+            listener
+                .borrow()
+                .answers
+                .borrow_mut()
+                .set_retry_after(&cluster.cluster_id, cluster.retry_after.clone());
+        }
         self.clusters.insert(cluster.cluster_id.clone(), cluster);
         Ok(None)
     }
@@ -1019,11 +1166,10 @@ impl HttpsProxy {
     ) -> Result<Option<ResponseContent>, ProxyError> {
         self.clusters.remove(cluster_id);
         for listener in self.listeners.values() {
-            listener
-                .borrow()
-                .answers
-                .borrow_mut()
-                .remove_custom_answer(cluster_id);
+            let listener = listener.borrow();
+            let mut answers = listener.answers.borrow_mut();
+            answers.remove_custom_answer(cluster_id);
+            answers.set_retry_after(cluster_id, None);
         }
 
         Ok(None)
@@ -1192,6 +1338,14 @@ impl ProxyConfiguration for HttpsProxy {
         }
 
         let owned = listener.borrow();
+        if let Some(keepalive) = &owned.config.tcp_keepalive {
+            if let Err(e) = set_tcp_keepalive(&frontend_sock, keepalive) {
+                error!(
+                    "error setting keepalive on front socket({:?}): {:?}",
+                    frontend_sock, e
+                );
+            }
+        }
         let rustls_details = ServerConnection::new(owned.rustls_details.clone()).map_err(|e| {
             error!("failed to create server session: {:?}", e);
             AcceptError::IoError
@@ -1587,6 +1741,7 @@ mod tests {
             address: address.into(),
             fronts,
             rustls_details,
+            rustls_alpn_fallback: None,
             resolver,
             answers: Rc::new(RefCell::new(
                 HttpAnswers::new(&Some(CustomHttpAnswers::default())).unwrap(),
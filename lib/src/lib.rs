@@ -356,7 +356,9 @@ use tls::CertificateResolverError;
 
 use sozu_command::{
     logging::{CachedTags, LogContext},
-    proto::command::{Cluster, ListenerType, RequestHttpFrontend, WorkerRequest, WorkerResponse},
+    proto::command::{
+        Cluster, ListenerType, RequestHttpFrontend, SessionSnapshot, WorkerRequest, WorkerResponse,
+    },
     ready::Ready,
     state::ClusterId,
     AsStr, ObjectKind,
@@ -402,6 +404,10 @@ pub trait ProxySession {
     fn last_event(&self) -> Instant;
     /// display the session's internal state (for debugging purpose)
     fn print_session(&self);
+    /// structured state of the session, with its protocol, tokens, readiness and metrics
+    fn snapshot(&self) -> SessionSnapshot {
+        SessionSnapshot::default()
+    }
     /// get the token associated with the frontend
     fn frontend_token(&self) -> Token;
     /// tell the session it has to shut down if possible
@@ -510,6 +516,10 @@ macro_rules! StateMachineBuilder {
                     _fn_impl!{timeout(&mut, self, token: Token, metrics: &mut SessionMetrics) -> StateResult}
                     _fn_impl!{cancel_timeouts(&mut, self)}
                     _fn_impl!{print_state(&, self, context: &str) | marker => error!("{} Session(FailedUpgrade({:?}))", context, marker)}
+                    _fn_impl!{snapshot(&, self) -> sozu_command::proto::command::SessionSnapshot | marker => sozu_command::proto::command::SessionSnapshot {
+                        protocol: format!("FailedUpgrade({:?})", marker),
+                        ..Default::default()
+                    }}
                     _fn_impl!{close(&mut, self, proxy: Rc<RefCell<dyn L7Proxy>>, metrics: &mut SessionMetrics) | _ => {}}
                     _fn_impl!{shutting_down(&mut, self) -> SessionIsToBeClosed | _ => true}
                 }
@@ -548,6 +558,48 @@ pub trait L7ListenerHandler {
 
     fn get_connect_timeout(&self) -> u32;
 
+    /// whether requests must target the host negotiated with TLS SNI
+    fn strict_sni_binding(&self) -> bool;
+
+    /// whether requests in absolute-form are accepted, as a forward proxy would
+    fn forward_proxy(&self) -> bool;
+
+    /// the Alt-Svc header added to the responses, if any
+    fn alt_svc(&self) -> Option<&str>;
+
+    /// whether the Alt-Svc headers of the backends are replaced instead of kept
+    fn replace_alt_svc(&self) -> bool;
+
+    /// largest request header section, as counted for SETTINGS_MAX_HEADER_LIST_SIZE
+    fn max_request_header_size(&self) -> Option<u32>;
+
+    /// largest response header section, as counted for SETTINGS_MAX_HEADER_LIST_SIZE
+    fn max_response_header_size(&self) -> Option<u32>;
+
+    /// cluster of the requests without a host, they are answered with a 400 if none
+    fn no_host_cluster_id(&self) -> Option<&str>;
+
+    /// whether X-Forwarded-TLS-* headers describing the TLS handshake are added to the requests
+    fn forward_tls_headers(&self) -> bool;
+
+    /// unforwarded request body bytes over which reading from the client pauses
+    fn read_buffer_high_water_mark(&self) -> Option<u32>;
+
+    /// longest request target, answered with a 414 above it, 0 means no limit
+    fn max_uri_length(&self) -> u32;
+
+    /// name of the header carrying the id of the request to the backend, none if it is not sent
+    fn request_id_header(&self) -> Option<&str>;
+
+    /// whether the id a client sends in the request id header is kept instead of replaced
+    fn trust_client_request_id(&self) -> bool;
+
+    /// whether the requests whose path has an encoded slash or backslash are refused
+    fn reject_encoded_slashes(&self) -> bool;
+
+    /// whether the backends get the normalized path used for routing instead of the client's
+    fn forward_normalized_path(&self) -> bool;
+
     /// retrieve a frontend by parsing a request's hostname, uri and method
     fn frontend_from_request(
         &self,
@@ -575,6 +627,8 @@ pub enum BackendConnectAction {
     New,
     Reuse,
     Replace,
+    /// all the backends of the cluster are down, the request waits for one to come back
+    Queued,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -602,6 +656,8 @@ pub enum RetrieveClusterError {
     NoPath,
     #[error("unauthorized route")]
     UnauthorizedRoute,
+    #[error("host {host} does not match the TLS server name {server_name}")]
+    MisdirectedRequest { host: String, server_name: String },
     #[error("{0}")]
     RetrieveFrontend(FrontendFromRequestError),
 }
@@ -615,6 +671,8 @@ pub enum AcceptError {
     RegisterError,
     WrongSocketAddress,
     BufferCapacityReached,
+    /// the buffer pool was dropped, the proxy is shutting down
+    PoolDropped,
 }
 
 /// returned by the HTTP, HTTPS and TCP listeners
@@ -626,6 +684,8 @@ pub enum ListenerError {
     PemParse(String),
     #[error("failed to parse template {0}: {1}")]
     TemplateParse(u16, TemplateError),
+    #[error("invalid Retry-After {0:?}, expected a number of seconds or an HTTP-date")]
+    InvalidRetryAfter(String),
     #[error("failed to build rustls context, {0}")]
     BuildRustls(String),
     #[error("could not activate listener with address {address:?}: {error}")]
@@ -994,6 +1054,14 @@ impl SessionMetrics {
         self.backend_bout = 0;
     }
 
+    /// add the bytes transferred by the session to its snapshot
+    pub fn fill_snapshot(&self, snapshot: &mut SessionSnapshot) {
+        snapshot.bytes_in = Some(self.bin as u64);
+        snapshot.bytes_out = Some(self.bout as u64);
+        snapshot.backend_bytes_in = Some(self.backend_bin as u64);
+        snapshot.backend_bytes_out = Some(self.backend_bout as u64);
+    }
+
     pub fn service_start(&mut self) {
         let now = Instant::now();
 
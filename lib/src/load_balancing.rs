@@ -8,7 +8,15 @@ use rand::{
 
 use crate::{backends::Backend, sozu_command::proto::command::LoadMetric};
 
+/// Selects the backend of a cluster a new connection goes to.
+///
+/// Each cluster has its own instance, built from its [LoadBalancingAlgorithms]
+/// configuration, or plugged with `BackendMap::set_load_balancing_algorithm_for_cluster`.
+/// Sticky sessions are resolved before it is called.
+///
+/// [LoadBalancingAlgorithms]: crate::sozu_command::proto::command::LoadBalancingAlgorithms
 pub trait LoadBalancingAlgorithm: Debug {
+    /// pick one of the available backends, or none
     fn next_available_backend(
         &mut self,
         backends: &mut Vec<Rc<RefCell<Backend>>>,
@@ -381,6 +381,30 @@ macro_rules! time (
   })
 );
 
+/// record a value in a histogram, like time metrics but for any kind of quantity
+#[macro_export]
+macro_rules! histogram (
+  ($key:expr, $value: expr) => ({
+    use $crate::metrics::{MetricValue,Subscriber};
+    let v = $value;
+    $crate::metrics::METRICS.with(|metrics| {
+      let m = &mut *metrics.borrow_mut();
+
+      m.receive_metric($key, None, None, MetricValue::Time(v as usize));
+    });
+  });
+  ($key:expr, $cluster_id:expr, $value: expr) => ({
+    use $crate::metrics::{MetricValue,Subscriber};
+    let v = $value;
+    $crate::metrics::METRICS.with(|metrics| {
+      let m = &mut *metrics.borrow_mut();
+      let cluster_id: Option<&str> = $cluster_id;
+
+      m.receive_metric($key, cluster_id, None, MetricValue::Time(v as usize));
+    });
+  })
+);
+
 #[macro_export]
 macro_rules! record_backend_metrics (
   ($cluster_id:expr, $backend_id:expr, $response_time: expr, $backend_connection_time: expr, $bin: expr, $bout: expr) => {
@@ -0,0 +1,214 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use mio::Token;
+use sozu_command::proto::command::{BackendProtocol, Cluster};
+
+use super::parser::MAX_STREAM_ID;
+
+/// An open HTTP/2 connection to a backend, that new streams can share
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackendConnection {
+    pub token: Token,
+    /// SETTINGS_MAX_CONCURRENT_STREAMS advertised by the backend
+    pub max_concurrent_streams: u32,
+    /// id of the next stream opened on the connection, odd as sozu is the client
+    next_stream_id: u32,
+    /// backend stream id -> id of the frontend stream it serves. The backend
+    /// numbers the streams of its connection on its own, the frames it sends
+    /// are routed back to the frontend streams with this map
+    streams: HashMap<u32, u32>,
+}
+
+impl BackendConnection {
+    pub fn active_streams(&self) -> u32 {
+        self.streams.len() as u32
+    }
+
+    /// Whether a new stream can be opened: under the limit of the backend,
+    /// and the stream ids of the connection are not exhausted
+    fn has_room(&self) -> bool {
+        self.active_streams() < self.max_concurrent_streams && self.next_stream_id <= MAX_STREAM_ID
+    }
+}
+
+/// Index of the open backend connections, consulted before opening a new one
+/// so that streams to the same backend coalesce on the same connection.
+#[derive(Debug, Default)]
+pub struct ConnectionIndex {
+    connections: HashMap<SocketAddr, Vec<BackendConnection>>,
+}
+
+impl ConnectionIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, address: SocketAddr, token: Token, max_concurrent_streams: u32) {
+        self.connections
+            .entry(address)
+            .or_default()
+            .push(BackendConnection {
+                token,
+                max_concurrent_streams,
+                next_stream_id: 1,
+                streams: HashMap::new(),
+            });
+    }
+
+    /// Reserve a stream for the frontend stream `frontend_stream` on a connection
+    /// to `address` that has room for it, returning the connection and the id of
+    /// the stream on it. Returns None if a new connection must be opened.
+    pub fn open_stream(
+        &mut self,
+        address: &SocketAddr,
+        frontend_stream: u32,
+    ) -> Option<(Token, u32)> {
+        let connection = self
+            .connections
+            .get_mut(address)?
+            .iter_mut()
+            .find(|connection| connection.has_room())?;
+        let stream_id = connection.next_stream_id;
+        connection.next_stream_id += 2;
+        connection.streams.insert(stream_id, frontend_stream);
+        Some((connection.token, stream_id))
+    }
+
+    /// The frontend stream served by the stream `stream_id` of a backend connection,
+    /// where the frames the backend sends on that stream go
+    pub fn frontend_stream(
+        &self,
+        address: &SocketAddr,
+        token: Token,
+        stream_id: u32,
+    ) -> Option<u32> {
+        self.connections
+            .get(address)?
+            .iter()
+            .find(|connection| connection.token == token)?
+            .streams
+            .get(&stream_id)
+            .copied()
+    }
+
+    /// The stream `stream_id` of a backend connection is done, returns the
+    /// frontend stream it served
+    pub fn close_stream(
+        &mut self,
+        address: &SocketAddr,
+        token: Token,
+        stream_id: u32,
+    ) -> Option<u32> {
+        self.get_mut(address, token)?.streams.remove(&stream_id)
+    }
+
+    /// The backend sent new settings
+    pub fn update_max_concurrent_streams(
+        &mut self,
+        address: &SocketAddr,
+        token: Token,
+        max_concurrent_streams: u32,
+    ) {
+        if let Some(connection) = self.get_mut(address, token) {
+            connection.max_concurrent_streams = max_concurrent_streams;
+        }
+    }
+
+    /// The connection was closed or received a GOAWAY, no new stream should use it
+    pub fn remove(&mut self, address: &SocketAddr, token: Token) {
+        if let Some(connections) = self.connections.get_mut(address) {
+            connections.retain(|connection| connection.token != token);
+            if connections.is_empty() {
+                self.connections.remove(address);
+            }
+        }
+    }
+
+    fn get_mut(&mut self, address: &SocketAddr, token: Token) -> Option<&mut BackendConnection> {
+        self.connections
+            .get_mut(address)?
+            .iter_mut()
+            .find(|connection| connection.token == token)
+    }
+}
+
+/// Protocol spoken to the backends of a cluster, for a request received over
+/// HTTP/2 or not. HTTP/1.1 unless the cluster opted in, AUTO keeps the protocol
+/// of the client connection: only those requests coalesce on a `ConnectionIndex`.
+/// Nothing calls it yet, the sessions only open HTTP/1.1 backend connections.
+pub fn backend_protocol(cluster: Option<&Cluster>, frontend_is_h2: bool) -> BackendProtocol {
+    match cluster.map_or(BackendProtocol::H1, Cluster::backend_protocol) {
+        BackendProtocol::Auto if frontend_is_h2 => BackendProtocol::H2,
+        BackendProtocol::Auto => BackendProtocol::H1,
+        protocol => protocol,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streams_coalesce_within_max_concurrent_streams() {
+        let address: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let mut index = ConnectionIndex::new();
+        assert_eq!(index.open_stream(&address, 1), None);
+
+        index.insert(address, Token(1), 2);
+        assert_eq!(index.open_stream(&address, 1), Some((Token(1), 1)));
+        assert_eq!(index.open_stream(&address, 3), Some((Token(1), 3)));
+        assert_eq!(index.open_stream(&address, 5), None);
+
+        index.insert(address, Token(2), 100);
+        assert_eq!(index.open_stream(&address, 5), Some((Token(2), 1)));
+
+        assert_eq!(index.close_stream(&address, Token(1), 1), Some(1));
+        assert_eq!(index.open_stream(&address, 7), Some((Token(1), 5)));
+
+        index.remove(&address, Token(1));
+        index.update_max_concurrent_streams(&address, Token(2), 1);
+        assert_eq!(index.open_stream(&address, 9), None);
+
+        index.remove(&address, Token(2));
+        assert!(index.connections.is_empty());
+    }
+
+    #[test]
+    fn backend_streams_map_to_their_frontend_stream() {
+        let address: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let mut index = ConnectionIndex::new();
+        index.insert(address, Token(1), 100);
+        index.insert(address, Token(2), 100);
+
+        // two frontend connections may both have a stream 1
+        assert_eq!(index.open_stream(&address, 1), Some((Token(1), 1)));
+        assert_eq!(index.open_stream(&address, 1), Some((Token(1), 3)));
+        assert_eq!(index.frontend_stream(&address, Token(1), 3), Some(1));
+        assert_eq!(index.frontend_stream(&address, Token(1), 5), None);
+        assert_eq!(index.frontend_stream(&address, Token(2), 1), None);
+
+        assert_eq!(index.close_stream(&address, Token(1), 3), Some(1));
+        assert_eq!(index.frontend_stream(&address, Token(1), 3), None);
+        assert_eq!(index.close_stream(&address, Token(1), 3), None);
+
+        // a connection that used all its stream ids takes no new stream
+        index.get_mut(&address, Token(1)).unwrap().next_stream_id = MAX_STREAM_ID + 2;
+        assert_eq!(index.open_stream(&address, 9), Some((Token(2), 1)));
+    }
+
+    #[test]
+    fn backend_protocol_defaults_to_h1() {
+        let mut cluster = Cluster::default();
+        assert_eq!(backend_protocol(None, true), BackendProtocol::H1);
+        assert_eq!(backend_protocol(Some(&cluster), true), BackendProtocol::H1);
+
+        cluster.set_backend_protocol(BackendProtocol::Auto);
+        assert_eq!(backend_protocol(Some(&cluster), true), BackendProtocol::H2);
+        assert_eq!(backend_protocol(Some(&cluster), false), BackendProtocol::H1);
+
+        cluster.set_backend_protocol(BackendProtocol::H2);
+        assert_eq!(backend_protocol(Some(&cluster), false), BackendProtocol::H2);
+        cluster.set_backend_protocol(BackendProtocol::H1);
+        assert_eq!(backend_protocol(Some(&cluster), true), BackendProtocol::H1);
+    }
+}
@@ -1,25 +1,84 @@
 //FIXME: we disallow warnings for the HTTP/2 module temporarily
 #![allow(warnings)]
-use std::{cell::RefCell, net::SocketAddr, rc::Weak};
+use std::{
+    cell::RefCell,
+    io::ErrorKind,
+    net::{Shutdown, SocketAddr},
+    rc::{Rc, Weak},
+    time::{Duration, Instant},
+};
 
 use mio::{net::TcpStream, *};
 use rusty_ulid::Ulid;
 
 use crate::{
     pool::{Checkout, Pool},
+    protocol::http::{answers::HttpAnswers, parser::host_matches_server_name},
     socket::{SocketHandler, SocketResult},
     sozu_command::buffer::fixed::Buffer,
+    sozu_command::proto::command::{SessionSnapshot, StreamSnapshot},
     sozu_command::ready::Ready,
-    {Protocol, Readiness, SessionMetrics, StateResult},
+    timer::TimeoutContainer,
+    {AcceptError, Protocol, Readiness, SessionMetrics, StateResult},
 };
 
+mod coalescing;
 mod parser;
 mod serializer;
 mod state;
 mod stream;
+mod translation;
 
 type BackendToken = Token;
 
+/// bytes received from the client kept in an `ErrorContext`
+const ERROR_CONTEXT_BYTES: usize = 64;
+
+/// time given to the streams the client opened before it got the first GOAWAY
+/// of a graceful shutdown, the final GOAWAY is sent after it
+const GOAWAY_DRAIN_DELAY: Duration = Duration::from_secs(1);
+
+/// time given to the client to acknowledge our SETTINGS, the connection is
+/// closed with a SETTINGS_TIMEOUT error past it (RFC 9113 section 6.5.3)
+const SETTINGS_ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What the connection was doing when a fatal protocol error closed it, to
+/// diagnose interoperability issues with some clients
+#[derive(Clone, Debug, PartialEq)]
+pub struct ErrorContext {
+    pub state: state::St,
+    /// stream whose header block was incomplete
+    pub continuation_stream: Option<u32>,
+    pub streams: usize,
+    /// last bytes received from the client. They are only captured with the
+    /// logs-debug feature, to keep the traffic out of production logs
+    pub received: Vec<u8>,
+}
+
+impl ErrorContext {
+    fn new(state: &state::State, received: &[u8]) -> ErrorContext {
+        ErrorContext {
+            state: state.state.clone(),
+            continuation_stream: state.continuation_stream,
+            streams: state.streams.len(),
+            #[cfg(feature = "logs-debug")]
+            received: received[received.len().saturating_sub(ERROR_CONTEXT_BYTES)..].to_vec(),
+            #[cfg(not(feature = "logs-debug"))]
+            received: Vec::new(),
+        }
+    }
+}
+
+/// How the streams already started are treated by `Http2::shutdown`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShutdownMode {
+    /// they finish, the connection closes once none is left. The streams opened
+    /// during the drain period are accepted and finish too
+    Graceful,
+    /// they are reset, the connection closes once the frames are written
+    Hard,
+}
+
 #[derive(PartialEq)]
 pub enum SessionStatus {
     Normal,
@@ -31,13 +90,42 @@ pub struct Http2<Front: SocketHandler> {
     backend: Option<TcpStream>,
     frontend_token: Token,
     backend_token: Option<Token>,
+    /// stream whose request is forwarded on the backend connection
+    backend_stream: Option<u32>,
+    /// streams over this number of backend connections are refused, 0 means no limit
+    max_backend_connections: u32,
     back_buf: Option<Checkout>,
+    /// armed while connecting to the backend, cleared once connected
+    container_backend_timeout: TimeoutContainer,
+    /// armed until the client preface and SETTINGS are received
+    container_handshake_timeout: TimeoutContainer,
+    /// armed between the two GOAWAY frames of a graceful shutdown
+    container_drain_timeout: TimeoutContainer,
+    /// armed while our SETTINGS wait for their acknowledgement
+    container_settings_timeout: TimeoutContainer,
+    /// armed while response data waits for the client to read it, a zero duration
+    /// disables it
+    container_slow_read_timeout: TimeoutContainer,
+    /// set while the slow read timeout is armed
+    slow_read_watched: bool,
     pub cluster_id: Option<String>,
     pub request_id: Ulid,
     pub back_readiness: Readiness,
     pub log_ctx: String,
     public_address: Option<SocketAddr>,
     pub state: Option<state::State>,
+    /// set when a fatal protocol error closes the connection
+    pub error_context: Option<ErrorContext>,
+    /// frames handled in one call to `readable` before yielding to the other sessions, 0 means no limit
+    max_frames_per_read: u32,
+    /// set when `readable` yielded with complete frames left in the read buffer
+    frames_pending: bool,
+    /// set by `shutdown`, the connection closes after its final frames
+    shutdown_mode: Option<ShutdownMode>,
+    /// cluster of the streams without a host, they are answered with a 400 if none
+    no_host_cluster_id: Option<String>,
+    /// answers of the listener, for the Retry-After of the 503 answers of the clusters
+    answers: Option<Rc<RefCell<HttpAnswers>>>,
     pool: Weak<RefCell<Pool>>,
 }
 
@@ -49,23 +137,43 @@ impl<Front: SocketHandler> Http2<Front> {
         public_address: Option<SocketAddr>,
         client_address: Option<SocketAddr>,
         sticky_name: String,
-    ) -> Http2<Front> {
+        configured_connect_timeout: Duration,
+        max_backend_connections: u32,
+    ) -> Result<Http2<Front>, AcceptError> {
         let request_id = Ulid::generate();
         let log_ctx = format!("{}\tunknown\t", &request_id);
-        let (read, write) = {
-            let p0 = pool.upgrade().unwrap();
-            let mut p = p0.borrow_mut();
-            let res = (p.checkout().unwrap(), p.checkout().unwrap());
-            res
+        let (read, write, buffer_size) = match pool.upgrade() {
+            Some(pool) => {
+                let mut pool = pool.borrow_mut();
+                match (pool.checkout(), pool.checkout()) {
+                    (Some(read), Some(write)) => (read, write, pool.buffer_size),
+                    _ => return Err(AcceptError::BufferCapacityReached),
+                }
+            }
+            None => return Err(AcceptError::PoolDropped),
         };
         let session = Http2 {
             frontend: Connection::new(frontend, read, write),
             frontend_token,
             backend: None,
             backend_token: None,
+            backend_stream: None,
+            max_backend_connections,
             back_buf: None,
+            container_backend_timeout: TimeoutContainer::new_empty(configured_connect_timeout),
+            container_handshake_timeout: TimeoutContainer::new_empty(Duration::ZERO),
+            container_drain_timeout: TimeoutContainer::new_empty(GOAWAY_DRAIN_DELAY),
+            container_settings_timeout: TimeoutContainer::new_empty(SETTINGS_ACK_TIMEOUT),
+            container_slow_read_timeout: TimeoutContainer::new_empty(Duration::ZERO),
+            slow_read_watched: false,
             cluster_id: None,
-            state: Some(state::State::new()),
+            state: Some(state::State::new(buffer_size)),
+            error_context: None,
+            max_frames_per_read: 0,
+            frames_pending: false,
+            shutdown_mode: None,
+            no_host_cluster_id: None,
+            answers: None,
             request_id,
             back_readiness: Readiness {
                 interest: Ready::READABLE | Ready::WRITABLE | Ready::HUP | Ready::ERROR,
@@ -76,8 +184,9 @@ impl<Front: SocketHandler> Http2<Front> {
             pool,
         };
 
+        incr!("https.connections.h2");
         trace!("created http2");
-        session
+        Ok(session)
     }
 
     pub fn front_socket(&self) -> &TcpStream {
@@ -112,8 +221,338 @@ impl<Front: SocketHandler> Http2<Front> {
         }
     }
 
+    /// state of the connection and of each of its streams, ordered by id
+    pub fn snapshot(&self) -> SessionSnapshot {
+        let mut streams: Vec<StreamSnapshot> = self
+            .state
+            .iter()
+            .flat_map(|state| state.streams.values())
+            .map(|stream| StreamSnapshot {
+                id: stream.id,
+                state: format!("{:?}", stream.state),
+                authority: stream
+                    .authority()
+                    .map(|authority| String::from_utf8_lossy(authority).into_owned()),
+                path: stream
+                    .header(b":path")
+                    .map(|path| String::from_utf8_lossy(path).into_owned()),
+                phase: Some(format!("{:?}", stream.phase)),
+            })
+            .collect();
+        streams.sort_by_key(|stream| stream.id);
+
+        SessionSnapshot {
+            protocol: String::from("HTTP2"),
+            request_id: Some(self.request_id.to_string()),
+            cluster_id: self.cluster_id.clone(),
+            frontend_token: Some(self.frontend_token.0 as u64),
+            frontend_readiness: Some(self.frontend.readiness.to_string()),
+            backend_token: self.backend_token.map(|token| token.0 as u64),
+            backend_readiness: Some(self.back_readiness.to_string()),
+            streams,
+            ..Default::default()
+        }
+    }
+
+    /// display the state of the session and of each of its streams, see `SessionState::print_state`
+    pub fn print_state(&self, context: &str) {
+        let streams = self
+            .state
+            .iter()
+            .flat_map(|state| state.streams.values())
+            .map(|stream| {
+                format!(
+                    "\n\t\tstream {}: {:?}, {:?}",
+                    stream.id, stream.state, stream.phase
+                )
+            })
+            .collect::<String>();
+        error!(
+            "{} Session(HTTP2)\n\tFrontend:\n\t\ttoken: {:?}\treadiness: {:?}\n\tBackend:\n\t\ttoken: {:?}\treadiness: {:?}\tstream: {:?}\n\tStreams:{}",
+            context,
+            self.frontend_token,
+            self.frontend.readiness,
+            self.backend_token,
+            self.back_readiness,
+            self.backend_stream,
+            streams
+        );
+    }
+
+    /// The backend socket is registered and connecting, the connect timeout starts
     pub fn set_back_token(&mut self, token: Token) {
         self.backend_token = Some(token);
+        self.container_backend_timeout.set(token);
+    }
+
+    /// Let the streams opened over the concurrency limit wait for a slot, see `state::StreamQueue`
+    pub fn set_stream_queue(&mut self, depth: u32, timeout: Duration) {
+        if let Some(state) = self.state.as_mut() {
+            state.stream_queue.depth = depth as usize;
+            state.stream_queue.timeout = timeout;
+        }
+    }
+
+    /// Yield to the other sessions after this number of frames, 0 means no limit
+    pub fn set_max_frames_per_read(&mut self, max_frames_per_read: u32) {
+        self.max_frames_per_read = max_frames_per_read;
+    }
+
+    /// Close the connection if the client does not send its preface and SETTINGS within
+    /// `duration`. The timeout of the TLS handshake is taken over, restarting now
+    pub fn set_handshake_timeout(&mut self, mut timeout: TimeoutContainer, duration: Duration) {
+        timeout.set_duration(duration);
+        self.container_handshake_timeout = timeout;
+    }
+
+    /// Refuse the streams opened faster than this rate, 0 means no limit
+    pub fn set_max_new_streams_per_second(&mut self, rate: u32) {
+        if let Some(state) = self.state.as_mut() {
+            state.stream_rate_limit = state::StreamRateLimit::new(rate);
+        }
+    }
+
+    /// Give the receive windows back once `threshold` percent of them is consumed
+    pub fn set_window_update_threshold(&mut self, threshold: u32) {
+        if let Some(state) = self.state.as_mut() {
+            state.window_update_threshold = threshold.clamp(1, 100);
+        }
+    }
+
+    /// Reset the streams whose response data stays unread for this long, close
+    /// the connection when all of them are stalled. `Duration::ZERO` disables it
+    pub fn set_slow_read_timeout(&mut self, timeout: Duration) {
+        self.container_slow_read_timeout.set_duration(timeout);
+    }
+
+    /// Route the streams without a host to this cluster instead of answering them with a 400
+    pub fn set_no_host_cluster_id(&mut self, no_host_cluster_id: Option<String>) {
+        if let Some(state) = self.state.as_mut() {
+            state.accept_requests_without_host = no_host_cluster_id.is_some();
+        }
+        self.no_host_cluster_id = no_host_cluster_id;
+    }
+
+    /// Answers of the listener, the 503 answers get the Retry-After set for their cluster
+    pub fn set_answers(&mut self, answers: Rc<RefCell<HttpAnswers>>) {
+        self.answers = Some(answers);
+    }
+
+    /// Retry-After of the cluster of the session, if it has one
+    fn retry_after(&self) -> Option<String> {
+        let cluster_id = self.cluster_id.as_deref()?;
+        let answers = self.answers.as_ref()?.borrow();
+        answers.retry_after(cluster_id).map(str::to_owned)
+    }
+
+    /// Send a GOAWAY with `error_code` and the last stream id, the connection
+    /// is closed once it is written and, in graceful mode, once the started
+    /// streams are done. A graceful shutdown without error first sends a GOAWAY
+    /// with the largest stream id, the final one follows after a drain period.
+    /// False if the connection is not established or is already going away
+    pub fn shutdown(&mut self, error_code: u32, mode: ShutdownMode) -> bool {
+        let Some(state) = self.state.as_mut() else {
+            return false;
+        };
+        if !state.shutdown(error_code, mode == ShutdownMode::Graceful) {
+            return false;
+        }
+        if state.is_draining() {
+            self.container_drain_timeout.set(self.frontend_token);
+        } else {
+            self.container_drain_timeout.cancel();
+        }
+        self.frontend.readiness.interest.insert(Ready::WRITABLE);
+        self.shutdown_mode = Some(mode);
+        true
+    }
+
+    /// Empty the HPACK dynamic tables of the client and of our decoder, see
+    /// `State::reset_hpack_context`
+    pub fn reset_hpack_context(&mut self) {
+        if let Some(state) = self.state.as_mut() {
+            state.reset_hpack_context();
+        }
+    }
+
+    /// Limit the request and response header lists, only the buffer size applies to a `None`
+    pub fn set_max_header_sizes(&mut self, request: Option<u32>, response: Option<u32>) {
+        if let Some(state) = self.state.as_mut() {
+            state.max_request_header_size = request.unwrap_or(u32::MAX);
+            // advertised to the client in the server preface
+            state.settings.max_header_list_size = state.max_request_header_size;
+            state.max_response_header_size = response.unwrap_or(u32::MAX);
+        }
+    }
+
+    /// Push new listener settings to this live connection, see `State::update_settings`
+    pub fn update_settings(&mut self, settings: &state::H2Settings) -> bool {
+        let was_awaiting_ack = self.awaiting_settings_ack();
+        let updated = self
+            .state
+            .as_mut()
+            .is_some_and(|state| state.update_settings(settings));
+        if updated {
+            self.frontend.readiness.interest.insert(Ready::WRITABLE);
+        }
+        self.watch_settings_ack(was_awaiting_ack);
+        updated
+    }
+
+    fn awaiting_settings_ack(&self) -> bool {
+        self.state
+            .as_ref()
+            .is_some_and(|state| state.awaiting_settings_ack())
+    }
+
+    /// Arm the SETTINGS timeout when our SETTINGS start waiting for their
+    /// acknowledgement, and cancel it once all of them are acknowledged. It is
+    /// not pushed back by the SETTINGS sent while others are still waiting
+    fn watch_settings_ack(&mut self, was_awaiting_ack: bool) {
+        match (was_awaiting_ack, self.awaiting_settings_ack()) {
+            (false, true) => self.container_settings_timeout.set(self.frontend_token),
+            (true, false) => {
+                self.container_settings_timeout.cancel();
+            }
+            _ => {}
+        }
+    }
+
+    /// Arm the slow read timeout when response data starts waiting for the client,
+    /// and cancel it once none is left. Unlike the data itself, it is not pushed
+    /// back by each frame written: the streams are checked when it fires
+    fn watch_slow_reads(&mut self) {
+        if self.container_slow_read_timeout.duration().is_zero() {
+            return;
+        }
+        let pending = self
+            .state
+            .as_ref()
+            .is_some_and(|state| state.streams_with_pending_data() > 0);
+        match (self.slow_read_watched, pending) {
+            (false, true) => self.container_slow_read_timeout.set(self.frontend_token),
+            (true, false) => {
+                self.container_slow_read_timeout.cancel();
+            }
+            _ => {}
+        }
+        self.slow_read_watched = pending;
+    }
+
+    /// Deregister the timeouts of the session from the timer, none fires once
+    /// the session is closed
+    pub fn cancel_timeouts(&mut self) {
+        self.container_handshake_timeout.cancel();
+        self.container_backend_timeout.cancel();
+        self.container_drain_timeout.cancel();
+        self.container_settings_timeout.cancel();
+        self.container_slow_read_timeout.cancel();
+        self.slow_read_watched = false;
+    }
+
+    pub fn backend_connected(&mut self) {
+        self.container_backend_timeout.cancel();
+    }
+
+    /// A backend that did not accept the connection in time gets its stream
+    /// answered with a 504, the frontend connection stays open for the others.
+    /// A client that did not complete the handshake in time is closed, as well as
+    /// one that did not acknowledge our SETTINGS. At the end of the drain period
+    /// of a graceful shutdown, the final GOAWAY is sent. Streams whose response
+    /// is not read are reset, see `slow_read_timeout`.
+    pub fn timeout(&mut self, token: Token, metrics: &mut SessionMetrics) -> StateResult {
+        if token == self.frontend_token && self.state.as_ref().is_some_and(|s| s.is_draining()) {
+            self.container_drain_timeout.triggered();
+            if let Some(state) = self.state.as_mut() {
+                state.goaway(parser::NO_ERROR);
+                self.frontend.readiness.interest = state.interest;
+            }
+            return StateResult::Continue;
+        }
+        if token == self.frontend_token && self.awaiting_settings_ack() {
+            self.container_settings_timeout.triggered();
+            incr!("http2.settings_timeouts");
+            error!(
+                "{} SETTINGS not acknowledged after {}, closing",
+                self.log_context(),
+                self.container_settings_timeout
+            );
+            // the GOAWAY is written before the connection closes
+            if self.shutdown(parser::SETTINGS_TIMEOUT, ShutdownMode::Hard) {
+                return StateResult::Continue;
+            }
+            return StateResult::CloseSession;
+        }
+        if token == self.frontend_token && self.slow_read_watched {
+            self.container_slow_read_timeout.triggered();
+            self.slow_read_watched = false;
+            return self.slow_read_timeout();
+        }
+        if token == self.frontend_token {
+            self.container_handshake_timeout.triggered();
+            incr!("http2.handshake_timeouts");
+            error!(
+                "{} no client preface and SETTINGS after {}, closing",
+                self.log_context(),
+                self.container_handshake_timeout
+            );
+            return StateResult::CloseSession;
+        }
+        if self.backend_token != Some(token) {
+            return StateResult::CloseSession;
+        }
+        self.container_backend_timeout.triggered();
+        error!(
+            "{} backend connection timed out after {}",
+            self.log_context(),
+            self.container_backend_timeout
+        );
+        incr!("http.504.errors", self.cluster_id.as_deref(), None);
+        if let (Some(stream_id), Some(state)) = (self.backend_stream, self.state.as_mut()) {
+            state.answer(stream_id, 504);
+            self.frontend.readiness.interest = state.interest;
+        }
+        self.close_backend();
+        StateResult::Continue
+    }
+
+    /// A client may open streams and read their responses slowly, or not at all,
+    /// to hold buffers and connections. The streams whose response data waited a
+    /// whole timeout without any of it being read are reset with ENHANCE_YOUR_CALM.
+    /// If the client reads none of its streams, the connection is closed.
+    fn slow_read_timeout(&mut self) -> StateResult {
+        let grace = self.container_slow_read_timeout.duration();
+        let Some(state) = self.state.as_ref() else {
+            return StateResult::CloseSession;
+        };
+        let stalled = state.stalled_streams(Instant::now(), grace);
+        if !stalled.is_empty() && stalled.len() == state.streams_with_pending_data() {
+            incr!("http2.slow_read.connections");
+            error!(
+                "{} client read none of the response data of its {} streams for {}, closing",
+                self.log_context(),
+                stalled.len(),
+                self.container_slow_read_timeout
+            );
+            // the client reads nothing, a GOAWAY would stay behind the data
+            return StateResult::CloseSession;
+        }
+        for stream_id in stalled {
+            incr!("http2.slow_read.streams");
+            warn!(
+                "{} stream {} read none of its response data for {}, resetting",
+                self.log_context(),
+                stream_id,
+                self.container_slow_read_timeout
+            );
+            if let Some(state) = self.state.as_mut() {
+                state.reset_slow_stream(stream_id);
+                self.frontend.readiness.interest = state.interest;
+            }
+            self.cancel_stream(stream_id);
+        }
+        self.watch_slow_reads();
+        StateResult::Continue
     }
 
     pub fn front_hup(&mut self) -> StateResult {
@@ -121,8 +560,7 @@ impl<Front: SocketHandler> Http2<Front> {
     }
 
     pub fn back_hup(&mut self) -> StateResult {
-        error!("todo[{}:{}]: back_hup", file!(), line!());
-        StateResult::CloseSession
+        self.backend_stream_failed()
         /*
         if self.back_buf.output_data_size() == 0 || self.back_buf.next_output_data().len() == 0 {
           if self.back_readiness.event.is_readable() {
@@ -142,6 +580,139 @@ impl<Front: SocketHandler> Http2<Front> {
         */
     }
 
+    /// Tear down the session, in an order that loses nothing already answered
+    /// and leaves no buffer behind:
+    /// 1. no new work starts: the timeouts are cancelled, and a final GOAWAY is
+    ///    queued if the connection was established,
+    /// 2. the bytes left in the write buffer by a short write, then the frames
+    ///    queued, are written to the frontend as far as its socket takes them,
+    /// 3. the frontend socket is shut down,
+    /// 4. the backend socket is shut down and its buffer goes back to the pool.
+    ///
+    /// The frontend buffers go back to the pool with the session.
+    pub fn close(&mut self, metrics: &mut SessionMetrics) {
+        self.cancel_timeouts();
+        if let Some(mut state) = self.state.take() {
+            // a GOAWAY queued by `shutdown` is flushed too
+            state.goaway(parser::NO_ERROR);
+            if !self.frontend.broken {
+                self.flush_frontend(&mut state, metrics);
+            }
+        }
+        self.frontend.close();
+        self.close_backend();
+    }
+
+    /// Write what is left for the frontend, until all of it is written or its
+    /// socket would block. Best effort, the socket is closed right after
+    fn flush_frontend(&mut self, state: &mut state::State, metrics: &mut SessionMetrics) {
+        loop {
+            // whole frames only, the client would misread the rest of a cut one
+            while state
+                .next_frame_len()
+                .is_some_and(|length| length <= self.frontend.write_buffer.space().len())
+            {
+                match state.gen(self.frontend.write_buffer.space()) {
+                    Ok(sz) if sz > 0 => {
+                        self.frontend.write_buffer.fill(sz);
+                    }
+                    _ => break,
+                }
+            }
+            if self.frontend.write_buffer.available_data() == 0
+                || self.frontend.write(metrics) != SocketResult::Continue
+            {
+                return;
+            }
+        }
+    }
+
+    fn close_backend(&mut self) {
+        if let Some(backend) = self.backend.take() {
+            if let Err(e) = backend.shutdown(Shutdown::Both) {
+                if e.kind() != ErrorKind::NotConnected {
+                    error!(
+                        "{} error shutting down backend socket: {:?}",
+                        self.log_context(),
+                        e
+                    );
+                }
+            }
+        }
+        self.container_backend_timeout.cancel();
+        self.backend_stream = None;
+        self.back_buf = None;
+        self.back_readiness.reset();
+    }
+
+    /// The backend connection of a stream failed. Only that stream ends, with a
+    /// 502 if it was not answered yet: the session keeps serving the other
+    /// streams, so a backend failure never closes it.
+    fn backend_stream_failed(&mut self) -> StateResult {
+        error!(
+            "{} backend connection of stream {:?} failed",
+            self.log_context(),
+            self.backend_stream
+        );
+        incr!("http2.backend_stream_errors");
+        if let (Some(stream_id), Some(state)) = (self.backend_stream, self.state.as_mut()) {
+            let answered = state
+                .streams
+                .get(&stream_id)
+                .map_or(true, |stream| stream.state == stream::StreamState::Closed);
+            if !answered {
+                state.answer(stream_id, 502);
+            }
+            self.frontend.readiness.interest = state.interest;
+        }
+        self.close_backend();
+        StateResult::Continue
+    }
+
+    /// The client canceled a stream. If its request was forwarded, the backend
+    /// connection is closed: an HTTP/1.1 backend has no other way to abort a
+    /// request, and it should not keep working on a response nobody reads.
+    fn cancel_stream(&mut self, stream_id: u32) {
+        if self.backend_stream != Some(stream_id) {
+            return;
+        }
+        debug!(
+            "{} stream {} canceled, closing the backend connection",
+            self.log_context(),
+            stream_id
+        );
+        incr!("http2.canceled_streams");
+        self.close_backend();
+    }
+
+    /// A stream is misdirected if its authority is not the server name
+    /// negotiated by TLS on this connection
+    fn is_misdirected(&self, stream_id: u32) -> bool {
+        let server_name = match self.frontend.socket.server_name() {
+            Some(server_name) => server_name,
+            None => return false,
+        };
+        let authority = self
+            .state
+            .as_ref()
+            .and_then(|state| state.streams.get(&stream_id))
+            .and_then(|stream| stream.authority())
+            .and_then(|authority| std::str::from_utf8(authority).ok());
+        match authority {
+            Some(authority) => !host_matches_server_name(authority, server_name),
+            None => true,
+        }
+    }
+
+    fn backend_connections(&self) -> u32 {
+        self.backend_stream.is_some() as u32
+    }
+
+    fn backend_connections_exhausted(&self) -> bool {
+        self.max_backend_connections != 0
+            && self.backend_connections() >= self.max_backend_connections
+    }
+
     // Read content from the session
     pub fn readable(&mut self, metrics: &mut SessionMetrics) -> StateResult {
         trace!("http2 readable");
@@ -160,6 +731,11 @@ impl<Front: SocketHandler> Http2<Front> {
         }
         */
 
+        // the frames left by the previous call are handled before reading more
+        if std::mem::take(&mut self.frames_pending) {
+            return self.readable_parse(metrics);
+        }
+
         if self.frontend.read_buffer.available_space() == 0 {
             if self.backend_token == None {
                 //let answer_413 = "HTTP/1.1 413 Payload Too Large\r\nContent-Length: 0\r\n\r\n";
@@ -173,39 +749,76 @@ impl<Front: SocketHandler> Http2<Front> {
             return StateResult::Continue;
         }
 
-        let res = self.frontend.read(metrics);
-
-        match res {
+        match self.frontend.read(metrics) {
             SocketResult::Error => {
-                let front_readiness = self.frontend.readiness.clone();
-                let back_readiness = self.back_readiness.clone();
                 error!(
-                    "front socket error, closing the connection. Readiness: {:?} -> {:?}",
-                    front_readiness, back_readiness
+                    "{} front socket error, closing the connection. Readiness: {:?} -> {:?}",
+                    self.log_context(),
+                    self.frontend.readiness,
+                    self.back_readiness
                 );
-                return StateResult::CloseSession;
+                incr!("http2.front_read_errors");
+                // nothing can be written on the socket anymore, not even a GOAWAY
+                self.frontend.broken = true;
+                self.frontend.readiness.reset();
+                StateResult::CloseSession
             }
             SocketResult::Closed => {
-                return StateResult::CloseSession;
+                // the client may only have shut down its side, close sends it a GOAWAY
+                debug!("{} the client closed the connection", self.log_context());
+                self.frontend.readiness.interest.remove(Ready::READABLE);
+                self.frontend.readiness.event.remove(Ready::READABLE);
+                StateResult::CloseSession
             }
-            SocketResult::WouldBlock => {}
-            SocketResult::Continue => {}
-        };
-
-        self.readable_parse(metrics)
+            SocketResult::WouldBlock | SocketResult::Continue => self.readable_parse(metrics),
+        }
     }
 
+    /// Handle the frames read from the client. A read often brings several frames:
+    /// all the complete ones already buffered are handled before reading again,
+    /// until one needs the session to act, like connecting to a backend. A flood
+    /// of small frames is handled over several calls, so that one connection does
+    /// not starve the others.
     pub fn readable_parse(&mut self, metrics: &mut SessionMetrics) -> StateResult {
-        let mut state = self.state.take().unwrap();
-        let (sz, cont) = { state.parse_and_handle(self.frontend.read_buffer.data()) };
-        self.frontend.read_buffer.consume(sz);
-        self.frontend.readiness.interest = state.interest;
-        self.state = Some(state);
+        let mut frames = 0;
+        loop {
+            let mut state = self.state.take().unwrap();
+            let was_awaiting_ack = state.awaiting_settings_ack();
+            let (sz, cont) = { state.parse_and_handle(self.frontend.read_buffer.data()) };
+            if cont == state::FrameResult::Close {
+                self.capture_error_context(&state);
+            }
+            self.frontend.read_buffer.consume(sz);
+            self.frontend.readiness.interest = state.interest;
+            if state.state == state::St::ServerPrefaceSent {
+                self.container_handshake_timeout.cancel();
+            }
+            let more = sz > 0 && state.has_complete_frame(self.frontend.read_buffer.data());
+            self.state = Some(state);
+            self.watch_settings_ack(was_awaiting_ack);
+            self.watch_slow_reads();
 
-        match cont {
-            state::FrameResult::Close => StateResult::CloseSession,
-            state::FrameResult::Continue => StateResult::Continue,
-            state::FrameResult::ConnectBackend(id) => StateResult::ConnectBackend,
+            let result = match cont {
+                state::FrameResult::Close => StateResult::CloseSession,
+                state::FrameResult::Continue => self.start_queued_stream(),
+                state::FrameResult::ConnectBackend(id) => self.connect_stream(id),
+                state::FrameResult::CancelStream(id) => {
+                    self.cancel_stream(id);
+                    self.start_queued_stream()
+                }
+            };
+            if result != StateResult::Continue || !more {
+                return result;
+            }
+            frames += 1;
+            if self.max_frames_per_read != 0 && frames >= self.max_frames_per_read {
+                incr!("http2.frames_per_read_limit");
+                // the session is polled again for the frames left in the buffer
+                self.frames_pending = true;
+                self.frontend.readiness.interest.insert(Ready::READABLE);
+                self.frontend.readiness.event.insert(Ready::READABLE);
+                return StateResult::Continue;
+            }
         }
 
         /*let is_initial = unwrap_msg!(self.state.as_ref()).request == Some(RequestState::Initial);
@@ -229,23 +842,108 @@ impl<Front: SocketHandler> Http2<Front> {
         */
     }
 
+    /// Keep and log the context of a fatal protocol error, before the
+    /// connection closes and its buffers go back to the pool
+    fn capture_error_context(&mut self, state: &state::State) {
+        let context = ErrorContext::new(state, self.frontend.read_buffer.data());
+        error!(
+            "{} fatal HTTP/2 error in state {:?}, {} streams, continuation of {:?}",
+            self.log_context(),
+            context.state,
+            context.streams,
+            context.continuation_stream
+        );
+        if !context.received.is_empty() {
+            debug!(
+                "{} last bytes received: {:02x?}",
+                self.log_context(),
+                context.received
+            );
+        }
+        self.error_context = Some(context);
+    }
+
+    /// Start a queued stream if the backend connection is free
+    fn start_queued_stream(&mut self) -> StateResult {
+        if self.backend_stream.is_some() {
+            return StateResult::Continue;
+        }
+        let state = self.state.as_mut().unwrap();
+        let next = state.next_queued_stream(Instant::now());
+        self.frontend.readiness.interest = state.interest;
+        match next {
+            Some(id) => self.connect_stream(id),
+            None => StateResult::Continue,
+        }
+    }
+
+    fn connect_stream(&mut self, id: u32) -> StateResult {
+        incr!("https.requests.h2");
+        let has_host = self
+            .state
+            .as_ref()
+            .and_then(|state| state.streams.get(&id))
+            .is_some_and(|stream| stream.authority().is_some());
+        if !has_host {
+            // admitted by the state only if requests without a host have a cluster,
+            // no virtual host to check against the SNI
+            self.cluster_id = self.no_host_cluster_id.clone();
+        } else if self.is_misdirected(id) {
+            // the client coalesced a request for another origin on this
+            // connection, a 421 tells it to open a new one
+            incr!("http.421.errors");
+            let state = self.state.as_mut().unwrap();
+            state.answer(id, 421);
+            self.frontend.readiness.interest = state.interest;
+            return StateResult::Continue;
+        }
+        if self.backend_connections_exhausted() {
+            // a single client must not open an unbounded number of
+            // backend connections, the stream is refused
+            incr!("http2.backend_connections_limit");
+            let retry_after = self.retry_after();
+            let state = self.state.as_mut().unwrap();
+            match &retry_after {
+                Some(retry_after) => state.answer_with_headers(
+                    id,
+                    503,
+                    &[(&b"retry-after"[..], retry_after.as_bytes())],
+                ),
+                None => state.answer(id, 503),
+            }
+            self.frontend.readiness.interest = state.interest;
+            return StateResult::Continue;
+        }
+        if let Some(stream) = self
+            .state
+            .as_mut()
+            .and_then(|state| state.streams.get_mut(&id))
+        {
+            stream.forwarded();
+        }
+        self.backend_stream = Some(id);
+        StateResult::ConnectBackend
+    }
+
     // Forward content to session
     pub fn writable(&mut self, metrics: &mut SessionMetrics) -> StateResult {
         trace!("http2 writable");
         error!("todo[{}:{}]: writable", file!(), line!());
 
         let mut state = self.state.take().unwrap();
+        // the next frame is serialized once the previous one is completely
+        // written, after a short write its remaining bytes go out first
         //FIXME: do that in a loop until no more frames or WouldBlock
-        match state.gen(self.frontend.write_buffer.space()) {
-            Ok(sz) => {
-                self.frontend.write_buffer.fill(sz);
-                //FIXME: use real condition here to indicate there was nothing to write
-                if sz == 0 {}
-            }
-            Err(e) => {
-                self.state = Some(state);
-                error!("error serializing to front write buffer: {:?}", e);
-                return StateResult::CloseSession;
+        if self.frontend.write_buffer.available_data() == 0 {
+            match state.gen(self.frontend.write_buffer.space()) {
+                Ok(sz) => {
+                    self.frontend.write_buffer.fill(sz);
+                }
+                Err(e) => {
+                    self.state = Some(state);
+                    error!("error serializing to front write buffer: {:?}", e);
+                    return StateResult::CloseSession;
+                }
             }
         }
 
@@ -268,8 +966,24 @@ impl<Front: SocketHandler> Http2<Front> {
             SocketResult::WouldBlock => {}
             SocketResult::Continue => {}
         }
+        if self.frontend.write_buffer.available_data() > 0 {
+            // the state has no frame left to send, but the last one is not written yet
+            self.frontend.readiness.interest.insert(Ready::WRITABLE);
+        }
 
+        let flushed = state.output.is_empty() && self.frontend.write_buffer.available_data() == 0;
+        let done = match self.shutdown_mode {
+            Some(ShutdownMode::Hard) => flushed,
+            Some(ShutdownMode::Graceful) => {
+                flushed && state.active_streams() == 0 && !state.is_draining()
+            }
+            None => false,
+        };
         self.state = Some(state);
+        self.watch_slow_reads();
+        if done {
+            return StateResult::CloseSession;
+        }
         StateResult::Continue
     }
 
@@ -396,6 +1110,8 @@ pub struct Connection<Socket: SocketHandler> {
     pub readiness: Readiness,
     pub read_buffer: Checkout,
     pub write_buffer: Checkout,
+    /// set when the socket failed, nothing can be written on it anymore
+    pub broken: bool,
 }
 
 impl<Socket: SocketHandler> Connection<Socket> {
@@ -410,6 +1126,7 @@ impl<Socket: SocketHandler> Connection<Socket> {
             read_buffer,
             //FIXME: capacity can be configured
             write_buffer,
+            broken: false,
         }
     }
 
@@ -436,6 +1153,17 @@ impl<Socket: SocketHandler> Connection<Socket> {
         res
     }
 
+    /// Shut down the socket without reading from it. The buffers are dropped
+    /// with the connection, which returns them to the pool.
+    pub fn close(&mut self) {
+        if let Err(e) = self.socket.socket_ref().shutdown(Shutdown::Both) {
+            if e.kind() != ErrorKind::NotConnected {
+                error!("error shutting down socket: {:?}", e);
+            }
+        }
+        self.readiness.reset();
+    }
+
     pub fn write(&mut self, metrics: &mut SessionMetrics) -> SocketResult {
         let mut sz = 0usize;
         let mut res = SocketResult::Continue;
@@ -458,3 +1186,699 @@ impl<Socket: SocketHandler> Connection<Socket> {
         res
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener as StdTcpListener, TcpStream as StdTcpStream},
+        rc::Rc,
+    };
+
+    use sozu_command::proto::command::{
+        filtered_metrics::Inner, response_content::ContentType, QueryMetricsOptions,
+    };
+
+    use super::*;
+    use crate::{metrics::METRICS, socket::TransportProtocol};
+
+    /// TCP socket counting the reads made on it, they fail if `failing` is set.
+    /// Writes stop after `write_limit` bytes, as if the socket buffer was full
+    struct CountingSocket {
+        stream: TcpStream,
+        reads: usize,
+        failing: bool,
+        write_limit: Option<usize>,
+    }
+
+    impl SocketHandler for CountingSocket {
+        fn socket_read(&mut self, buf: &mut [u8]) -> (usize, SocketResult) {
+            self.reads += 1;
+            if self.failing {
+                return (0, SocketResult::Error);
+            }
+            self.stream.socket_read(buf)
+        }
+
+        fn socket_write(&mut self, buf: &[u8]) -> (usize, SocketResult) {
+            match self.write_limit {
+                Some(limit) if buf.len() > limit => {
+                    let (size, _) = self.stream.socket_write(&buf[..limit]);
+                    (size, SocketResult::WouldBlock)
+                }
+                _ => self.stream.socket_write(buf),
+            }
+        }
+
+        fn socket_write_vectored(&mut self, bufs: &[std::io::IoSlice]) -> (usize, SocketResult) {
+            self.stream.socket_write_vectored(bufs)
+        }
+
+        fn socket_ref(&self) -> &TcpStream {
+            &self.stream
+        }
+
+        fn socket_mut(&mut self) -> &mut TcpStream {
+            &mut self.stream
+        }
+
+        fn protocol(&self) -> TransportProtocol {
+            TransportProtocol::Tcp
+        }
+
+        fn read_error(&self) {}
+
+        fn write_error(&self) {}
+    }
+
+    #[test]
+    fn close_does_not_read_and_gives_the_buffers_back() {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = StdTcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        server.set_nonblocking(true).unwrap();
+        // the client sent something the session never read
+        client
+            .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n")
+            .unwrap();
+
+        let pool = Rc::new(RefCell::new(Pool::with_capacity(2, 2, 16384)));
+        let socket = CountingSocket {
+            stream: TcpStream::from_std(server),
+            reads: 0,
+            failing: false,
+            write_limit: None,
+        };
+        let mut session = Http2::new(
+            socket,
+            Token(0),
+            Rc::downgrade(&pool),
+            None,
+            None,
+            String::from("SOZUBALANCEID"),
+            Duration::from_secs(3),
+            0,
+        )
+        .unwrap();
+        assert_eq!(pool.borrow().used(), 2);
+
+        session.close(&mut SessionMetrics::new(None));
+        assert_eq!(session.frontend.socket.reads, 0);
+        assert_eq!(session.frontend.read_buffer.available_data(), 0);
+
+        drop(session);
+        assert_eq!(pool.borrow().used(), 0);
+    }
+
+    #[test]
+    fn error_context_only_keeps_the_received_bytes_with_logs_debug() {
+        let state = state::State::new(16393);
+        let received = vec![0xab; 100];
+        let context = ErrorContext::new(&state, &received);
+        assert_eq!(context.state, state::St::Init);
+        assert_eq!(context.streams, 0);
+        if cfg!(feature = "logs-debug") {
+            assert_eq!(context.received, vec![0xab; ERROR_CONTEXT_BYTES]);
+        } else {
+            assert!(context.received.is_empty());
+        }
+    }
+
+    /// Session on a connected socket, with the client end of the connection
+    fn connected_session(
+        pool: &Rc<RefCell<Pool>>,
+        failing: bool,
+    ) -> (Http2<CountingSocket>, StdTcpStream) {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let client = StdTcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        server.set_nonblocking(true).unwrap();
+        let socket = CountingSocket {
+            stream: TcpStream::from_std(server),
+            reads: 0,
+            failing,
+            write_limit: None,
+        };
+        let session = Http2::new(
+            socket,
+            Token(0),
+            Rc::downgrade(pool),
+            None,
+            None,
+            String::from("SOZUBALANCEID"),
+            Duration::from_secs(3),
+            0,
+        )
+        .unwrap();
+        (session, client)
+    }
+
+    #[test]
+    fn a_client_closing_its_side_gets_a_goaway() {
+        let pool = Rc::new(RefCell::new(Pool::with_capacity(2, 2, 16384)));
+        let (mut session, mut client) = connected_session(&pool, false);
+        let mut metrics = SessionMetrics::new(None);
+
+        client
+            .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0")
+            .unwrap();
+        assert_eq!(session.readable(&mut metrics), StateResult::Continue);
+
+        client.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(session.readable(&mut metrics), StateResult::CloseSession);
+        assert!(!session.frontend.readiness.interest.is_readable());
+
+        session.close(&mut metrics);
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).unwrap();
+        assert!(received.ends_with(&[0, 0, 8, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn close_sends_the_buffered_response_before_the_goaway() {
+        let pool = Rc::new(RefCell::new(Pool::with_capacity(2, 2, 16384)));
+        let (mut session, mut client) = connected_session(&pool, false);
+        let mut metrics = SessionMetrics::new(None);
+        session.state.as_mut().unwrap().state = state::St::ServerPrefaceSent;
+
+        // the end of a frame left by a short write, then a whole frame
+        let pending = b"pending";
+        session.frontend.write_buffer.space()[..pending.len()].copy_from_slice(pending);
+        session.frontend.write_buffer.fill(pending.len());
+        session
+            .state
+            .as_mut()
+            .unwrap()
+            .output
+            .push_back(state::OutputFrame::data(1, b"hello".to_vec(), true));
+
+        session.close(&mut metrics);
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).unwrap();
+        let mut expected = pending.to_vec();
+        expected.extend_from_slice(&[0, 0, 5, 0, 1, 0, 0, 0, 1]);
+        expected.extend_from_slice(b"hello");
+        expected.extend_from_slice(&[0, 0, 8, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(received, expected);
+
+        drop(session);
+        assert_eq!(pool.borrow().used(), 0);
+    }
+
+    #[test]
+    fn a_failing_socket_is_closed_without_writing() {
+        let pool = Rc::new(RefCell::new(Pool::with_capacity(2, 2, 16384)));
+        let (mut session, mut client) = connected_session(&pool, true);
+        let mut metrics = SessionMetrics::new(None);
+        session.state.as_mut().unwrap().state = state::St::ServerPrefaceSent;
+
+        assert_eq!(session.readable(&mut metrics), StateResult::CloseSession);
+        assert!(session.frontend.broken);
+
+        session.close(&mut metrics);
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).unwrap();
+        assert!(received.is_empty());
+    }
+
+    #[test]
+    fn a_hard_shutdown_closes_once_the_goaway_is_written() {
+        let pool = Rc::new(RefCell::new(Pool::with_capacity(2, 2, 16384)));
+        let (mut session, mut client) = connected_session(&pool, false);
+        let mut metrics = SessionMetrics::new(None);
+        assert!(!session.shutdown(parser::NO_ERROR, ShutdownMode::Hard));
+
+        client
+            .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0")
+            .unwrap();
+        assert_eq!(session.readable(&mut metrics), StateResult::Continue);
+        assert!(session.shutdown(parser::ENHANCE_YOUR_CALM, ShutdownMode::Hard));
+        assert!(!session.shutdown(parser::NO_ERROR, ShutdownMode::Hard));
+
+        let mut result = StateResult::Continue;
+        for _ in 0..8 {
+            result = session.writable(&mut metrics);
+            if result == StateResult::CloseSession {
+                break;
+            }
+        }
+        assert_eq!(result, StateResult::CloseSession);
+
+        session.close(&mut metrics);
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).unwrap();
+        // a single GOAWAY, with the code of the shutdown
+        assert!(received.ends_with(&[0, 0, 8, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xb]));
+        assert_eq!(
+            received.windows(4).filter(|w| w == &[0, 0, 8, 7]).count(),
+            1
+        );
+    }
+
+    #[test]
+    fn unacknowledged_settings_close_the_connection() {
+        let pool = Rc::new(RefCell::new(Pool::with_capacity(2, 2, 16384)));
+        let (mut session, mut client) = connected_session(&pool, false);
+        let mut metrics = SessionMetrics::new(None);
+
+        client
+            .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0")
+            .unwrap();
+        assert_eq!(session.readable(&mut metrics), StateResult::Continue);
+        assert!(session.awaiting_settings_ack());
+        assert_eq!(
+            session.timeout(Token(0), &mut metrics),
+            StateResult::Continue
+        );
+
+        let mut result = StateResult::Continue;
+        for _ in 0..8 {
+            result = session.writable(&mut metrics);
+            if result == StateResult::CloseSession {
+                break;
+            }
+        }
+        assert_eq!(result, StateResult::CloseSession);
+        session.close(&mut metrics);
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).unwrap();
+        assert!(received.ends_with(&[0, 0, 8, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4]));
+    }
+
+    #[test]
+    fn the_settings_timeout_stops_with_the_ack() {
+        let pool = Rc::new(RefCell::new(Pool::with_capacity(2, 2, 16384)));
+        let (mut session, mut client) = connected_session(&pool, false);
+        let mut metrics = SessionMetrics::new(None);
+
+        client
+            .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0")
+            .unwrap();
+        assert_eq!(session.readable(&mut metrics), StateResult::Continue);
+        // SETTINGS with the ACK flag
+        client.write_all(&[0, 0, 0, 4, 1, 0, 0, 0, 0]).unwrap();
+        assert_eq!(session.readable(&mut metrics), StateResult::Continue);
+        assert!(!session.awaiting_settings_ack());
+        assert!(!session.container_settings_timeout.cancel());
+    }
+
+    #[test]
+    fn a_graceful_shutdown_waits_for_the_started_streams() {
+        let pool = Rc::new(RefCell::new(Pool::with_capacity(2, 2, 16384)));
+        let (mut session, mut client) = connected_session(&pool, false);
+        let mut metrics = SessionMetrics::new(None);
+        {
+            let state = session.state.as_mut().unwrap();
+            state.state = state::St::ServerPrefaceSent;
+            let mut stream = stream::Stream::new(1);
+            stream.state = stream::StreamState::HalfClosedRemote;
+            state.streams.insert(1, stream);
+        }
+        assert!(session.shutdown(parser::NO_ERROR, ShutdownMode::Graceful));
+        assert!(!session.shutdown(parser::NO_ERROR, ShutdownMode::Graceful));
+
+        for _ in 0..8 {
+            assert_eq!(session.writable(&mut metrics), StateResult::Continue);
+        }
+
+        // the drain period is over, the final GOAWAY gives the last stream id
+        assert_eq!(
+            session.timeout(Token(0), &mut metrics),
+            StateResult::Continue
+        );
+        assert!(session.frontend.readiness.interest.is_writable());
+        for _ in 0..8 {
+            assert_eq!(session.writable(&mut metrics), StateResult::Continue);
+        }
+        let mut received = [0; 34];
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(
+            received,
+            [
+                0, 0, 8, 7, 0, 0, 0, 0, 0, 0x7f, 0xff, 0xff, 0xff, 0, 0, 0, 0, //
+                0, 0, 8, 7, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0,
+            ]
+        );
+
+        let state = session.state.as_mut().unwrap();
+        state.streams.get_mut(&1).unwrap().state = stream::StreamState::Closed;
+        assert_eq!(session.writable(&mut metrics), StateResult::CloseSession);
+    }
+
+    #[test]
+    fn a_flood_of_frames_is_handled_over_several_reads() {
+        let pool = Rc::new(RefCell::new(Pool::with_capacity(2, 2, 16384)));
+        let (mut session, mut client) = connected_session(&pool, false);
+        let mut metrics = SessionMetrics::new(None);
+        session.set_max_frames_per_read(2);
+
+        let mut input = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0".to_vec();
+        for i in 0..3 {
+            // PING with an 8 bytes payload
+            input.extend_from_slice(&[0, 0, 8, 6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, i]);
+        }
+        client.write_all(&input).unwrap();
+
+        assert_eq!(session.readable(&mut metrics), StateResult::Continue);
+        assert!(session.frames_pending);
+        assert!(session.frontend.readiness.event.is_readable());
+        assert!(session.frontend.read_buffer.available_data() > 0);
+
+        while session.frames_pending {
+            assert_eq!(session.readable(&mut metrics), StateResult::Continue);
+        }
+        assert_eq!(session.frontend.read_buffer.available_data(), 0);
+    }
+
+    #[test]
+    fn the_handshake_timeout_stops_once_the_client_settings_arrive() {
+        let pool = Rc::new(RefCell::new(Pool::with_capacity(2, 2, 16384)));
+        let mut metrics = SessionMetrics::new(None);
+
+        let (mut session, _client) = connected_session(&pool, false);
+        session.set_handshake_timeout(
+            TimeoutContainer::new(Duration::from_secs(10), Token(0)),
+            Duration::from_secs(5),
+        );
+        assert_eq!(
+            session.container_handshake_timeout.duration(),
+            Duration::from_secs(5)
+        );
+        assert_eq!(
+            session.timeout(Token(0), &mut metrics),
+            StateResult::CloseSession
+        );
+        session.close(&mut metrics);
+        drop(session);
+
+        let (mut session, mut client) = connected_session(&pool, false);
+        session.set_handshake_timeout(
+            TimeoutContainer::new(Duration::from_secs(10), Token(0)),
+            Duration::from_secs(5),
+        );
+        client
+            .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0")
+            .unwrap();
+        assert_eq!(session.readable(&mut metrics), StateResult::Continue);
+        assert_eq!(
+            session.state.as_ref().unwrap().state,
+            state::St::ServerPrefaceSent
+        );
+        // already canceled
+        assert!(!session.container_handshake_timeout.cancel());
+    }
+
+    #[test]
+    fn closing_cancels_the_timeouts() {
+        let pool = Rc::new(RefCell::new(Pool::with_capacity(2, 2, 16384)));
+        let (mut session, _client) = connected_session(&pool, false);
+        let mut metrics = SessionMetrics::new(None);
+        session.set_handshake_timeout(
+            TimeoutContainer::new(Duration::from_secs(10), Token(0)),
+            Duration::from_millis(1),
+        );
+        session
+            .container_backend_timeout
+            .set_duration(Duration::from_millis(1));
+        session.set_back_token(Token(1));
+        // a timeout left alone, that fires in the same tick
+        let _witness = TimeoutContainer::new(Duration::from_millis(1), Token(2));
+
+        session.close(&mut metrics);
+        // already canceled
+        assert!(!session.container_handshake_timeout.cancel());
+        assert!(!session.container_backend_timeout.cancel());
+
+        // once their deadline passed, only the witness fires
+        std::thread::sleep(Duration::from_millis(250));
+        let fired: Vec<Token> =
+            std::iter::from_fn(|| crate::server::TIMER.with(|timer| timer.borrow_mut().poll()))
+                .collect();
+        assert_eq!(fired, vec![Token(2)]);
+    }
+
+    #[test]
+    fn a_rejected_stream_holds_nothing_back() {
+        let pool = Rc::new(RefCell::new(Pool::with_capacity(2, 2, 16384)));
+        let (mut session, mut client) = connected_session(&pool, false);
+        let mut metrics = SessionMetrics::new(None);
+        assert_eq!(pool.borrow().used(), 2);
+
+        // a request without :path, END_STREAM and END_HEADERS on stream 1
+        let block = hpack::Encoder::new().encode([
+            (&b":method"[..], &b"GET"[..]),
+            (&b":scheme"[..], &b"https"[..]),
+            (&b":authority"[..], &b"example.com"[..]),
+        ]);
+        let mut input = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0".to_vec();
+        input.extend_from_slice(&(block.len() as u32).to_be_bytes()[1..]);
+        input.extend_from_slice(&[1, 5, 0, 0, 0, 1]);
+        input.extend_from_slice(&block);
+        client.write_all(&input).unwrap();
+        assert_eq!(session.readable(&mut metrics), StateResult::Continue);
+
+        let stream = &session.state.as_ref().unwrap().streams[&1];
+        assert_eq!(stream.state, stream::StreamState::Closed);
+        assert!(stream.request_headers.is_empty());
+        assert_eq!(session.backend_stream, None);
+        assert_eq!(pool.borrow().used(), 2);
+
+        session.close(&mut metrics);
+        drop(session);
+        assert_eq!(pool.borrow().used(), 0);
+    }
+
+    #[test]
+    fn short_writes_of_the_server_preface_keep_the_frames_whole() {
+        let pool = Rc::new(RefCell::new(Pool::with_capacity(2, 2, 16384)));
+        let (mut session, mut client) = connected_session(&pool, false);
+        let mut metrics = SessionMetrics::new(None);
+        client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        client
+            .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0")
+            .unwrap();
+        assert_eq!(session.readable(&mut metrics), StateResult::Continue);
+
+        session.frontend.socket.write_limit = Some(5);
+        for _ in 0..64 {
+            if !session.frontend.readiness.interest.is_writable() {
+                break;
+            }
+            assert_eq!(session.writable(&mut metrics), StateResult::Continue);
+        }
+        assert!(!session.frontend.readiness.interest.is_writable());
+        assert_eq!(session.frontend.write_buffer.available_data(), 0);
+
+        // the SETTINGS of the server preface, then the acknowledgement of the client ones
+        let mut header = [0; 9];
+        client.read_exact(&mut header).unwrap();
+        assert_eq!(header[3..], [4, 0, 0, 0, 0, 0]);
+        let mut payload =
+            vec![0; u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize];
+        client.read_exact(&mut payload).unwrap();
+        client.read_exact(&mut header).unwrap();
+        assert_eq!(header, [0, 0, 0, 4, 1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn a_backend_connect_timeout_answers_its_stream_with_a_504() {
+        let pool = Rc::new(RefCell::new(Pool::with_capacity(2, 2, 16384)));
+        let (mut session, _client) = connected_session(&pool, false);
+        let mut metrics = SessionMetrics::new(None);
+        let state = session.state.as_mut().unwrap();
+        state.state = state::St::ServerPrefaceSent;
+        state.streams.insert(1, stream::Stream::new(1));
+        state.streams.get_mut(&1).unwrap().state = stream::StreamState::HalfClosedRemote;
+        session.backend_stream = Some(1);
+        session.set_back_token(Token(1));
+
+        assert_eq!(
+            session.timeout(Token(1), &mut metrics),
+            StateResult::Continue
+        );
+        assert_eq!(session.backend_stream, None);
+        assert!(session.frontend.readiness.interest.is_writable());
+
+        // the stream is answered, the connection stays open for the others
+        let state = session.state.as_mut().unwrap();
+        assert_eq!(state.streams[&1].state, stream::StreamState::Closed);
+        let mut buffer = [0; 32];
+        let size = state.gen(&mut buffer).unwrap();
+        let headers = hpack::Decoder::new().decode(&buffer[9..size]).unwrap();
+        assert_eq!(headers, vec![(b":status".to_vec(), b"504".to_vec())]);
+    }
+
+    #[test]
+    fn streams_over_the_backend_connection_limit_get_a_503() {
+        let pool = Rc::new(RefCell::new(Pool::with_capacity(2, 2, 16384)));
+        let (mut session, _client) = connected_session(&pool, false);
+        session.max_backend_connections = 1;
+        let state = session.state.as_mut().unwrap();
+        for id in [1, 3] {
+            let mut stream = stream::Stream::new(id);
+            stream.state = stream::StreamState::HalfClosedRemote;
+            stream.request_headers = vec![(b":authority".to_vec(), b"localhost".to_vec())];
+            state.streams.insert(id, stream);
+        }
+
+        assert_eq!(session.connect_stream(1), StateResult::ConnectBackend);
+        assert_eq!(session.backend_stream, Some(1));
+        // the only backend connection allowed is taken
+        assert_eq!(session.connect_stream(3), StateResult::Continue);
+        assert_eq!(session.backend_stream, Some(1));
+        let state = session.state.as_mut().unwrap();
+        assert_eq!(state.streams[&3].state, stream::StreamState::Closed);
+        let mut buffer = [0; 32];
+        let size = state.gen(&mut buffer).unwrap();
+        let headers = hpack::Decoder::new().decode(&buffer[9..size]).unwrap();
+        assert_eq!(headers, vec![(b":status".to_vec(), b"503".to_vec())]);
+
+        // 0 means no limit
+        session.max_backend_connections = 0;
+        assert!(!session.backend_connections_exhausted());
+    }
+
+    #[test]
+    fn the_snapshot_lists_the_streams_by_id() {
+        let pool = Rc::new(RefCell::new(Pool::with_capacity(2, 2, 16384)));
+        let (mut session, _client) = connected_session(&pool, false);
+        session.cluster_id = Some(String::from("cluster_0"));
+        let state = session.state.as_mut().unwrap();
+        for id in [3, 1] {
+            let mut stream = stream::Stream::new(id);
+            stream.request_headers = vec![
+                (b":authority".to_vec(), b"example.com".to_vec()),
+                (b":path".to_vec(), format!("/{id}").into_bytes()),
+            ];
+            state.streams.insert(id, stream);
+        }
+        state.streams.get_mut(&3).unwrap().forwarded();
+
+        let snapshot = session.snapshot();
+        assert_eq!(snapshot.protocol, "HTTP2");
+        assert_eq!(snapshot.cluster_id.as_deref(), Some("cluster_0"));
+        assert_eq!(snapshot.frontend_token, Some(0));
+        assert_eq!(snapshot.backend_token, None);
+        let streams: Vec<_> = snapshot
+            .streams
+            .iter()
+            .map(|stream| {
+                (
+                    stream.id,
+                    stream.authority.as_deref(),
+                    stream.path.as_deref(),
+                    stream.phase.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            streams,
+            vec![
+                (1, Some("example.com"), Some("/1"), Some("AwaitingHeaders")),
+                (3, Some("example.com"), Some("/3"), Some("StreamingRequest")),
+            ]
+        );
+    }
+
+    #[test]
+    fn connections_and_requests_are_counted_as_h2() {
+        let pool = Rc::new(RefCell::new(Pool::with_capacity(2, 2, 16384)));
+        let (mut session, _client) = connected_session(&pool, false);
+        let state = session.state.as_mut().unwrap();
+        let mut stream = stream::Stream::new(1);
+        stream.state = stream::StreamState::HalfClosedRemote;
+        stream.request_headers = vec![(b":authority".to_vec(), b"localhost".to_vec())];
+        state.streams.insert(1, stream);
+        assert_eq!(session.connect_stream(1), StateResult::ConnectBackend);
+
+        let response = METRICS
+            .with(|metrics| {
+                metrics.borrow_mut().query(&QueryMetricsOptions {
+                    metric_names: vec![
+                        String::from("https.connections.h2"),
+                        String::from("https.requests.h2"),
+                    ],
+                    no_clusters: true,
+                    ..Default::default()
+                })
+            })
+            .unwrap();
+        let Some(ContentType::WorkerMetrics(worker_metrics)) = response.content_type else {
+            panic!("expected worker metrics, got {response:?}");
+        };
+        for name in ["https.connections.h2", "https.requests.h2"] {
+            assert_eq!(worker_metrics.proxy[name].inner, Some(Inner::Count(1)));
+        }
+    }
+
+    #[test]
+    fn sessions_are_refused_without_buffers() {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let new_session = |pool| {
+            let _client = StdTcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (server, _) = listener.accept().unwrap();
+            let socket = CountingSocket {
+                stream: TcpStream::from_std(server),
+                reads: 0,
+                failing: false,
+                write_limit: None,
+            };
+            Http2::new(
+                socket,
+                Token(0),
+                pool,
+                None,
+                None,
+                String::from("SOZUBALANCEID"),
+                Duration::from_secs(3),
+                0,
+            )
+        };
+
+        // a session needs two buffers
+        let pool = Rc::new(RefCell::new(Pool::with_capacity(1, 1, 16384)));
+        assert!(matches!(
+            new_session(Rc::downgrade(&pool)),
+            Err(AcceptError::BufferCapacityReached)
+        ));
+
+        // the proxy dropped its pool while shutting down
+        let dropped = Rc::downgrade(&pool);
+        drop(pool);
+        assert!(matches!(
+            new_session(dropped),
+            Err(AcceptError::PoolDropped)
+        ));
+    }
+
+    #[test]
+    fn the_server_preface_advertises_the_request_header_limit() {
+        let pool = Rc::new(RefCell::new(Pool::with_capacity(2, 2, 16384)));
+        let (mut session, mut client) = connected_session(&pool, false);
+        let mut metrics = SessionMetrics::new(None);
+        session.set_max_header_sizes(Some(8192), None);
+
+        client
+            .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0")
+            .unwrap();
+        assert_eq!(session.readable(&mut metrics), StateResult::Continue);
+        // one frame is written per call
+        assert_eq!(session.writable(&mut metrics), StateResult::Continue);
+        assert_eq!(session.writable(&mut metrics), StateResult::Continue);
+
+        // our SETTINGS with SETTINGS_MAX_HEADER_LIST_SIZE and
+        // SETTINGS_NO_RFC7540_PRIORITIES, then the ACK of the client's
+        let mut received = [0; 30];
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(
+            received,
+            [
+                0, 0, 12, 4, 0, 0, 0, 0, 0, 0, 6, 0, 0, 0x20, 0, 0, 9, 0, 0, 0, 1, 0, 0, 0, 4, 1,
+                0, 0, 0, 0
+            ]
+        );
+    }
+}
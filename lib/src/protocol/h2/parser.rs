@@ -30,24 +30,33 @@ pub enum FrameType {
     GoAway,
     WindowUpdate,
     Continuation,
+    /// extension frame type, its payload must be skipped
+    Unknown(u8),
 }
 
-/*
-const NO_ERROR: u32 = 0x0;
-const PROTOCOL_ERROR: u32 = 0x1;
-const INTERNAL_ERROR: u32 = 0x2;
-const FLOW_CONTROL_ERROR: u32 = 0x3;
-const SETTINGS_TIMEOUT: u32 = 0x4;
-const STREAM_CLOSED: u32 = 0x5;
-const FRAME_SIZE_ERROR: u32 = 0x6;
-const REFUSED_STREAM: u32 = 0x7;
-const CANCEL: u32 = 0x8;
-const COMPRESSION_ERROR: u32 = 0x9;
-const CONNECT_ERROR: u32 = 0xa;
-const ENHANCE_YOUR_CALM: u32 = 0xb;
-const INADEQUATE_SECURITY: u32 = 0xc;
-const HTTP_1_1_REQUIRED: u32 = 0xd;
-*/
+pub const FLAG_END_STREAM: u8 = 0x1;
+pub const FLAG_ACK: u8 = 0x1;
+pub const FLAG_END_HEADERS: u8 = 0x4;
+pub const FLAG_PADDED: u8 = 0x8;
+pub const FLAG_PRIORITY: u8 = 0x20;
+
+pub const NO_ERROR: u32 = 0x0;
+pub const PROTOCOL_ERROR: u32 = 0x1;
+pub const INTERNAL_ERROR: u32 = 0x2;
+pub const FLOW_CONTROL_ERROR: u32 = 0x3;
+pub const SETTINGS_TIMEOUT: u32 = 0x4;
+pub const STREAM_CLOSED: u32 = 0x5;
+pub const FRAME_SIZE_ERROR: u32 = 0x6;
+pub const REFUSED_STREAM: u32 = 0x7;
+pub const CANCEL: u32 = 0x8;
+pub const COMPRESSION_ERROR: u32 = 0x9;
+pub const CONNECT_ERROR: u32 = 0xa;
+pub const ENHANCE_YOUR_CALM: u32 = 0xb;
+pub const INADEQUATE_SECURITY: u32 = 0xc;
+pub const HTTP_1_1_REQUIRED: u32 = 0xd;
+
+/// stream ids are 31 bits long (RFC 9113 section 5.1.1)
+pub const MAX_STREAM_ID: u32 = (1 << 31) - 1;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Error<'a> {
@@ -127,7 +136,7 @@ pub fn preface(i: &[u8]) -> IResult<&[u8], &[u8]> {
 
 pub fn frame_header(input: &[u8]) -> IResult<&[u8], FrameHeader, Error> {
     let (i1, payload_len) = be_u24(input)?;
-    let (i2, frame_type) = map_opt(be_u8, convert_frame_type)(i1)?;
+    let (i2, frame_type) = map(be_u8, convert_frame_type)(i1)?;
     let (i3, flags) = be_u8(i2)?;
     let (i4, stream_id) = be_u32(i3)?;
 
@@ -142,20 +151,20 @@ pub fn frame_header(input: &[u8]) -> IResult<&[u8], FrameHeader, Error> {
     ))
 }
 
-fn convert_frame_type(t: u8) -> Option<FrameType> {
+fn convert_frame_type(t: u8) -> FrameType {
     info!("got frame type: {}", t);
     match t {
-        0 => Some(FrameType::Data),
-        1 => Some(FrameType::Headers),
-        2 => Some(FrameType::Priority),
-        3 => Some(FrameType::RstStream),
-        4 => Some(FrameType::Settings),
-        5 => Some(FrameType::PushPromise),
-        6 => Some(FrameType::Ping),
-        7 => Some(FrameType::GoAway),
-        8 => Some(FrameType::WindowUpdate),
-        9 => Some(FrameType::Continuation),
-        _ => None,
+        0 => FrameType::Data,
+        1 => FrameType::Headers,
+        2 => FrameType::Priority,
+        3 => FrameType::RstStream,
+        4 => FrameType::Settings,
+        5 => FrameType::PushPromise,
+        6 => FrameType::Ping,
+        7 => FrameType::GoAway,
+        8 => FrameType::WindowUpdate,
+        9 => FrameType::Continuation,
+        t => FrameType::Unknown(t),
     }
 }
 
@@ -163,14 +172,15 @@ fn convert_frame_type(t: u8) -> Option<FrameType> {
 pub enum Frame<'a> {
     Data(Data<'a>),
     Headers(Headers<'a>),
-    Priority,
+    Priority(Priority),
     RstStream(RstStream),
     Settings(Settings),
-    PushPromise,
     Ping(Ping),
-    GoAway,
+    GoAway(GoAway),
     WindowUpdate(WindowUpdate),
-    Continuation,
+    Continuation(Continuation<'a>),
+    /// a frame of a type this parser does not know, it must be ignored
+    Unknown(Unknown<'a>),
 }
 
 impl<'a> Frame<'a> {
@@ -178,11 +188,10 @@ impl<'a> Frame<'a> {
         match self {
             Frame::Data(_)
             | Frame::Headers(_)
-            | Frame::Priority
+            | Frame::Priority(_)
             | Frame::RstStream(_)
-            | Frame::PushPromise
-            | Frame::Continuation => true,
-            Frame::Settings(_) | Frame::Ping(_) | Frame::GoAway => false,
+            | Frame::Continuation(_) => true,
+            Frame::Settings(_) | Frame::Ping(_) | Frame::GoAway(_) | Frame::Unknown(_) => false,
             Frame::WindowUpdate(w) => w.stream_id != 0,
         }
     }
@@ -191,12 +200,12 @@ impl<'a> Frame<'a> {
         match self {
             Frame::Data(d) => d.stream_id,
             Frame::Headers(h) => h.stream_id,
-            Frame::Priority => unimplemented!(),
+            Frame::Priority(p) => p.stream_id,
             Frame::RstStream(r) => r.stream_id,
-            Frame::PushPromise => unimplemented!(),
-            Frame::Continuation => unimplemented!(),
-            Frame::Settings(_) | Frame::Ping(_) | Frame::GoAway => 0,
+            Frame::Continuation(c) => c.stream_id,
+            Frame::Settings(_) | Frame::Ping(_) | Frame::GoAway(_) => 0,
             Frame::WindowUpdate(w) => w.stream_id,
+            Frame::Unknown(u) => u.stream_id,
         }
     }
 }
@@ -218,7 +227,7 @@ pub fn frame<'a>(input: &'a [u8], max_frame_size: u32) -> IResult<&'a [u8], Fram
         | FrameType::PushPromise
         | FrameType::Continuation => header.stream_id != 0,
         FrameType::Settings | FrameType::Ping | FrameType::GoAway => header.stream_id == 0,
-        FrameType::WindowUpdate => true,
+        FrameType::WindowUpdate | FrameType::Unknown(_) => true,
     };
 
     if !valid_stream_id {
@@ -232,7 +241,7 @@ pub fn frame<'a>(input: &'a [u8], max_frame_size: u32) -> IResult<&'a [u8], Fram
             if header.payload_len != 5 {
                 return Err(Err::Failure(Error::new(input, InnerError::FrameSizeError)));
             }
-            unimplemented!();
+            priority_frame(i, &header)?
         }
         FrameType::RstStream => {
             if header.payload_len != 4 {
@@ -240,12 +249,12 @@ pub fn frame<'a>(input: &'a [u8], max_frame_size: u32) -> IResult<&'a [u8], Fram
             }
             rst_stream_frame(i, &header)?
         }
+        // a client cannot push, its PUSH_PROMISE is a connection error of type
+        // PROTOCOL_ERROR (RFC 9113 section 8.4)
         FrameType::PushPromise => {
-            unimplemented!();
-        }
-        FrameType::Continuation => {
-            unimplemented!();
+            return Err(Err::Failure(Error::new(input, InnerError::ProtocolError)));
         }
+        FrameType::Continuation => continuation_frame(i, &header)?,
         FrameType::Settings => {
             if header.payload_len % 6 != 0 {
                 return Err(Err::Failure(Error::new(input, InnerError::FrameSizeError)));
@@ -259,7 +268,10 @@ pub fn frame<'a>(input: &'a [u8], max_frame_size: u32) -> IResult<&'a [u8], Fram
             ping_frame(i, &header)?
         }
         FrameType::GoAway => {
-            unimplemented!();
+            if header.payload_len < 8 {
+                return Err(Err::Failure(Error::new(input, InnerError::FrameSizeError)));
+            }
+            goaway_frame(i, &header)?
         }
         FrameType::WindowUpdate => {
             if header.payload_len != 4 {
@@ -267,6 +279,17 @@ pub fn frame<'a>(input: &'a [u8], max_frame_size: u32) -> IResult<&'a [u8], Fram
             }
             window_update_frame(i, &header)?
         }
+        FrameType::Unknown(frame_type) => {
+            let (i, payload) = take(header.payload_len)(i)?;
+            (
+                i,
+                Frame::Unknown(Unknown {
+                    frame_type,
+                    stream_id: header.stream_id,
+                    payload,
+                }),
+            )
+        }
     };
 
     Ok(f)
@@ -277,6 +300,21 @@ pub struct Data<'a> {
     pub stream_id: u32,
     pub payload: &'a [u8],
     pub end_stream: bool,
+    /// length counted against the flow control windows, see `flow_controlled_length`
+    pub flow_controlled_length: u32,
+}
+
+/// Length of a frame counted against the flow control windows.
+///
+/// Only DATA frames are flow controlled, and their whole payload counts,
+/// the Pad Length field and the padding included (RFC 9113 section 6.9.1).
+/// Window checks and WINDOW_UPDATE increments must all use this value, or the
+/// windows of both peers drift apart.
+pub fn flow_controlled_length(header: &FrameHeader) -> u32 {
+    match header.frame_type {
+        FrameType::Data => header.payload_len,
+        _ => 0,
+    }
 }
 
 pub fn data_frame<'a, 'b>(
@@ -292,7 +330,8 @@ pub fn data_frame<'a, 'b>(
         (i, None)
     };
 
-    if pad_length.is_some() && i1.len() <= pad_length.unwrap() as usize {
+    // the padding may take all the payload after the Pad Length field
+    if pad_length.is_some() && i1.len() < pad_length.unwrap() as usize {
         return Err(Err::Failure(Error::new(input, InnerError::ProtocolError)));
     }
 
@@ -304,6 +343,7 @@ pub fn data_frame<'a, 'b>(
             stream_id: header.stream_id,
             payload,
             end_stream: header.flags & 0x1 != 0,
+            flow_controlled_length: flow_controlled_length(header),
         }),
     ))
 }
@@ -355,7 +395,8 @@ pub fn headers_frame<'a, 'b>(
         (i2, None)
     };
 
-    if pad_length.is_some() && i3.len() <= pad_length.unwrap() as usize {
+    // the padding may leave an empty header block fragment
+    if pad_length.is_some() && i3.len() < pad_length.unwrap() as usize {
         return Err(Err::Failure(Error::new(input, InnerError::ProtocolError)));
     }
 
@@ -375,6 +416,34 @@ pub fn headers_frame<'a, 'b>(
     ))
 }
 
+/// PRIORITY frame of RFC 7540, deprecated by RFC 9113: it is parsed to be ignored
+#[derive(Clone, Debug, PartialEq)]
+pub struct Priority {
+    pub stream_id: u32,
+    pub stream_dependency: StreamDependency,
+    pub weight: u8,
+}
+
+pub fn priority_frame<'a, 'b>(
+    input: &'a [u8],
+    header: &'b FrameHeader,
+) -> IResult<&'a [u8], Frame<'a>, Error<'a>> {
+    let (i, stream_dependency) = map(be_u32, |i| StreamDependency {
+        exclusive: i & 0x8000_0000 != 0,
+        stream_id: i & 0x7FFF_FFFF,
+    })(input)?;
+    let (i, weight) = be_u8(i)?;
+
+    Ok((
+        i,
+        Frame::Priority(Priority {
+            stream_id: header.stream_id,
+            stream_dependency,
+            weight,
+        }),
+    ))
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct RstStream {
     pub stream_id: u32,
@@ -395,9 +464,33 @@ pub fn rst_stream_frame<'a, 'b>(
     ))
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub struct Continuation<'a> {
+    pub stream_id: u32,
+    pub header_block_fragment: &'a [u8],
+    pub end_headers: bool,
+}
+
+pub fn continuation_frame<'a, 'b>(
+    input: &'a [u8],
+    header: &'b FrameHeader,
+) -> IResult<&'a [u8], Frame<'a>, Error<'a>> {
+    let (i, header_block_fragment) = take(header.payload_len)(input)?;
+    Ok((
+        i,
+        Frame::Continuation(Continuation {
+            stream_id: header.stream_id,
+            header_block_fragment,
+            end_headers: header.flags & FLAG_END_HEADERS != 0,
+        }),
+    ))
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Settings {
     pub settings: Vec<Setting>,
+    /// acknowledgement of the SETTINGS we sent, it carries no setting
+    pub ack: bool,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -417,12 +510,19 @@ pub fn settings_frame<'a, 'b>(
         |(identifier, value)| Setting { identifier, value },
     ))(data)?;
 
-    Ok((i, Frame::Settings(Settings { settings })))
+    Ok((
+        i,
+        Frame::Settings(Settings {
+            settings,
+            ack: header.flags & FLAG_ACK != 0,
+        }),
+    ))
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Ping {
     pub payload: [u8; 8],
+    pub ack: bool,
 }
 
 pub fn ping_frame<'a, 'b>(
@@ -431,7 +531,10 @@ pub fn ping_frame<'a, 'b>(
 ) -> IResult<&'a [u8], Frame<'a>, Error<'a>> {
     let (i, data) = take(8usize)(input)?;
 
-    let mut p = Ping { payload: [0; 8] };
+    let mut p = Ping {
+        payload: [0; 8],
+        ack: header.flags & FLAG_ACK != 0,
+    };
 
     for i in 0..8 {
         p.payload[i] = data[i];
@@ -440,6 +543,38 @@ pub fn ping_frame<'a, 'b>(
     Ok((i, Frame::Ping(p)))
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub struct GoAway {
+    pub last_stream_id: u32,
+    pub error_code: u32,
+}
+
+pub fn goaway_frame<'a, 'b>(
+    input: &'a [u8],
+    header: &'b FrameHeader,
+) -> IResult<&'a [u8], Frame<'a>, Error<'a>> {
+    let (i, last_stream_id) = be_u32(input)?;
+    let (i, error_code) = be_u32(i)?;
+    // the additional debug data is only meant for diagnostics
+    let (i, _) = take(header.payload_len - 8)(i)?;
+    Ok((
+        i,
+        Frame::GoAway(GoAway {
+            last_stream_id: last_stream_id & 0x7FFF_FFFF,
+            error_code,
+        }),
+    ))
+}
+
+/// Frame of an extension or of a later version of the protocol. Its payload is
+/// kept so that the frame is skipped whole, using its length
+#[derive(Clone, Debug, PartialEq)]
+pub struct Unknown<'a> {
+    pub frame_type: u8,
+    pub stream_id: u32,
+    pub payload: &'a [u8],
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct WindowUpdate {
     pub stream_id: u32,
@@ -451,12 +586,9 @@ pub fn window_update_frame<'a, 'b>(
     header: &'b FrameHeader,
 ) -> IResult<&'a [u8], Frame<'a>, Error<'a>> {
     let (i, increment) = be_u32(input)?;
-    let increment = increment & 0x7FFF;
-
-    //FIXME: if stream id is 0, trat it as connection error?
-    if increment == 0 {
-        return Err(Err::Failure(Error::new(input, InnerError::ProtocolError)));
-    }
+    // the reserved bit is ignored, a zero increment is refused by the state
+    // machine, as a stream or a connection error depending on the stream
+    let increment = increment & 0x7FFF_FFFF;
 
     Ok((
         i,
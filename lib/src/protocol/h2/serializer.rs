@@ -33,5 +33,6 @@ pub fn serialize_frame_type(f: &FrameType) -> u8 {
         FrameType::GoAway => 7,
         FrameType::WindowUpdate => 8,
         FrameType::Continuation => 9,
+        FrameType::Unknown(t) => t,
     }
 }
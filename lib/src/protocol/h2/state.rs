@@ -1,9 +1,14 @@
-use std::collections::{HashMap, VecDeque};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
 
+use hpack::{Decoder, Encoder};
 use nom::Offset;
 
 use crate::{
-    protocol::h2::{parser, serializer, stream},
+    protocol::h2::{parser, serializer, stream, translation::h1_to_h2_trailers},
     Ready,
 };
 
@@ -13,12 +18,587 @@ pub struct OutputFrame {
     payload: Option<Vec<u8>>,
 }
 
+impl OutputFrame {
+    pub fn rst_stream(stream_id: u32, error_code: u32) -> OutputFrame {
+        OutputFrame {
+            header: parser::FrameHeader {
+                payload_len: 4,
+                frame_type: parser::FrameType::RstStream,
+                flags: 0,
+                stream_id,
+            },
+            payload: Some(error_code.to_be_bytes().to_vec()),
+        }
+    }
+
+    pub fn headers(stream_id: u32, header_block: Vec<u8>, end_stream: bool) -> OutputFrame {
+        OutputFrame {
+            header: parser::FrameHeader {
+                payload_len: header_block.len() as u32,
+                frame_type: parser::FrameType::Headers,
+                flags: if end_stream {
+                    parser::FLAG_END_HEADERS | parser::FLAG_END_STREAM
+                } else {
+                    parser::FLAG_END_HEADERS
+                },
+                stream_id,
+            },
+            payload: Some(header_block),
+        }
+    }
+
+    /// HEADERS frame carrying the first fragment of a header block that continues
+    /// in CONTINUATION frames
+    pub fn headers_fragment(stream_id: u32, fragment: Vec<u8>, end_stream: bool) -> OutputFrame {
+        OutputFrame {
+            header: parser::FrameHeader {
+                payload_len: fragment.len() as u32,
+                frame_type: parser::FrameType::Headers,
+                flags: if end_stream {
+                    parser::FLAG_END_STREAM
+                } else {
+                    0
+                },
+                stream_id,
+            },
+            payload: Some(fragment),
+        }
+    }
+
+    pub fn continuation(stream_id: u32, fragment: Vec<u8>, end_headers: bool) -> OutputFrame {
+        OutputFrame {
+            header: parser::FrameHeader {
+                payload_len: fragment.len() as u32,
+                frame_type: parser::FrameType::Continuation,
+                flags: if end_headers {
+                    parser::FLAG_END_HEADERS
+                } else {
+                    0
+                },
+                stream_id,
+            },
+            payload: Some(fragment),
+        }
+    }
+
+    pub fn data(stream_id: u32, payload: Vec<u8>, end_stream: bool) -> OutputFrame {
+        OutputFrame {
+            header: parser::FrameHeader {
+                payload_len: payload.len() as u32,
+                frame_type: parser::FrameType::Data,
+                flags: if end_stream {
+                    parser::FLAG_END_STREAM
+                } else {
+                    0
+                },
+                stream_id,
+            },
+            payload: Some(payload),
+        }
+    }
+
+    pub fn window_update(stream_id: u32, increment: u32) -> OutputFrame {
+        OutputFrame {
+            header: parser::FrameHeader {
+                payload_len: 4,
+                frame_type: parser::FrameType::WindowUpdate,
+                flags: 0,
+                stream_id,
+            },
+            payload: Some(increment.to_be_bytes().to_vec()),
+        }
+    }
+
+    pub fn settings(settings: &[parser::Setting]) -> OutputFrame {
+        let mut payload = Vec::with_capacity(settings.len() * 6);
+        for setting in settings {
+            payload.extend_from_slice(&setting.identifier.to_be_bytes());
+            payload.extend_from_slice(&setting.value.to_be_bytes());
+        }
+        OutputFrame {
+            header: parser::FrameHeader {
+                payload_len: payload.len() as u32,
+                frame_type: parser::FrameType::Settings,
+                flags: 0,
+                stream_id: 0,
+            },
+            payload: Some(payload),
+        }
+    }
+
+    pub fn settings_ack() -> OutputFrame {
+        OutputFrame {
+            header: parser::FrameHeader {
+                payload_len: 0,
+                frame_type: parser::FrameType::Settings,
+                flags: parser::FLAG_ACK,
+                stream_id: 0,
+            },
+            payload: None,
+        }
+    }
+
+    pub fn ping_ack(payload: [u8; 8]) -> OutputFrame {
+        OutputFrame {
+            header: parser::FrameHeader {
+                payload_len: 8,
+                frame_type: parser::FrameType::Ping,
+                flags: parser::FLAG_ACK,
+                stream_id: 0,
+            },
+            payload: Some(payload.to_vec()),
+        }
+    }
+
+    pub fn goaway(last_stream_id: u32, error_code: u32) -> OutputFrame {
+        let mut payload = last_stream_id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&error_code.to_be_bytes());
+        OutputFrame {
+            header: parser::FrameHeader {
+                payload_len: 8,
+                frame_type: parser::FrameType::GoAway,
+                flags: 0,
+                stream_id: 0,
+            },
+            payload: Some(payload),
+        }
+    }
+
+    /// Kind and error code of a RST_STREAM or GOAWAY frame, once sent
+    fn sent_error_code(&self) -> Option<(ErrorFrame, u32)> {
+        let (kind, offset) = match self.header.frame_type {
+            parser::FrameType::RstStream => (ErrorFrame::RstStreamSent, 0),
+            parser::FrameType::GoAway => (ErrorFrame::GoAwaySent, 4),
+            _ => return None,
+        };
+        let code = self.payload.as_ref()?.get(offset..offset + 4)?;
+        Some((kind, u32::from_be_bytes(code.try_into().ok()?)))
+    }
+}
+
+/// Frames carrying an error code, counted per code in each direction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorFrame {
+    GoAwaySent,
+    GoAwayReceived,
+    RstStreamSent,
+    RstStreamReceived,
+}
+
+macro_rules! error_code_key {
+    ($prefix:literal, $error_code:expr) => {
+        match $error_code {
+            parser::NO_ERROR => concat!($prefix, ".no_error"),
+            parser::PROTOCOL_ERROR => concat!($prefix, ".protocol_error"),
+            parser::INTERNAL_ERROR => concat!($prefix, ".internal_error"),
+            parser::FLOW_CONTROL_ERROR => concat!($prefix, ".flow_control_error"),
+            parser::SETTINGS_TIMEOUT => concat!($prefix, ".settings_timeout"),
+            parser::STREAM_CLOSED => concat!($prefix, ".stream_closed"),
+            parser::FRAME_SIZE_ERROR => concat!($prefix, ".frame_size_error"),
+            parser::REFUSED_STREAM => concat!($prefix, ".refused_stream"),
+            parser::CANCEL => concat!($prefix, ".cancel"),
+            parser::COMPRESSION_ERROR => concat!($prefix, ".compression_error"),
+            parser::CONNECT_ERROR => concat!($prefix, ".connect_error"),
+            parser::ENHANCE_YOUR_CALM => concat!($prefix, ".enhance_your_calm"),
+            parser::INADEQUATE_SECURITY => concat!($prefix, ".inadequate_security"),
+            parser::HTTP_1_1_REQUIRED => concat!($prefix, ".http_1_1_required"),
+            // codes outside of RFC 9113 carry no special meaning
+            _ => concat!($prefix, ".unknown"),
+        }
+    };
+}
+
+/// Counter of the frames of a kind carrying an error code,
+/// like `http2.goaway.received.protocol_error`
+pub fn error_code_metric(frame: ErrorFrame, error_code: u32) -> &'static str {
+    match frame {
+        ErrorFrame::GoAwaySent => error_code_key!("http2.goaway.sent", error_code),
+        ErrorFrame::GoAwayReceived => error_code_key!("http2.goaway.received", error_code),
+        ErrorFrame::RstStreamSent => error_code_key!("http2.rst_stream.sent", error_code),
+        ErrorFrame::RstStreamReceived => {
+            error_code_key!("http2.rst_stream.received", error_code)
+        }
+    }
+}
+
+/// Settings advertised by the peer, with the default values of RFC 9113 section 6.5.2
+#[derive(Clone, Debug, PartialEq)]
+pub struct H2Settings {
+    pub header_table_size: u32,
+    pub enable_push: bool,
+    pub max_concurrent_streams: u32,
+    pub initial_window_size: u32,
+    pub max_frame_size: u32,
+    /// unlimited by default
+    pub max_header_list_size: u32,
+    /// the peer uses the priority header of RFC 9218 instead of PRIORITY frames
+    pub no_rfc7540_priorities: bool,
+    /// the peer accepts extended CONNECT requests, with a `:protocol` (RFC 8441)
+    pub enable_connect_protocol: bool,
+}
+
+impl Default for H2Settings {
+    fn default() -> Self {
+        H2Settings {
+            header_table_size: 4096,
+            enable_push: true,
+            max_concurrent_streams: u32::MAX,
+            initial_window_size: 65535,
+            max_frame_size: 16384,
+            max_header_list_size: u32::MAX,
+            no_rfc7540_priorities: false,
+            enable_connect_protocol: false,
+        }
+    }
+}
+
+impl H2Settings {
+    pub fn apply(&mut self, settings: &parser::Settings) {
+        for setting in &settings.settings {
+            match setting.identifier {
+                1 => self.header_table_size = setting.value,
+                2 => self.enable_push = setting.value == 1,
+                3 => self.max_concurrent_streams = setting.value,
+                4 => self.initial_window_size = setting.value,
+                5 => self.max_frame_size = setting.value,
+                6 => self.max_header_list_size = setting.value,
+                8 => self.enable_connect_protocol = setting.value == 1,
+                9 => self.no_rfc7540_priorities = setting.value == 1,
+                // unknown settings must be ignored
+                _ => {}
+            }
+        }
+    }
+
+    /// Check the values of a SETTINGS frame received from the peer (RFC 9113
+    /// section 6.5.2), the error code of the connection error if one is invalid
+    pub fn validate(settings: &parser::Settings) -> Result<(), u32> {
+        for setting in &settings.settings {
+            match (setting.identifier, setting.value) {
+                (2, value) if value > 1 => return Err(parser::PROTOCOL_ERROR),
+                (4, value) if value > i32::MAX as u32 => return Err(parser::FLOW_CONTROL_ERROR),
+                (5, value) if !(MIN_MAX_FRAME_SIZE..=MAX_MAX_FRAME_SIZE).contains(&value) => {
+                    return Err(parser::PROTOCOL_ERROR)
+                }
+                (8 | 9, value) if value > 1 => return Err(parser::PROTOCOL_ERROR),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy the settings of `new` that can change on a live connection.
+    ///
+    /// SETTINGS_MAX_CONCURRENT_STREAMS and SETTINGS_MAX_HEADER_LIST_SIZE only
+    /// bound what the peer sends next. The header table size would resize the
+    /// HPACK table of a running decoder, the initial window size would shift the
+    /// windows of the open streams and the frame size is tied to the buffers:
+    /// those only apply to new connections.
+    pub fn with_live_changes(&self, new: &H2Settings) -> H2Settings {
+        H2Settings {
+            max_concurrent_streams: new.max_concurrent_streams,
+            max_header_list_size: new.max_header_list_size,
+            ..self.clone()
+        }
+    }
+
+    /// Settings of `new` that differ from these, as sent in a SETTINGS frame
+    pub fn diff(&self, new: &H2Settings) -> Vec<parser::Setting> {
+        [
+            (1, self.header_table_size, new.header_table_size),
+            (2, self.enable_push as u32, new.enable_push as u32),
+            (3, self.max_concurrent_streams, new.max_concurrent_streams),
+            (4, self.initial_window_size, new.initial_window_size),
+            (5, self.max_frame_size, new.max_frame_size),
+            (6, self.max_header_list_size, new.max_header_list_size),
+            (
+                8,
+                self.enable_connect_protocol as u32,
+                new.enable_connect_protocol as u32,
+            ),
+            (
+                9,
+                self.no_rfc7540_priorities as u32,
+                new.no_rfc7540_priorities as u32,
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, old, new)| old != new)
+        .map(|(identifier, _, value)| parser::Setting { identifier, value })
+        .collect()
+    }
+}
+
+/// Size of a header list as defined for SETTINGS_MAX_HEADER_LIST_SIZE:
+/// the uncompressed length of each name and value, plus 32 bytes per field
+pub fn header_list_size(headers: &[(Vec<u8>, Vec<u8>)]) -> usize {
+    headers
+        .iter()
+        .map(|(name, value)| name.len() + value.len() + 32)
+        .sum()
+}
+
+/// pseudo-header fields in the order they are encoded, before any regular field
+const PSEUDO_HEADER_ORDER: [&[u8]; 6] = [
+    b":status",
+    b":method",
+    b":scheme",
+    b":authority",
+    b":path",
+    b":protocol",
+];
+
+/// Order in which the fields of a header block are encoded: pseudo-header
+/// fields first, in `PSEUDO_HEADER_ORDER`, then the regular fields in their
+/// arrival order. The same headers always give the same block, which keeps
+/// the HPACK dynamic table usage reproducible.
+pub fn header_emission_order(headers: &[(Vec<u8>, Vec<u8>)]) -> Vec<(&[u8], &[u8])> {
+    let mut ordered: Vec<(&[u8], &[u8])> = headers
+        .iter()
+        .map(|(name, value)| (name.as_slice(), value.as_slice()))
+        .collect();
+    // the sort is stable, regular fields keep their relative order
+    ordered.sort_by_key(|(name, _)| {
+        PSEUDO_HEADER_ORDER
+            .iter()
+            .position(|pseudo| pseudo == name)
+            .unwrap_or(PSEUDO_HEADER_ORDER.len())
+    });
+    ordered
+}
+
+/// Encode a header block in the order of `header_emission_order`
+pub fn encode_headers(encoder: &mut Encoder, headers: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+    encoder.encode(header_emission_order(headers))
+}
+
+/// HPACK dynamic table size update (RFC 7541 section 6.3): the `001` pattern
+/// followed by the size, an integer with a 5 bits prefix
+pub fn dynamic_table_size_update(size: u32) -> Vec<u8> {
+    const PREFIX_MAX: u32 = (1 << 5) - 1;
+    if size < PREFIX_MAX {
+        return vec![0x20 | size as u8];
+    }
+    let mut encoded = vec![0x20 | PREFIX_MAX as u8];
+    let mut rest = size - PREFIX_MAX;
+    while rest >= 0x80 {
+        encoded.push(0x80 | (rest & 0x7f) as u8);
+        rest >>= 7;
+    }
+    encoded.push(rest as u8);
+    encoded
+}
+
+/// HPACK integer at the start of `input`, with a prefix of `prefix_bits` bits
+/// (RFC 7541 section 5.1), and the number of bytes it takes. None if it is
+/// truncated or too large for a table size
+fn decode_integer(input: &[u8], prefix_bits: u8) -> Option<(u32, usize)> {
+    let prefix_max = (1u8 << prefix_bits) - 1;
+    let first = input.first()? & prefix_max;
+    if first < prefix_max {
+        return Some((first as u32, 1));
+    }
+    let mut value = prefix_max as u32;
+    for (index, byte) in input[1..].iter().take(4).enumerate() {
+        value = value.checked_add(((byte & 0x7f) as u32) << (7 * index))?;
+        if byte & 0x80 == 0 {
+            return Some((value, index + 2));
+        }
+    }
+    None
+}
+
+/// HPACK decoder of a connection. Each header block received updates its dynamic
+/// table, so they all go through it in the order they arrive, including the
+/// blocks of refused streams and of trailers (RFC 9113 section 4.3)
+pub struct HeaderDecoder {
+    decoder: Decoder<'static>,
+    /// largest dynamic table the client may use, from our SETTINGS_HEADER_TABLE_SIZE
+    pub max_table_size: u32,
+}
+
+impl std::fmt::Debug for HeaderDecoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HeaderDecoder")
+            .field("max_table_size", &self.max_table_size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for HeaderDecoder {
+    fn default() -> Self {
+        HeaderDecoder {
+            decoder: Decoder::new(),
+            max_table_size: H2Settings::default().header_table_size,
+        }
+    }
+}
+
+impl HeaderDecoder {
+    /// Decode a complete header block, calling `field` for each field in order.
+    /// The dynamic table size updates the block starts with may not go over
+    /// `max_table_size` (RFC 7541 section 4.2): an error is a COMPRESSION_ERROR
+    /// of the connection.
+    pub fn decode<F>(&mut self, header_block: &[u8], field: F) -> Result<(), ()>
+    where
+        F: FnMut(Cow<[u8]>, Cow<[u8]>),
+    {
+        let mut rest = header_block;
+        while rest.first().is_some_and(|byte| byte & 0xe0 == 0x20) {
+            match decode_integer(rest, 5) {
+                Some((size, length)) if size <= self.max_table_size => rest = &rest[length..],
+                update => {
+                    error!(
+                        "invalid dynamic table size update {:?}, the limit is {}",
+                        update.map(|(size, _)| size),
+                        self.max_table_size
+                    );
+                    return Err(());
+                }
+            }
+        }
+        self.decoder
+            .decode_with_cb(header_block, field)
+            .map_err(|e| error!("error decoding headers: {:?}", e))
+    }
+}
+
+/// size of a frame header
+pub const FRAME_HEADER_SIZE: usize = 9;
+/// smallest SETTINGS_MAX_FRAME_SIZE allowed by RFC 9113
+pub const MIN_MAX_FRAME_SIZE: u32 = 1 << 14;
+/// largest SETTINGS_MAX_FRAME_SIZE allowed by RFC 9113
+pub const MAX_MAX_FRAME_SIZE: u32 = (1 << 24) - 1;
+
+/// Largest frame we can receive in a buffer of `buffer_size` bytes, within the
+/// bounds of SETTINGS_MAX_FRAME_SIZE. A frame must fit whole in the read buffer
+/// to be parsed, so we never advertise more than that.
+pub fn max_frame_size_for_buffer(buffer_size: usize) -> u32 {
+    let available = buffer_size.saturating_sub(FRAME_HEADER_SIZE);
+    if available < MIN_MAX_FRAME_SIZE as usize {
+        warn!(
+            "buffers of {} bytes cannot hold a frame of {} bytes, HTTP/2 needs a buffer_size of at least {}",
+            buffer_size,
+            MIN_MAX_FRAME_SIZE,
+            MIN_MAX_FRAME_SIZE as usize + FRAME_HEADER_SIZE
+        );
+        return MIN_MAX_FRAME_SIZE;
+    }
+    available.min(MAX_MAX_FRAME_SIZE as usize) as u32
+}
+
+/// initial flow control window of the connection and of the streams (RFC 9113 section 6.9.2)
+pub const DEFAULT_WINDOW_SIZE: u32 = 65535;
+
+/// share of a receive window, in percent, consumed before it is given back
+pub const DEFAULT_WINDOW_UPDATE_THRESHOLD: u32 = 50;
+
+/// Receive window of the connection or of a stream, counted in the lengths
+/// given by `parser::flow_controlled_length`
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecvWindow {
+    /// bytes the peer may still send, negative once it overflowed the window
+    pub available: i64,
+    /// bytes received and not given back with a WINDOW_UPDATE yet
+    pub unacknowledged: u32,
+    size: u32,
+}
+
+impl RecvWindow {
+    pub fn new(size: u32) -> RecvWindow {
+        RecvWindow {
+            available: size as i64,
+            unacknowledged: 0,
+            size,
+        }
+    }
+
+    /// Account for a received frame, false if the peer sent more than the window allows
+    pub fn consume(&mut self, length: u32) -> bool {
+        self.available -= length as i64;
+        self.unacknowledged += length;
+        self.available >= 0
+    }
+
+    /// Increment of the WINDOW_UPDATE to send, once `threshold` percent of the window
+    /// is consumed. Batching the updates avoids a WINDOW_UPDATE per DATA frame.
+    pub fn update(&mut self, threshold: u32) -> Option<u32> {
+        let threshold = (self.size as u64 * threshold.clamp(1, 100) as u64 / 100) as u32;
+        if self.unacknowledged == 0 || self.unacknowledged < threshold {
+            return None;
+        }
+        let increment = std::mem::take(&mut self.unacknowledged);
+        self.available += increment as i64;
+        Some(increment)
+    }
+}
+
+/// Streams opened over SETTINGS_MAX_CONCURRENT_STREAMS, waiting for an active
+/// stream to complete. With a depth of 0 they are refused immediately.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StreamQueue {
+    pub depth: usize,
+    pub timeout: Duration,
+    /// queued streams with the time they were queued, oldest first. The most
+    /// urgent of them starts first, see `stream::Priority`
+    pub streams: VecDeque<(u32, Instant)>,
+}
+
+/// Token bucket bounding the rate at which a client opens streams, whatever their
+/// concurrency. A rate of 0 means no limit.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StreamRateLimit {
+    /// streams per second, also the burst a client may open at once
+    pub rate: u32,
+    tokens: f64,
+    last_refill: Option<Instant>,
+    /// streams refused since the last admitted one
+    pub refused: u32,
+}
+
+impl StreamRateLimit {
+    pub fn new(rate: u32) -> StreamRateLimit {
+        StreamRateLimit {
+            rate,
+            tokens: rate as f64,
+            last_refill: None,
+            refused: 0,
+        }
+    }
+
+    /// Take a token for a new stream, false if the client opens them too fast
+    pub fn allow(&mut self, now: Instant) -> bool {
+        if self.rate == 0 {
+            return true;
+        }
+        if let Some(last_refill) = self.last_refill {
+            let elapsed = now.saturating_duration_since(last_refill).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        }
+        self.last_refill = Some(now);
+        if self.tokens < 1.0 {
+            self.refused += 1;
+            return false;
+        }
+        self.tokens -= 1.0;
+        self.refused = 0;
+        true
+    }
+
+    /// The client kept opening streams while a second worth of them was refused
+    pub fn sustained(&self) -> bool {
+        self.rate != 0 && self.refused >= self.rate
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum FrameResult {
     Close,
     Continue,
     //parameter is the stream id
     ConnectBackend(u32),
+    //parameter is the stream id
+    CancelStream(u32),
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -28,32 +608,95 @@ pub enum St {
     ServerPrefaceSent,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Debug)]
 pub struct State {
     pub output: VecDeque<OutputFrame>,
     pub state: St,
     pub interest: Ready,
-    //FIXME: make it configurable,
+    /// largest frame accepted from the peer, derived from the buffer size
     pub max_frame_size: u32,
+    pub peer_settings: H2Settings,
+    /// our settings, as acknowledged by the peer
+    pub local_settings: H2Settings,
+    /// settings sent to the peer and not acknowledged yet, oldest first
+    pub pending_settings: VecDeque<H2Settings>,
+    /// settings sent in the server preface, configured from the listener
+    pub settings: H2Settings,
+    pub stream_queue: StreamQueue,
+    pub stream_rate_limit: StreamRateLimit,
     pub streams: HashMap<u32, stream::Stream>,
+    /// stream whose header block misses its END_HEADERS flag: until it comes,
+    /// only CONTINUATION frames of this stream are allowed
+    pub continuation_stream: Option<u32>,
+    /// request body bytes the client may still send on the whole connection
+    pub recv_window: RecvWindow,
+    /// share of the receive windows, in percent, consumed before they are given back
+    pub window_update_threshold: u32,
+    /// largest request header list accepted, streams over it are answered with a 431
+    pub max_request_header_size: u32,
+    /// largest response header list accepted from a backend, on top of the client's limit
+    pub max_response_header_size: u32,
+    /// last stream id of the GOAWAY sent, the streams the client opens after it are refused
+    pub goaway_last_stream: Option<u32>,
+    /// set once a GOAWAY announced a graceful shutdown, the streams are still accepted
+    /// until the final GOAWAY gives the last stream id
+    pub goaway_announced: bool,
+    /// requests without a host go to a default cluster instead of being answered with a 400
+    pub accept_requests_without_host: bool,
+    /// the next header block we send starts by emptying the dynamic table of the
+    /// client, see `reset_hpack_context`
+    hpack_reset_pending: bool,
+    /// decoder of the header blocks the client sends, shared by all the streams
+    pub decoder: HeaderDecoder,
+    /// header block of a refused stream received so far, it is decoded once complete
+    /// to keep the dynamic table of `decoder` in sync, then dropped
+    refused_header_block: Option<Vec<u8>>,
 }
 
 impl State {
-    pub fn new() -> State {
+    pub fn new(buffer_size: usize) -> State {
         State {
             output: VecDeque::new(),
             state: St::Init,
             interest: Ready::READABLE | Ready::HUP | Ready::ERROR,
-            max_frame_size: 16384,
+            max_frame_size: max_frame_size_for_buffer(buffer_size),
+            peer_settings: H2Settings::default(),
+            local_settings: H2Settings::default(),
+            pending_settings: VecDeque::new(),
+            // priorities come from the priority header, PRIORITY frames are ignored
+            settings: H2Settings {
+                no_rfc7540_priorities: true,
+                ..H2Settings::default()
+            },
+            stream_queue: StreamQueue::default(),
+            stream_rate_limit: StreamRateLimit::default(),
             streams: HashMap::new(),
+            continuation_stream: None,
+            recv_window: RecvWindow::new(DEFAULT_WINDOW_SIZE),
+            window_update_threshold: DEFAULT_WINDOW_UPDATE_THRESHOLD,
+            max_request_header_size: u32::MAX,
+            max_response_header_size: u32::MAX,
+            goaway_last_stream: None,
+            goaway_announced: false,
+            accept_requests_without_host: false,
+            hpack_reset_pending: false,
+            decoder: HeaderDecoder::default(),
+            refused_header_block: None,
         }
     }
 
-    pub fn parse<'a>(&mut self, mut input: &'a [u8]) -> (usize, Result<parser::Frame<'a>, ()>) {
+    /// Parse the client preface if it is expected, then the next frame. A frame
+    /// not received whole yet gives `Ok(None)`, with the preface consumed if it was
+    /// there: the rest of the frame comes with the next reads.
+    pub fn parse<'a>(
+        &mut self,
+        mut input: &'a [u8],
+    ) -> (usize, Result<Option<parser::Frame<'a>>, ()>) {
         let mut consumed = 0usize;
 
         if self.state == St::Init {
             match parser::preface(input) {
+                Err(nom::Err::Incomplete(_)) => return (0, Ok(None)),
                 Err(e) => {
                     error!("parser::preface error: {:?}", e);
                     return (0, Err(()));
@@ -67,57 +710,215 @@ impl State {
         }
 
         match parser::frame(input, self.max_frame_size) {
+            Err(nom::Err::Incomplete(_)) => (consumed, Ok(None)),
             Err(e) => {
                 error!("parser::frame error: {:?}", e);
-                return (consumed, Err(()));
+                (consumed, Err(()))
             }
             Ok((i, frame)) => {
                 consumed += input.offset(i);
-                (consumed, Ok(frame))
+                (consumed, Ok(Some(frame)))
             }
         }
     }
 
     pub fn handle(&mut self, frame: &parser::Frame) -> FrameResult {
+        match frame {
+            parser::Frame::RstStream(rst) => incr!(error_code_metric(
+                ErrorFrame::RstStreamReceived,
+                rst.error_code
+            )),
+            parser::Frame::GoAway(goaway) => incr!(error_code_metric(
+                ErrorFrame::GoAwayReceived,
+                goaway.error_code
+            )),
+            _ => {}
+        }
+        match (self.continuation_stream, frame) {
+            (Some(expected), parser::Frame::Continuation(c)) if c.stream_id == expected => {
+                if c.end_headers {
+                    self.continuation_stream = None;
+                }
+            }
+            (Some(expected), frame) => {
+                error!(
+                    "expected a CONTINUATION frame for stream {}, got {:?}",
+                    expected, frame
+                );
+                return FrameResult::Close;
+            }
+            (None, parser::Frame::Continuation(c)) => {
+                error!("unexpected CONTINUATION frame for stream {}", c.stream_id);
+                return FrameResult::Close;
+            }
+            (None, parser::Frame::Headers(h)) if !h.end_headers => {
+                self.continuation_stream = Some(h.stream_id);
+            }
+            _ => {}
+        }
+        // frames of unknown types are ignored, outside of a header block
+        // (RFC 9113 sections 4.1 and 5.5)
+        if let parser::Frame::Unknown(unknown) = frame {
+            debug!(
+                "ignoring a frame of unknown type {} of {} bytes",
+                unknown.frame_type,
+                unknown.payload.len()
+            );
+            return FrameResult::Continue;
+        }
+        // priorities come from the priority header, PRIORITY frames are ignored
+        // whatever stream they name (RFC 9113 section 5.3.2)
+        if let parser::Frame::Priority(priority) = frame {
+            debug!("ignoring a PRIORITY frame: {:?}", priority);
+            return FrameResult::Continue;
+        }
+
+        // the rest of a header block dropped with its stream
+        if self.refused_header_block.is_some() && matches!(frame, parser::Frame::Continuation(_)) {
+            return self.drop_header_block(frame);
+        }
+
         let stream_id = frame.stream_id();
         if stream_id != 0 {
-            return self.stream_handle(stream_id, frame);
+            if let parser::Frame::Data(data) = frame {
+                return self.handle_data(data, frame);
+            }
+            if let parser::Frame::WindowUpdate(update) = frame {
+                return self.handle_window_update(update);
+            }
+            // streams opened after our GOAWAY are refused, the client may retry
+            // them on another connection (RFC 9113 section 6.8)
+            if self.goaway_last_stream.is_some_and(|last| stream_id > last)
+                && !self.streams.contains_key(&stream_id)
+            {
+                if matches!(frame, parser::Frame::Headers(_)) {
+                    self.reset_stream(stream_id, parser::REFUSED_STREAM);
+                }
+                return self.drop_header_block(frame);
+            }
+            if !self.streams.contains_key(&stream_id) {
+                match frame {
+                    parser::Frame::Headers(_) => match self.check_stream_rate(stream_id) {
+                        Some(FrameResult::Continue) => return self.drop_header_block(frame),
+                        Some(result) => return result,
+                        None => {}
+                    },
+                    // the rest of the header block of a refused stream
+                    parser::Frame::Continuation(_) => return self.drop_header_block(frame),
+                    _ => {}
+                }
+            }
+            if let Some(result) = self.check_remote_closed(stream_id, frame) {
+                return result;
+            }
+            return match self.stream_handle(stream_id, frame) {
+                FrameResult::ConnectBackend(stream_id)
+                    if self.check_request_headers(stream_id)
+                        && self.check_request_host(stream_id) =>
+                {
+                    self.admit_stream(stream_id)
+                }
+                FrameResult::ConnectBackend(_) => FrameResult::Continue,
+                result => result,
+            };
+        }
+        // the connection itself carries no request (RFC 9113 section 6.1 and 6.2)
+        if matches!(
+            frame,
+            parser::Frame::Data(_) | parser::Frame::Headers(_) | parser::Frame::Continuation(_)
+        ) {
+            error!("request frame on stream 0: {:?}", frame);
+            return FrameResult::Close;
         }
 
         match self.state {
             St::Init => FrameResult::Continue,
-            St::ClientPrefaceReceived => {
-                match frame {
-                    parser::Frame::Settings(s) => {
-                        let server_settings = OutputFrame {
-                            header: parser::FrameHeader {
-                                payload_len: 0,
-                                frame_type: parser::FrameType::Settings,
-                                //FIXME: setting 1 for ACK?
-                                flags: 1,
-                                stream_id: 0,
-                            },
-                            payload: None,
-                        };
-
-                        self.output.push_back(server_settings);
-                        self.state = St::ServerPrefaceSent;
-                        self.interest.insert(Ready::WRITABLE);
-                        FrameResult::Continue
-                    }
-                    f => {
-                        unimplemented!("invalid frame: {:?}, should send back an error", f);
-                    }
+            St::ClientPrefaceReceived => match frame {
+                parser::Frame::Settings(s) if !s.ack => {
+                    // the server preface comes before the acknowledgement
+                    self.send_settings(self.settings.clone());
+                    self.state = St::ServerPrefaceSent;
+                    self.apply_peer_settings(s)
                 }
-            }
+                frame => {
+                    error!(
+                        "the client preface must end with a SETTINGS frame, got {:?}",
+                        frame
+                    );
+                    FrameResult::Close
+                }
+            },
             St::ServerPrefaceSent => match frame {
+                // our settings only apply once acknowledged, until then the
+                // values the peer knows about still hold
+                parser::Frame::Settings(s) if s.ack => {
+                    match self.pending_settings.pop_front() {
+                        Some(settings) => self.local_settings = settings,
+                        None => debug!("SETTINGS ACK without pending settings"),
+                    }
+                    self.update_decoder_limit();
+                    FrameResult::Continue
+                }
+                parser::Frame::Settings(s) => self.apply_peer_settings(s),
+                parser::Frame::WindowUpdate(update) => self.handle_window_update(update),
+                // the client opens no more streams, the started ones may finish
+                parser::Frame::GoAway(goaway) if goaway.error_code == parser::NO_ERROR => {
+                    info!(
+                        "GOAWAY from the client, last stream {}",
+                        goaway.last_stream_id
+                    );
+                    FrameResult::Continue
+                }
+                parser::Frame::GoAway(goaway) => {
+                    error!(
+                        "GOAWAY from the client with error code {}",
+                        goaway.error_code
+                    );
+                    FrameResult::Close
+                }
+                // the acknowledgement carries the same payload (RFC 9113 section 6.7)
+                parser::Frame::Ping(ping) if !ping.ack => {
+                    self.output.push_back(OutputFrame::ping_ack(ping.payload));
+                    self.interest.insert(Ready::WRITABLE);
+                    FrameResult::Continue
+                }
+                // we send no PING, an acknowledgement answers nothing
+                parser::Frame::Ping(_) => FrameResult::Continue,
                 frame => {
-                    panic!("unknown frame for now: {:?}", frame);
+                    error!("unexpected frame on stream 0: {:?}", frame);
+                    self.goaway(parser::PROTOCOL_ERROR);
+                    FrameResult::Close
                 }
             },
         }
     }
 
+    /// Apply the settings of the peer before handling any later frame, then
+    /// acknowledge them. Invalid values are a connection error.
+    fn apply_peer_settings(&mut self, settings: &parser::Settings) -> FrameResult {
+        if let Err(error_code) = H2Settings::validate(settings) {
+            error!("invalid SETTINGS from the peer: {:?}", settings.settings);
+            self.goaway(error_code);
+            return FrameResult::Close;
+        }
+        self.peer_settings.apply(settings);
+        self.output.push_back(OutputFrame::settings_ack());
+        self.interest.insert(Ready::WRITABLE);
+        FrameResult::Continue
+    }
+
+    /// Whether `input` starts with a whole frame, that can be handled without reading
+    /// more. An oversized frame counts: it is refused without waiting for its payload.
+    pub fn has_complete_frame(&self, input: &[u8]) -> bool {
+        match parser::frame_header(input) {
+            Ok((i, header)) => {
+                header.payload_len > self.max_frame_size
+                    || input.len() >= input.offset(i) + header.payload_len as usize
+            }
+            Err(_) => false,
+        }
+    }
+
     pub fn parse_and_handle<'a>(&mut self, mut input: &'a [u8]) -> (usize, FrameResult) {
         let (sz, res) = self.parse(input);
         match res {
@@ -125,20 +926,52 @@ impl State {
                 error!("error parsing frame: {:?}", e);
                 (sz, FrameResult::Close)
             }
-            Ok(frame) => {
+            Ok(None) => (sz, FrameResult::Continue),
+            Ok(Some(frame)) => {
                 info!("parsed frame: {:?}", frame);
                 (sz, self.handle(&frame))
             }
         }
     }
 
+    /// Serialized length of the next frame to send, header included
+    pub fn next_frame_len(&self) -> Option<usize> {
+        self.output
+            .front()
+            .map(|frame| FRAME_HEADER_SIZE + frame.payload.as_ref().map_or(0, Vec::len))
+    }
+
     pub fn gen(&mut self, mut output: &mut [u8]) -> Result<usize, ()> {
         if let Some(frame) = self.output.pop_front() {
+            if let Some((kind, error_code)) = frame.sent_error_code() {
+                incr!(error_code_metric(kind, error_code));
+            }
+            if frame.header.frame_type == parser::FrameType::Data {
+                if let Some(stream) = self.streams.get_mut(&frame.header.stream_id) {
+                    stream.data_frame_written(Instant::now());
+                }
+            }
+            // frames are split on the frame size, a buffer too small for one is an error
             match serializer::gen_frame_header((output, 0), &frame.header) {
                 Err(e) => {
-                    panic!("error serializing: {:?}", e);
+                    error!("error serializing frame header: {:?}", e);
+                    Err(())
                 }
-                Ok((sl, index)) => Ok(index),
+                Ok((sl, index)) => match frame.payload {
+                    Some(payload) if sl.len() < payload.len() => {
+                        error!(
+                            "a frame payload of {} bytes does not fit in {} bytes",
+                            payload.len(),
+                            sl.len()
+                        );
+                        Err(())
+                    }
+                    Some(payload) => {
+                        sl[..payload.len()].copy_from_slice(&payload);
+                        Ok(index + payload.len())
+                    }
+                    None => Ok(index),
+                },
             }
         } else {
             self.interest.remove(Ready::WRITABLE);
@@ -146,12 +979,2835 @@ impl State {
         }
     }
 
-    pub fn stream_handle(&mut self, stream_id: u32, frame: &parser::Frame) -> FrameResult {
-        assert!(stream_id != 0);
+    /// Check response headers coming from a backend against the limit advertised
+    /// by the client and the one of the listener. If they do not fit, the stream is
+    /// closed: the caller should reset the backend stream (see `OutputFrame::rst_stream`)
+    /// and answer with a 502 instead of forwarding them. Otherwise its response starts.
+    pub fn check_response_headers(
+        &mut self,
+        stream_id: u32,
+        headers: &[(Vec<u8>, Vec<u8>)],
+    ) -> bool {
+        let size = header_list_size(headers);
+        let limit = self
+            .peer_settings
+            .max_header_list_size
+            .min(self.max_response_header_size);
+        if size <= limit as usize {
+            if let Some(stream) = self.streams.get_mut(&stream_id) {
+                stream.set_phase(stream::StreamPhase::StreamingResponse);
+            }
+            return true;
+        }
+
+        error!(
+            "stream[{}] response headers of {} bytes exceed the limit of {}",
+            stream_id, size, limit
+        );
+        incr!("http2.response_headers_too_large");
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.close();
+        }
+        false
+    }
+
+    /// Check the request headers of a stream against the limit of the listener.
+    /// A stream over it is answered with a 431 and never reaches a backend.
+    fn check_request_headers(&mut self, stream_id: u32) -> bool {
+        let size = self.streams.get(&stream_id).map_or(0, |stream| {
+            stream
+                .request_header_size
+                .max(header_list_size(&stream.request_headers))
+        });
+        if size <= self.max_request_header_size as usize {
+            return true;
+        }
+
+        error!(
+            "stream[{}] request headers of {} bytes exceed the limit of {}",
+            stream_id, size, self.max_request_header_size
+        );
+        incr!("http2.request_headers_too_large");
+        self.answer(stream_id, 431);
+        false
+    }
+
+    /// Check that a request names a host, in `:authority` or `Host`, to be routed on.
+    /// Without one, it is answered with a 400 unless requests without a host are accepted
+    fn check_request_host(&mut self, stream_id: u32) -> bool {
+        let has_host = self
+            .streams
+            .get(&stream_id)
+            .is_some_and(|stream| stream.authority().is_some());
+        if has_host {
+            return true;
+        }
+        if self.accept_requests_without_host {
+            incr!("http.requests_without_host.routed");
+            return true;
+        }
+
+        error!("stream[{}] request without a host", stream_id);
+        incr!("http.requests_without_host.rejected");
+        self.answer(stream_id, 400);
+        false
+    }
+
+    /// Answer a stream with a bodiless response. The stream is closed, or half-closed
+    /// (local) if the client has not finished sending the request
+    pub fn answer(&mut self, stream_id: u32, status: u16) {
+        self.answer_with_headers(stream_id, status, &[]);
+    }
+
+    /// Same as `answer`, with header fields after the status. Their names must be in
+    /// the static table, like "retry-after", for the same reason
+    pub fn answer_with_headers(&mut self, stream_id: u32, status: u16, headers: &[(&[u8], &[u8])]) {
+        // ":status" is in the static table, so this encoding does not touch the
+        // dynamic table and a fresh encoder does not desynchronize the peer
+        let status = status.to_string();
+        let fields = std::iter::once((&b":status"[..], status.as_bytes()));
+        let mut header_block = self.take_hpack_reset();
+        header_block.extend(Encoder::new().encode(fields.chain(headers.iter().copied())));
+        self.queue_header_block(stream_id, header_block, true);
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.end_response();
+        }
+        self.interest.insert(Ready::WRITABLE);
+    }
+
+    /// Queue the response headers of a stream, once `check_response_headers` accepted
+    /// them. With `end_stream`, the response has neither body nor trailers. False if
+    /// the stream can not send or its response headers were already sent
+    pub fn send_response_headers(
+        &mut self,
+        stream_id: u32,
+        headers: &[(Vec<u8>, Vec<u8>)],
+        end_stream: bool,
+    ) -> bool {
+        let can_send = self.streams.get(&stream_id).is_some_and(|stream| {
+            matches!(
+                stream.state,
+                stream::StreamState::Open | stream::StreamState::HalfClosedRemote
+            ) && stream.phase == stream::StreamPhase::StreamingResponse
+                && !stream.response_headers_sent
+        });
+        if !can_send {
+            return false;
+        }
+        // a fresh encoder never refers to the dynamic table of the client, see `answer`
+        let mut header_block = self.take_hpack_reset();
+        header_block.extend(encode_headers(&mut Encoder::new(), headers));
+        self.queue_header_block(stream_id, header_block, end_stream);
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.response_headers_sent = true;
+            if end_stream {
+                stream.end_response();
+            }
+        }
+        self.interest.insert(Ready::WRITABLE);
+        true
+    }
+
+    /// End the response of a stream with the trailers of its HTTP/1.1 response, in
+    /// a HEADERS frame carrying END_STREAM. They may follow the response headers
+    /// right away, without any DATA, like the trailers-only responses of gRPC. If the
+    /// request did not accept trailers or none is left once translated, an empty DATA
+    /// frame ends the response instead. False if the stream can not send or its
+    /// response headers were not sent: these trailers would be taken for them
+    pub fn send_response_trailers(
+        &mut self,
+        stream_id: u32,
+        trailers: &[(Vec<u8>, Vec<u8>)],
+    ) -> bool {
+        let Some(stream) = self.streams.get(&stream_id) else {
+            return false;
+        };
+        if !stream.response_headers_sent {
+            return false;
+        }
+        match h1_to_h2_trailers(trailers, stream.accepts_trailers) {
+            None => self.send_response_data(stream_id, &[], true),
+            Some(trailers) => {
+                let Some(stream) = self.streams.get_mut(&stream_id) else {
+                    return false;
+                };
+                let can_send = matches!(
+                    stream.state,
+                    stream::StreamState::Open | stream::StreamState::HalfClosedRemote
+                );
+                if !can_send || stream.phase != stream::StreamPhase::StreamingResponse {
+                    return false;
+                }
+                let mut header_block = self.take_hpack_reset();
+                header_block.extend(encode_headers(&mut Encoder::new(), &trailers));
+                self.queue_header_block(stream_id, header_block, true);
+                if let Some(stream) = self.streams.get_mut(&stream_id) {
+                    stream.end_response();
+                }
+                self.interest.insert(Ready::WRITABLE);
+                true
+            }
+        }
+    }
+
+    /// Largest frame payload we send: the SETTINGS_MAX_FRAME_SIZE of the client,
+    /// within what our buffers hold
+    fn max_payload(&self) -> usize {
+        (self.peer_settings.max_frame_size as usize).min(self.max_frame_size as usize)
+    }
+
+    /// Queue a header block in a HEADERS frame, followed by CONTINUATION frames if
+    /// it does not fit in one. They are queued together, as no other frame may
+    /// come between them (RFC 9113 section 6.10)
+    fn queue_header_block(&mut self, stream_id: u32, header_block: Vec<u8>, end_stream: bool) {
+        let max_payload = self.max_payload();
+        if header_block.len() <= max_payload {
+            self.output
+                .push_back(OutputFrame::headers(stream_id, header_block, end_stream));
+            return;
+        }
+        let mut fragments = header_block.chunks(max_payload).peekable();
+        if let Some(first) = fragments.next() {
+            self.output.push_back(OutputFrame::headers_fragment(
+                stream_id,
+                first.to_vec(),
+                end_stream,
+            ));
+        }
+        while let Some(fragment) = fragments.next() {
+            let last = fragments.peek().is_none();
+            self.output.push_back(OutputFrame::continuation(
+                stream_id,
+                fragment.to_vec(),
+                last,
+            ));
+        }
+    }
+
+    /// Queue a part of the response body of a stream whose response started, while
+    /// its request may still be arriving: both directions of a stream are independent,
+    /// the client keeps sending the request body as long as the response is not complete.
+    /// With `end_stream`, the response is complete. False if the stream can not send
+    pub fn send_response_data(&mut self, stream_id: u32, payload: &[u8], end_stream: bool) -> bool {
+        // a DATA frame carries at most the SETTINGS_MAX_FRAME_SIZE of the client,
+        // and the END_STREAM flag goes on the last one
+        let max_payload = self.max_payload();
+        let Some(stream) = self.streams.get_mut(&stream_id) else {
+            return false;
+        };
+        let can_send = matches!(
+            stream.state,
+            stream::StreamState::Open | stream::StreamState::HalfClosedRemote
+        );
+        if !can_send || stream.phase != stream::StreamPhase::StreamingResponse {
+            return false;
+        }
+        let now = Instant::now();
+        let mut chunks = payload.chunks(max_payload).peekable();
+        if chunks.peek().is_none() {
+            self.output
+                .push_back(OutputFrame::data(stream_id, Vec::new(), end_stream));
+            stream.data_frame_queued(now);
+        }
+        while let Some(chunk) = chunks.next() {
+            let last = chunks.peek().is_none();
+            self.output.push_back(OutputFrame::data(
+                stream_id,
+                chunk.to_vec(),
+                end_stream && last,
+            ));
+            stream.data_frame_queued(now);
+        }
+        if end_stream {
+            stream.end_response();
+        }
+        self.interest.insert(Ready::WRITABLE);
+        true
+    }
 
+    /// Streams having response DATA waiting to be written to the client
+    pub fn streams_with_pending_data(&self) -> usize {
         self.streams
-            .entry(stream_id)
-            .or_insert(stream::Stream::new(stream_id))
-            .handle(frame)
+            .values()
+            .filter(|stream| stream.pending_data_frames > 0)
+            .count()
+    }
+
+    /// Streams whose response DATA waited at least `grace` without the client
+    /// reading any of it: the client opened them but does not consume them
+    pub fn stalled_streams(&self, now: Instant, grace: Duration) -> Vec<u32> {
+        let mut stalled: Vec<u32> = self
+            .streams
+            .values()
+            .filter(|stream| {
+                stream
+                    .send_stalled_since
+                    .is_some_and(|since| now.saturating_duration_since(since) >= grace)
+            })
+            .map(|stream| stream.id)
+            .collect();
+        stalled.sort_unstable();
+        stalled
+    }
+
+    /// Reset a stream the client does not read, dropping its queued response DATA
+    /// so it stops holding memory and delaying the other streams
+    pub fn reset_slow_stream(&mut self, stream_id: u32) {
+        self.output.retain(|frame| {
+            frame.header.stream_id != stream_id
+                || frame.header.frame_type != parser::FrameType::Data
+        });
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.pending_data_frames = 0;
+            stream.send_stalled_since = None;
+        }
+        self.reset_stream(stream_id, parser::ENHANCE_YOUR_CALM);
+    }
+
+    /// Reset a stream, closing it without affecting the rest of the connection
+    pub fn reset_stream(&mut self, stream_id: u32, error_code: u32) {
+        self.output
+            .push_back(OutputFrame::rst_stream(stream_id, error_code));
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.close();
+            stream.release_request();
+        }
+        self.interest.insert(Ready::WRITABLE);
+    }
+
+    /// Send our SETTINGS as the server preface, listing the values that differ
+    /// from the defaults of RFC 9113. They take effect in `local_settings` once
+    /// the peer acknowledges them.
+    fn send_settings(&mut self, settings: H2Settings) {
+        let changes = H2Settings::default().diff(&settings);
+        self.output.push_back(OutputFrame::settings(&changes));
+        self.pending_settings.push_back(settings);
+        self.update_decoder_limit();
+        self.interest.insert(Ready::WRITABLE);
+    }
+
+    /// The client may use the largest header table size we sent, acknowledged or
+    /// not yet: until the acknowledgement, it does not know about the new one
+    fn update_decoder_limit(&mut self) {
+        self.decoder.max_table_size = self
+            .pending_settings
+            .iter()
+            .map(|settings| settings.header_table_size)
+            .fold(self.local_settings.header_table_size, u32::max);
+    }
+
+    /// Our SETTINGS were sent and are not acknowledged yet. Frames keep flowing
+    /// meanwhile, under the settings the peer already knows
+    pub fn awaiting_settings_ack(&self) -> bool {
+        !self.pending_settings.is_empty()
+    }
+
+    /// Empty the HPACK dynamic tables of both directions. The next header block we
+    /// send starts with a dynamic table size update to 0, then another one back to
+    /// the size the client allows (RFC 7541 section 4.2), which empties the table
+    /// of the client. Our decoder is emptied the same way by the client: we send
+    /// SETTINGS_HEADER_TABLE_SIZE at 0 then back to its value, and once both are
+    /// acknowledged its next header block starts with the matching updates.
+    pub fn reset_hpack_context(&mut self) {
+        self.hpack_reset_pending = true;
+        // before the server preface, no header block was decoded
+        if self.state != St::ServerPrefaceSent {
+            return;
+        }
+        let current = self
+            .pending_settings
+            .back()
+            .unwrap_or(&self.local_settings)
+            .clone();
+        if current.header_table_size == 0 {
+            return;
+        }
+        let emptied = H2Settings {
+            header_table_size: 0,
+            ..current.clone()
+        };
+        self.output
+            .push_back(OutputFrame::settings(&current.diff(&emptied)));
+        self.output
+            .push_back(OutputFrame::settings(&emptied.diff(&current)));
+        self.pending_settings.push_back(emptied);
+        self.pending_settings.push_back(current);
+        self.update_decoder_limit();
+        self.interest.insert(Ready::WRITABLE);
+    }
+
+    /// Dynamic table size updates starting the next header block, if a reset is pending
+    fn take_hpack_reset(&mut self) -> Vec<u8> {
+        if !std::mem::take(&mut self.hpack_reset_pending) {
+            return Vec::new();
+        }
+        let mut updates = dynamic_table_size_update(0);
+        updates.extend(dynamic_table_size_update(
+            self.peer_settings.header_table_size,
+        ));
+        updates
+    }
+
+    /// Send new settings to the peer on a live connection, without dropping it.
+    /// Only the settings allowed by `H2Settings::with_live_changes` are sent, they
+    /// take effect in `local_settings` once the peer acknowledges them. Returns
+    /// false if nothing was sent.
+    pub fn update_settings(&mut self, settings: &H2Settings) -> bool {
+        if self.state != St::ServerPrefaceSent {
+            return false;
+        }
+        let current = self.pending_settings.back().unwrap_or(&self.local_settings);
+        let updated = current.with_live_changes(settings);
+        let changes = current.diff(&updated);
+        if changes.is_empty() {
+            return false;
+        }
+        self.output.push_back(OutputFrame::settings(&changes));
+        self.pending_settings.push_back(updated);
+        self.interest.insert(Ready::WRITABLE);
+        true
+    }
+
+    /// Queue a GOAWAY for the connection, unless the preface exchange did not happen
+    /// or one was already sent. An error still follows a graceful GOAWAY, to give
+    /// its code to the client
+    pub fn goaway(&mut self, error_code: u32) -> bool {
+        if self.state != St::ServerPrefaceSent
+            || (self.goaway_last_stream.is_some() && error_code == parser::NO_ERROR)
+        {
+            return false;
+        }
+        let last_stream_id = self.streams.keys().max().copied().unwrap_or(0);
+        self.goaway_last_stream = Some(last_stream_id);
+        self.output
+            .push_back(OutputFrame::goaway(last_stream_id, error_code));
+        self.interest.insert(Ready::WRITABLE);
+        true
+    }
+
+    /// First GOAWAY of a graceful shutdown, with the largest stream id: the client
+    /// stops opening streams, and the ones already in flight are still accepted.
+    /// The final GOAWAY, sent after a drain period with `goaway`, gives the last
+    /// stream id (RFC 9113 section 6.8)
+    pub fn announce_goaway(&mut self) -> bool {
+        if self.state != St::ServerPrefaceSent
+            || self.goaway_announced
+            || self.goaway_last_stream.is_some()
+        {
+            return false;
+        }
+        self.goaway_announced = true;
+        self.output
+            .push_back(OutputFrame::goaway(parser::MAX_STREAM_ID, parser::NO_ERROR));
+        self.interest.insert(Ready::WRITABLE);
+        true
+    }
+
+    /// Whether a graceful shutdown was announced and its final GOAWAY is not sent yet
+    pub fn is_draining(&self) -> bool {
+        self.goaway_announced && self.goaway_last_stream.is_none()
+    }
+
+    /// Start closing the connection on demand, with a GOAWAY carrying `error_code`.
+    /// The streams already opened may finish if `graceful`, else they are reset.
+    /// A graceful shutdown without error is only announced, see `announce_goaway`.
+    /// False if the connection is not established or already going away.
+    pub fn shutdown(&mut self, error_code: u32, graceful: bool) -> bool {
+        if graceful && error_code == parser::NO_ERROR {
+            return self.announce_goaway();
+        }
+        if !self.goaway(error_code) {
+            return false;
+        }
+        if !graceful {
+            let open: Vec<u32> = self
+                .streams
+                .iter()
+                .filter(|(_, stream)| stream.state != stream::StreamState::Closed)
+                .map(|(id, _)| *id)
+                .collect();
+            for stream_id in open {
+                self.reset_stream(stream_id, parser::CANCEL);
+            }
+        }
+        true
+    }
+
+    /// Check a DATA frame against the connection and stream windows before handing
+    /// it to its stream, then give the window back to the client
+    fn handle_data(&mut self, data: &parser::Data, frame: &parser::Frame) -> FrameResult {
+        let length = data.flow_controlled_length;
+        if !self.recv_window.consume(length) {
+            error!(
+                "DATA frame of {} bytes overflows the connection window",
+                length
+            );
+            self.goaway(parser::FLOW_CONTROL_ERROR);
+            return FrameResult::Close;
+        }
+        // the refused frame still counted against the connection window
+        if let Some(result) = self.check_remote_closed(data.stream_id, frame) {
+            return result;
+        }
+        if let Some(stream) = self.streams.get_mut(&data.stream_id) {
+            if !stream.recv_window.consume(length) {
+                error!(
+                    "stream[{}] DATA frame of {} bytes overflows the stream window",
+                    data.stream_id, length
+                );
+                self.reset_stream(data.stream_id, parser::FLOW_CONTROL_ERROR);
+                self.queue_window_updates(data.stream_id);
+                return FrameResult::CancelStream(data.stream_id);
+            }
+        }
+
+        let result = self.stream_handle(data.stream_id, frame);
+        self.queue_window_updates(data.stream_id);
+        result
+    }
+
+    /// A zero increment is a PROTOCOL_ERROR, of the connection on stream 0 and of the
+    /// stream otherwise (RFC 9113 section 6.9). Responses are not limited by the
+    /// send windows yet, so valid increments are accepted without being tracked
+    fn handle_window_update(&mut self, update: &parser::WindowUpdate) -> FrameResult {
+        if update.increment != 0 {
+            return FrameResult::Continue;
+        }
+        if update.stream_id == 0 {
+            error!("WINDOW_UPDATE with a zero increment for the connection");
+            self.goaway(parser::PROTOCOL_ERROR);
+            return FrameResult::Close;
+        }
+        error!(
+            "stream[{}] WINDOW_UPDATE with a zero increment",
+            update.stream_id
+        );
+        if !self.streams.contains_key(&update.stream_id) {
+            return FrameResult::Continue;
+        }
+        self.reset_stream(update.stream_id, parser::PROTOCOL_ERROR);
+        FrameResult::CancelStream(update.stream_id)
+    }
+
+    /// The client already ended the request of a half-closed (remote) stream:
+    /// more DATA or HEADERS would be appended to a complete request, so the stream
+    /// is reset with STREAM_CLOSED (RFC 9113 section 5.1)
+    fn check_remote_closed(
+        &mut self,
+        stream_id: u32,
+        frame: &parser::Frame,
+    ) -> Option<FrameResult> {
+        let half_closed = self
+            .streams
+            .get(&stream_id)
+            .is_some_and(|stream| stream.state == stream::StreamState::HalfClosedRemote);
+        if !half_closed
+            || !matches!(
+                frame,
+                parser::Frame::Data(_) | parser::Frame::Headers(_) | parser::Frame::Continuation(_)
+            )
+        {
+            return None;
+        }
+        error!(
+            "stream[{}] received {:?} after END_STREAM",
+            stream_id, frame
+        );
+        incr!("http2.frames_after_end_stream");
+        self.reset_stream(stream_id, parser::STREAM_CLOSED);
+        match self.drop_header_block(frame) {
+            FrameResult::Continue => Some(FrameResult::CancelStream(stream_id)),
+            result => Some(result),
+        }
+    }
+
+    /// Streams started and not complete yet, the queued ones excluded
+    pub fn active_streams(&self) -> usize {
+        let open = self
+            .streams
+            .values()
+            .filter(|stream| {
+                matches!(
+                    stream.state,
+                    stream::StreamState::Open
+                        | stream::StreamState::HalfClosedLocal
+                        | stream::StreamState::HalfClosedRemote
+                )
+            })
+            .count();
+        open.saturating_sub(self.stream_queue.streams.len())
+    }
+
+    /// A client opening streams faster than the rate limit gets them refused with
+    /// REFUSED_STREAM. If it keeps doing so, the connection is closed with
+    /// GOAWAY(ENHANCE_YOUR_CALM)
+    fn check_stream_rate(&mut self, stream_id: u32) -> Option<FrameResult> {
+        if self.stream_rate_limit.allow(Instant::now()) {
+            return None;
+        }
+        incr!("http2.streams.rate_limited");
+        if self.stream_rate_limit.sustained() {
+            error!(
+                "{} streams refused in a row over the rate limit, closing the connection",
+                self.stream_rate_limit.refused
+            );
+            self.goaway(parser::ENHANCE_YOUR_CALM);
+            return Some(FrameResult::Close);
+        }
+        self.reset_stream(stream_id, parser::REFUSED_STREAM);
+        Some(FrameResult::Continue)
+    }
+
+    /// A stream completed its request headers: it starts if the connection is
+    /// under its concurrency limit, waits in the stream queue if there is room,
+    /// or is refused
+    fn admit_stream(&mut self, stream_id: u32) -> FrameResult {
+        if self.active_streams() <= self.local_settings.max_concurrent_streams as usize {
+            return FrameResult::ConnectBackend(stream_id);
+        }
+        if self.stream_queue.streams.len() < self.stream_queue.depth {
+            incr!("http2.streams.queued");
+            self.stream_queue
+                .streams
+                .push_back((stream_id, Instant::now()));
+            return FrameResult::Continue;
+        }
+        incr!("http2.streams.refused");
+        self.reset_stream(stream_id, parser::REFUSED_STREAM);
+        FrameResult::Continue
+    }
+
+    /// Next queued stream that can start, now that active streams may have
+    /// completed. The streams that waited too long are refused.
+    pub fn next_queued_stream(&mut self, now: Instant) -> Option<u32> {
+        let streams = &self.streams;
+        let timeout = self.stream_queue.timeout;
+        let mut expired = Vec::new();
+        self.stream_queue.streams.retain(|&(stream_id, queued_at)| {
+            let closed = streams
+                .get(&stream_id)
+                .map_or(true, |stream| stream.state == stream::StreamState::Closed);
+            // a closed stream was canceled by the client while waiting
+            if !closed && now.duration_since(queued_at) >= timeout {
+                expired.push(stream_id);
+                return false;
+            }
+            !closed
+        });
+        for stream_id in expired {
+            incr!("http2.streams.refused");
+            self.reset_stream(stream_id, parser::REFUSED_STREAM);
+        }
+        if self.active_streams() >= self.local_settings.max_concurrent_streams as usize {
+            return None;
+        }
+        // the most urgent stream, the oldest among equally urgent ones
+        let index = self
+            .stream_queue
+            .streams
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, (stream_id, _))| {
+                self.streams
+                    .get(stream_id)
+                    .map_or(stream::Priority::default().urgency, |stream| {
+                        stream.priority.urgency
+                    })
+            })
+            .map(|(index, _)| index)?;
+        self.stream_queue
+            .streams
+            .remove(index)
+            .map(|(stream_id, _)| stream_id)
+    }
+
+    /// Queue the WINDOW_UPDATE frames of the connection and of a stream, if any is due
+    fn queue_window_updates(&mut self, stream_id: u32) {
+        let threshold = self.window_update_threshold;
+        if let Some(increment) = self.recv_window.update(threshold) {
+            self.output
+                .push_back(OutputFrame::window_update(0, increment));
+            self.interest.insert(Ready::WRITABLE);
+        }
+        let stream_update = self
+            .streams
+            .get_mut(&stream_id)
+            .filter(|stream| stream.state != stream::StreamState::Closed)
+            .and_then(|stream| stream.recv_window.update(threshold));
+        if let Some(increment) = stream_update {
+            self.output
+                .push_back(OutputFrame::window_update(stream_id, increment));
+            self.interest.insert(Ready::WRITABLE);
+        }
+    }
+
+    /// Decode the header block of a refused stream once complete, without keeping
+    /// its fields: the fields it indexes are referenced by the next blocks
+    fn drop_header_block(&mut self, frame: &parser::Frame) -> FrameResult {
+        let (fragment, end_headers) = match frame {
+            parser::Frame::Headers(h) => (h.header_block_fragment, h.end_headers),
+            parser::Frame::Continuation(c) => (c.header_block_fragment, c.end_headers),
+            _ => return FrameResult::Continue,
+        };
+        let block = self.refused_header_block.get_or_insert_with(Vec::new);
+        block.extend_from_slice(fragment);
+        if !end_headers {
+            return FrameResult::Continue;
+        }
+        let block = self.refused_header_block.take().unwrap_or_default();
+        match self.decoder.decode(&block, |_, _| {}) {
+            Ok(()) => FrameResult::Continue,
+            Err(()) => FrameResult::Close,
+        }
+    }
+
+    pub fn stream_handle(&mut self, stream_id: u32, frame: &parser::Frame) -> FrameResult {
+        assert!(stream_id != 0);
+
+        let max_request_header_size = self.max_request_header_size as usize;
+        let stream = self.streams.entry(stream_id).or_insert_with(|| {
+            let mut stream = stream::Stream::new(stream_id);
+            stream.max_request_header_size = max_request_header_size;
+            stream
+        });
+        let result = stream.handle(frame, &mut self.decoder);
+        // a stream resetting itself, on a malformed request, queues its RST_STREAM
+        if stream.state == stream::StreamState::Closed && !stream.response_frames.is_empty() {
+            self.output.extend(stream.response_frames.drain(..));
+            self.interest.insert(Ready::WRITABLE);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response_headers_are_checked_against_the_client_settings() {
+        let mut state = State::new(16393);
+        let headers = vec![(b"x-large".to_vec(), vec![b'a'; 100])];
+        assert!(state.check_response_headers(1, &headers));
+
+        state.peer_settings.apply(&parser::Settings {
+            settings: vec![parser::Setting {
+                identifier: 6,
+                value: 100,
+            }],
+            ack: false,
+        });
+        assert_eq!(state.peer_settings.max_header_list_size, 100);
+        assert!(!state.check_response_headers(1, &headers));
+
+        // the listener limit applies even if the client accepts more
+        let mut state = State::new(16393);
+        state.max_response_header_size = 100;
+        assert!(!state.check_response_headers(1, &headers));
+    }
+
+    #[test]
+    fn request_headers_over_the_limit_are_answered_with_a_431() {
+        let block = hpack::Encoder::new().encode([
+            (&b":method"[..], &b"GET"[..]),
+            (&b":scheme"[..], &b"https"[..]),
+            (&b":path"[..], &b"/"[..]),
+            (&b":authority"[..], &b"example.com"[..]),
+            (&b"x-large"[..], &[b'a'; 100][..]),
+        ]);
+        let headers = |stream_id| {
+            parser::Frame::Headers(parser::Headers {
+                stream_id,
+                stream_dependency: None,
+                weight: None,
+                header_block_fragment: &block,
+                end_stream: true,
+                end_headers: true,
+                priority: false,
+            })
+        };
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        state.max_request_header_size = 1024;
+        assert_eq!(state.handle(&headers(1)), FrameResult::ConnectBackend(1));
+
+        state.max_request_header_size = 100;
+        assert_eq!(state.handle(&headers(3)), FrameResult::Continue);
+        assert_eq!(state.streams[&3].state, stream::StreamState::Closed);
+        let mut buffer = [0u8; 64];
+        let size = state.gen(&mut buffer).unwrap();
+        let headers = hpack::Decoder::new().decode(&buffer[9..size]).unwrap();
+        assert_eq!(headers, vec![(b":status".to_vec(), b"431".to_vec())]);
+    }
+
+    #[test]
+    fn repeated_request_headers_are_measured_while_decoded() {
+        // the large field is indexed once, then each repetition is a single byte
+        let large = [b'a'; 1000];
+        let mut fields = vec![
+            (&b":method"[..], &b"GET"[..]),
+            (&b":scheme"[..], &b"https"[..]),
+            (&b":path"[..], &b"/"[..]),
+            (&b":authority"[..], &b"example.com"[..]),
+        ];
+        fields.extend(std::iter::repeat((&b"x-large"[..], &large[..])).take(100));
+        let block = hpack::Encoder::new().encode(fields);
+        assert!(block.len() < 1200);
+
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        state.max_request_header_size = 8192;
+        let frame = parser::Frame::Headers(parser::Headers {
+            stream_id: 1,
+            stream_dependency: None,
+            weight: None,
+            header_block_fragment: &block,
+            end_stream: true,
+            end_headers: true,
+            priority: false,
+        });
+        assert_eq!(state.handle(&frame), FrameResult::Continue);
+        let stream = &state.streams[&1];
+        assert_eq!(stream.state, stream::StreamState::Closed);
+        assert!(stream.request_header_size > 100_000);
+        assert!(stream.request_headers.is_empty());
+        let mut buffer = [0u8; 64];
+        let size = state.gen(&mut buffer).unwrap();
+        let headers = hpack::Decoder::new().decode(&buffer[9..size]).unwrap();
+        assert_eq!(headers, vec![(b":status".to_vec(), b"431".to_vec())]);
+    }
+
+    #[test]
+    fn requests_referencing_the_dynamic_table_are_measured_while_decoded() {
+        let large = [b'a'; 1000];
+        let request: [(&[u8], &[u8]); 5] = [
+            (b":method", b"GET"),
+            (b":scheme", b"https"),
+            (b":path", b"/"),
+            (b":authority", b"example.com"),
+            (b"x-large", &large),
+        ];
+        let mut encoder = hpack::Encoder::new();
+        let first = encoder.encode(request);
+        // the large field indexed by the first request, repeated in a small block
+        let mut fields = request[..4].to_vec();
+        fields.extend(std::iter::repeat((&b"x-large"[..], &large[..])).take(100));
+        let second = encoder.encode(fields);
+        assert!(second.len() < 200);
+        let third = encoder.encode(request);
+        let headers = |stream_id, block| {
+            parser::Frame::Headers(parser::Headers {
+                stream_id,
+                stream_dependency: None,
+                weight: None,
+                header_block_fragment: block,
+                end_stream: true,
+                end_headers: true,
+                priority: false,
+            })
+        };
+
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        state.max_request_header_size = 8192;
+        assert_eq!(
+            state.handle(&headers(1, &first)),
+            FrameResult::ConnectBackend(1)
+        );
+        assert_eq!(state.handle(&headers(3, &second)), FrameResult::Continue);
+        let stream = &state.streams[&3];
+        assert_eq!(stream.state, stream::StreamState::Closed);
+        assert!(stream.request_header_size > 100_000);
+        assert!(stream.request_headers.is_empty());
+
+        // the oversized block was decoded whole, the table is still in sync
+        assert_eq!(
+            state.handle(&headers(5, &third)),
+            FrameResult::ConnectBackend(5)
+        );
+        assert_eq!(state.streams[&5].header(b"x-large"), Some(&large[..]));
+    }
+
+    #[test]
+    fn configured_settings_are_sent_in_the_server_preface() {
+        let mut state = State::new(16393);
+        state.settings.max_concurrent_streams = 100;
+        state.settings.max_header_list_size = 8192;
+        state.state = St::ClientPrefaceReceived;
+        let settings = parser::Frame::Settings(parser::Settings {
+            settings: Vec::new(),
+            ack: false,
+        });
+        assert_eq!(state.handle(&settings), FrameResult::Continue);
+        assert_eq!(state.state, St::ServerPrefaceSent);
+
+        let mut buffer = [0u8; 64];
+        let size = state.gen(&mut buffer).unwrap();
+        assert_eq!(
+            &buffer[..size],
+            &[
+                0, 0, 18, 4, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 100, 0, 6, 0, 0, 0x20, 0, 0, 9, 0, 0, 0,
+                1
+            ][..]
+        );
+        assert_eq!(state.output.pop_front(), Some(OutputFrame::settings_ack()));
+
+        // they apply once the client acknowledges them
+        assert_eq!(state.local_settings.max_concurrent_streams, u32::MAX);
+        let ack = parser::Frame::Settings(parser::Settings {
+            settings: Vec::new(),
+            ack: true,
+        });
+        assert_eq!(state.handle(&ack), FrameResult::Continue);
+        assert_eq!(state.local_settings, state.settings);
+    }
+
+    #[test]
+    fn peer_settings_apply_at_once_and_ours_once_acknowledged() {
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        let mut settings = state.local_settings.clone();
+        settings.max_concurrent_streams = 10;
+        assert!(state.update_settings(&settings));
+        state.output.clear();
+
+        let peer = parser::Frame::Settings(parser::Settings {
+            settings: vec![parser::Setting {
+                identifier: 6,
+                value: 100,
+            }],
+            ack: false,
+        });
+        assert_eq!(state.handle(&peer), FrameResult::Continue);
+        assert_eq!(state.peer_settings.max_header_list_size, 100);
+        assert_eq!(state.output.pop_front(), Some(OutputFrame::settings_ack()));
+        // the peer did not acknowledge our change yet
+        assert_eq!(state.local_settings.max_concurrent_streams, u32::MAX);
+
+        let ack = parser::Frame::Settings(parser::Settings {
+            settings: Vec::new(),
+            ack: true,
+        });
+        assert_eq!(state.handle(&ack), FrameResult::Continue);
+        assert_eq!(state.local_settings.max_concurrent_streams, 10);
+        assert!(state.output.is_empty());
+    }
+
+    #[test]
+    fn invalid_peer_settings_close_the_connection() {
+        for (identifier, value, error_code) in [
+            (2, 2, parser::PROTOCOL_ERROR),
+            (4, 1 << 31, parser::FLOW_CONTROL_ERROR),
+            (5, 100, parser::PROTOCOL_ERROR),
+        ] {
+            let mut state = State::new(16393);
+            state.state = St::ServerPrefaceSent;
+            let settings = parser::Frame::Settings(parser::Settings {
+                settings: vec![parser::Setting { identifier, value }],
+                ack: false,
+            });
+            assert_eq!(state.handle(&settings), FrameResult::Close);
+            assert_eq!(
+                state.output.pop_front(),
+                Some(OutputFrame::goaway(0, error_code))
+            );
+            assert!(state.output.is_empty());
+        }
+    }
+
+    #[test]
+    fn streams_after_a_graceful_shutdown_are_refused() {
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        let mut stream = stream::Stream::new(1);
+        stream.state = stream::StreamState::HalfClosedRemote;
+        state.streams.insert(1, stream);
+
+        assert!(state.shutdown(parser::NO_ERROR, true));
+        assert!(!state.shutdown(parser::NO_ERROR, true));
+        assert_eq!(
+            state.output.pop_front(),
+            Some(OutputFrame::goaway(parser::MAX_STREAM_ID, parser::NO_ERROR))
+        );
+        // end of the drain period
+        assert!(state.goaway(parser::NO_ERROR));
+        assert!(!state.goaway(parser::NO_ERROR));
+        assert!(!state.is_draining());
+        assert_eq!(
+            state.output.pop_front(),
+            Some(OutputFrame::goaway(1, parser::NO_ERROR))
+        );
+        assert!(state.goaway(parser::PROTOCOL_ERROR));
+        assert_eq!(
+            state.output.pop_front(),
+            Some(OutputFrame::goaway(1, parser::PROTOCOL_ERROR))
+        );
+        // the started stream may finish
+        assert_eq!(state.active_streams(), 1);
+
+        let result = state.handle(&parser::Frame::Headers(parser::Headers {
+            stream_id: 3,
+            stream_dependency: None,
+            weight: None,
+            header_block_fragment: &[],
+            end_stream: true,
+            end_headers: true,
+            priority: false,
+        }));
+        assert_eq!(result, FrameResult::Continue);
+        assert!(!state.streams.contains_key(&3));
+        assert_eq!(
+            state.output.pop_front(),
+            Some(OutputFrame::rst_stream(3, parser::REFUSED_STREAM))
+        );
+    }
+
+    #[test]
+    fn streams_in_flight_during_the_drain_are_accepted() {
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        state.accept_requests_without_host = true;
+        assert!(state.shutdown(parser::NO_ERROR, true));
+        assert!(state.is_draining());
+        state.output.clear();
+
+        // the client opened it before it got the first GOAWAY
+        let block = Encoder::new().encode([
+            (&b":method"[..], &b"GET"[..]),
+            (b":scheme", b"https"),
+            (b":path", b"/"),
+        ]);
+        let headers = |stream_id| {
+            parser::Frame::Headers(parser::Headers {
+                stream_id,
+                stream_dependency: None,
+                weight: None,
+                header_block_fragment: &block,
+                end_stream: true,
+                end_headers: true,
+                priority: false,
+            })
+        };
+        assert_eq!(state.handle(&headers(1)), FrameResult::ConnectBackend(1));
+        assert!(state.output.is_empty());
+
+        assert!(state.goaway(parser::NO_ERROR));
+        assert_eq!(
+            state.output.pop_front(),
+            Some(OutputFrame::goaway(1, parser::NO_ERROR))
+        );
+        assert_eq!(state.handle(&headers(3)), FrameResult::Continue);
+        assert_eq!(
+            state.output.pop_front(),
+            Some(OutputFrame::rst_stream(3, parser::REFUSED_STREAM))
+        );
+    }
+
+    #[test]
+    fn a_hard_shutdown_resets_the_streams() {
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        let mut stream = stream::Stream::new(1);
+        stream.state = stream::StreamState::Open;
+        state.streams.insert(1, stream);
+
+        assert!(state.shutdown(parser::ENHANCE_YOUR_CALM, false));
+        assert_eq!(
+            state.output.pop_front(),
+            Some(OutputFrame::goaway(1, parser::ENHANCE_YOUR_CALM))
+        );
+        assert_eq!(
+            state.output.pop_front(),
+            Some(OutputFrame::rst_stream(1, parser::CANCEL))
+        );
+        assert_eq!(state.active_streams(), 0);
+    }
+
+    #[test]
+    fn the_request_body_is_received_after_the_response() {
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        let mut stream = stream::Stream::new(1);
+        stream.state = stream::StreamState::Open;
+        state.streams.insert(1, stream);
+
+        state.answer(1, 413);
+        assert_eq!(
+            state.streams[&1].state,
+            stream::StreamState::HalfClosedLocal
+        );
+        assert_eq!(state.active_streams(), 1);
+        state.output.clear();
+
+        let payload = vec![0; 40000];
+        let data = |length: usize, end_stream| {
+            parser::Frame::Data(parser::Data {
+                stream_id: 1,
+                payload: &payload[..length],
+                end_stream,
+                flow_controlled_length: length as u32,
+            })
+        };
+        assert_eq!(state.handle(&data(40000, false)), FrameResult::Continue);
+        assert_eq!(
+            state.streams[&1].state,
+            stream::StreamState::HalfClosedLocal
+        );
+        // the windows are still given back, for the client to reach its END_STREAM
+        assert!(state
+            .output
+            .iter()
+            .any(|frame| frame == &OutputFrame::window_update(1, 40000)));
+
+        assert_eq!(state.handle(&data(20000, true)), FrameResult::Continue);
+        assert_eq!(state.streams[&1].state, stream::StreamState::Closed);
+        assert_eq!(state.active_streams(), 0);
+    }
+
+    #[test]
+    fn request_trailers_end_the_request() {
+        let mut encoder = hpack::Encoder::new();
+        let request = encoder.encode([
+            (&b":method"[..], &b"POST"[..]),
+            (&b":scheme"[..], &b"https"[..]),
+            (&b":path"[..], &b"/"[..]),
+            (&b":authority"[..], &b"example.com"[..]),
+            (&b"te"[..], &b"trailers"[..]),
+        ]);
+        let trailers = encoder.encode([(&b"grpc-status"[..], &b"0"[..])]);
+        let headers = |stream_id, block, end_stream| {
+            parser::Frame::Headers(parser::Headers {
+                stream_id,
+                stream_dependency: None,
+                weight: None,
+                header_block_fragment: block,
+                end_stream,
+                end_headers: true,
+                priority: false,
+            })
+        };
+        let data = parser::Frame::Data(parser::Data {
+            stream_id: 1,
+            payload: b"body",
+            end_stream: false,
+            flow_controlled_length: 4,
+        });
+
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        assert_eq!(
+            state.handle(&headers(1, &request, false)),
+            FrameResult::ConnectBackend(1)
+        );
+        assert_eq!(state.handle(&data), FrameResult::Continue);
+        assert_eq!(
+            state.handle(&headers(1, &trailers, true)),
+            FrameResult::Continue
+        );
+        let stream = &state.streams[&1];
+        assert_eq!(stream.state, stream::StreamState::HalfClosedRemote);
+        assert_eq!(
+            stream.request_trailers,
+            vec![(b"grpc-status".to_vec(), b"0".to_vec())]
+        );
+
+        // trailers must end the stream
+        let mut encoder = hpack::Encoder::new();
+        let request = encoder.encode([
+            (&b":method"[..], &b"POST"[..]),
+            (&b":scheme"[..], &b"https"[..]),
+            (&b":path"[..], &b"/"[..]),
+            (&b":authority"[..], &b"example.com"[..]),
+        ]);
+        let trailers = encoder.encode([(&b"grpc-status"[..], &b"0"[..])]);
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        state.handle(&headers(1, &request, false));
+        state.output.clear();
+        assert_eq!(
+            state.handle(&headers(1, &trailers, false)),
+            FrameResult::CancelStream(1)
+        );
+        assert_eq!(state.streams[&1].state, stream::StreamState::Closed);
+        assert_eq!(
+            state.output.pop_front(),
+            Some(OutputFrame::rst_stream(1, parser::PROTOCOL_ERROR))
+        );
+    }
+
+    #[test]
+    fn error_codes_have_a_counter_in_each_direction() {
+        assert_eq!(
+            error_code_metric(ErrorFrame::GoAwayReceived, parser::PROTOCOL_ERROR),
+            "http2.goaway.received.protocol_error"
+        );
+        assert_eq!(
+            error_code_metric(ErrorFrame::RstStreamSent, parser::FLOW_CONTROL_ERROR),
+            "http2.rst_stream.sent.flow_control_error"
+        );
+        assert_eq!(
+            error_code_metric(ErrorFrame::RstStreamReceived, 0xff),
+            "http2.rst_stream.received.unknown"
+        );
+        assert_eq!(
+            OutputFrame::goaway(3, parser::ENHANCE_YOUR_CALM).sent_error_code(),
+            Some((ErrorFrame::GoAwaySent, parser::ENHANCE_YOUR_CALM))
+        );
+        assert_eq!(
+            OutputFrame::rst_stream(3, parser::CANCEL).sent_error_code(),
+            Some((ErrorFrame::RstStreamSent, parser::CANCEL))
+        );
+        assert_eq!(OutputFrame::window_update(3, 1).sent_error_code(), None);
+    }
+
+    #[test]
+    fn a_goaway_from_the_client_is_parsed() {
+        let input = [
+            0, 0, 10, 7, 0, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 1, b'o', b'k',
+        ];
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        let (consumed, frame) = state.parse(&input);
+        assert_eq!(consumed, input.len());
+        let frame = frame.unwrap().unwrap();
+        assert_eq!(
+            frame,
+            parser::Frame::GoAway(parser::GoAway {
+                last_stream_id: 5,
+                error_code: parser::PROTOCOL_ERROR,
+            })
+        );
+        assert_eq!(state.handle(&frame), FrameResult::Close);
+    }
+
+    #[test]
+    fn priority_frames_are_ignored_and_push_promises_refused() {
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        // PRIORITY of stream 3, exclusively depending on stream 1 with a weight of 16
+        let priority = [0, 0, 5, 2, 0, 0, 0, 0, 3, 0x80, 0, 0, 1, 16];
+        let (consumed, frame) = state.parse(&priority);
+        assert_eq!(consumed, priority.len());
+        let frame = frame.unwrap().unwrap();
+        assert_eq!(
+            frame,
+            parser::Frame::Priority(parser::Priority {
+                stream_id: 3,
+                stream_dependency: parser::StreamDependency {
+                    exclusive: true,
+                    stream_id: 1,
+                },
+                weight: 16,
+            })
+        );
+        assert_eq!(state.handle(&frame), FrameResult::Continue);
+        assert!(state.streams.is_empty());
+        assert!(state.output.is_empty());
+
+        // a PRIORITY frame has a 5 bytes payload
+        let (_, result) = state.parse_and_handle(&[0, 0, 4, 2, 0, 0, 0, 0, 3, 0, 0, 0, 1]);
+        assert_eq!(result, FrameResult::Close);
+
+        let push_promise = [0, 0, 4, 5, 4, 0, 0, 0, 1, 0, 0, 0, 2];
+        let (_, result) = state.parse_and_handle(&push_promise);
+        assert_eq!(result, FrameResult::Close);
+    }
+
+    #[test]
+    fn pings_are_acknowledged_with_their_payload() {
+        let input = [0, 0, 8, 6, 0, 0, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8];
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        let (_, frame) = state.parse(&input);
+        let frame = frame.unwrap().unwrap();
+        assert_eq!(state.handle(&frame), FrameResult::Continue);
+        assert_eq!(
+            state.output.pop_front(),
+            Some(OutputFrame::ping_ack([1, 2, 3, 4, 5, 6, 7, 8]))
+        );
+
+        // an acknowledgement is not answered
+        let ack = parser::Frame::Ping(parser::Ping {
+            payload: [1, 2, 3, 4, 5, 6, 7, 8],
+            ack: true,
+        });
+        assert_eq!(state.handle(&ack), FrameResult::Continue);
+        assert!(state.output.is_empty());
+    }
+
+    #[test]
+    fn unexpected_connection_frames_are_a_protocol_error() {
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        let frame = parser::Frame::RstStream(parser::RstStream {
+            stream_id: 0,
+            error_code: parser::NO_ERROR,
+        });
+        assert_eq!(state.handle(&frame), FrameResult::Close);
+        assert_eq!(
+            state.output.pop_back(),
+            Some(OutputFrame::goaway(0, parser::PROTOCOL_ERROR))
+        );
+    }
+
+    #[test]
+    fn requests_without_a_host_follow_the_listener_policy() {
+        for authority in [None, Some(&b""[..])] {
+            let mut headers = vec![
+                (&b":method"[..], &b"GET"[..]),
+                (b":scheme", b"https"),
+                (b":path", b"/"),
+            ];
+            headers.extend(authority.map(|authority| (&b":authority"[..], authority)));
+            let block = Encoder::new().encode(headers);
+            let frame = parser::Frame::Headers(parser::Headers {
+                stream_id: 1,
+                stream_dependency: None,
+                weight: None,
+                header_block_fragment: &block,
+                end_stream: true,
+                end_headers: true,
+                priority: false,
+            });
+
+            let mut state = State::new(16393);
+            state.state = St::ServerPrefaceSent;
+            assert_eq!(state.handle(&frame), FrameResult::Continue);
+            assert_eq!(state.streams[&1].state, stream::StreamState::Closed);
+            assert_eq!(
+                state.output.pop_front(),
+                Some(OutputFrame::headers(
+                    1,
+                    Encoder::new().encode([(&b":status"[..], &b"400"[..])]),
+                    true
+                ))
+            );
+
+            let mut state = State::new(16393);
+            state.state = St::ServerPrefaceSent;
+            state.accept_requests_without_host = true;
+            assert_eq!(state.handle(&frame), FrameResult::ConnectBackend(1));
+        }
+    }
+
+    #[test]
+    fn connect_requests_are_routed_on_their_authority() {
+        let block = Encoder::new().encode([
+            (&b":method"[..], &b"CONNECT"[..]),
+            (b":authority", b"example.com:443"),
+        ]);
+        let frame = parser::Frame::Headers(parser::Headers {
+            stream_id: 1,
+            stream_dependency: None,
+            weight: None,
+            header_block_fragment: &block,
+            end_stream: false,
+            end_headers: true,
+            priority: false,
+        });
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        assert_eq!(state.handle(&frame), FrameResult::ConnectBackend(1));
+        assert!(state.streams[&1].is_connect());
+        assert_eq!(state.streams[&1].state, stream::StreamState::Open);
+
+        let mut settings = H2Settings::default();
+        settings.apply(&parser::Settings {
+            settings: vec![parser::Setting {
+                identifier: 8,
+                value: 1,
+            }],
+            ack: false,
+        });
+        assert!(settings.enable_connect_protocol);
+    }
+
+    #[test]
+    fn complete_frames_are_detected_in_the_buffer() {
+        let state = State::new(16393);
+        let ping = [0, 0, 8, 6, 0, 0, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8];
+        assert!(state.has_complete_frame(&ping));
+        // a frame followed by the start of the next one
+        let mut two = ping.to_vec();
+        two.extend_from_slice(&ping[..4]);
+        assert!(state.has_complete_frame(&two));
+        assert!(!state.has_complete_frame(&two[ping.len()..]));
+        assert!(!state.has_complete_frame(&ping[..12]));
+        assert!(!state.has_complete_frame(&[]));
+        // no need to wait for the payload of a frame that will be refused
+        assert!(state.has_complete_frame(&[0xff, 0xff, 0xff, 0, 0, 0, 0, 0, 1]));
+    }
+
+    #[test]
+    fn rst_stream_is_serialized_with_its_error_code() {
+        let mut state = State::new(16393);
+        state
+            .output
+            .push_back(OutputFrame::rst_stream(3, parser::CANCEL));
+
+        let mut buffer = [0; 32];
+        assert_eq!(state.gen(&mut buffer), Ok(13));
+        assert_eq!(&buffer[..13], &[0, 0, 4, 3, 0, 0, 0, 0, 3, 0, 0, 0, 8][..]);
+    }
+
+    #[test]
+    fn goaway_is_only_sent_on_established_connections() {
+        let mut state = State::new(16393);
+        assert!(!state.goaway(parser::NO_ERROR));
+
+        state.state = St::ServerPrefaceSent;
+        state.streams.insert(5, stream::Stream::new(5));
+        assert!(state.goaway(parser::NO_ERROR));
+
+        let mut buffer = [0; 32];
+        assert_eq!(state.gen(&mut buffer), Ok(17));
+        assert_eq!(
+            &buffer[..17],
+            &[0, 0, 8, 7, 0, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0][..]
+        );
+    }
+
+    #[test]
+    fn max_frame_size_is_capped_by_the_buffer_size() {
+        assert_eq!(max_frame_size_for_buffer(16393), 16384);
+        assert_eq!(max_frame_size_for_buffer(32768), 32759);
+        assert_eq!(max_frame_size_for_buffer(4096), MIN_MAX_FRAME_SIZE);
+        assert_eq!(max_frame_size_for_buffer(1 << 25), MAX_MAX_FRAME_SIZE);
+        assert_eq!(State::new(20000).max_frame_size, 19991);
+    }
+
+    #[test]
+    fn answer_closes_the_stream_with_a_status_only_response() {
+        let mut state = State::new(16393);
+        state.streams.insert(1, stream::Stream::new(1));
+        state.answer(1, 421);
+        assert_eq!(state.streams[&1].state, stream::StreamState::Closed);
+
+        let mut buffer = [0; 32];
+        let size = state.gen(&mut buffer).unwrap();
+        let (_, header) = parser::frame_header(&buffer[..size]).unwrap();
+        assert_eq!(header.frame_type, parser::FrameType::Headers);
+        assert_eq!(
+            header.flags,
+            parser::FLAG_END_HEADERS | parser::FLAG_END_STREAM
+        );
+        assert_eq!(header.stream_id, 1);
+
+        let headers = hpack::Decoder::new().decode(&buffer[9..size]).unwrap();
+        assert_eq!(headers, vec![(b":status".to_vec(), b"421".to_vec())]);
+    }
+
+    #[test]
+    fn answers_may_carry_a_retry_after() {
+        let mut state = State::new(16393);
+        state.streams.insert(1, stream::Stream::new(1));
+        state.answer_with_headers(1, 503, &[(&b"retry-after"[..], &b"30"[..])]);
+
+        let mut buffer = [0; 32];
+        let size = state.gen(&mut buffer).unwrap();
+        let headers = hpack::Decoder::new().decode(&buffer[9..size]).unwrap();
+        assert_eq!(
+            headers,
+            vec![
+                (b":status".to_vec(), b"503".to_vec()),
+                (b"retry-after".to_vec(), b"30".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn oversized_header_blocks_close_the_connection() {
+        let mut state = State::new(16393);
+        let mut input = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+        input.extend_from_slice(&[0, 0, 0, 4, 0, 0, 0, 0, 0]);
+        let (size, result) = state.parse_and_handle(&input);
+        assert_eq!(size, input.len());
+        assert_eq!(result, FrameResult::Continue);
+        assert_eq!(state.state, St::ServerPrefaceSent);
+
+        // a CONTINUATION frame larger than what the buffer can hold is refused
+        // before its payload is read, whatever it claims to carry
+        let len = state.max_frame_size + 1;
+        let header = [
+            (len >> 16) as u8,
+            (len >> 8) as u8,
+            len as u8,
+            9,
+            0,
+            0,
+            0,
+            0,
+            1,
+        ];
+        let (_, result) = state.parse_and_handle(&header);
+        assert_eq!(result, FrameResult::Close);
+    }
+
+    #[test]
+    fn header_blocks_are_decoded_once_complete() {
+        let block = hpack::Encoder::new().encode([
+            (&b":method"[..], &b"GET"[..]),
+            (&b":scheme"[..], &b"https"[..]),
+            (&b":path"[..], &b"/"[..]),
+            (&b":authority"[..], &b"example.com"[..]),
+        ]);
+        let (first, second) = block.split_at(block.len() / 2);
+        let start = || {
+            let mut state = State::new(16393);
+            state.state = St::ServerPrefaceSent;
+            let result = state.handle(&parser::Frame::Headers(parser::Headers {
+                stream_id: 1,
+                stream_dependency: None,
+                weight: None,
+                header_block_fragment: first,
+                end_stream: true,
+                end_headers: false,
+                priority: false,
+            }));
+            assert_eq!(result, FrameResult::Continue);
+            state
+        };
+
+        let mut state = start();
+        assert_eq!(state.continuation_stream, Some(1));
+        assert!(state.streams[&1].request_headers.is_empty());
+
+        // nothing but the rest of the block may come before END_HEADERS
+        let mut interleaved = start();
+        let result = interleaved.handle(&parser::Frame::Ping(parser::Ping {
+            payload: [0; 8],
+            ack: false,
+        }));
+        assert_eq!(result, FrameResult::Close);
+
+        let result = state.handle(&parser::Frame::Continuation(parser::Continuation {
+            stream_id: 1,
+            header_block_fragment: second,
+            end_headers: true,
+        }));
+        assert_eq!(result, FrameResult::ConnectBackend(1));
+        assert_eq!(state.continuation_stream, None);
+        assert_eq!(state.streams[&1].authority(), Some(&b"example.com"[..]));
+    }
+
+    #[test]
+    fn reset_stream_is_canceled() {
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        let mut stream = stream::Stream::new(3);
+        stream.state = stream::StreamState::Open;
+        state.streams.insert(3, stream);
+
+        let result = state.handle(&parser::Frame::RstStream(parser::RstStream {
+            stream_id: 3,
+            error_code: parser::CANCEL,
+        }));
+        assert_eq!(result, FrameResult::CancelStream(3));
+        assert_eq!(state.streams[&3].state, stream::StreamState::Closed);
+    }
+
+    #[test]
+    fn no_rfc7540_priorities_is_stored() {
+        let mut settings = H2Settings::default();
+        settings.apply(&parser::Settings {
+            settings: vec![
+                parser::Setting {
+                    identifier: 9,
+                    value: 1,
+                },
+                parser::Setting {
+                    identifier: 0xf0f0,
+                    value: 42,
+                },
+            ],
+            ack: false,
+        });
+        assert!(settings.no_rfc7540_priorities);
+        assert_eq!(settings.max_concurrent_streams, u32::MAX);
+    }
+
+    #[test]
+    fn client_settings_split_across_reads_are_assembled() {
+        let mut state = State::new(16393);
+        let mut input = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+        // SETTINGS_MAX_CONCURRENT_STREAMS = 100, SETTINGS_INITIAL_WINDOW_SIZE = 65535
+        input.extend_from_slice(&[0, 0, 12, 4, 0, 0, 0, 0, 0]);
+        input.extend_from_slice(&[0, 3, 0, 0, 0, 100, 0, 4, 0, 0, 0xff, 0xff]);
+
+        // one byte per read, what was not consumed stays in the buffer
+        let mut buffer = Vec::new();
+        for (index, byte) in input.iter().enumerate() {
+            buffer.push(*byte);
+            let (size, result) = state.parse_and_handle(&buffer);
+            assert_eq!(result, FrameResult::Continue);
+            buffer.drain(..size);
+            if index < input.len() - 1 {
+                assert_ne!(state.state, St::ServerPrefaceSent);
+            }
+        }
+        assert!(buffer.is_empty());
+        assert_eq!(state.state, St::ServerPrefaceSent);
+        assert_eq!(state.peer_settings.max_concurrent_streams, 100);
+    }
+
+    #[test]
+    fn unknown_frames_are_skipped() {
+        let mut state = State::new(16393);
+        let mut input = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+        input.extend_from_slice(&[0, 0, 3, 0xfa, 0, 0, 0, 0, 0, 1, 2, 3]);
+        input.extend_from_slice(&[0, 0, 0, 4, 0, 0, 0, 0, 0]);
+        let (size, result) = state.parse_and_handle(&input);
+        assert_eq!(size, input.len() - 9);
+        assert_eq!(result, FrameResult::Continue);
+        assert_eq!(state.state, St::ClientPrefaceReceived);
+
+        let (size, result) = state.parse_and_handle(&input[size..]);
+        assert_eq!(size, 9);
+        assert_eq!(result, FrameResult::Continue);
+        assert_eq!(state.state, St::ServerPrefaceSent);
+
+        // an unknown frame on a stream is ignored as well, not inside a header block
+        let (_, frame) = parser::frame(&[0, 0, 1, 0xfa, 0, 0, 0, 0, 3, 0], 16384).unwrap();
+        assert_eq!(
+            frame,
+            parser::Frame::Unknown(parser::Unknown {
+                frame_type: 0xfa,
+                stream_id: 3,
+                payload: &[0],
+            })
+        );
+        assert_eq!(state.handle(&frame), FrameResult::Continue);
+        assert!(state.streams.is_empty());
+
+        state.continuation_stream = Some(1);
+        assert_eq!(state.handle(&frame), FrameResult::Close);
+    }
+
+    #[test]
+    fn reset_stream_only_closes_that_stream() {
+        let mut state = State::new(16393);
+        for id in [1, 3] {
+            let mut stream = stream::Stream::new(id);
+            stream.state = stream::StreamState::Open;
+            state.streams.insert(id, stream);
+        }
+
+        state.reset_stream(1, parser::INTERNAL_ERROR);
+        assert_eq!(state.streams[&1].state, stream::StreamState::Closed);
+        assert_eq!(state.streams[&3].state, stream::StreamState::Open);
+        assert_eq!(
+            state.output.front(),
+            Some(&OutputFrame::rst_stream(1, parser::INTERNAL_ERROR))
+        );
+    }
+
+    #[test]
+    fn headers_are_encoded_in_a_stable_order() {
+        let headers = vec![
+            (b"x-b".to_vec(), b"1".to_vec()),
+            (b":path".to_vec(), b"/".to_vec()),
+            (b"x-a".to_vec(), b"2".to_vec()),
+            (b":method".to_vec(), b"GET".to_vec()),
+            (b"x-b".to_vec(), b"3".to_vec()),
+        ];
+        assert_eq!(
+            header_emission_order(&headers),
+            vec![
+                (&b":method"[..], &b"GET"[..]),
+                (&b":path"[..], &b"/"[..]),
+                (&b"x-b"[..], &b"1"[..]),
+                (&b"x-a"[..], &b"2"[..]),
+                (&b"x-b"[..], &b"3"[..]),
+            ]
+        );
+
+        let block = encode_headers(&mut Encoder::new(), &headers);
+        assert_eq!(block, encode_headers(&mut Encoder::new(), &headers));
+        let decoded = hpack::Decoder::new().decode(&block).unwrap();
+        assert_eq!(decoded[0].0, b":method".to_vec());
+        assert_eq!(decoded[4], (b"x-b".to_vec(), b"3".to_vec()));
+    }
+
+    #[test]
+    fn request_headers_only_reach_their_own_stream() {
+        let block = hpack::Encoder::new().encode([
+            (&b":method"[..], &b"GET"[..]),
+            (&b":scheme"[..], &b"https"[..]),
+            (&b":path"[..], &b"/"[..]),
+            (&b":authority"[..], &b"example.com"[..]),
+        ]);
+        let headers = |stream_id| {
+            parser::Frame::Headers(parser::Headers {
+                stream_id,
+                stream_dependency: None,
+                weight: None,
+                header_block_fragment: &block,
+                end_stream: true,
+                end_headers: true,
+                priority: false,
+            })
+        };
+
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        assert_eq!(state.handle(&headers(0)), FrameResult::Close);
+        assert!(state.streams.is_empty());
+
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        assert_eq!(state.handle(&headers(1)), FrameResult::ConnectBackend(1));
+        let stream = &state.streams[&1];
+        assert_eq!(stream.authority(), Some(&b"example.com"[..]));
+        assert!(stream.response_frames.is_empty());
+        assert!(state.output.is_empty());
+    }
+
+    #[test]
+    fn header_blocks_share_the_dynamic_table_of_the_connection() {
+        let mut encoder = hpack::Encoder::new();
+        let request: [(&[u8], &[u8]); 5] = [
+            (b":method", b"GET"),
+            (b":scheme", b"https"),
+            (b":path", b"/"),
+            (b":authority", b"example.com"),
+            (b"x-request", b"first"),
+        ];
+        let first = encoder.encode(request);
+        // the second block only references the fields the first one indexed
+        let second = encoder.encode(request);
+        assert!(second.len() < first.len());
+        let headers = |stream_id, block| {
+            parser::Frame::Headers(parser::Headers {
+                stream_id,
+                stream_dependency: None,
+                weight: None,
+                header_block_fragment: block,
+                end_stream: true,
+                end_headers: true,
+                priority: false,
+            })
+        };
+
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        assert_eq!(
+            state.handle(&headers(1, &first)),
+            FrameResult::ConnectBackend(1)
+        );
+        assert_eq!(
+            state.handle(&headers(3, &second)),
+            FrameResult::ConnectBackend(3)
+        );
+        assert_eq!(state.streams[&3].header(b"x-request"), Some(&b"first"[..]));
+
+        // the block of a refused stream still goes through the decoder
+        state.goaway(parser::NO_ERROR);
+        let refused = encoder.encode([(&b"x-refused"[..], &b"yes"[..])]);
+        assert_eq!(state.handle(&headers(5, &refused)), FrameResult::Continue);
+        assert!(!state.streams.contains_key(&5));
+        let mut decoded = Vec::new();
+        let reference = encoder.encode([(&b"x-refused"[..], &b"yes"[..])]);
+        state
+            .decoder
+            .decode(&reference, |name, value| {
+                decoded.push((name.into_owned(), value.into_owned()))
+            })
+            .unwrap();
+        assert_eq!(decoded, vec![(b"x-refused".to_vec(), b"yes".to_vec())]);
+    }
+
+    #[test]
+    fn table_size_updates_over_our_setting_are_refused() {
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        let mut block = dynamic_table_size_update(4097);
+        block.extend(hpack::Encoder::new().encode([
+            (&b":method"[..], &b"GET"[..]),
+            (&b":scheme"[..], &b"https"[..]),
+            (&b":path"[..], &b"/"[..]),
+            (&b":authority"[..], &b"example.com"[..]),
+        ]));
+        let headers = parser::Frame::Headers(parser::Headers {
+            stream_id: 1,
+            stream_dependency: None,
+            weight: None,
+            header_block_fragment: &block,
+            end_stream: true,
+            end_headers: true,
+            priority: false,
+        });
+        assert_eq!(state.handle(&headers), FrameResult::Close);
+
+        // the limit follows the settings we sent
+        let mut state = State::new(16393);
+        state.state = St::ClientPrefaceReceived;
+        state.settings.header_table_size = 8192;
+        let settings = parser::Frame::Settings(parser::Settings {
+            settings: Vec::new(),
+            ack: false,
+        });
+        assert_eq!(state.handle(&settings), FrameResult::Continue);
+        assert_eq!(state.decoder.max_table_size, 8192);
+        assert_eq!(state.handle(&headers), FrameResult::ConnectBackend(1));
+    }
+
+    #[test]
+    fn the_phase_of_a_stream_follows_its_exchange() {
+        let block = hpack::Encoder::new().encode([
+            (&b":method"[..], &b"POST"[..]),
+            (&b":scheme"[..], &b"https"[..]),
+            (&b":path"[..], &b"/"[..]),
+            (&b":authority"[..], &b"example.com"[..]),
+        ]);
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        let headers = parser::Frame::Headers(parser::Headers {
+            stream_id: 1,
+            stream_dependency: None,
+            weight: None,
+            header_block_fragment: &block,
+            end_stream: false,
+            end_headers: true,
+            priority: false,
+        });
+        assert_eq!(state.handle(&headers), FrameResult::ConnectBackend(1));
+        assert_eq!(state.streams[&1].phase, stream::StreamPhase::Routing);
+
+        state.streams.get_mut(&1).unwrap().forwarded();
+        assert_eq!(
+            state.streams[&1].phase,
+            stream::StreamPhase::StreamingRequest
+        );
+
+        let end_of_body = parser::Frame::Data(parser::Data {
+            stream_id: 1,
+            payload: &[],
+            end_stream: true,
+            flow_controlled_length: 0,
+        });
+        assert_eq!(state.handle(&end_of_body), FrameResult::Continue);
+        assert_eq!(
+            state.streams[&1].phase,
+            stream::StreamPhase::AwaitingResponse
+        );
+
+        let response = vec![(b":status".to_vec(), b"200".to_vec())];
+        assert!(state.check_response_headers(1, &response));
+        assert_eq!(
+            state.streams[&1].phase,
+            stream::StreamPhase::StreamingResponse
+        );
+
+        state.reset_stream(1, parser::CANCEL);
+        assert_eq!(state.streams[&1].phase, stream::StreamPhase::Done);
+    }
+
+    #[test]
+    fn request_and_response_bodies_flow_at_the_same_time() {
+        let block = hpack::Encoder::new().encode([
+            (&b":method"[..], &b"POST"[..]),
+            (&b":scheme"[..], &b"https"[..]),
+            (&b":path"[..], &b"/echo.Echo/Chat"[..]),
+            (&b":authority"[..], &b"example.com"[..]),
+        ]);
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        let headers = parser::Frame::Headers(parser::Headers {
+            stream_id: 1,
+            stream_dependency: None,
+            weight: None,
+            header_block_fragment: &block,
+            end_stream: false,
+            end_headers: true,
+            priority: false,
+        });
+        assert_eq!(state.handle(&headers), FrameResult::ConnectBackend(1));
+        state.streams.get_mut(&1).unwrap().forwarded();
+        // nothing to send before the response starts
+        assert!(!state.send_response_data(1, b"early", false));
+
+        let response = vec![(b":status".to_vec(), b"200".to_vec())];
+        assert!(state.check_response_headers(1, &response));
+        for _ in 0..4 {
+            assert_eq!(state.handle(&data_frame(1, 16384)), FrameResult::Continue);
+            assert!(state.send_response_data(1, b"pong", false));
+        }
+        assert_eq!(state.streams[&1].state, stream::StreamState::Open);
+        let sent: Vec<_> = state.output.drain(..).collect();
+        assert_eq!(
+            sent.iter()
+                .filter(|frame| **frame == OutputFrame::data(1, b"pong".to_vec(), false))
+                .count(),
+            4
+        );
+        // the request body is given back while the response streams
+        assert!(sent.contains(&OutputFrame::window_update(1, 32768)));
+
+        // the end of the request does not end the response
+        let end_of_request = parser::Frame::Data(parser::Data {
+            stream_id: 1,
+            payload: &[],
+            end_stream: true,
+            flow_controlled_length: 0,
+        });
+        assert_eq!(state.handle(&end_of_request), FrameResult::Continue);
+        assert_eq!(
+            state.streams[&1].state,
+            stream::StreamState::HalfClosedRemote
+        );
+        assert_eq!(
+            state.streams[&1].phase,
+            stream::StreamPhase::StreamingResponse
+        );
+
+        // the last part is split at the frame size of the client, END_STREAM on the last frame
+        assert!(state.send_response_data(1, &[0; 20000], true));
+        assert_eq!(
+            state.output.drain(..).collect::<Vec<_>>(),
+            vec![
+                OutputFrame::data(1, vec![0; 16384], false),
+                OutputFrame::data(1, vec![0; 3616], true),
+            ]
+        );
+        assert_eq!(state.streams[&1].state, stream::StreamState::Closed);
+        assert!(!state.send_response_data(1, b"late", false));
+    }
+
+    #[test]
+    fn streams_whose_response_is_not_read_are_found_and_reset() {
+        let block = hpack::Encoder::new().encode([
+            (&b":method"[..], &b"GET"[..]),
+            (&b":scheme"[..], &b"https"[..]),
+            (&b":path"[..], &b"/large"[..]),
+            (&b":authority"[..], &b"example.com"[..]),
+        ]);
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        let response = vec![(b":status".to_vec(), b"200".to_vec())];
+        for stream_id in [1, 3] {
+            let headers = parser::Frame::Headers(parser::Headers {
+                stream_id,
+                stream_dependency: None,
+                weight: None,
+                header_block_fragment: &block,
+                end_stream: true,
+                end_headers: true,
+                priority: false,
+            });
+            state.handle(&headers);
+            state.streams.get_mut(&stream_id).unwrap().forwarded();
+            assert!(state.check_response_headers(stream_id, &response));
+        }
+        state.output.clear();
+
+        assert!(state.send_response_data(1, b"first", false));
+        assert!(state.send_response_data(3, b"second", false));
+        assert_eq!(state.streams_with_pending_data(), 2);
+        let grace = Duration::from_secs(30);
+        let now = Instant::now();
+        assert!(state.stalled_streams(now, grace).is_empty());
+        assert_eq!(state.stalled_streams(now + grace, grace), vec![1, 3]);
+
+        // writing the response of stream 1 clears it
+        let mut buffer = [0; 64];
+        assert!(state.gen(&mut buffer).unwrap() > 0);
+        assert_eq!(state.streams_with_pending_data(), 1);
+        assert_eq!(state.stalled_streams(now + grace, grace), vec![3]);
+
+        state.reset_slow_stream(3);
+        assert_eq!(state.streams_with_pending_data(), 0);
+        assert!(state.stalled_streams(now + grace, grace).is_empty());
+        assert_eq!(
+            state.output.drain(..).collect::<Vec<_>>(),
+            vec![OutputFrame::rst_stream(3, parser::ENHANCE_YOUR_CALM)]
+        );
+        assert_eq!(state.streams[&3].state, stream::StreamState::Closed);
+    }
+
+    #[test]
+    fn large_header_blocks_continue_in_continuation_frames() {
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        let block = hpack::Encoder::new().encode([
+            (&b":method"[..], &b"GET"[..]),
+            (&b":scheme"[..], &b"https"[..]),
+            (&b":path"[..], &b"/"[..]),
+            (&b":authority"[..], &b"example.com"[..]),
+        ]);
+        state.handle(&parser::Frame::Headers(parser::Headers {
+            stream_id: 1,
+            stream_dependency: None,
+            weight: None,
+            header_block_fragment: &block,
+            end_stream: true,
+            end_headers: true,
+            priority: false,
+        }));
+        state.streams.get_mut(&1).unwrap().forwarded();
+        state.output.clear();
+
+        // random values, that no Huffman code shortens below the frame size
+        let mut seed = 1u32;
+        let large = (0..40000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                b'!' + (seed >> 16) as u8 % 90
+            })
+            .collect::<Vec<u8>>();
+        let response = vec![
+            (b":status".to_vec(), b"200".to_vec()),
+            (b"x-large".to_vec(), large.clone()),
+        ];
+        assert!(state.check_response_headers(1, &response));
+        assert!(state.send_response_headers(1, &response, true));
+
+        let frames = state.output.drain(..).collect::<Vec<_>>();
+        assert_eq!(frames.len(), 3);
+        let mut header_block = Vec::new();
+        for (index, frame) in frames.iter().enumerate() {
+            let expected_type = if index == 0 {
+                parser::FrameType::Headers
+            } else {
+                parser::FrameType::Continuation
+            };
+            assert_eq!(frame.header.frame_type, expected_type);
+            assert!(frame.header.payload_len <= 16384);
+            assert_eq!(
+                frame.header.flags & parser::FLAG_END_HEADERS != 0,
+                index == 2
+            );
+            header_block.extend_from_slice(frame.payload.as_ref().unwrap());
+        }
+        assert_eq!(
+            frames[0].header.flags & parser::FLAG_END_STREAM,
+            parser::FLAG_END_STREAM
+        );
+        assert_eq!(
+            hpack::Decoder::new().decode(&header_block).unwrap(),
+            response
+        );
+
+        // a frame that does not fit in the buffer is an error, not a panic
+        state.output.extend(frames);
+        let mut buffer = [0u8; 100];
+        assert_eq!(state.gen(&mut buffer), Err(()));
+    }
+
+    #[test]
+    fn trailers_may_follow_the_response_headers_without_data() {
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        // only the request of stream 1 accepts trailers
+        for stream_id in [1, 3] {
+            let mut fields = vec![
+                (&b":method"[..], &b"POST"[..]),
+                (&b":scheme"[..], &b"https"[..]),
+                (&b":path"[..], &b"/echo.Echo/Say"[..]),
+                (&b":authority"[..], &b"example.com"[..]),
+            ];
+            if stream_id == 1 {
+                fields.push((&b"te"[..], &b"trailers"[..]));
+            }
+            let block = hpack::Encoder::new().encode(fields);
+            let headers = parser::Frame::Headers(parser::Headers {
+                stream_id,
+                stream_dependency: None,
+                weight: None,
+                header_block_fragment: &block,
+                end_stream: true,
+                end_headers: true,
+                priority: false,
+            });
+            state.handle(&headers);
+            state.streams.get_mut(&stream_id).unwrap().forwarded();
+        }
+        state.output.clear();
+
+        let response = vec![
+            (b":status".to_vec(), b"200".to_vec()),
+            (b"content-type".to_vec(), b"application/grpc".to_vec()),
+        ];
+        let trailers = vec![(b"Grpc-Status".to_vec(), b"13".to_vec())];
+        // trailers can not be sent before the response headers
+        assert!(!state.send_response_trailers(1, &trailers));
+
+        assert!(state.check_response_headers(1, &response));
+        assert!(state.send_response_headers(1, &response, false));
+        assert!(!state.send_response_headers(1, &response, false));
+        assert!(state.send_response_trailers(1, &trailers));
+        assert_eq!(
+            state.output.drain(..).collect::<Vec<_>>(),
+            vec![
+                OutputFrame::headers(1, encode_headers(&mut Encoder::new(), &response), false),
+                OutputFrame::headers(
+                    1,
+                    Encoder::new().encode([(&b"grpc-status"[..], &b"13"[..])]),
+                    true
+                ),
+            ]
+        );
+        assert_eq!(state.streams[&1].state, stream::StreamState::Closed);
+
+        // without te: trailers, the response ends with an empty DATA frame
+        assert!(state.check_response_headers(3, &response));
+        assert!(state.send_response_headers(3, &response, false));
+        assert!(state.send_response_trailers(3, &trailers));
+        assert_eq!(
+            state.output.drain(..).collect::<Vec<_>>(),
+            vec![
+                OutputFrame::headers(3, encode_headers(&mut Encoder::new(), &response), false),
+                OutputFrame::data(3, Vec::new(), true),
+            ]
+        );
+        assert_eq!(state.streams[&3].state, stream::StreamState::Closed);
+    }
+
+    #[test]
+    fn hpack_reset_goes_in_the_next_header_block() {
+        assert_eq!(dynamic_table_size_update(0), vec![0x20]);
+        assert_eq!(dynamic_table_size_update(30), vec![0x3e]);
+        assert_eq!(dynamic_table_size_update(4096), vec![0x3f, 0xe1, 0x1f]);
+
+        let mut state = State::new(16393);
+        state.reset_hpack_context();
+        state.answer(1, 404);
+        state.answer(3, 404);
+        let status = Encoder::new().encode([(&b":status"[..], &b"404"[..])]);
+        let mut reset = vec![0x20, 0x3f, 0xe1, 0x1f];
+        reset.extend_from_slice(&status);
+        assert_eq!(
+            state.output.drain(..).collect::<Vec<_>>(),
+            vec![
+                OutputFrame::headers(1, reset.clone(), true),
+                OutputFrame::headers(3, status, true),
+            ]
+        );
+        // the updates come before the fields, the block decodes as before
+        assert_eq!(
+            hpack::Decoder::new().decode(&reset).unwrap(),
+            vec![(b":status".to_vec(), b"404".to_vec())]
+        );
+    }
+
+    #[test]
+    fn hpack_reset_empties_our_decoder_through_the_settings() {
+        let mut encoder = hpack::Encoder::new();
+        let request: [(&[u8], &[u8]); 5] = [
+            (b":method", b"GET"),
+            (b":scheme", b"https"),
+            (b":path", b"/"),
+            (b":authority", b"example.com"),
+            (b"x-request", b"first"),
+        ];
+        let first = encoder.encode(request);
+        let indexed = encoder.encode(request);
+        let headers = |stream_id, block| {
+            parser::Frame::Headers(parser::Headers {
+                stream_id,
+                stream_dependency: None,
+                weight: None,
+                header_block_fragment: block,
+                end_stream: true,
+                end_headers: true,
+                priority: false,
+            })
+        };
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        assert_eq!(
+            state.handle(&headers(1, &first)),
+            FrameResult::ConnectBackend(1)
+        );
+
+        state.reset_hpack_context();
+        assert_eq!(
+            state.output.drain(..).collect::<Vec<_>>(),
+            vec![
+                OutputFrame::settings(&[parser::Setting {
+                    identifier: 1,
+                    value: 0
+                }]),
+                OutputFrame::settings(&[parser::Setting {
+                    identifier: 1,
+                    value: 4096
+                }]),
+            ]
+        );
+        let ack = parser::Frame::Settings(parser::Settings {
+            settings: Vec::new(),
+            ack: true,
+        });
+        assert_eq!(state.handle(&ack), FrameResult::Continue);
+        assert_eq!(state.decoder.max_table_size, 4096);
+        assert_eq!(state.handle(&ack), FrameResult::Continue);
+        assert_eq!(state.local_settings.header_table_size, 4096);
+
+        // the client empties its table, then the entries it indexed are gone
+        let mut emptied = dynamic_table_size_update(0);
+        emptied.extend(dynamic_table_size_update(4096));
+        let mut reindexed = emptied.clone();
+        reindexed.extend(hpack::Encoder::new().encode(request));
+        assert_eq!(
+            state.handle(&headers(3, &reindexed)),
+            FrameResult::ConnectBackend(3)
+        );
+        let mut stale = State::new(16393);
+        stale.state = St::ServerPrefaceSent;
+        assert_eq!(
+            stale.handle(&headers(1, &first)),
+            FrameResult::ConnectBackend(1)
+        );
+        emptied.extend(indexed);
+        assert_eq!(stale.handle(&headers(3, &emptied)), FrameResult::Close);
+    }
+
+    fn data_frame(stream_id: u32, flow_controlled_length: u32) -> parser::Frame<'static> {
+        parser::Frame::Data(parser::Data {
+            stream_id,
+            payload: &[],
+            end_stream: false,
+            flow_controlled_length,
+        })
+    }
+
+    #[test]
+    fn requests_flow_while_our_settings_wait_for_their_ack() {
+        let mut state = State::new(16393);
+        state.state = St::ClientPrefaceReceived;
+        state.settings.initial_window_size = 1 << 20;
+        let client_settings = parser::Frame::Settings(parser::Settings {
+            settings: Vec::new(),
+            ack: false,
+        });
+        assert_eq!(state.handle(&client_settings), FrameResult::Continue);
+        assert!(state.awaiting_settings_ack());
+
+        let block = hpack::Encoder::new().encode([
+            (&b":method"[..], &b"POST"[..]),
+            (&b":scheme"[..], &b"https"[..]),
+            (&b":path"[..], &b"/"[..]),
+            (&b":authority"[..], &b"example.com"[..]),
+        ]);
+        let headers = parser::Frame::Headers(parser::Headers {
+            stream_id: 1,
+            stream_dependency: None,
+            weight: None,
+            header_block_fragment: &block,
+            end_stream: false,
+            end_headers: true,
+            priority: false,
+        });
+        assert_eq!(state.handle(&headers), FrameResult::ConnectBackend(1));
+        // the client may only use the default window until it acknowledges ours
+        for _ in 0..3 {
+            assert_eq!(state.handle(&data_frame(1, 16384)), FrameResult::Continue);
+        }
+        assert!(state.awaiting_settings_ack());
+
+        let ack = parser::Frame::Settings(parser::Settings {
+            settings: Vec::new(),
+            ack: true,
+        });
+        assert_eq!(state.handle(&ack), FrameResult::Continue);
+        assert!(!state.awaiting_settings_ack());
+        assert_eq!(state.local_settings.initial_window_size, 1 << 20);
+    }
+
+    #[test]
+    fn padding_counts_against_flow_control() {
+        // a padded DATA frame: Pad Length of 10, 5 bytes of data, 10 bytes of padding
+        let mut input = vec![0, 0, 16, 0, parser::FLAG_PADDED, 0, 0, 0, 1, 10];
+        input.extend_from_slice(b"hello");
+        input.extend_from_slice(&[0; 10]);
+        let (_, frame) = parser::frame(&input, MIN_MAX_FRAME_SIZE).unwrap();
+        let parser::Frame::Data(data) = frame else {
+            panic!("expected a DATA frame, got {frame:?}");
+        };
+        assert_eq!(data.payload, b"hello");
+        assert_eq!(data.flow_controlled_length, 16);
+
+        // the padding may fill the frame, leaving no data
+        let mut input = vec![0, 0, 16, 0, parser::FLAG_PADDED, 0, 0, 0, 1, 15];
+        input.extend_from_slice(&[0; 15]);
+        let (_, frame) = parser::frame(&input, MIN_MAX_FRAME_SIZE).unwrap();
+        let parser::Frame::Data(data) = frame else {
+            panic!("expected a DATA frame, got {frame:?}");
+        };
+        assert!(data.payload.is_empty());
+        assert_eq!(data.flow_controlled_length, 16);
+
+        // but not overflow it
+        input[9] = 16;
+        assert!(parser::frame(&input, MIN_MAX_FRAME_SIZE).is_err());
+
+        let unpadded = parser::FrameHeader {
+            payload_len: 5,
+            frame_type: parser::FrameType::Data,
+            flags: 0,
+            stream_id: 1,
+        };
+        assert_eq!(parser::flow_controlled_length(&unpadded), 5);
+        let headers = parser::FrameHeader {
+            payload_len: 20,
+            frame_type: parser::FrameType::Headers,
+            flags: parser::FLAG_PADDED,
+            stream_id: 1,
+        };
+        assert_eq!(parser::flow_controlled_length(&headers), 0);
+    }
+
+    #[test]
+    fn receive_window_is_given_back_by_halves() {
+        let mut window = RecvWindow::new(DEFAULT_WINDOW_SIZE);
+        assert!(window.consume(16384));
+        assert_eq!(window.update(DEFAULT_WINDOW_UPDATE_THRESHOLD), None);
+        assert!(window.consume(16384));
+        assert_eq!(window.update(DEFAULT_WINDOW_UPDATE_THRESHOLD), Some(32768));
+        assert_eq!(window.available, DEFAULT_WINDOW_SIZE as i64);
+        assert!(window.consume(DEFAULT_WINDOW_SIZE));
+        assert!(!window.consume(1));
+    }
+
+    #[test]
+    fn steady_uploads_never_exhaust_the_client_windows() {
+        let upload = |threshold: u32, increment: u32| {
+            let mut state = State::new(16393);
+            state.state = St::ServerPrefaceSent;
+            state.window_update_threshold = threshold;
+            let mut stream = stream::Stream::new(1);
+            stream.state = stream::StreamState::Open;
+            state.streams.insert(1, stream);
+
+            // the windows as the client sees them
+            let mut connection_window = DEFAULT_WINDOW_SIZE;
+            let mut stream_window = DEFAULT_WINDOW_SIZE;
+            let mut updates = 0;
+            for _ in 0..64 {
+                assert!(connection_window >= 16384 && stream_window >= 16384);
+                connection_window -= 16384;
+                stream_window -= 16384;
+                assert_eq!(state.handle(&data_frame(1, 16384)), FrameResult::Continue);
+                while let Some(frame) = state.output.pop_front() {
+                    if frame == OutputFrame::window_update(0, increment) {
+                        connection_window += increment;
+                    } else if frame == OutputFrame::window_update(1, increment) {
+                        stream_window += increment;
+                    } else {
+                        panic!("unexpected frame: {frame:?}");
+                    }
+                    updates += 1;
+                }
+            }
+            updates
+        };
+        // a WINDOW_UPDATE for the connection and one for the stream every other frame
+        assert_eq!(upload(DEFAULT_WINDOW_UPDATE_THRESHOLD, 32768), 64);
+        // or after each of them
+        assert_eq!(upload(25, 16384), 128);
+    }
+
+    #[test]
+    fn window_overflows_are_flow_control_errors() {
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        state.recv_window = RecvWindow::new(10);
+        assert_eq!(state.handle(&data_frame(1, 16)), FrameResult::Close);
+        assert_eq!(
+            state.output.pop_front(),
+            Some(OutputFrame::goaway(0, parser::FLOW_CONTROL_ERROR))
+        );
+
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        let mut stream = stream::Stream::new(1);
+        stream.state = stream::StreamState::Open;
+        stream.recv_window = RecvWindow::new(10);
+        state.streams.insert(1, stream);
+        assert_eq!(
+            state.handle(&data_frame(1, 16)),
+            FrameResult::CancelStream(1)
+        );
+        assert_eq!(
+            state.output.pop_front(),
+            Some(OutputFrame::rst_stream(1, parser::FLOW_CONTROL_ERROR))
+        );
+        // the connection window still accounts for the refused frame
+        assert_eq!(state.recv_window.available, DEFAULT_WINDOW_SIZE as i64 - 16);
+    }
+
+    #[test]
+    fn zero_window_increments_are_protocol_errors() {
+        let window_update = |stream_id, increment| {
+            parser::Frame::WindowUpdate(parser::WindowUpdate {
+                stream_id,
+                increment,
+            })
+        };
+        // the reserved bit is dropped, the 31 bits of the increment are kept
+        let input = [0, 0, 4, 8, 0, 0, 0, 0, 1, 0xFF, 0xFF, 0xFF, 0xFF];
+        let (_, frame) = parser::frame(&input, MIN_MAX_FRAME_SIZE).unwrap();
+        assert_eq!(frame, window_update(1, 0x7FFF_FFFF));
+        let input = [0, 0, 4, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let (_, frame) = parser::frame(&input, MIN_MAX_FRAME_SIZE).unwrap();
+        assert_eq!(frame, window_update(0, 0));
+
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        let mut stream = stream::Stream::new(1);
+        stream.state = stream::StreamState::Open;
+        state.streams.insert(1, stream);
+
+        assert_eq!(state.handle(&window_update(0, 1024)), FrameResult::Continue);
+        assert_eq!(state.handle(&window_update(1, 1024)), FrameResult::Continue);
+        assert!(state.output.is_empty());
+
+        assert_eq!(
+            state.handle(&window_update(1, 0)),
+            FrameResult::CancelStream(1)
+        );
+        assert_eq!(
+            state.output.pop_front(),
+            Some(OutputFrame::rst_stream(1, parser::PROTOCOL_ERROR))
+        );
+        assert_eq!(state.streams[&1].state, stream::StreamState::Closed);
+
+        assert_eq!(state.handle(&window_update(0, 0)), FrameResult::Close);
+        assert_eq!(
+            state.output.pop_front(),
+            Some(OutputFrame::goaway(1, parser::PROTOCOL_ERROR))
+        );
+    }
+
+    #[test]
+    fn request_bodies_must_match_their_content_length() {
+        let open_stream = |content_length: &[u8]| {
+            let block = hpack::Encoder::new().encode([
+                (&b":method"[..], &b"POST"[..]),
+                (&b":scheme"[..], &b"https"[..]),
+                (&b":path"[..], &b"/"[..]),
+                (&b":authority"[..], &b"example.com"[..]),
+                (&b"content-length"[..], content_length),
+            ]);
+            let mut state = State::new(16393);
+            state.state = St::ServerPrefaceSent;
+            let result = state.handle(&parser::Frame::Headers(parser::Headers {
+                stream_id: 1,
+                stream_dependency: None,
+                weight: None,
+                header_block_fragment: &block,
+                end_stream: false,
+                end_headers: true,
+                priority: false,
+            }));
+            (state, result)
+        };
+        let data = |payload: &'static [u8], end_stream| {
+            parser::Frame::Data(parser::Data {
+                stream_id: 1,
+                payload,
+                end_stream,
+                flow_controlled_length: payload.len() as u32,
+            })
+        };
+
+        let (mut state, result) = open_stream(b"10");
+        assert_eq!(result, FrameResult::ConnectBackend(1));
+        assert_eq!(state.handle(&data(b"hello", false)), FrameResult::Continue);
+        assert_eq!(state.handle(&data(b"world", true)), FrameResult::Continue);
+        assert_eq!(
+            state.streams[&1].state,
+            stream::StreamState::HalfClosedRemote
+        );
+
+        let (mut state, _) = open_stream(b"4");
+        assert_eq!(
+            state.handle(&data(b"hello", false)),
+            FrameResult::CancelStream(1)
+        );
+        assert_eq!(
+            state.output.pop_front(),
+            Some(OutputFrame::rst_stream(1, parser::PROTOCOL_ERROR))
+        );
+        // DATA already in flight when the client gets the RST_STREAM is dropped
+        assert_eq!(state.handle(&data(b"!", true)), FrameResult::Continue);
+
+        let (mut state, _) = open_stream(b"10");
+        assert_eq!(
+            state.handle(&data(b"hello", true)),
+            FrameResult::CancelStream(1)
+        );
+        assert_eq!(state.streams[&1].state, stream::StreamState::Closed);
+
+        let (state, result) = open_stream(b"ten");
+        assert_eq!(result, FrameResult::CancelStream(1));
+        assert_eq!(
+            state.output.front(),
+            Some(&OutputFrame::rst_stream(1, parser::PROTOCOL_ERROR))
+        );
+    }
+
+    #[test]
+    fn data_after_end_stream_resets_the_stream() {
+        let block = hpack::Encoder::new().encode([
+            (&b":method"[..], &b"GET"[..]),
+            (&b":scheme"[..], &b"https"[..]),
+            (&b":path"[..], &b"/"[..]),
+            (&b":authority"[..], &b"example.com"[..]),
+        ]);
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        let headers = parser::Frame::Headers(parser::Headers {
+            stream_id: 1,
+            stream_dependency: None,
+            weight: None,
+            header_block_fragment: &block,
+            end_stream: true,
+            end_headers: true,
+            priority: false,
+        });
+        assert_eq!(state.handle(&headers), FrameResult::ConnectBackend(1));
+        assert_eq!(
+            state.streams[&1].state,
+            stream::StreamState::HalfClosedRemote
+        );
+
+        assert_eq!(
+            state.handle(&data_frame(1, 5)),
+            FrameResult::CancelStream(1)
+        );
+        assert_eq!(
+            state.output.pop_front(),
+            Some(OutputFrame::rst_stream(1, parser::STREAM_CLOSED))
+        );
+        assert_eq!(state.streams[&1].state, stream::StreamState::Closed);
+        assert_eq!(state.recv_window.available, DEFAULT_WINDOW_SIZE as i64 - 5);
+    }
+
+    #[test]
+    fn live_settings_apply_once_acknowledged() {
+        let mut state = State::new(16393);
+        let mut settings = H2Settings {
+            max_concurrent_streams: 200,
+            header_table_size: 65536,
+            ..H2Settings::default()
+        };
+        // the connection did not exchange its prefaces yet
+        assert!(!state.update_settings(&settings));
+
+        state.state = St::ServerPrefaceSent;
+        assert!(state.update_settings(&settings));
+        assert_eq!(
+            state.output.pop_front(),
+            Some(OutputFrame::settings(&[parser::Setting {
+                identifier: 3,
+                value: 200,
+            }]))
+        );
+        // nothing new to send
+        assert!(!state.update_settings(&settings));
+        settings.max_header_list_size = 8192;
+        assert!(state.update_settings(&settings));
+        assert_eq!(state.pending_settings.len(), 2);
+        assert_eq!(state.local_settings.max_concurrent_streams, u32::MAX);
+
+        let ack = parser::Frame::Settings(parser::Settings {
+            settings: Vec::new(),
+            ack: true,
+        });
+        assert_eq!(state.handle(&ack), FrameResult::Continue);
+        assert_eq!(state.local_settings.max_concurrent_streams, 200);
+        assert_eq!(state.local_settings.max_header_list_size, u32::MAX);
+        assert_eq!(state.handle(&ack), FrameResult::Continue);
+        assert_eq!(state.local_settings.max_header_list_size, 8192);
+        assert_eq!(state.local_settings.header_table_size, 4096);
+        assert!(state.pending_settings.is_empty());
+    }
+
+    #[test]
+    fn streams_over_the_limit_wait_in_the_queue() {
+        let block = hpack::Encoder::new().encode([
+            (&b":method"[..], &b"GET"[..]),
+            (&b":scheme"[..], &b"https"[..]),
+            (&b":path"[..], &b"/"[..]),
+            (&b":authority"[..], &b"example.com"[..]),
+        ]);
+        let headers = |stream_id| {
+            parser::Frame::Headers(parser::Headers {
+                stream_id,
+                stream_dependency: None,
+                weight: None,
+                header_block_fragment: &block,
+                end_stream: true,
+                end_headers: true,
+                priority: false,
+            })
+        };
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        state.local_settings.max_concurrent_streams = 1;
+        state.stream_queue.depth = 1;
+        state.stream_queue.timeout = Duration::from_millis(100);
+
+        assert_eq!(state.handle(&headers(1)), FrameResult::ConnectBackend(1));
+        assert_eq!(state.handle(&headers(3)), FrameResult::Continue);
+        assert!(state.output.is_empty());
+        // the queue is full
+        assert_eq!(state.handle(&headers(5)), FrameResult::Continue);
+        assert_eq!(
+            state.output.pop_front(),
+            Some(OutputFrame::rst_stream(5, parser::REFUSED_STREAM))
+        );
+
+        let now = Instant::now();
+        assert_eq!(state.next_queued_stream(now), None);
+        state.streams.get_mut(&1).unwrap().state = stream::StreamState::Closed;
+        assert_eq!(state.next_queued_stream(now), Some(3));
+        assert_eq!(state.active_streams(), 1);
+
+        state.streams.get_mut(&3).unwrap().state = stream::StreamState::Closed;
+        state.local_settings.max_concurrent_streams = 0;
+        assert_eq!(state.handle(&headers(7)), FrameResult::Continue);
+        let later = Instant::now() + Duration::from_millis(100);
+        assert_eq!(state.next_queued_stream(later), None);
+        assert_eq!(
+            state.output.pop_front(),
+            Some(OutputFrame::rst_stream(7, parser::REFUSED_STREAM))
+        );
+        assert!(state.stream_queue.streams.is_empty());
+    }
+
+    #[test]
+    fn new_streams_are_limited_by_a_token_bucket() {
+        let start = Instant::now();
+        let mut limit = StreamRateLimit::new(2);
+        assert!(limit.allow(start));
+        assert!(limit.allow(start));
+        assert!(!limit.allow(start));
+        assert_eq!(limit.refused, 1);
+        // half a second gives one stream back
+        let later = start + Duration::from_millis(500);
+        assert!(limit.allow(later));
+        assert_eq!(limit.refused, 0);
+        assert!(!limit.allow(later));
+        assert!(!limit.sustained());
+        assert!(!limit.allow(later));
+        assert!(limit.sustained());
+
+        let mut unlimited = StreamRateLimit::default();
+        assert!((0..1000).all(|_| unlimited.allow(start)));
+    }
+
+    #[test]
+    fn streams_opened_too_fast_are_refused_then_the_connection_closed() {
+        let block = hpack::Encoder::new().encode([
+            (&b":method"[..], &b"GET"[..]),
+            (&b":scheme"[..], &b"https"[..]),
+            (&b":path"[..], &b"/"[..]),
+            (&b":authority"[..], &b"example.com"[..]),
+        ]);
+        let headers = |stream_id, end_headers| {
+            parser::Frame::Headers(parser::Headers {
+                stream_id,
+                stream_dependency: None,
+                weight: None,
+                header_block_fragment: &block,
+                end_stream: true,
+                end_headers,
+                priority: false,
+            })
+        };
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        state.stream_rate_limit = StreamRateLimit::new(2);
+
+        assert_eq!(
+            state.handle(&headers(1, true)),
+            FrameResult::ConnectBackend(1)
+        );
+        assert_eq!(
+            state.handle(&headers(3, true)),
+            FrameResult::ConnectBackend(3)
+        );
+        // refused, the rest of its header block is skipped
+        assert_eq!(state.handle(&headers(5, false)), FrameResult::Continue);
+        assert_eq!(
+            state.output.pop_front(),
+            Some(OutputFrame::rst_stream(5, parser::REFUSED_STREAM))
+        );
+        let continuation = parser::Frame::Continuation(parser::Continuation {
+            stream_id: 5,
+            header_block_fragment: &[],
+            end_headers: true,
+        });
+        assert_eq!(state.handle(&continuation), FrameResult::Continue);
+
+        assert_eq!(state.handle(&headers(7, true)), FrameResult::Close);
+        assert_eq!(
+            state.output.pop_front(),
+            Some(OutputFrame::goaway(3, parser::ENHANCE_YOUR_CALM))
+        );
+    }
+
+    #[test]
+    fn empty_header_blocks_do_not_panic() {
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        // an empty HEADERS frame, then one whose payload is all padding
+        let empty = [0, 0, 0, 1, parser::FLAG_END_HEADERS, 0, 0, 0, 1];
+        let padded = [
+            0,
+            0,
+            3,
+            1,
+            parser::FLAG_END_HEADERS | parser::FLAG_PADDED,
+            0,
+            0,
+            0,
+            3,
+            2,
+            0,
+            0,
+        ];
+        for (input, stream_id) in [(&empty[..], 1), (&padded[..], 3)] {
+            let (_, frame) = parser::frame(input, MIN_MAX_FRAME_SIZE).unwrap();
+            // an empty request misses its pseudo-headers
+            assert_eq!(state.handle(&frame), FrameResult::CancelStream(stream_id));
+            assert!(state.streams[&stream_id].request_headers.is_empty());
+            assert_eq!(
+                state.output.pop_front(),
+                Some(OutputFrame::rst_stream(stream_id, parser::PROTOCOL_ERROR))
+            );
+        }
+
+        // header values are not always UTF-8
+        let block = hpack::Encoder::new().encode([
+            (&b":method"[..], &b"GET"[..]),
+            (&b":scheme"[..], &b"https"[..]),
+            (&b":path"[..], &b"/"[..]),
+            (&b":authority"[..], &b"example.com"[..]),
+            (&b"x-binary"[..], &[0xff, 0xfe][..]),
+        ]);
+        let headers = parser::Frame::Headers(parser::Headers {
+            stream_id: 5,
+            stream_dependency: None,
+            weight: None,
+            header_block_fragment: &block,
+            end_stream: true,
+            end_headers: true,
+            priority: false,
+        });
+        assert_eq!(state.handle(&headers), FrameResult::ConnectBackend(5));
+        assert_eq!(
+            state.streams[&5].header(b"x-binary"),
+            Some(&[0xff, 0xfe][..])
+        );
+    }
+
+    #[test]
+    fn priority_headers_are_parsed() {
+        let parse = |value: &str| stream::Priority::parse(value.as_bytes());
+        let priority = |urgency, incremental| stream::Priority {
+            urgency,
+            incremental,
+        };
+
+        assert_eq!(parse(""), priority(3, false));
+        assert_eq!(parse("u=0"), priority(0, false));
+        assert_eq!(parse("u=5, i"), priority(5, true));
+        assert_eq!(parse("i=?1,u=1"), priority(1, true));
+        assert_eq!(parse("u=2;foo=bar, i=?0"), priority(2, false));
+        // invalid values and unknown members are ignored
+        assert_eq!(parse("u=8"), priority(3, false));
+        assert_eq!(parse("u=a, i=1, x=4"), priority(3, false));
+    }
+
+    #[test]
+    fn the_most_urgent_queued_stream_starts_first() {
+        let blocks = [None, Some("u=5"), Some("u=1"), Some("u=1, i")].map(|priority| {
+            let mut headers = vec![
+                (&b":method"[..], &b"GET"[..]),
+                (&b":scheme"[..], &b"https"[..]),
+                (&b":path"[..], &b"/"[..]),
+                (&b":authority"[..], &b"example.com"[..]),
+            ];
+            if let Some(priority) = priority {
+                headers.push((&b"priority"[..], priority.as_bytes()));
+            }
+            hpack::Encoder::new().encode(headers)
+        });
+        let mut state = State::new(16393);
+        state.state = St::ServerPrefaceSent;
+        state.local_settings.max_concurrent_streams = 1;
+        state.stream_queue.depth = 3;
+        state.stream_queue.timeout = Duration::from_secs(10);
+
+        for (index, block) in blocks.iter().enumerate() {
+            let stream_id = 2 * index as u32 + 1;
+            state.handle(&parser::Frame::Headers(parser::Headers {
+                stream_id,
+                stream_dependency: None,
+                weight: None,
+                header_block_fragment: block,
+                end_stream: true,
+                end_headers: true,
+                priority: false,
+            }));
+        }
+        assert_eq!(state.streams[&1].priority, stream::Priority::default());
+        assert_eq!(state.stream_queue.streams.len(), 3);
+
+        let now = Instant::now();
+        let mut active = 1;
+        for stream_id in [5, 7, 3] {
+            state.streams.get_mut(&active).unwrap().state = stream::StreamState::Closed;
+            assert_eq!(state.next_queued_stream(now), Some(stream_id));
+            active = stream_id;
+        }
+    }
+
+    #[test]
+    fn request_pseudo_headers_are_validated() {
+        let request = |fields: &[(&str, &str)]| -> Vec<(Vec<u8>, Vec<u8>)> {
+            fields
+                .iter()
+                .map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+                .collect()
+        };
+        let validate =
+            |fields: &[(&str, &str)]| stream::validate_request_pseudo_headers(&request(fields));
+
+        let get = [
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":authority", "example.com"),
+            (":path", "/"),
+            ("accept", "*/*"),
+        ];
+        assert_eq!(validate(&get), Ok(()));
+        assert!(validate(&get[1..]).is_err());
+        assert!(validate(&[get[0], get[2], get[3]]).is_err());
+        assert!(validate(&[get[0], get[1], get[2]]).is_err());
+        assert!(validate(&[get[0], get[1], (":path", "")]).is_err());
+        assert!(validate(&[get[0], get[1], get[3], get[0]]).is_err());
+        assert!(validate(&[get[0], get[1], get[4], get[3]]).is_err());
+        assert!(validate(&[get[0], get[1], get[3], (":status", "200")]).is_err());
+        assert!(validate(&[get[0], get[1], get[3], (":protocol", "websocket")]).is_err());
+
+        let connect = [(":method", "CONNECT"), (":authority", "example.com:443")];
+        assert_eq!(validate(&connect), Ok(()));
+        assert!(validate(&connect[..1]).is_err());
+        assert!(validate(&[connect[0], connect[1], (":path", "/")]).is_err());
+        assert!(validate(&[connect[0], connect[1], (":scheme", "https")]).is_err());
+        // extended CONNECT (RFC 8441) looks like a regular request
+        assert_eq!(
+            validate(&[
+                connect[0],
+                (":protocol", "websocket"),
+                (":scheme", "https"),
+                (":path", "/chat"),
+                connect[1],
+            ]),
+            Ok(())
+        );
     }
 }
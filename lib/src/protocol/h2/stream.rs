@@ -1,13 +1,9 @@
-use std::{
-    collections::{HashMap, VecDeque},
-    str::from_utf8,
-};
-
-use hpack::Decoder;
+use std::{borrow::Cow, collections::VecDeque, time::Instant};
 
 use super::{
     parser,
-    state::{FrameResult, OutputFrame},
+    state::{FrameResult, HeaderDecoder, OutputFrame, RecvWindow, DEFAULT_WINDOW_SIZE},
+    translation::{request_accepts_trailers, request_host},
 };
 
 #[derive(Clone, Debug, PartialEq)]
@@ -17,12 +13,176 @@ pub enum St {
     ServerPrefaceSent,
 }
 
+/// A stream of a client connection.
+///
+/// Sōzu is the server side of the connection: what a stream receives is the request,
+/// going from the client to the backend, what it sends is the response, going from the
+/// backend to the client. Fields are named after that direction, never after the
+/// position (frontend or backend) of the code using them.
 #[derive(Clone, Debug, PartialEq)]
 pub struct Stream {
     pub id: u32,
     pub state: StreamState,
-    pub output: VecDeque<OutputFrame>,
-    pub inbound_headers: HashMap<Vec<u8>, Vec<u8>>,
+    /// response frames waiting to be sent to the client
+    pub response_frames: VecDeque<OutputFrame>,
+    /// request headers received from the client, in their arrival order
+    pub request_headers: Vec<(Vec<u8>, Vec<u8>)>,
+    /// trailers ending the request body, in their arrival order
+    pub request_trailers: Vec<(Vec<u8>, Vec<u8>)>,
+    /// uncompressed size of the request header list, counted while it is decoded
+    pub request_header_size: usize,
+    /// past this request header list size, the decoded fields are only counted
+    pub max_request_header_size: usize,
+    /// header block received so far, while waiting for the END_HEADERS flag
+    pub header_block: Option<Vec<u8>>,
+    /// the request HEADERS carried END_STREAM, the stream becomes half-closed
+    /// (remote) once the header block is complete
+    pub end_stream_received: bool,
+    /// request body bytes the client may still send on this stream
+    pub recv_window: RecvWindow,
+    /// priority signaled by the client in the request headers
+    pub priority: Priority,
+    /// length of the request body announced by its Content-Length header
+    pub request_content_length: Option<usize>,
+    /// request body bytes received so far, without the padding
+    pub request_body_length: usize,
+    /// the request has `te: trailers`, the trailers of the response can be forwarded
+    pub accepts_trailers: bool,
+    /// where the exchange of the stream stands, to tell what a stuck stream waits for
+    pub phase: StreamPhase,
+    /// the HEADERS of the response were queued, a later HEADERS carries its trailers
+    pub response_headers_sent: bool,
+    /// response DATA frames queued for the client and not written yet
+    pub pending_data_frames: usize,
+    /// since when the queued response DATA frames wait without any of them being
+    /// written, None when there are none
+    pub send_stalled_since: Option<Instant>,
+}
+
+/// Progress of the request and response of a stream. Unlike `StreamState`, which
+/// follows the frames of RFC 9113, it tells which side the stream waits on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamPhase {
+    /// the header block of the request is not complete
+    AwaitingHeaders,
+    /// the request headers are complete, the stream waits for a backend
+    Routing,
+    /// forwarded to a backend while the client sends the request body
+    StreamingRequest,
+    /// the request is complete, its response did not start
+    AwaitingResponse,
+    /// the response headers are sent, its body follows
+    StreamingResponse,
+    /// answered, reset or refused
+    Done,
+}
+
+/// Priority of a response, from the `priority` request header of RFC 9218
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Priority {
+    /// from 0, the most urgent, to 7
+    pub urgency: u8,
+    /// the response is useful as it arrives and can share the bandwidth
+    pub incremental: bool,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority {
+            urgency: 3,
+            incremental: false,
+        }
+    }
+}
+
+impl Priority {
+    /// Parse the value of a `priority` header, a structured field dictionary.
+    /// Unknown members and invalid values are ignored, as RFC 9218 requires.
+    pub fn parse(value: &[u8]) -> Priority {
+        let mut priority = Priority::default();
+        for member in value.split(|c| *c == b',') {
+            // the parameters of a member carry nothing for us
+            let member = member.split(|c| *c == b';').next().unwrap_or_default();
+            let member = member.trim_ascii();
+            let (key, value) = match member.iter().position(|c| *c == b'=') {
+                Some(index) => (&member[..index], Some(&member[index + 1..])),
+                None => (member, None),
+            };
+            match (key, value) {
+                (b"u", Some([digit @ b'0'..=b'7'])) => priority.urgency = digit - b'0',
+                (b"i", None | Some(b"?1")) => priority.incremental = true,
+                (b"i", Some(b"?0")) => priority.incremental = false,
+                _ => {}
+            }
+        }
+        priority
+    }
+}
+
+/// pseudo-header fields a request may carry (RFC 9113 section 8.3.1, RFC 8441)
+const REQUEST_PSEUDO_HEADERS: [&[u8]; 5] = [
+    b":method",
+    b":scheme",
+    b":authority",
+    b":path",
+    b":protocol",
+];
+
+/// Check the pseudo-header fields of a request: all known, none repeated, all
+/// before the regular fields, with `:method`, `:scheme` and a non empty `:path`.
+/// A CONNECT request has an `:authority` and neither `:scheme` nor `:path`,
+/// unless it is an extended CONNECT carrying a `:protocol`.
+pub fn validate_request_pseudo_headers(headers: &[(Vec<u8>, Vec<u8>)]) -> Result<(), &'static str> {
+    let mut pseudo_headers: Vec<(&[u8], &[u8])> = Vec::new();
+    let mut regular_seen = false;
+    for (name, value) in headers {
+        if !name.starts_with(b":") {
+            regular_seen = true;
+            continue;
+        }
+        if regular_seen {
+            return Err("pseudo-header after a regular header");
+        }
+        if !REQUEST_PSEUDO_HEADERS.contains(&name.as_slice()) {
+            return Err("unknown pseudo-header");
+        }
+        if pseudo_headers
+            .iter()
+            .any(|(seen, _)| *seen == name.as_slice())
+        {
+            return Err("repeated pseudo-header");
+        }
+        pseudo_headers.push((name, value));
+    }
+
+    let get = |name: &[u8]| {
+        pseudo_headers
+            .iter()
+            .find(|(seen, _)| *seen == name)
+            .map(|(_, value)| *value)
+    };
+    let method = get(b":method").ok_or("missing :method")?;
+    let extended_connect = get(b":protocol").is_some();
+    if extended_connect && method != b"CONNECT" {
+        return Err(":protocol outside of a CONNECT request");
+    }
+    if method == b"CONNECT" && !extended_connect {
+        if get(b":scheme").is_some() || get(b":path").is_some() {
+            return Err("CONNECT request with :scheme or :path");
+        }
+        if get(b":authority").is_none() {
+            return Err("CONNECT request without :authority");
+        }
+        return Ok(());
+    }
+    if get(b":scheme").is_none() {
+        return Err("missing :scheme");
+    }
+    match get(b":path") {
+        None => Err("missing :path"),
+        Some([]) => Err("empty :path"),
+        Some(_) => Ok(()),
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -43,59 +203,412 @@ impl Stream {
         Stream {
             id,
             state: StreamState::Idle,
-            output: VecDeque::new(),
-            inbound_headers: HashMap::new(),
+            response_frames: VecDeque::new(),
+            request_headers: Vec::new(),
+            request_trailers: Vec::new(),
+            request_header_size: 0,
+            max_request_header_size: usize::MAX,
+            header_block: None,
+            end_stream_received: false,
+            recv_window: RecvWindow::new(DEFAULT_WINDOW_SIZE),
+            priority: Priority::default(),
+            request_content_length: None,
+            request_body_length: 0,
+            accepts_trailers: false,
+            phase: StreamPhase::AwaitingHeaders,
+            response_headers_sent: false,
+            pending_data_frames: 0,
+            send_stalled_since: None,
+        }
+    }
+
+    pub fn set_phase(&mut self, phase: StreamPhase) {
+        if self.phase != phase {
+            debug!("stream[{}] phase is now {:?}", self.id, phase);
+            self.phase = phase;
+        }
+    }
+
+    /// The stream is closed, whether it completed or was reset
+    pub fn close(&mut self) {
+        self.state = StreamState::Closed;
+        self.set_phase(StreamPhase::Done);
+    }
+
+    /// The request is forwarded to a backend, the rest of its body follows
+    pub fn forwarded(&mut self) {
+        self.set_phase(if self.end_stream_received {
+            StreamPhase::AwaitingResponse
+        } else {
+            StreamPhase::StreamingRequest
+        });
+    }
+
+    /// Drop what the stream holds for its request, once it is refused or reset:
+    /// nothing of it will be forwarded
+    pub fn release_request(&mut self) {
+        self.request_headers = Vec::new();
+        self.request_trailers = Vec::new();
+        self.header_block = None;
+    }
+
+    /// value of the first request header with this name
+    pub fn header(&self, name: &[u8]) -> Option<&[u8]> {
+        self.request_headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// The request is a CONNECT, extended or not: the DATA frames of the stream
+    /// carry the bytes of a tunnel in both directions, until an END_STREAM
+    pub fn is_connect(&self) -> bool {
+        self.header(b":method") == Some(b"CONNECT")
+    }
+
+    /// host of the request, from its `:authority` or its `Host` header. An empty
+    /// one names no host either
+    pub fn authority(&self) -> Option<&[u8]> {
+        request_host(&self.request_headers)
+            .ok()
+            .flatten()
+            .filter(|authority| !authority.is_empty())
+    }
+
+    /// A response DATA frame was queued for the client
+    pub fn data_frame_queued(&mut self, now: Instant) {
+        self.pending_data_frames += 1;
+        self.send_stalled_since.get_or_insert(now);
+    }
+
+    /// A response DATA frame was written: the client reads, the wait for the
+    /// remaining ones starts over
+    pub fn data_frame_written(&mut self, now: Instant) {
+        self.pending_data_frames = self.pending_data_frames.saturating_sub(1);
+        self.send_stalled_since = (self.pending_data_frames > 0).then_some(now);
+    }
+
+    /// Our response was sent with END_STREAM. If the client is still sending the
+    /// request body, the stream becomes half-closed (local), else it is closed
+    pub fn end_response(&mut self) {
+        self.state = match self.state {
+            StreamState::Open => StreamState::HalfClosedLocal,
+            _ => StreamState::Closed,
+        };
+        self.set_phase(StreamPhase::Done);
+        info!("stream[{}] state is now {:?}", self.id, self.state);
+    }
+
+    /// Collect a HEADERS or CONTINUATION frame, giving the header block once it is
+    /// complete. The block continues in CONTINUATION frames until END_HEADERS,
+    /// decoding a part of it would corrupt the HPACK dynamic table
+    fn complete_header_block<'a>(
+        &mut self,
+        frame: &parser::Frame<'a>,
+    ) -> Result<Option<Cow<'a, [u8]>>, ()> {
+        match frame {
+            parser::Frame::Headers(h) => {
+                self.end_stream_received = h.end_stream;
+                if h.end_headers {
+                    return Ok(Some(Cow::Borrowed(h.header_block_fragment)));
+                }
+                self.header_block = Some(h.header_block_fragment.to_vec());
+                Ok(None)
+            }
+            parser::Frame::Continuation(c) => {
+                let Some(block) = self.header_block.as_mut() else {
+                    error!("stream[{}] CONTINUATION without a header block", self.id);
+                    return Err(());
+                };
+                block.extend_from_slice(c.header_block_fragment);
+                if !c.end_headers {
+                    return Ok(None);
+                }
+                Ok(self.header_block.take().map(Cow::Owned))
+            }
+            _ => Ok(None),
         }
     }
 
-    pub fn handle(&mut self, frame: &parser::Frame) -> FrameResult {
+    pub fn handle(&mut self, frame: &parser::Frame, decoder: &mut HeaderDecoder) -> FrameResult {
+        if let parser::Frame::RstStream(rst) = frame {
+            info!(
+                "stream[{}] reset by the peer with error code {}",
+                self.id, rst.error_code
+            );
+            self.close();
+            return FrameResult::CancelStream(self.id);
+        }
+
         match self.state {
             StreamState::Idle => match frame {
-                parser::Frame::Headers(h) => {
-                    let mut decoder = Decoder::new();
-                    match decoder.decode(h.header_block_fragment) {
-                        Err(e) => {
-                            error!("error decoding headers: {:?}", e);
+                parser::Frame::Headers(_) | parser::Frame::Continuation(_) => {
+                    match self.complete_header_block(frame) {
+                        Err(()) => FrameResult::Close,
+                        Ok(None) => FrameResult::Continue,
+                        Ok(Some(block)) => self.decode_headers(&block, decoder),
+                    }
+                }
+                frame => self.unexpected(frame, parser::PROTOCOL_ERROR),
+            },
+            StreamState::Open => match frame {
+                parser::Frame::Data(data) => self.receive_data(data),
+                parser::Frame::Headers(_) | parser::Frame::Continuation(_) => {
+                    match self.complete_header_block(frame) {
+                        Err(()) => FrameResult::Close,
+                        Ok(None) => FrameResult::Continue,
+                        Ok(Some(block)) => self.decode_trailers(&block, decoder),
+                    }
+                }
+                frame => self.unexpected(frame, parser::PROTOCOL_ERROR),
+            },
+            // the stream was reset, the frames the client sent before receiving the
+            // RST_STREAM are dropped (RFC 9113 section 5.4.2), header blocks once
+            // decoded for the dynamic table
+            StreamState::Closed => match frame {
+                parser::Frame::Headers(_) | parser::Frame::Continuation(_) => {
+                    match self.complete_header_block(frame) {
+                        Err(()) => FrameResult::Close,
+                        Ok(Some(block)) if decoder.decode(&block, |_, _| {}).is_err() => {
                             FrameResult::Close
                         }
-                        Ok(mut h) => {
-                            let mut has_authority = false;
-                            let mut has_path = false;
-
-                            self.inbound_headers.extend(h.drain(..).map(|(k, v)| {
-                                if &k == b"authority" {
-                                    has_authority = true;
-                                }
-
-                                if &k == b"path" {
-                                    has_path = true;
+                        Ok(_) => FrameResult::Continue,
+                    }
+                }
+                _ => FrameResult::Continue,
+            },
+            // the response is complete, the rest of the request is not needed anymore
+            // but the client sends it until its END_STREAM: the DATA frames were
+            // counted against the windows by the connection and are dropped here
+            StreamState::HalfClosedLocal => {
+                let end_stream = match frame {
+                    parser::Frame::Data(data) => data.end_stream,
+                    // the trailers of the request are decoded for the dynamic table,
+                    // then dropped
+                    parser::Frame::Headers(_) | parser::Frame::Continuation(_) => {
+                        match self.complete_header_block(frame) {
+                            Err(()) => return FrameResult::Close,
+                            Ok(None) => false,
+                            Ok(Some(block)) => {
+                                if decoder.decode(&block, |_, _| {}).is_err() {
+                                    return FrameResult::Close;
                                 }
-
-                                info!("{} -> {}", from_utf8(&k).unwrap(), from_utf8(&v).unwrap());
-                                (k, v)
-                            }));
-
-                            self.state = StreamState::Open;
-                            info!("stream[{}] state is now {:?}", self.id, self.state);
-                            info!("headers: {:?}", self.inbound_headers);
-
-                            if self.inbound_headers.contains_key(&b":authority"[..])
-                                && self.inbound_headers.contains_key(&b":path"[..])
-                            {
-                                info!("will send connect_to_backend");
-                                FrameResult::ConnectBackend(self.id)
-                            } else {
-                                FrameResult::Continue
+                                self.end_stream_received
                             }
                         }
                     }
+                    _ => false,
+                };
+                if end_stream {
+                    self.close();
+                    info!("stream[{}] state is now {:?}", self.id, self.state);
+                }
+                FrameResult::Continue
+            }
+            // the request is complete, the connection resets a half-closed (remote)
+            // stream receiving more of it. No stream is reserved, we never push
+            _ => self.unexpected(frame, parser::STREAM_CLOSED),
+        }
+    }
+
+    /// Decode the trailers of the request, which end it: the HEADERS frame must carry
+    /// END_STREAM and the trailers no pseudo-header field (RFC 9113 section 8.1)
+    fn decode_trailers(&mut self, header_block: &[u8], decoder: &mut HeaderDecoder) -> FrameResult {
+        let mut size = 0;
+        let mut fields = Vec::new();
+        let limit = self.max_request_header_size;
+        let decoded = decoder.decode(header_block, |name, value| {
+            size += name.len() + value.len() + 32;
+            if size <= limit {
+                fields.push((name.into_owned(), value.into_owned()));
+            }
+        });
+        if decoded.is_err() {
+            return FrameResult::Close;
+        }
+        if !self.end_stream_received {
+            return self.malformed("request trailers without END_STREAM");
+        }
+        if size > limit {
+            return self.malformed("request trailers over the header list limit");
+        }
+        if fields.iter().any(|(name, _)| name.starts_with(b":")) {
+            return self.malformed("pseudo-header in the request trailers");
+        }
+        if self
+            .request_content_length
+            .is_some_and(|expected| self.request_body_length < expected)
+        {
+            return self.malformed("request body shorter than its Content-Length");
+        }
+        self.request_trailers = fields;
+        self.state = StreamState::HalfClosedRemote;
+        if self.phase == StreamPhase::StreamingRequest {
+            self.set_phase(StreamPhase::AwaitingResponse);
+        }
+        info!("stream[{}] state is now {:?}", self.id, self.state);
+        FrameResult::Continue
+    }
+
+    /// Reset the stream on a frame its state does not allow (RFC 9113 section 5.1)
+    fn unexpected(&mut self, frame: &parser::Frame, error_code: u32) -> FrameResult {
+        error!(
+            "stream[{}] unexpected {:?} in state {:?}",
+            self.id, frame, self.state
+        );
+        self.reset(error_code)
+    }
+
+    /// Count a DATA frame of the request body. A body longer than its Content-Length,
+    /// or shorter once it ends, makes the request malformed (RFC 9113 section 8.1.1):
+    /// the stream is reset before the inconsistent body goes further.
+    fn receive_data(&mut self, data: &parser::Data) -> FrameResult {
+        self.request_body_length += data.payload.len();
+        if let Some(expected) = self.request_content_length {
+            if self.request_body_length > expected {
+                return self.malformed("request body longer than its Content-Length");
+            }
+            if data.end_stream && self.request_body_length < expected {
+                return self.malformed("request body shorter than its Content-Length");
+            }
+        }
+        if data.end_stream {
+            self.end_stream_received = true;
+            self.state = StreamState::HalfClosedRemote;
+            if self.phase == StreamPhase::StreamingRequest {
+                self.set_phase(StreamPhase::AwaitingResponse);
+            }
+            info!("stream[{}] state is now {:?}", self.id, self.state);
+        }
+        FrameResult::Continue
+    }
+
+    /// The Content-Length of the request is a single number, that an empty request
+    /// body must match
+    fn parse_content_length(&mut self) -> Result<(), &'static str> {
+        let mut values = self
+            .request_headers
+            .iter()
+            .filter(|(name, _)| name == b"content-length")
+            .map(|(_, value)| value);
+        let value = match (values.next(), values.next()) {
+            (None, _) => return Ok(()),
+            (Some(value), None) => value,
+            (Some(_), Some(_)) => return Err("repeated content-length"),
+        };
+        let length = std::str::from_utf8(value)
+            .ok()
+            .filter(|value| value.bytes().all(|c| c.is_ascii_digit()))
+            .and_then(|value| value.parse::<usize>().ok())
+            .ok_or("invalid content-length")?;
+        if self.end_stream_received && length != 0 {
+            return Err("request without a body has a non zero content-length");
+        }
+        self.request_content_length = Some(length);
+        Ok(())
+    }
+
+    /// Reset a malformed request with a PROTOCOL_ERROR. Its cancellation also closes
+    /// the backend connection the request may already be forwarded on
+    fn malformed(&mut self, reason: &str) -> FrameResult {
+        error!("stream[{}] malformed request: {}", self.id, reason);
+        incr!("http2.malformed_requests");
+        self.reset(parser::PROTOCOL_ERROR)
+    }
+
+    /// Close the stream and queue its RST_STREAM, the connection sends it
+    fn reset(&mut self, error_code: u32) -> FrameResult {
+        self.close();
+        self.release_request();
+        self.response_frames
+            .push_back(OutputFrame::rst_stream(self.id, error_code));
+        FrameResult::CancelStream(self.id)
+    }
+
+    /// Decode a complete header block, opening the stream. The size of the header
+    /// list is counted as the fields are decoded, whatever the size of the block:
+    /// a small block referencing the same large field many times is not expanded
+    /// past the limit. Such a stream is handed to the connection, that answers it
+    /// with a 431
+    fn decode_headers(&mut self, header_block: &[u8], decoder: &mut HeaderDecoder) -> FrameResult {
+        let mut size = 0;
+        let mut fields = Vec::new();
+        let limit = self.max_request_header_size;
+        let decoded = decoder.decode(header_block, |name, value| {
+            size += name.len() + value.len() + 32;
+            if size <= limit {
+                fields.push((name.into_owned(), value.into_owned()));
+            }
+        });
+        self.request_header_size = size;
+        match decoded {
+            Err(()) => FrameResult::Close,
+            Ok(()) if size > limit => {
+                error!(
+                    "stream[{}] request header list of {} bytes decoded past the limit of {}",
+                    self.id, size, limit
+                );
+                self.request_headers = Vec::new();
+                self.state = if self.end_stream_received {
+                    StreamState::HalfClosedRemote
+                } else {
+                    StreamState::Open
+                };
+                self.set_phase(StreamPhase::Routing);
+                FrameResult::ConnectBackend(self.id)
+            }
+            Ok(()) => {
+                let mut has_authority = false;
+                let mut has_path = false;
+
+                self.request_headers.extend(fields.drain(..).map(|(k, v)| {
+                    if &k == b"authority" {
+                        has_authority = true;
+                    }
+
+                    if &k == b"path" {
+                        has_path = true;
+                    }
+
+                    // names and values are not necessarily UTF-8
+                    info!(
+                        "{} -> {}",
+                        String::from_utf8_lossy(&k),
+                        String::from_utf8_lossy(&v)
+                    );
+                    (k, v)
+                }));
+
+                let valid = validate_request_pseudo_headers(&self.request_headers)
+                    .and_then(|_| request_host(&self.request_headers).map(|_| ()))
+                    .and_then(|_| self.parse_content_length())
+                    .and_then(|_| request_accepts_trailers(&self.request_headers));
+                match valid {
+                    Ok(accepts_trailers) => self.accepts_trailers = accepts_trailers,
+                    Err(reason) => return self.malformed(reason),
                 }
-                frame => {
-                    panic!("unknown frame for now: {:?}", frame);
+
+                if let Some(priority) = self.header(b"priority") {
+                    self.priority = Priority::parse(priority);
+                }
+
+                self.state = if self.end_stream_received {
+                    StreamState::HalfClosedRemote
+                } else {
+                    StreamState::Open
+                };
+                self.set_phase(StreamPhase::Routing);
+                info!("stream[{}] state is now {:?}", self.id, self.state);
+                info!("headers: {:?}", self.request_headers);
+
+                // a request without a host is checked against the policy of the listener,
+                // a CONNECT is routed on its :authority
+                if self.header(b":path").is_some() || self.is_connect() {
+                    info!("will send connect_to_backend");
+                    FrameResult::ConnectBackend(self.id)
+                } else {
+                    FrameResult::Continue
                 }
-            },
-            s => {
-                unimplemented!("stream[{}] state {:?} not implemented", self.id, self.state);
             }
         }
     }
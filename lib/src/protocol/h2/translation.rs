@@ -0,0 +1,399 @@
+use sozu_command_lib::proto::command::ResponseRewrite;
+
+use super::{state::H2Settings, stream::validate_request_pseudo_headers};
+use crate::protocol::http::{
+    parser::compare_no_case,
+    rewrite::{rewrite_location, rewrite_set_cookie},
+};
+
+type Headers = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// HTTP/1.1 fields describing the connection itself, they have no meaning in
+/// HTTP/2 (RFC 9113 section 8.2.2)
+const CONNECTION_SPECIFIC_HEADERS: [&[u8]; 5] = [
+    b"connection",
+    b"keep-alive",
+    b"proxy-connection",
+    b"transfer-encoding",
+    b"upgrade",
+];
+
+/// Host of an HTTP/2 request: its `:authority`, or its `Host` header when the
+/// client sent none. When both are present they must name the same host, else
+/// the request is malformed (RFC 9113 section 8.3.1).
+pub fn request_host(headers: &[(Vec<u8>, Vec<u8>)]) -> Result<Option<&[u8]>, &'static str> {
+    let authority = headers
+        .iter()
+        .find(|(name, _)| name == b":authority")
+        .map(|(_, value)| value.as_slice());
+    let mut hosts = headers
+        .iter()
+        .filter(|(name, _)| name == b"host")
+        .map(|(_, value)| value.as_slice());
+    let host = hosts.next();
+    if hosts.next().is_some() {
+        return Err("repeated Host header");
+    }
+    match (authority, host) {
+        (Some(authority), Some(host)) if !compare_no_case(authority, host) => {
+            Err(":authority and Host disagree")
+        }
+        (Some(authority), _) => Ok(Some(authority)),
+        (None, host) => Ok(host),
+    }
+}
+
+/// Whether an HTTP/2 request accepts trailers in its response, with `te: trailers`.
+/// Any other TE value makes the request malformed (RFC 9113 section 8.2.2).
+pub fn request_accepts_trailers(headers: &[(Vec<u8>, Vec<u8>)]) -> Result<bool, &'static str> {
+    let mut accepts_trailers = false;
+    for (_, value) in headers.iter().filter(|(name, _)| name == b"te") {
+        if !compare_no_case(value.trim_ascii(), b"trailers") {
+            return Err("TE other than trailers");
+        }
+        accepts_trailers = true;
+    }
+    Ok(accepts_trailers)
+}
+
+/// Header fields of the HTTP/1.1 request forwarding an HTTP/2 one: the host
+/// becomes the first field, `Host`, and the pseudo-header fields are dropped,
+/// they go in the request line. A request without a host gets an empty `Host`,
+/// as RFC 9112 section 3.2 requires. TE is a hop-by-hop field in HTTP/1.1, a
+/// `te: trailers` is listed in a `Connection` header for the backend to see it.
+pub fn h2_to_h1_headers(headers: &[(Vec<u8>, Vec<u8>)]) -> Result<Headers, &'static str> {
+    let host = request_host(headers)?.unwrap_or_default();
+    let mut translated = vec![(b"Host".to_vec(), host.to_vec())];
+    translated.extend(
+        headers
+            .iter()
+            .filter(|(name, _)| !name.starts_with(b":") && name != b"host")
+            .cloned(),
+    );
+    if request_accepts_trailers(headers)? {
+        translated.push((b"Connection".to_vec(), b"TE".to_vec()));
+    }
+    Ok(translated)
+}
+
+/// Header fields of the request forwarding an HTTP/2 one to a backend speaking
+/// HTTP/2. The pseudo-header fields are kept: a CONNECT stays a CONNECT, its DATA
+/// frames are relayed as they are in both directions. An extended CONNECT only
+/// goes to a backend that enabled it in its SETTINGS (RFC 8441 section 3). The
+/// request is checked again for this hop, the backend gets a valid one
+pub fn h2_to_h2_headers(
+    headers: &[(Vec<u8>, Vec<u8>)],
+    backend_settings: &H2Settings,
+) -> Result<Headers, &'static str> {
+    request_host(headers)?;
+    let translated: Headers = headers
+        .iter()
+        .filter(|(name, value)| {
+            !CONNECTION_SPECIFIC_HEADERS.contains(&name.as_slice())
+                && (name != b"te" || compare_no_case(value.trim_ascii(), b"trailers"))
+        })
+        .cloned()
+        .collect();
+    if translated.iter().any(|(name, _)| name == b":protocol")
+        && !backend_settings.enable_connect_protocol
+    {
+        return Err("extended CONNECT to a backend that did not enable it");
+    }
+    validate_request_pseudo_headers(&translated)?;
+    Ok(translated)
+}
+
+/// Trailer fields of an HTTP/1.1 response, forwarded to the HTTP/2 client only
+/// if its request had `te: trailers`. Names are lowercased and the
+/// connection-specific fields dropped, none is left for an empty trailer section.
+pub fn h1_to_h2_trailers(
+    trailers: &[(Vec<u8>, Vec<u8>)],
+    accepts_trailers: bool,
+) -> Option<Headers> {
+    if !accepts_trailers {
+        return None;
+    }
+    let translated: Headers = trailers
+        .iter()
+        .map(|(name, value)| (name.to_ascii_lowercase(), value.clone()))
+        .filter(|(name, _)| {
+            name != b"te" && !CONNECTION_SPECIFIC_HEADERS.contains(&name.as_slice())
+        })
+        .collect();
+    (!translated.is_empty()).then_some(translated)
+}
+
+/// Framing of the HTTP/1.1 response forwarding an HTTP/2 one whose trailers came
+/// right after its headers, without any DATA, like the trailers-only responses of
+/// gRPC: the header field to add to the response head, and the body. A client
+/// that can receive a chunked response gets a last chunk carrying the trailers at
+/// once. Others, like HTTP/1.0 clients, get an empty body with `Content-Length: 0`
+/// and the trailers are dropped, as RFC 9110 section 6.5.1 allows.
+pub fn h2_trailers_only_to_h1(
+    trailers: &[(Vec<u8>, Vec<u8>)],
+    chunked: bool,
+) -> ((Vec<u8>, Vec<u8>), Vec<u8>) {
+    if !chunked {
+        return ((b"Content-Length".to_vec(), b"0".to_vec()), Vec::new());
+    }
+    let mut body = b"0\r\n".to_vec();
+    for (name, value) in trailers.iter().filter(|(name, _)| {
+        !name.starts_with(b":")
+            && name != b"te"
+            && !CONNECTION_SPECIFIC_HEADERS.contains(&name.as_slice())
+    }) {
+        body.extend_from_slice(name);
+        body.extend_from_slice(b": ");
+        body.extend_from_slice(value);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(b"\r\n");
+    ((b"Transfer-Encoding".to_vec(), b"chunked".to_vec()), body)
+}
+
+/// Header fields of the HTTP/2 request forwarding an HTTP/1.1 one: the `Host`
+/// header becomes `:authority`, after `:method` and `:scheme` and before
+/// `:path`. Names are lowercased and the connection-specific fields dropped.
+pub fn h1_to_h2_headers(
+    method: &[u8],
+    scheme: &[u8],
+    path: &[u8],
+    headers: &[(Vec<u8>, Vec<u8>)],
+) -> Headers {
+    let mut translated = vec![
+        (b":method".to_vec(), method.to_vec()),
+        (b":scheme".to_vec(), scheme.to_vec()),
+    ];
+    if let Some((_, host)) = headers
+        .iter()
+        .find(|(name, _)| compare_no_case(name, b"host"))
+    {
+        translated.push((b":authority".to_vec(), host.clone()));
+    }
+    translated.push((b":path".to_vec(), path.to_vec()));
+    translated.extend(
+        headers
+            .iter()
+            .map(|(name, value)| (name.to_ascii_lowercase(), value.clone()))
+            .filter(|(name, value)| {
+                name != b"host"
+                    && !CONNECTION_SPECIFIC_HEADERS.contains(&name.as_slice())
+                    // "TE: trailers" is the only TE allowed in HTTP/2
+                    && (name != b"te" || compare_no_case(value, b"trailers"))
+            }),
+    );
+    translated
+}
+
+/// Point the `location` and `set-cookie` fields of a translated response naming a
+/// backend to the public host, like the HTTP/1.1 responses of the cluster.
+pub fn rewrite_response_headers(
+    rewrite: &ResponseRewrite,
+    scheme: &str,
+    authority: &str,
+    headers: &mut [(Vec<u8>, Vec<u8>)],
+) {
+    for (name, value) in headers.iter_mut() {
+        let rewritten = if compare_no_case(name, b"location") {
+            rewrite_location(rewrite, value, scheme, authority)
+        } else if compare_no_case(name, b"set-cookie") {
+            rewrite_set_cookie(rewrite, value, authority)
+        } else {
+            None
+        };
+        if let Some(rewritten) = rewritten {
+            *value = rewritten.into_bytes();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(fields: &[(&str, &str)]) -> Headers {
+        fields
+            .iter()
+            .map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn authority_and_host_must_agree() {
+        let authority_only = fields(&[(":authority", "example.com")]);
+        assert_eq!(request_host(&authority_only), Ok(Some(&b"example.com"[..])));
+
+        let host_only = fields(&[("host", "example.com")]);
+        assert_eq!(request_host(&host_only), Ok(Some(&b"example.com"[..])));
+
+        let both = fields(&[(":authority", "example.com"), ("host", "EXAMPLE.com")]);
+        assert_eq!(request_host(&both), Ok(Some(&b"example.com"[..])));
+
+        let conflict = fields(&[(":authority", "example.com"), ("host", "other.com")]);
+        assert!(request_host(&conflict).is_err());
+        assert!(h2_to_h1_headers(&conflict).is_err());
+
+        let repeated = fields(&[("host", "example.com"), ("host", "example.com")]);
+        assert!(request_host(&repeated).is_err());
+
+        assert_eq!(request_host(&fields(&[(":path", "/")])), Ok(None));
+        assert_eq!(
+            h2_to_h1_headers(&fields(&[(":path", "/")])),
+            Ok(fields(&[("Host", "")]))
+        );
+    }
+
+    #[test]
+    fn host_is_translated_in_both_directions() {
+        let h2 = fields(&[
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":authority", "example.com"),
+            (":path", "/"),
+            ("host", "example.com"),
+            ("accept", "*/*"),
+        ]);
+        assert_eq!(
+            h2_to_h1_headers(&h2),
+            Ok(fields(&[("Host", "example.com"), ("accept", "*/*")]))
+        );
+
+        let h1 = fields(&[
+            ("Host", "example.com"),
+            ("Connection", "keep-alive"),
+            ("TE", "trailers"),
+            ("Accept", "*/*"),
+        ]);
+        assert_eq!(
+            h1_to_h2_headers(b"GET", b"https", b"/", &h1),
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":authority", "example.com"),
+                (":path", "/"),
+                ("te", "trailers"),
+                ("accept", "*/*"),
+            ])
+        );
+    }
+
+    #[test]
+    fn te_trailers_is_negotiated_end_to_end() {
+        let h2 = fields(&[
+            (":method", "POST"),
+            (":scheme", "https"),
+            (":authority", "example.com"),
+            (":path", "/grpc.Service/Call"),
+            ("te", "trailers"),
+        ]);
+        assert_eq!(request_accepts_trailers(&h2), Ok(true));
+        assert_eq!(
+            h2_to_h1_headers(&h2),
+            Ok(fields(&[
+                ("Host", "example.com"),
+                ("te", "trailers"),
+                ("Connection", "TE"),
+            ]))
+        );
+
+        let gzip = fields(&[(":authority", "example.com"), ("te", "gzip")]);
+        assert!(request_accepts_trailers(&gzip).is_err());
+        assert!(h2_to_h1_headers(&gzip).is_err());
+
+        let trailers = fields(&[("Grpc-Status", "0"), ("Connection", "close")]);
+        assert_eq!(
+            h1_to_h2_trailers(&trailers, true),
+            Some(fields(&[("grpc-status", "0")]))
+        );
+        assert_eq!(h1_to_h2_trailers(&trailers, false), None);
+        assert_eq!(
+            h1_to_h2_trailers(&fields(&[("Connection", "close")]), true),
+            None
+        );
+    }
+
+    #[test]
+    fn trailers_only_responses_get_an_empty_body() {
+        let trailers = fields(&[
+            ("grpc-status", "13"),
+            ("grpc-message", "internal"),
+            ("connection", "close"),
+        ]);
+        assert_eq!(
+            h2_trailers_only_to_h1(&trailers, true),
+            (
+                (b"Transfer-Encoding".to_vec(), b"chunked".to_vec()),
+                b"0\r\ngrpc-status: 13\r\ngrpc-message: internal\r\n\r\n".to_vec()
+            )
+        );
+        // an HTTP/1.0 client can not receive trailers
+        assert_eq!(
+            h2_trailers_only_to_h1(&trailers, false),
+            ((b"Content-Length".to_vec(), b"0".to_vec()), Vec::new())
+        );
+    }
+
+    #[test]
+    fn translated_responses_are_rewritten() {
+        let rewrite = ResponseRewrite {
+            location_authorities: vec!["10.0.0.1:8080".to_owned()],
+            cookie_domains: vec!["backend.internal".to_owned()],
+            ..Default::default()
+        };
+        let mut headers = fields(&[
+            (":status", "302"),
+            ("location", "http://10.0.0.1:8080/login"),
+            ("set-cookie", "id=1; Domain=backend.internal"),
+            ("set-cookie", "lang=en"),
+        ]);
+        rewrite_response_headers(&rewrite, "https", "example.com", &mut headers);
+        assert_eq!(
+            headers,
+            fields(&[
+                (":status", "302"),
+                ("location", "https://example.com/login"),
+                ("set-cookie", "id=1; Domain=example.com"),
+                ("set-cookie", "lang=en"),
+            ])
+        );
+    }
+
+    #[test]
+    fn connect_requests_stay_connect_on_an_h2_backend() {
+        let backend = H2Settings::default();
+        let connect = fields(&[
+            (":method", "CONNECT"),
+            (":authority", "example.com:443"),
+            ("proxy-connection", "keep-alive"),
+            ("user-agent", "curl"),
+        ]);
+        assert_eq!(
+            h2_to_h2_headers(&connect, &backend),
+            Ok(fields(&[
+                (":method", "CONNECT"),
+                (":authority", "example.com:443"),
+                ("user-agent", "curl"),
+            ]))
+        );
+        let with_path = fields(&[
+            (":method", "CONNECT"),
+            (":authority", "example.com:443"),
+            (":path", "/"),
+        ]);
+        assert!(h2_to_h2_headers(&with_path, &backend).is_err());
+
+        let websocket = fields(&[
+            (":method", "CONNECT"),
+            (":protocol", "websocket"),
+            (":scheme", "https"),
+            (":authority", "example.com"),
+            (":path", "/chat"),
+        ]);
+        assert!(h2_to_h2_headers(&websocket, &backend).is_err());
+        let backend = H2Settings {
+            enable_connect_protocol: true,
+            ..H2Settings::default()
+        };
+        assert_eq!(h2_to_h2_headers(&websocket, &backend), Ok(websocket));
+    }
+}
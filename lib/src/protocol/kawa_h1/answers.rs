@@ -1,4 +1,7 @@
-use crate::{protocol::http::DefaultAnswer, sozu_command::state::ClusterId};
+use crate::{
+    protocol::http::{parser::compare_no_case, DefaultAnswer},
+    sozu_command::state::ClusterId,
+};
 use kawa::{
     h1::NoCallbacks, AsBuffer, Block, BodySize, Buffer, Chunk, Kawa, Kind, Pair, ParsingPhase,
     ParsingPhaseMarker, StatusLine, Store,
@@ -307,6 +310,14 @@ pub struct ListenerAnswers {
     pub answer_408: Template,
     /// PayloadTooLarge
     pub answer_413: Template,
+    /// URITooLong
+    pub answer_414: Template,
+    /// MisdirectedRequest
+    pub answer_421: Template,
+    /// RequestHeaderFieldsTooLarge
+    pub answer_431: Template,
+    /// NotImplemented
+    pub answer_501: Template,
     /// BadGateway
     pub answer_502: Template,
     /// ServiceUnavailable
@@ -327,6 +338,77 @@ pub struct ClusterAnswers {
 pub struct HttpAnswers {
     pub listener_answers: ListenerAnswers, // configurated answers
     pub cluster_custom_answers: HashMap<ClusterId, ClusterAnswers>,
+    /// Retry-After header of the 503 answers, set for some clusters
+    pub cluster_retry_after: HashMap<ClusterId, String>,
+}
+
+/// Whether a Retry-After value is a number of seconds, or an HTTP-date in the
+/// IMF-fixdate format, like "Sun, 06 Nov 1994 08:49:37 GMT" (RFC 9110 section 10.2.3)
+pub fn is_valid_retry_after(value: &str) -> bool {
+    const DAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let digits = |s: &str, len: usize| s.len() == len && s.bytes().all(|c| c.is_ascii_digit());
+    if !value.is_empty() && value.bytes().all(|c| c.is_ascii_digit()) {
+        return true;
+    }
+    let parts = value.split(' ').collect::<Vec<_>>();
+    let &[day_name, day, month, year, time, "GMT"] = parts.as_slice() else {
+        return false;
+    };
+    let time = time.split(':').collect::<Vec<_>>();
+    day_name
+        .strip_suffix(',')
+        .is_some_and(|day_name| DAYS.contains(&day_name))
+        && digits(day, 2)
+        && MONTHS.contains(&month)
+        && digits(year, 4)
+        && time.len() == 3
+        && time.iter().all(|part| digits(part, 2))
+}
+
+/// Add a Retry-After header to a filled answer, unless its template already has one
+fn add_retry_after(answer: &mut DefaultAnswerStream, retry_after: &str) {
+    let buf = answer.storage.buffer();
+    let has_retry_after = answer.blocks.iter().any(|block| match block {
+        Block::Header(Pair { key, .. }) => compare_no_case(key.data(buf), b"retry-after"),
+        _ => false,
+    });
+    if !has_retry_after {
+        // right after the status line
+        answer.blocks.insert(
+            1,
+            Block::Header(Pair {
+                key: Store::Static(b"Retry-After"),
+                val: Store::from_slice(retry_after.as_bytes()),
+            }),
+        );
+    }
+}
+
+/// Let a filled answer keep the connection open, by eliding its Connection header.
+/// Returns false if it has no Content-Length, the end of its body is then the end
+/// of the connection
+pub fn keep_connection(answer: &mut DefaultAnswerStream) -> bool {
+    let buf = answer.storage.buffer();
+    let has_length = answer.blocks.iter().any(|block| match block {
+        Block::Header(header) if !header.is_elided() => {
+            compare_no_case(header.key.data(buf), b"content-length")
+        }
+        _ => false,
+    });
+    if !has_length {
+        return false;
+    }
+    for block in &mut answer.blocks {
+        if let Block::Header(header) = block {
+            if !header.is_elided() && compare_no_case(header.key.data(buf), b"connection") {
+                header.elide();
+            }
+        }
+    }
+    true
 }
 
 // const HEADERS: &str = "Connection: close\r
@@ -409,6 +491,7 @@ fn default_401() -> String {
 HTTP/1.1 401 Unauthorized\r
 Cache-Control: no-cache\r
 Connection: close\r
+%Content-Length: %CONTENT_LENGTH\r
 Sozu-Id: %REQUEST_ID\r
 \r
 <html><head><meta charset='utf-8'><head><body>
@@ -425,12 +508,106 @@ Sozu-Id: %REQUEST_ID\r
     )
 }
 
+fn default_414() -> String {
+    String::from(
+        "\
+HTTP/1.1 414 URI Too Long\r
+Cache-Control: no-cache\r
+Connection: close\r
+Sozu-Id: %REQUEST_ID\r
+\r
+<html><head><meta charset='utf-8'><head><body>
+<style>pre{background:#EEE;padding:10px;border:1px solid #AAA;border-radius: 5px;}</style>
+<h1>414 URI Too Long</h1>
+<pre>
+{
+    \"status_code\": 414,
+    \"route\": \"%ROUTE\",
+    \"request_id\": \"%REQUEST_ID\"
+}
+</pre>
+<p>The target of the request is longer than this listener accepts.</p>
+<footer>This is an automatic answer by Sōzu.</footer></body></html>",
+    )
+}
+
+fn default_421() -> String {
+    String::from(
+        "\
+HTTP/1.1 421 Misdirected Request\r
+Cache-Control: no-cache\r
+Connection: close\r
+%Content-Length: %CONTENT_LENGTH\r
+Sozu-Id: %REQUEST_ID\r
+\r
+<html><head><meta charset='utf-8'><head><body>
+<style>pre{background:#EEE;padding:10px;border:1px solid #AAA;border-radius: 5px;}</style>
+<h1>421 Misdirected Request</h1>
+<pre>
+{
+    \"status_code\": 421,
+    \"route\": \"%ROUTE\",
+    \"request_id\": \"%REQUEST_ID\"
+}
+</pre>
+<p>This server is not able to produce a response for this host on this connection.</p>
+<footer>This is an automatic answer by Sōzu.</footer></body></html>",
+    )
+}
+
+fn default_431() -> String {
+    String::from(
+        "\
+HTTP/1.1 431 Request Header Fields Too Large\r
+Cache-Control: no-cache\r
+Connection: close\r
+Sozu-Id: %REQUEST_ID\r
+\r
+<html><head><meta charset='utf-8'><head><body>
+<style>pre{background:#EEE;padding:10px;border:1px solid #AAA;border-radius: 5px;}</style>
+<h1>431 Request Header Fields Too Large</h1>
+<pre>
+{
+    \"status_code\": 431,
+    \"route\": \"%ROUTE\",
+    \"request_id\": \"%REQUEST_ID\"
+}
+</pre>
+<p>The header section of the request is larger than this listener accepts.</p>
+<footer>This is an automatic answer by Sōzu.</footer></body></html>",
+    )
+}
+
+fn default_501() -> String {
+    String::from(
+        "\
+HTTP/1.1 501 Not Implemented\r
+Cache-Control: no-cache\r
+Connection: close\r
+Sozu-Id: %REQUEST_ID\r
+\r
+<html><head><meta charset='utf-8'><head><body>
+<style>pre{background:#EEE;padding:10px;border:1px solid #AAA;border-radius: 5px;}</style>
+<h1>501 Not Implemented</h1>
+<pre>
+{
+    \"status_code\": 501,
+    \"route\": \"%ROUTE\",
+    \"request_id\": \"%REQUEST_ID\"
+}
+</pre>
+<p>The request uses a transfer coding that is not supported, only chunked is.</p>
+<footer>This is an automatic answer by Sōzu.</footer></body></html>",
+    )
+}
+
 fn default_404() -> String {
     String::from(
         "\
 HTTP/1.1 404 Not Found\r
 Cache-Control: no-cache\r
 Connection: close\r
+%Content-Length: %CONTENT_LENGTH\r
 Sozu-Id: %REQUEST_ID\r
 \r
 <html><head><meta charset='utf-8'><head><body>
@@ -751,6 +928,26 @@ impl HttpAnswers {
                 answer,
                 &[length, route, request_id, capacity, message, phase],
             ),
+            414 => Template::new(
+                414,
+                answer,
+                &[length, route, request_id]
+            ),
+            421 => Template::new(
+                421,
+                answer,
+                &[length, route, request_id]
+            ),
+            431 => Template::new(
+                431,
+                answer,
+                &[length, route, request_id]
+            ),
+            501 => Template::new(
+                501,
+                answer,
+                &[length, route, request_id]
+            ),
             502 => Template::new(
                 502,
                 answer,
@@ -815,6 +1012,30 @@ impl HttpAnswers {
                         .and_then(|c| c.answer_413.clone())
                         .unwrap_or(default_413()),
                 )?,
+                answer_414: Self::template(
+                    414,
+                    conf.as_ref()
+                        .and_then(|c| c.answer_414.clone())
+                        .unwrap_or(default_414()),
+                )?,
+                answer_421: Self::template(
+                    421,
+                    conf.as_ref()
+                        .and_then(|c| c.answer_421.clone())
+                        .unwrap_or(default_421()),
+                )?,
+                answer_431: Self::template(
+                    431,
+                    conf.as_ref()
+                        .and_then(|c| c.answer_431.clone())
+                        .unwrap_or(default_431()),
+                )?,
+                answer_501: Self::template(
+                    501,
+                    conf.as_ref()
+                        .and_then(|c| c.answer_501.clone())
+                        .unwrap_or(default_501()),
+                )?,
                 answer_502: Self::template(
                     502,
                     conf.as_ref()
@@ -841,6 +1062,7 @@ impl HttpAnswers {
                 )?,
             },
             cluster_custom_answers: HashMap::new(),
+            cluster_retry_after: HashMap::new(),
         })
     }
 
@@ -859,6 +1081,20 @@ impl HttpAnswers {
         self.cluster_custom_answers.remove(cluster_id);
     }
 
+    /// Set or remove the Retry-After header of the 503 answers of a cluster
+    pub fn set_retry_after(&mut self, cluster_id: &str, retry_after: Option<String>) {
+        match retry_after {
+            Some(retry_after) => self
+                .cluster_retry_after
+                .insert(cluster_id.to_string(), retry_after),
+            None => self.cluster_retry_after.remove(cluster_id),
+        };
+    }
+
+    pub fn retry_after(&self, cluster_id: &str) -> Option<&str> {
+        self.cluster_retry_after.get(cluster_id).map(String::as_str)
+    }
+
     pub fn get(
         &self,
         answer: DefaultAnswer,
@@ -867,6 +1103,10 @@ impl HttpAnswers {
         backend_id: Option<&str>,
         route: String,
     ) -> DefaultAnswerStream {
+        let retry_after = match answer {
+            DefaultAnswer::Answer503 { .. } => cluster_id.and_then(|id| self.retry_after(id)),
+            _ => None,
+        };
         let variables: Vec<Vec<u8>>;
         let mut variables_once: Vec<Vec<u8>>;
         let template = match answer {
@@ -922,6 +1162,26 @@ impl HttpAnswers {
                 variables_once = vec![message.into()];
                 &self.listener_answers.answer_413
             }
+            DefaultAnswer::Answer414 {} => {
+                variables = vec![route.into(), request_id.into()];
+                variables_once = vec![];
+                &self.listener_answers.answer_414
+            }
+            DefaultAnswer::Answer421 {} => {
+                variables = vec![route.into(), request_id.into()];
+                variables_once = vec![];
+                &self.listener_answers.answer_421
+            }
+            DefaultAnswer::Answer431 {} => {
+                variables = vec![route.into(), request_id.into()];
+                variables_once = vec![];
+                &self.listener_answers.answer_431
+            }
+            DefaultAnswer::Answer501 {} => {
+                variables = vec![route.into(), request_id.into()];
+                variables_once = vec![];
+                &self.listener_answers.answer_501
+            }
             DefaultAnswer::Answer502 {
                 message,
                 phase,
@@ -985,6 +1245,86 @@ impl HttpAnswers {
         };
         // kawa::debug_kawa(&template.kawa);
         // println!("{template:#?}");
-        template.fill(&variables, &mut variables_once)
+        let mut answer = template.fill(&variables, &mut variables_once);
+        if let Some(retry_after) = retry_after {
+            add_retry_after(&mut answer, retry_after);
+        }
+        answer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn serialize(mut answer: DefaultAnswerStream) -> String {
+        answer.prepare(&mut kawa::h1::BlockConverter);
+        let bytes = answer
+            .as_io_slice()
+            .iter()
+            .flat_map(|slice| slice.iter().copied())
+            .collect::<Vec<_>>();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn retry_after_values_are_seconds_or_http_dates() {
+        assert!(is_valid_retry_after("120"));
+        assert!(is_valid_retry_after("Wed, 21 Oct 2026 07:28:00 GMT"));
+        assert!(!is_valid_retry_after(""));
+        assert!(!is_valid_retry_after("-1"));
+        assert!(!is_valid_retry_after("2 minutes"));
+        assert!(!is_valid_retry_after("Wednesday, 21-Oct-26 07:28:00 GMT"));
+        assert!(!is_valid_retry_after("Wed, 21 Oct 2026 07:28:00 UTC"));
+        assert!(!is_valid_retry_after("30\r\nSet-Cookie: a=b"));
+    }
+
+    #[test]
+    fn only_the_503_answers_of_the_cluster_get_a_retry_after() {
+        let mut answers = HttpAnswers::new(&None).unwrap();
+        answers.set_retry_after("cluster_1", Some("30".to_string()));
+        let answer_503 = || DefaultAnswer::Answer503 {
+            message: "no backend".to_string(),
+        };
+
+        let answer = answers.get(
+            answer_503(),
+            "id".to_string(),
+            Some("cluster_1"),
+            None,
+            "/".to_string(),
+        );
+        let answer = serialize(answer);
+        assert!(answer.starts_with("HTTP/1.1 503 Service Unavailable\r\nRetry-After: 30\r\n"));
+
+        let answer = answers.get(
+            answer_503(),
+            "id".to_string(),
+            Some("cluster_2"),
+            None,
+            "/".to_string(),
+        );
+        assert!(!serialize(answer).contains("Retry-After"));
+
+        let answer = answers.get(
+            DefaultAnswer::Answer504 {
+                duration: "30s".to_string(),
+            },
+            "id".to_string(),
+            Some("cluster_1"),
+            None,
+            "/".to_string(),
+        );
+        assert!(!serialize(answer).contains("Retry-After"));
+
+        answers.set_retry_after("cluster_1", None);
+        let answer = answers.get(
+            answer_503(),
+            "id".to_string(),
+            Some("cluster_1"),
+            None,
+            "/".to_string(),
+        );
+        assert!(!serialize(answer).contains("Retry-After"));
     }
 }
@@ -0,0 +1,166 @@
+//! Responses of the clusters in BUFFER_COMPLETE mode, kept in the buffer until the
+//! backend sent them whole, then sent at once with a Content-Length when possible
+
+use kawa::{Block, BodySize, Flags, Pair, Store};
+
+use crate::protocol::http::{parser::compare_no_case, GenericHttpStream};
+
+/// Whether a response must be streamed: its body is over `max_size`, or it is not
+/// complete yet while it fills the buffer or its end is only known when the backend closes
+pub fn outgrows_buffer(response: &GenericHttpStream, max_size: usize) -> bool {
+    let body_size: usize = response
+        .blocks
+        .iter()
+        .map(|block| match block {
+            Block::Chunk(chunk) => chunk.data.len(),
+            _ => 0,
+        })
+        .sum();
+    body_size > max_size
+        || (!response.is_terminated()
+            && (response.body_size == BodySize::Empty || response.storage.is_full()))
+}
+
+/// Frame a complete chunked response with a Content-Length instead. The single
+/// "Transfer-Encoding: chunked" header is replaced, and the chunk delimiters are
+/// removed. Responses with trailers, with other transfer codings, or cut short by the
+/// backend, are left as is. Returns whether the response was changed
+pub fn chunked_to_length(response: &mut GenericHttpStream) -> bool {
+    if !response.is_terminated() || response.body_size != BodySize::Chunked {
+        return false;
+    }
+    let buf = response.storage.buffer();
+    let mut transfer_encoding = None;
+    let mut in_body = false;
+    let mut last_chunk = false;
+    let mut length = 0;
+    for (index, block) in response.blocks.iter().enumerate() {
+        match block {
+            Block::Header(Pair { key, val })
+                if !in_body && compare_no_case(key.data(buf), b"transfer-encoding") =>
+            {
+                if transfer_encoding.is_some()
+                    || !compare_no_case(val.data(buf).trim_ascii(), b"chunked")
+                {
+                    return false;
+                }
+                transfer_encoding = Some(index);
+            }
+            Block::Header(_) if !in_body => {}
+            // trailers
+            Block::Header(_) => return false,
+            Block::Flags(Flags {
+                end_header: true, ..
+            }) => in_body = true,
+            Block::Flags(Flags { end_body: true, .. }) => last_chunk = true,
+            Block::Chunk(chunk) => length += chunk.data.len(),
+            _ => {}
+        }
+    }
+    // a response is also terminated when the backend closes in the middle of it
+    let (Some(transfer_encoding), true) = (transfer_encoding, last_chunk) else {
+        return false;
+    };
+
+    let mut in_body = false;
+    let blocks = std::mem::take(&mut response.blocks);
+    for (index, block) in blocks.into_iter().enumerate() {
+        match block {
+            _ if index == transfer_encoding => {
+                response.blocks.push_back(Block::Header(Pair {
+                    key: Store::Static(b"Content-Length"),
+                    val: Store::from_string(length.to_string()),
+                }));
+            }
+            Block::Flags(Flags {
+                end_header: true, ..
+            }) if !in_body => {
+                in_body = true;
+                response.blocks.push_back(block);
+            }
+            Block::ChunkHeader(_) => {}
+            Block::Flags(Flags { end_body: true, .. }) => {
+                response.blocks.push_back(Block::Flags(Flags {
+                    end_body: true,
+                    end_chunk: false,
+                    end_header: false,
+                    end_stream: true,
+                }));
+            }
+            // the end of the chunks and of the empty trailer section
+            Block::Flags(_) if in_body => {}
+            block => response.blocks.push_back(block),
+        }
+    }
+    response.body_size = BodySize::Length(length);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use kawa::{h1::NoCallbacks, Buffer, Kawa, Kind};
+
+    use super::*;
+    use crate::pool::Pool;
+
+    fn parse_response(pool: &Rc<RefCell<Pool>>, input: &[u8]) -> GenericHttpStream {
+        let checkout = pool.borrow_mut().checkout().unwrap();
+        let mut response = Kawa::new(Kind::Response, Buffer::new(checkout));
+        response.storage.space()[..input.len()].copy_from_slice(input);
+        response.storage.fill(input.len());
+        kawa::h1::parse(&mut response, &mut NoCallbacks);
+        response
+    }
+
+    fn serialize(response: &mut GenericHttpStream) -> Vec<u8> {
+        response.prepare(&mut kawa::h1::BlockConverter);
+        response
+            .as_io_slice()
+            .iter()
+            .flat_map(|slice| slice.iter().copied())
+            .collect()
+    }
+
+    #[test]
+    fn complete_chunked_responses_get_a_content_length() {
+        let pool = Rc::new(RefCell::new(Pool::with_capacity(1, 1, 16384)));
+        let mut response = parse_response(
+            &pool,
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Type: text/plain\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+        );
+        assert!(response.is_terminated());
+        assert!(chunked_to_length(&mut response));
+        assert_eq!(
+            serialize(&mut response),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\nContent-Type: text/plain\r\n\r\nhello world"
+        );
+    }
+
+    #[test]
+    fn trailers_and_other_codings_keep_the_chunks() {
+        let pool = Rc::new(RefCell::new(Pool::with_capacity(3, 3, 16384)));
+        let mut response = parse_response(
+            &pool,
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\nChecksum: 1\r\n\r\n",
+        );
+        assert!(!chunked_to_length(&mut response));
+
+        let mut response = parse_response(
+            &pool,
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+        );
+        assert!(!chunked_to_length(&mut response));
+
+        let mut response = parse_response(
+            &pool,
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n",
+        );
+        assert!(!outgrows_buffer(&response, 5));
+        assert!(outgrows_buffer(&response, 4));
+        // the backend closed before the last chunk
+        response.parsing_phase = kawa::ParsingPhase::Terminated;
+        assert!(!chunked_to_length(&mut response));
+    }
+}
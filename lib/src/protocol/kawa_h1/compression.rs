@@ -0,0 +1,315 @@
+use std::{collections::VecDeque, fmt, io::Write, str::from_utf8};
+
+use brotli::CompressorWriter;
+use flate2::write::GzEncoder;
+use kawa::{Block, Chunk, ChunkHeader, Flags, Store};
+
+use crate::protocol::http::{parser::compare_no_case, GenericHttpStream};
+
+/// media types compressed when the cluster does not list any
+const DEFAULT_CONTENT_TYPES: [&str; 9] = [
+    "text/html",
+    "text/plain",
+    "text/css",
+    "text/javascript",
+    "application/javascript",
+    "application/json",
+    "application/xml",
+    "text/xml",
+    "image/svg+xml",
+];
+
+/// content codings used when the cluster does not list any, in order of preference
+const DEFAULT_ALGORITHMS: [Algorithm; 2] = [Algorithm::Brotli, Algorithm::Gzip];
+
+/// brotli quality used on the fly, higher ones cost too much CPU for a proxy
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Brotli,
+    Gzip,
+}
+
+impl Algorithm {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim() {
+            name if name.eq_ignore_ascii_case("br") => Some(Algorithm::Brotli),
+            name if name.eq_ignore_ascii_case("gzip") => Some(Algorithm::Gzip),
+            _ => None,
+        }
+    }
+
+    /// the value of the Content-Encoding header
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Algorithm::Brotli => "br",
+            Algorithm::Gzip => "gzip",
+        }
+    }
+
+    /// pick the first algorithm of the cluster that the client accepts
+    pub fn negotiate(cluster_algorithms: &[String], accept_encoding: &str) -> Option<Self> {
+        let configured: Vec<Algorithm> = if cluster_algorithms.is_empty() {
+            DEFAULT_ALGORITHMS.to_vec()
+        } else {
+            cluster_algorithms
+                .iter()
+                .filter_map(|name| Algorithm::from_name(name))
+                .collect()
+        };
+        configured
+            .into_iter()
+            .find(|algorithm| accepts(accept_encoding, algorithm.as_str()))
+    }
+}
+
+/// whether an Accept-Encoding value allows a content coding, "*" included,
+/// a coding with a "q=0" weight is refused
+fn accepts(accept_encoding: &str, coding: &str) -> bool {
+    let mut wildcard = false;
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or_default().trim();
+        let refused = parts.any(|param| {
+            let param = param.trim();
+            param.len() > 2
+                && param[..2].eq_ignore_ascii_case("q=")
+                && param[2..].trim().parse::<f32>().is_ok_and(|q| q == 0.0)
+        });
+        if name.eq_ignore_ascii_case(coding) {
+            return !refused;
+        }
+        if name == "*" {
+            wildcard = !refused;
+        }
+    }
+    wildcard
+}
+
+/// whether a Content-Type value is one of the media types to compress
+pub fn is_compressible(content_types: &[String], content_type: &[u8]) -> bool {
+    let media_type = content_type
+        .split(|c| *c == b';')
+        .next()
+        .unwrap_or_default()
+        .trim_ascii();
+    if content_types.is_empty() {
+        DEFAULT_CONTENT_TYPES
+            .iter()
+            .any(|name| compare_no_case(media_type, name.as_bytes()))
+    } else {
+        content_types
+            .iter()
+            .any(|name| compare_no_case(media_type, name.trim().as_bytes()))
+    }
+}
+
+/// Compressor of a response body
+pub enum ResponseEncoder {
+    Brotli(Box<CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+}
+
+impl fmt::Debug for ResponseEncoder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResponseEncoder::Brotli(_) => write!(f, "ResponseEncoder::Brotli"),
+            ResponseEncoder::Gzip(_) => write!(f, "ResponseEncoder::Gzip"),
+        }
+    }
+}
+
+impl ResponseEncoder {
+    pub fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Brotli => ResponseEncoder::Brotli(Box::new(CompressorWriter::new(
+                Vec::new(),
+                BROTLI_BUFFER_SIZE,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            ))),
+            Algorithm::Gzip => {
+                ResponseEncoder::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::default()))
+            }
+        }
+    }
+
+    // writing to a Vec never fails
+    fn write(&mut self, data: &[u8]) {
+        let _ = match self {
+            ResponseEncoder::Brotli(encoder) => encoder.write_all(data),
+            ResponseEncoder::Gzip(encoder) => encoder.write_all(data),
+        };
+    }
+
+    // a sync flush: the compressed bytes of all the data written so far can be decoded
+    fn flush(&mut self) {
+        let _ = match self {
+            ResponseEncoder::Brotli(encoder) => encoder.flush(),
+            ResponseEncoder::Gzip(encoder) => encoder.flush(),
+        };
+    }
+
+    /// the compressed bytes produced so far
+    fn take_output(&mut self) -> Vec<u8> {
+        match self {
+            ResponseEncoder::Brotli(encoder) => std::mem::take(encoder.get_mut()),
+            ResponseEncoder::Gzip(encoder) => std::mem::take(encoder.get_mut()),
+        }
+    }
+
+    /// the last compressed bytes, with the end of the compressed stream
+    fn finish(self) -> Vec<u8> {
+        match self {
+            ResponseEncoder::Brotli(encoder) => encoder.into_inner(),
+            ResponseEncoder::Gzip(encoder) => encoder.finish().unwrap_or_default(),
+        }
+    }
+}
+
+/// State of the compression of a response
+#[derive(Debug)]
+pub struct ResponseCompression {
+    encoder: Option<ResponseEncoder>,
+    /// the backend framed the body with a Content-Length, Kawa emits no final CRLF for it
+    length_framed: bool,
+}
+
+impl ResponseCompression {
+    pub fn new(algorithm: Algorithm, length_framed: bool) -> Self {
+        Self {
+            encoder: Some(ResponseEncoder::new(algorithm)),
+            length_framed,
+        }
+    }
+
+    /// Replace the body blocks of the response by chunks of compressed data.
+    ///
+    /// The body is copied in the encoder, so its bytes can be released from the buffer
+    /// even when the encoder did not produce anything yet. A chunked body may be a stream
+    /// of events (Server-Sent Events, long polling): the encoder is flushed so the client
+    /// gets what the backend sent without waiting for the end of the response.
+    pub fn compress_blocks(&mut self, response: &mut GenericHttpStream) {
+        let buf = response.storage.buffer();
+        let mut blocks = VecDeque::with_capacity(response.blocks.len());
+        for block in response.blocks.drain(..) {
+            match block {
+                Block::Chunk(Chunk { data }) => {
+                    if let Some(encoder) = &mut self.encoder {
+                        encoder.write(data.data(buf));
+                    }
+                }
+                Block::ChunkHeader(_) => {}
+                Block::Flags(Flags {
+                    end_body: false,
+                    end_chunk: true,
+                    end_header: false,
+                    ..
+                }) => {}
+                Block::Flags(flags) if flags.end_body => {
+                    if let Some(encoder) = self.encoder.take() {
+                        push_chunk(&mut blocks, encoder.finish());
+                    }
+                    blocks.push_back(Block::Flags(Flags {
+                        end_chunk: self.length_framed,
+                        ..flags
+                    }));
+                }
+                block => blocks.push_back(block),
+            }
+        }
+        if let Some(encoder) = &mut self.encoder {
+            if !self.length_framed {
+                encoder.flush();
+            }
+            push_chunk(&mut blocks, encoder.take_output());
+        }
+        response.blocks = blocks;
+    }
+}
+
+fn push_chunk(blocks: &mut VecDeque<Block>, data: Vec<u8>) {
+    if data.is_empty() {
+        return;
+    }
+    blocks.push_back(Block::ChunkHeader(ChunkHeader {
+        length: Store::from_string(format!("{:x}", data.len())),
+    }));
+    blocks.push_back(Block::Chunk(Chunk {
+        data: Store::from_vec(data),
+    }));
+    blocks.push_back(Block::Flags(Flags {
+        end_body: false,
+        end_chunk: true,
+        end_header: false,
+        end_stream: false,
+    }));
+}
+
+/// the weak version of an ETag, the compressed representation is not byte-identical
+pub fn weaken_etag(etag: &[u8]) -> Option<String> {
+    if etag.starts_with(b"W/") {
+        return None;
+    }
+    from_utf8(etag).ok().map(|etag| format!("W/{etag}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_follows_cluster_preference() {
+        let both = vec!["gzip".to_owned(), "br".to_owned()];
+        assert_eq!(
+            Algorithm::negotiate(&both, "br, gzip"),
+            Some(Algorithm::Gzip)
+        );
+        assert_eq!(
+            Algorithm::negotiate(&[], "gzip, deflate, br"),
+            Some(Algorithm::Brotli)
+        );
+        assert_eq!(
+            Algorithm::negotiate(&[], "gzip;q=0.5, br;q=0"),
+            Some(Algorithm::Gzip)
+        );
+        assert_eq!(Algorithm::negotiate(&[], "*"), Some(Algorithm::Brotli));
+        assert_eq!(Algorithm::negotiate(&[], "*, br;q=0, gzip;q=0"), None);
+        assert_eq!(Algorithm::negotiate(&[], "identity"), None);
+        assert_eq!(
+            Algorithm::negotiate(&["zstd".to_owned()], "zstd, gzip"),
+            None
+        );
+    }
+
+    #[test]
+    fn compressible_content_types() {
+        assert!(is_compressible(&[], b"text/html; charset=utf-8"));
+        assert!(is_compressible(&[], b"Application/JSON"));
+        assert!(!is_compressible(&[], b"image/png"));
+        assert!(!is_compressible(&[], b"text/event-stream"));
+        let custom = vec!["application/wasm".to_owned()];
+        assert!(is_compressible(&custom, b"application/wasm"));
+        assert!(!is_compressible(&custom, b"text/html"));
+    }
+
+    #[test]
+    fn flushed_output_decodes_before_the_end() {
+        let mut encoder = ResponseEncoder::new(Algorithm::Gzip);
+        encoder.write(b"data: 1\n\n");
+        encoder.flush();
+        let mut decoder = flate2::write::GzDecoder::new(Vec::new());
+        decoder.write_all(&encoder.take_output()).unwrap();
+        decoder.flush().unwrap();
+        assert_eq!(decoder.get_ref(), b"data: 1\n\n");
+    }
+
+    #[test]
+    fn etags_are_weakened() {
+        assert_eq!(weaken_etag(b"\"abc\"").as_deref(), Some("W/\"abc\""));
+        assert_eq!(weaken_etag(b"W/\"abc\""), None);
+    }
+}
@@ -85,6 +85,16 @@ pub fn diagnostic_400_502(
     }
 }
 
+pub fn diagnostic_502_h2_backend(kawa: &GenericHttpStream) -> (String, String, String, String) {
+    let buffer = kawa.storage.buffer();
+    (
+        "The backend answered with HTTP/2 frames while an HTTP/1.1 response was expected. Make sure it accepts HTTP/1.1 connections.".into(),
+        "null".into(),
+        "null".into(),
+        hex_dump(buffer, 32, kawa.storage.start, kawa.storage.end),
+    )
+}
+
 pub fn diagnostic_413_507(parsing_phase: ParsingPhase) -> String {
     match parsing_phase {
         kawa::ParsingPhase::StatusLine => {
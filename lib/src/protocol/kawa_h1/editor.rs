@@ -1,4 +1,5 @@
 use std::{
+    collections::VecDeque,
     net::{IpAddr, SocketAddr},
     str::{from_utf8, from_utf8_unchecked},
 };
@@ -7,11 +8,42 @@ use rusty_ulid::Ulid;
 
 use crate::{
     pool::Checkout,
-    protocol::http::{parser::compare_no_case, GenericHttpStream, Method},
+    protocol::http::{
+        buffering::{chunked_to_length, outgrows_buffer},
+        compression::{is_compressible, weaken_etag, Algorithm, ResponseCompression},
+        parser::compare_no_case,
+        rewrite::{rewrite_location, rewrite_set_cookie},
+        GenericHttpStream, Method,
+    },
+    router::path_normalization::{normalize_path, PathNormalizationError},
+    socket::TlsDetails,
     Protocol,
 };
 
-use sozu_command_lib::logging::LogContext;
+use sozu_command_lib::{
+    logging::LogContext,
+    proto::command::{Compression, ForwardedHeaders, ResponseRewrite},
+};
+
+/// fields that must not be sent in trailers (RFC 9110 section 6.5.1)
+const FORBIDDEN_TRAILERS: [&[u8]; 16] = [
+    b"authorization",
+    b"cache-control",
+    b"connection",
+    b"content-encoding",
+    b"content-length",
+    b"content-range",
+    b"content-type",
+    b"cookie",
+    b"expect",
+    b"host",
+    b"keep-alive",
+    b"proxy-authorization",
+    b"set-cookie",
+    b"te",
+    b"trailer",
+    b"transfer-encoding",
+];
 
 /// This is the container used to store and use information about the session from within a Kawa parser callback
 #[derive(Debug)]
@@ -36,6 +68,19 @@ pub struct HttpContext {
     pub reason: Option<String>,
     // ---------- Additional optional data
     pub user_agent: Option<String>,
+    /// the id of the request sent to the backend in the request id header, the one of
+    /// the client if it is trusted, the "Sozu-Id" otherwise
+    pub request_id: Option<String>,
+    /// number of body bytes of the request forwarded to the backend
+    pub request_body_size: usize,
+    /// number of body bytes of the response forwarded to the client
+    pub response_body_size: usize,
+    /// the value of the "Accept-Encoding" header of an HTTP/1.1 request
+    pub accept_encoding: Option<String>,
+    /// the compressor of the response body, if Kawa compresses it
+    pub response_compression: Option<ResponseCompression>,
+    /// signals wether the response outgrew the buffering limit and is streamed
+    pub response_streamed: bool,
 
     // ========== Read only
     /// signals wether Kawa should write a "Connection" header with a "close" value (request and response)
@@ -50,11 +95,72 @@ pub struct HttpContext {
     pub public_address: SocketAddr,
     /// the value of the session address Kawa should write in the Forwarded headers of the request
     pub session_address: Option<SocketAddr>,
+    /// what the TLS handshake of the client negotiated, read when the session starts
+    pub tls: Option<TlsDetails>,
+    /// signals wether Kawa should write the X-Forwarded-TLS-* headers in the request, set from the listener
+    pub forward_tls_headers: bool,
     /// the name of the cookie Kawa should read from the request to get the sticky session
     pub sticky_name: String,
     /// the sticky session that should be used
     /// used to create a "Set-Cookie" header in the response in case it differs from sticky_session_found
     pub sticky_session: Option<String>,
+    /// signals wether Kawa should write a "Server-Timing" header in the response, set from the cluster
+    pub send_server_timing: bool,
+    /// the value of the "Server-Timing" header Kawa should write in the response
+    pub server_timing: Option<String>,
+    /// signals wether the Host and Cookie header names should keep the client's case, set from the cluster
+    pub preserve_header_case: bool,
+    /// the name of the Host header as written by the client, if it is not "Host"
+    pub host_header_name: Option<String>,
+    /// the name of the Cookie header as written by the client, if it is not "Cookie"
+    pub cookie_header_name: Option<String>,
+    /// signals wether requests in absolute-form are accepted, set from the listener
+    pub forward_proxy: bool,
+    /// the name of the header carrying the id of the request to the backend and back in
+    /// the response, set from the listener
+    pub request_id_header: Option<String>,
+    /// signals wether the id a client sends in the request id header is kept, set from the listener
+    pub trust_client_request_id: bool,
+    /// signals wether paths with an encoded slash or backslash are refused, set from the listener
+    pub reject_encoded_slashes: bool,
+    /// signals wether the backend gets the normalized path instead of the client's, set from the listener
+    pub forward_normalized_path: bool,
+    /// the value of the "Alt-Svc" header Kawa should write in the response, set from the listener
+    pub alt_svc: Option<String>,
+    /// signals wether the "Alt-Svc" headers of the backend are replaced, set from the listener
+    pub replace_alt_svc: bool,
+    /// the largest request header section, set from the listener
+    pub max_request_header_size: Option<u32>,
+    /// the largest response header section, set from the listener
+    pub max_response_header_size: Option<u32>,
+    /// the compression Kawa should apply to the responses, set from the cluster
+    pub compression: Option<Compression>,
+    /// the forwarding headers Kawa should add to the request, set from the cluster
+    pub forwarded_headers: ForwardedHeaders,
+    /// the position of the forwarding headers among the request blocks, until they are added
+    pub forwarded_headers_index: Option<usize>,
+    /// the rewriting of the Location and Set-Cookie headers of the responses, set from the cluster
+    pub response_rewrite: Option<ResponseRewrite>,
+    /// the largest response body kept until the response is complete, set from the cluster
+    /// in BUFFER_COMPLETE mode
+    pub response_buffering: Option<usize>,
+    /// the request trailers Kawa should forward to the backend, all the allowed ones if empty, set from the cluster
+    pub allowed_request_trailers: Vec<String>,
+    /// signals wether the end of the request body was seen, the next headers are trailers
+    pub in_request_trailers: bool,
+    /// signals wether the request uses transfer codings other than a single "chunked"
+    pub unsupported_transfer_encoding: bool,
+    /// signals wether the request has both a Content-Length and a Transfer-Encoding
+    pub conflicting_framing: bool,
+    /// signals wether the request target is in absolute-form while it is not accepted,
+    /// or with a scheme other than http and https
+    pub rejected_absolute_form: bool,
+    /// signals wether the request header section is larger than the listener accepts
+    pub request_headers_too_large: bool,
+    /// signals wether the request is in HTTP/1.0, the interim responses are not forwarded
+    pub http10_client: bool,
+    /// the reason the path of the request can not be normalized for routing, if it can not
+    pub invalid_path: Option<PathNormalizationError>,
 }
 
 impl kawa::h1::ParserCallbacks<Checkout> for HttpContext {
@@ -69,7 +175,8 @@ impl kawa::h1::ParserCallbacks<Checkout> for HttpContext {
 impl HttpContext {
     /// Callback for request:
     ///
-    /// - edit headers (connection, forwarded, sticky cookie, sozu-id)
+    /// - edit headers (connection, sticky cookie, sozu-id)
+    /// - save the position of the forwarding headers
     /// - save information:
     ///   - method
     ///   - authority
@@ -78,17 +185,29 @@ impl HttpContext {
     ///   - sticky cookie
     ///   - user-agent
     fn on_request_headers(&mut self, request: &mut GenericHttpStream) {
+        // measured before Sōzu adds its own headers
+        if let Some(max) = self.max_request_header_size {
+            self.request_headers_too_large = header_section_size(request) > max as usize;
+        }
+
         let buf = &mut request.storage.mut_buffer();
 
         // Captures the request line
+        let mut chunked_client = false;
         if let kawa::StatusLine::Request {
+            version,
             method,
+            uri,
             authority,
             path,
             ..
-        } = &request.detached.status_line
+        } = &mut request.detached.status_line
         {
+            // an HTTP/1.0 client can not receive the chunks of a compressed response
+            chunked_client = matches!(version, kawa::Version::V11);
+            self.http10_client = matches!(version, kawa::Version::V10);
             self.method = method.data_opt(buf).map(Method::new);
+            self.host_header_name = original_header_name(buf, authority, b"Host");
             self.authority = authority
                 .data_opt(buf)
                 .and_then(|data| from_utf8(data).ok())
@@ -97,19 +216,58 @@ impl HttpContext {
                 .data_opt(buf)
                 .and_then(|data| from_utf8(data).ok())
                 .map(ToOwned::to_owned);
+
+            // Kawa took the authority and the path of an absolute-form target
+            // (http://host/path), the backend receives it in origin-form
+            let target = uri.data_opt(buf).unwrap_or_default();
+            if self.method != Some(Method::Connect)
+                && !target.is_empty()
+                && !target.starts_with(b"/")
+                && target != b"*"
+            {
+                let has_http_scheme = [&b"http://"[..], &b"https://"[..]].iter().any(|scheme| {
+                    target.len() > scheme.len() && compare_no_case(&target[..scheme.len()], scheme)
+                });
+                if self.forward_proxy && has_http_scheme {
+                    *uri = path.clone();
+                } else {
+                    self.rejected_absolute_form = true;
+                }
+            }
+
+            // routing uses the normalized path, the backend gets the one of the client
+            // unless the listener forwards the normalized one
+            if let Some(original) = self.path.as_deref().filter(|path| path.starts_with('/')) {
+                match normalize_path(original, self.reject_encoded_slashes) {
+                    Ok(normalized) => {
+                        if self.forward_normalized_path && normalized != original {
+                            *uri = kawa::Store::from_string(normalized.clone());
+                        }
+                        self.path = Some(normalized);
+                    }
+                    Err(error) => self.invalid_path = Some(error),
+                }
+            }
         }
 
-        // if self.method == Some(Method::Get) && request.body_size == kawa::BodySize::Empty {
-        //     request.parsing_phase = kawa::ParsingPhase::Terminated;
-        // }
+        // A request without Content-Length nor Transfer-Encoding has no body, the bytes
+        // that follow belong to the next pipelined request. Kawa would read them as a
+        // body until the connection closes. A tunnel (CONNECT or Upgrade) keeps them.
+        let opens_tunnel = self.method == Some(Method::Connect)
+            || request.blocks.iter().any(|block| {
+                matches!(block, kawa::Block::Header(header)
+                    if !header.is_elided() && compare_no_case(header.key.data(buf), b"upgrade"))
+            });
+        if request.body_size == kawa::BodySize::Empty && !opens_tunnel {
+            request.body_size = kawa::BodySize::Length(0);
+            request.parsing_phase = kawa::ParsingPhase::Terminated;
+        }
 
-        let public_ip = self.public_address.ip();
-        let public_port = self.public_address.port();
-        let proto = match self.protocol {
-            Protocol::HTTP => "http",
-            Protocol::HTTPS => "https",
-            _ => unreachable!(),
-        };
+        self.cookie_header_name = request
+            .detached
+            .jar
+            .iter()
+            .find_map(|cookie| original_header_name(buf, &cookie.key, b"Cookie"));
 
         // Find and remove the sticky_name cookie
         // if found its value is stored in sticky_session_found
@@ -122,24 +280,41 @@ impl HttpContext {
             }
         }
 
+        // Kawa frames the body with Transfer-Encoding and elides Content-Length only
+        // if it comes second, elided headers are checked too so the order does not matter
+        let has_transfer_encoding = request.blocks.iter().any(|block| {
+            matches!(block, kawa::Block::Header(header)
+                if !header.is_elided()
+                    && compare_no_case(header.key.data(buf), b"transfer-encoding"))
+        });
+        self.conflicting_framing = has_transfer_encoding
+            && request.blocks.iter().any(|block| {
+                matches!(block, kawa::Block::Header(header)
+                    if raw_header_name(buf, &header.val)
+                        .is_some_and(|name| compare_no_case(name, b"content-length")))
+            });
+
         // If found:
         // - set Connection to "close" if closing is set
         // - set keep_alive_frontend to false if Connection is "close"
-        // - update value of X-Forwarded-Proto
-        // - update value of X-Forwarded-Port
-        // - store X-Forwarded-For
-        // - store Forwarded
         // - store User-Agent
-        let mut x_for = None;
-        let mut forwarded = None;
-        let mut has_x_port = false;
-        let mut has_x_proto = false;
+        // - check the codings of Transfer-Encoding
+        // - keep the id of a trusted client in the request id header, remove the others
+        let request_id_header = self.request_id_header.clone();
         let mut has_connection = false;
+        let mut transfer_codings = 0;
+        let mut chunked_only = true;
         for block in &mut request.blocks {
             match block {
                 kawa::Block::Header(header) if !header.is_elided() => {
                     let key = header.key.data(buf);
-                    if compare_no_case(key, b"connection") {
+                    if self.protocol == Protocol::HTTPS
+                        && key.len() > 16
+                        && compare_no_case(&key[..16], b"X-Forwarded-TLS-")
+                    {
+                        // only Sōzu describes the handshake, even when it does not forward it
+                        header.elide();
+                    } else if compare_no_case(key, b"connection") {
                         has_connection = true;
                         if self.closing {
                             header.val = kawa::Store::Static(b"close");
@@ -147,125 +322,58 @@ impl HttpContext {
                             let val = header.val.data(buf);
                             self.keep_alive_frontend &= !compare_no_case(val, b"close");
                         }
-                    } else if compare_no_case(key, b"X-Forwarded-Proto") {
-                        has_x_proto = true;
-                        // header.val = kawa::Store::Static(proto.as_bytes());
-                        incr!("http.trusting.x_proto");
-                        let val = header.val.data(buf);
-                        if !compare_no_case(val, proto.as_bytes()) {
-                            incr!("http.trusting.x_proto.diff");
-                            debug!(
-                                "Trusting X-Forwarded-Proto for {:?} even though {:?} != {}",
-                                self.authority, val, proto
-                            );
-                        }
-                    } else if compare_no_case(key, b"X-Forwarded-Port") {
-                        has_x_port = true;
-                        // header.val = kawa::Store::from_string(public_port.to_string());
-                        incr!("http.trusting.x_port");
+                    } else if compare_no_case(key, b"Transfer-Encoding") {
                         let val = header.val.data(buf);
-                        let expected = public_port.to_string();
-                        if !compare_no_case(val, expected.as_bytes()) {
-                            incr!("http.trusting.x_port.diff");
-                            debug!(
-                                "Trusting X-Forwarded-Port for {:?} even though {:?} != {}",
-                                self.authority, val, expected
-                            );
+                        for coding in val
+                            .split(|c| *c == b',')
+                            .map(|coding| coding.trim_ascii())
+                            .filter(|coding| !coding.is_empty())
+                        {
+                            transfer_codings += 1;
+                            chunked_only &= compare_no_case(coding, b"chunked");
                         }
-                    } else if compare_no_case(key, b"X-Forwarded-For") {
-                        x_for = Some(header);
-                    } else if compare_no_case(key, b"Forwarded") {
-                        forwarded = Some(header);
+                    } else if compare_no_case(key, b"Accept-Encoding") && chunked_client {
+                        self.accept_encoding = header
+                            .val
+                            .data_opt(buf)
+                            .and_then(|data| from_utf8(data).ok())
+                            .map(ToOwned::to_owned);
                     } else if compare_no_case(key, b"User-Agent") {
                         self.user_agent = header
                             .val
                             .data_opt(buf)
                             .and_then(|data| from_utf8(data).ok())
                             .map(ToOwned::to_owned);
+                    } else if request_id_header
+                        .as_ref()
+                        .is_some_and(|name| compare_no_case(key, name.as_bytes()))
+                    {
+                        let client_id = header
+                            .val
+                            .data_opt(buf)
+                            .and_then(|data| from_utf8(data.trim_ascii()).ok())
+                            .filter(|id| is_valid_request_id(id));
+                        match client_id {
+                            Some(id)
+                                if self.trust_client_request_id && self.request_id.is_none() =>
+                            {
+                                self.request_id = Some(id.to_owned());
+                            }
+                            _ => header.elide(),
+                        }
                     }
                 }
                 _ => {}
             }
         }
 
-        // If session_address is set:
-        // - append its ip address to the list of "X-Forwarded-For" if it was found, creates it if not
-        // - append "proto=[PROTO];for=[PEER];by=[PUBLIC]" to the list of "Forwarded" if it was found, creates it if not
-        if let Some(peer_addr) = self.session_address {
-            let peer_ip = peer_addr.ip();
-            let peer_port = peer_addr.port();
-            let has_x_for = x_for.is_some();
-            let has_forwarded = forwarded.is_some();
+        // Only a single "chunked" coding is supported: with any other one, Sōzu
+        // and the backend could disagree on where the body ends
+        self.unsupported_transfer_encoding =
+            transfer_codings > 1 || (transfer_codings == 1 && !chunked_only);
 
-            if let Some(header) = x_for {
-                header.val = kawa::Store::from_string(format!("{}, {peer_ip}", unsafe {
-                    from_utf8_unchecked(header.val.data(buf))
-                }));
-            }
-            if let Some(header) = &mut forwarded {
-                let value = unsafe { from_utf8_unchecked(header.val.data(buf)) };
-                let new_value = match (peer_ip, public_ip) {
-                    (IpAddr::V4(_), IpAddr::V4(_)) => {
-                        format!("{value}, proto={proto};for={peer_ip}:{peer_port};by={public_ip}")
-                    }
-                    (IpAddr::V4(_), IpAddr::V6(_)) => {
-                        format!(
-                            "{value}, proto={proto};for={peer_ip}:{peer_port};by=\"{public_ip}\""
-                        )
-                    }
-                    (IpAddr::V6(_), IpAddr::V4(_)) => {
-                        format!(
-                            "{value}, proto={proto};for=\"{peer_ip}:{peer_port}\";by={public_ip}"
-                        )
-                    }
-                    (IpAddr::V6(_), IpAddr::V6(_)) => {
-                        format!(
-                        "{value}, proto={proto};for=\"{peer_ip}:{peer_port}\";by=\"{public_ip}\""
-                    )
-                    }
-                };
-                header.val = kawa::Store::from_string(new_value);
-            }
-
-            if !has_x_for {
-                request.push_block(kawa::Block::Header(kawa::Pair {
-                    key: kawa::Store::Static(b"X-Forwarded-For"),
-                    val: kawa::Store::from_string(peer_ip.to_string()),
-                }));
-            }
-            if !has_forwarded {
-                let value = match (peer_ip, public_ip) {
-                    (IpAddr::V4(_), IpAddr::V4(_)) => {
-                        format!("proto={proto};for={peer_ip}:{peer_port};by={public_ip}")
-                    }
-                    (IpAddr::V4(_), IpAddr::V6(_)) => {
-                        format!("proto={proto};for={peer_ip}:{peer_port};by=\"{public_ip}\"")
-                    }
-                    (IpAddr::V6(_), IpAddr::V4(_)) => {
-                        format!("proto={proto};for=\"{peer_ip}:{peer_port}\";by={public_ip}")
-                    }
-                    (IpAddr::V6(_), IpAddr::V6(_)) => {
-                        format!("proto={proto};for=\"{peer_ip}:{peer_port}\";by=\"{public_ip}\"")
-                    }
-                };
-                request.push_block(kawa::Block::Header(kawa::Pair {
-                    key: kawa::Store::Static(b"Forwarded"),
-                    val: kawa::Store::from_string(value),
-                }));
-            }
-        }
-        if !has_x_port {
-            request.push_block(kawa::Block::Header(kawa::Pair {
-                key: kawa::Store::Static(b"X-Forwarded-Port"),
-                val: kawa::Store::from_string(public_port.to_string()),
-            }));
-        }
-        if !has_x_proto {
-            request.push_block(kawa::Block::Header(kawa::Pair {
-                key: kawa::Store::Static(b"X-Forwarded-Proto"),
-                val: kawa::Store::Static(proto.as_bytes()),
-            }));
-        }
+        // The forwarding headers depend on the cluster, they are added here once it is known
+        self.forwarded_headers_index = Some(request.blocks.len());
 
         // Create a "Connection" header in case it was not found and closing it set
         if !has_connection && self.closing {
@@ -275,11 +383,30 @@ impl HttpContext {
             }));
         }
 
+        if let (true, Some(tls)) = (self.forward_tls_headers, &self.tls) {
+            for (key, val) in tls.forwarded_headers() {
+                request.push_block(kawa::Block::Header(kawa::Pair {
+                    key: kawa::Store::Static(key),
+                    val: kawa::Store::from_string(val),
+                }));
+            }
+        }
+
         // Create a custom "Sozu-Id" header
         request.push_block(kawa::Block::Header(kawa::Pair {
             key: kawa::Store::Static(b"Sozu-Id"),
             val: kawa::Store::from_string(self.id.to_string()),
         }));
+
+        // Create the request id header, unless the one of the client is kept
+        if let (Some(name), None) = (request_id_header, &self.request_id) {
+            let id = self.id.to_string();
+            request.push_block(kawa::Block::Header(kawa::Pair {
+                key: kawa::Store::from_string(name),
+                val: kawa::Store::from_string(id.clone()),
+            }));
+            self.request_id = Some(id);
+        }
     }
 
     /// Callback for response:
@@ -290,6 +417,18 @@ impl HttpContext {
     ///   - reason
     ///   - back keep-alive
     fn on_response_headers(&mut self, response: &mut GenericHttpStream) {
+        if let Some(max) = self.max_response_header_size {
+            if header_section_size(response) > max as usize {
+                incr!("http.response_headers_too_large");
+                // the response is answered with a 502, like an unparsable one
+                response.parsing_phase = kawa::ParsingPhase::Error {
+                    marker: kawa::ParsingPhaseMarker::Headers,
+                    kind: "the response headers exceed max_response_header_size".into(),
+                };
+                return;
+            }
+        }
+
         let buf = &mut response.storage.mut_buffer();
 
         // Captures the response line
@@ -305,6 +444,12 @@ impl HttpContext {
             response.parsing_phase = kawa::ParsingPhase::Terminated;
         }
 
+        // A 2xx response to a CONNECT has no body, the bytes that follow belong to the tunnel
+        if self.is_connect_established() {
+            response.body_size = kawa::BodySize::Length(0);
+            response.parsing_phase = kawa::ParsingPhase::Terminated;
+        }
+
         // If found:
         // - set Connection to "close" if closing is set
         // - set keep_alive_backend to false if Connection is "close"
@@ -325,6 +470,46 @@ impl HttpContext {
             }
         }
 
+        // Advertise the alternative services of the listener, after the ones of the
+        // backend unless they are replaced. A backend "clear" is left alone
+        if let Some(alt_svc) = &self.alt_svc {
+            if self.status.is_some_and(|status| status >= 200) {
+                let mut backend_cleared = false;
+                for block in &mut response.blocks {
+                    match block {
+                        kawa::Block::Header(header) if !header.is_elided() => {
+                            let key = header.key.data(buf);
+                            if compare_no_case(key, b"alt-svc") {
+                                if self.replace_alt_svc {
+                                    header.elide();
+                                } else {
+                                    backend_cleared |= compare_no_case(
+                                        header.val.data(buf).trim_ascii(),
+                                        b"clear",
+                                    );
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                if !backend_cleared {
+                    response.push_block(kawa::Block::Header(kawa::Pair {
+                        key: kawa::Store::Static(b"Alt-Svc"),
+                        val: kawa::Store::from_string(alt_svc.to_owned()),
+                    }));
+                }
+            }
+        }
+
+        if self.response_rewrite.is_some() {
+            self.rewrite_response_headers(response);
+        }
+
+        if self.compression.is_some() {
+            self.prepare_response_compression(response);
+        }
+
         // If the sticky_session is set and differs from the one found in the request
         // create a "Set-Cookie" header to update the sticky_name value
         if let Some(sticky_session) = &self.sticky_session {
@@ -339,11 +524,179 @@ impl HttpContext {
             }
         }
 
+        // Create a "Server-Timing" header with the timings measured by sozu
+        if let Some(server_timing) = self.server_timing.take() {
+            response.push_block(kawa::Block::Header(kawa::Pair {
+                key: kawa::Store::Static(b"Server-Timing"),
+                val: kawa::Store::from_string(server_timing),
+            }));
+        }
+
         // Create a custom "Sozu-Id" header
         response.push_block(kawa::Block::Header(kawa::Pair {
             key: kawa::Store::Static(b"Sozu-Id"),
             val: kawa::Store::from_string(self.id.to_string()),
         }));
+
+        // Echo the request id header, unless the backend did
+        if let (Some(name), Some(id)) = (&self.request_id_header, &self.request_id) {
+            let buf = response.storage.buffer();
+            let echoed = response.blocks.iter().any(|block| {
+                matches!(block, kawa::Block::Header(header)
+                    if !header.is_elided() && compare_no_case(header.key.data(buf), name.as_bytes()))
+            });
+            if !echoed {
+                response.push_block(kawa::Block::Header(kawa::Pair {
+                    key: kawa::Store::from_string(name.to_owned()),
+                    val: kawa::Store::from_string(id.to_owned()),
+                }));
+            }
+        }
+    }
+
+    /// Point the Location and Set-Cookie headers naming a backend to the public host
+    fn rewrite_response_headers(&mut self, response: &mut GenericHttpStream) {
+        let (Some(rewrite), Some(authority)) = (&self.response_rewrite, &self.authority) else {
+            return;
+        };
+        let scheme = match self.protocol {
+            Protocol::HTTPS => "https",
+            _ => "http",
+        };
+        let buf = response.storage.buffer();
+        for block in &mut response.blocks {
+            if let kawa::Block::Header(header) = block {
+                if header.is_elided() {
+                    continue;
+                }
+                let key = header.key.data(buf);
+                let rewritten = if compare_no_case(key, b"location") {
+                    rewrite_location(rewrite, header.val.data(buf), scheme, authority)
+                } else if compare_no_case(key, b"set-cookie") {
+                    rewrite_set_cookie(rewrite, header.val.data(buf), authority)
+                } else {
+                    None
+                };
+                if let Some(value) = rewritten {
+                    incr!("http.response_headers_rewritten");
+                    header.val = kawa::Store::from_string(value);
+                }
+            }
+        }
+    }
+
+    /// Compress the response if its cluster enables it, if the client accepts one of the
+    /// configured algorithms and if it has a compressible type and is not encoded yet:
+    /// - elide Content-Length, the compressed body is sent in chunks
+    /// - add the Content-Encoding header, and Accept-Encoding to the Vary header
+    /// - weaken the ETag, the compressed representation is not byte-identical
+    fn prepare_response_compression(&mut self, response: &mut GenericHttpStream) {
+        let (Some(compression), Some(accept_encoding)) = (&self.compression, &self.accept_encoding)
+        else {
+            return;
+        };
+        let status = self.status.unwrap_or_default();
+        if status < 200
+            || status == 204
+            || status == 206
+            || status == 304
+            || self.method == Some(Method::Head)
+            || self.is_connect_established()
+        {
+            return;
+        }
+        if !matches!(
+            response.detached.status_line,
+            kawa::StatusLine::Response {
+                version: kawa::Version::V11,
+                ..
+            }
+        ) {
+            return;
+        }
+        let length_framed = match response.body_size {
+            kawa::BodySize::Length(length) if length >= compression.min_size() as usize => true,
+            kawa::BodySize::Chunked => false,
+            // a close-delimited body can not be framed with chunks while Kawa parses it
+            kawa::BodySize::Length(_) | kawa::BodySize::Empty => return,
+        };
+        let Some(algorithm) = Algorithm::negotiate(&compression.algorithms, accept_encoding) else {
+            return;
+        };
+
+        let buf = response.storage.buffer();
+        let mut compressible = false;
+        for block in &response.blocks {
+            match block {
+                kawa::Block::Header(header) if !header.is_elided() => {
+                    let key = header.key.data(buf);
+                    let val = header.val.data(buf);
+                    if compare_no_case(key, b"content-encoding")
+                        && !compare_no_case(val.trim_ascii(), b"identity")
+                    {
+                        // never compress twice
+                        return;
+                    } else if compare_no_case(key, b"content-range")
+                        || (compare_no_case(key, b"cache-control")
+                            && contains_no_case(val, b"no-transform"))
+                    {
+                        return;
+                    } else if compare_no_case(key, b"content-type") {
+                        compressible = is_compressible(&compression.content_types, val);
+                    }
+                }
+                _ => {}
+            }
+        }
+        if !compressible {
+            return;
+        }
+
+        let mut has_vary = false;
+        for block in &mut response.blocks {
+            match block {
+                kawa::Block::Header(header) if !header.is_elided() => {
+                    let key = header.key.data(buf);
+                    let val = header.val.data(buf);
+                    if compare_no_case(key, b"content-length") {
+                        header.elide();
+                    } else if compare_no_case(key, b"vary") {
+                        has_vary = true;
+                        if !contains_no_case(val, b"accept-encoding") && val.trim_ascii() != b"*" {
+                            header.val = kawa::Store::from_string(format!(
+                                "{}, Accept-Encoding",
+                                String::from_utf8_lossy(val)
+                            ));
+                        }
+                    } else if compare_no_case(key, b"etag") {
+                        if let Some(weak_etag) = weaken_etag(val) {
+                            header.val = kawa::Store::from_string(weak_etag);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if length_framed {
+            response.body_size = kawa::BodySize::Chunked;
+            response.push_block(kawa::Block::Header(kawa::Pair {
+                key: kawa::Store::Static(b"Transfer-Encoding"),
+                val: kawa::Store::Static(b"chunked"),
+            }));
+        }
+        response.push_block(kawa::Block::Header(kawa::Pair {
+            key: kawa::Store::Static(b"Content-Encoding"),
+            val: kawa::Store::Static(algorithm.as_str().as_bytes()),
+        }));
+        if !has_vary {
+            response.push_block(kawa::Block::Header(kawa::Pair {
+                key: kawa::Store::Static(b"Vary"),
+                val: kawa::Store::Static(b"Accept-Encoding"),
+            }));
+        }
+        incr!("http.compressed_responses");
+        self.response_compression = Some(ResponseCompression::new(algorithm, length_framed));
     }
 
     pub fn reset(&mut self) {
@@ -356,6 +709,241 @@ impl HttpContext {
         self.status = None;
         self.reason = None;
         self.user_agent = None;
+        self.request_id = None;
+        self.request_body_size = 0;
+        self.response_body_size = 0;
+        self.accept_encoding = None;
+        self.response_compression = None;
+        self.response_streamed = false;
+        self.in_request_trailers = false;
+        self.unsupported_transfer_encoding = false;
+        self.conflicting_framing = false;
+        self.rejected_absolute_form = false;
+        self.request_headers_too_large = false;
+        self.http10_client = false;
+        self.invalid_path = None;
+        self.forwarded_headers_index = None;
+    }
+
+    /// Whether the response is an interim one the client can not receive: HTTP/1.0
+    /// has no 1xx responses (RFC 9110 section 15.2), so only the final response is sent
+    /// to such a client. A 101 is left alone, it ends the exchange
+    pub fn drops_interim_response(&self, response: &GenericHttpStream) -> bool {
+        let interim = match response.detached.status_line {
+            kawa::StatusLine::Response { code, .. } => (100..200).contains(&code) && code != 101,
+            _ => false,
+        };
+        self.http10_client && response.is_terminated() && interim
+    }
+
+    /// Whether the response is kept in the buffer until it is complete, for a cluster in
+    /// BUFFER_COMPLETE mode. A complete chunked response is sent with a Content-Length,
+    /// one that outgrows the buffering limit is streamed from then on
+    pub fn hold_response(&mut self, response: &mut GenericHttpStream) -> bool {
+        let Some(max_size) = self.response_buffering else {
+            return false;
+        };
+        // nothing to decide before the end of the response headers
+        if self.response_streamed || !response.is_main_phase() {
+            return false;
+        }
+        if outgrows_buffer(response, max_size) {
+            incr!("http.buffered_responses.streamed");
+            self.response_streamed = true;
+            return false;
+        }
+        if response.is_terminated() {
+            // a compressed body is framed by the compressor
+            if self.response_compression.is_none() && chunked_to_length(response) {
+                incr!("http.buffered_responses.chunked_to_length");
+            }
+            return false;
+        }
+        true
+    }
+
+    /// Edit the forwarding headers of the request once its cluster is known, at the
+    /// end of the request headers, depending on the headers the cluster asks for:
+    /// - append the client to "X-Forwarded-For" and "Forwarded" if they were found, create them if not
+    /// - create "X-Forwarded-Port" and "X-Forwarded-Proto" if they were not found
+    pub fn add_forwarded_headers(&mut self, request: &mut GenericHttpStream) {
+        let Some(index) = self.forwarded_headers_index.take() else {
+            return;
+        };
+        let (x_forwarded, forwarded) = match self.forwarded_headers {
+            ForwardedHeaders::All => (true, true),
+            ForwardedHeaders::XForwarded => (true, false),
+            ForwardedHeaders::Forwarded => (false, true),
+            ForwardedHeaders::Disabled => return,
+        };
+
+        let public_port = self.public_address.port();
+        let proto = match self.protocol {
+            Protocol::HTTP => "http",
+            Protocol::HTTPS => "https",
+            _ => unreachable!(),
+        };
+
+        let buf = request.storage.buffer();
+        let mut x_for = None;
+        let mut forwarded_header = None;
+        let mut has_x_port = false;
+        let mut has_x_proto = false;
+        for block in request.blocks.iter_mut().take(index) {
+            match block {
+                kawa::Block::Header(header) if !header.is_elided() => {
+                    let key = header.key.data(buf);
+                    if x_forwarded && compare_no_case(key, b"X-Forwarded-Proto") {
+                        has_x_proto = true;
+                        incr!("http.trusting.x_proto");
+                        let val = header.val.data(buf);
+                        if !compare_no_case(val, proto.as_bytes()) {
+                            incr!("http.trusting.x_proto.diff");
+                            debug!(
+                                "Trusting X-Forwarded-Proto for {:?} even though {:?} != {}",
+                                self.authority, val, proto
+                            );
+                        }
+                    } else if x_forwarded && compare_no_case(key, b"X-Forwarded-Port") {
+                        has_x_port = true;
+                        incr!("http.trusting.x_port");
+                        let val = header.val.data(buf);
+                        let expected = public_port.to_string();
+                        if !compare_no_case(val, expected.as_bytes()) {
+                            incr!("http.trusting.x_port.diff");
+                            debug!(
+                                "Trusting X-Forwarded-Port for {:?} even though {:?} != {}",
+                                self.authority, val, expected
+                            );
+                        }
+                    } else if x_forwarded && compare_no_case(key, b"X-Forwarded-For") {
+                        x_for = Some(header);
+                    } else if forwarded && compare_no_case(key, b"Forwarded") {
+                        forwarded_header = Some(header);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut created = Vec::new();
+        if let Some(peer_addr) = self.session_address {
+            let peer_ip = peer_addr.ip();
+            let element = forwarded_element(
+                proto,
+                peer_addr,
+                self.public_address.ip(),
+                self.authority.as_deref(),
+            );
+
+            match x_for {
+                Some(header) => {
+                    header.val = kawa::Store::from_string(format!("{}, {peer_ip}", unsafe {
+                        from_utf8_unchecked(header.val.data(buf))
+                    }));
+                }
+                None if x_forwarded => created.push((
+                    &b"X-Forwarded-For"[..],
+                    kawa::Store::from_string(peer_ip.to_string()),
+                )),
+                None => {}
+            }
+            match forwarded_header {
+                Some(header) => {
+                    header.val = kawa::Store::from_string(format!("{}, {element}", unsafe {
+                        from_utf8_unchecked(header.val.data(buf))
+                    }));
+                }
+                None if forwarded => {
+                    created.push((&b"Forwarded"[..], kawa::Store::from_string(element)))
+                }
+                None => {}
+            }
+        }
+        if x_forwarded && !has_x_port {
+            created.push((
+                &b"X-Forwarded-Port"[..],
+                kawa::Store::from_string(public_port.to_string()),
+            ));
+        }
+        if x_forwarded && !has_x_proto {
+            created.push((
+                &b"X-Forwarded-Proto"[..],
+                kawa::Store::Static(proto.as_bytes()),
+            ));
+        }
+
+        for (offset, (key, val)) in created.into_iter().enumerate() {
+            request.blocks.insert(
+                index + offset,
+                kawa::Block::Header(kawa::Pair {
+                    key: kawa::Store::Static(key),
+                    val,
+                }),
+            );
+        }
+    }
+
+    /// Elide the request trailers that should not reach the backend: the fields
+    /// that affect framing, routing or authentication, and the ones missing from
+    /// the cluster's allowlist if it is not empty
+    pub fn filter_request_trailers(&mut self, request: &mut GenericHttpStream) {
+        let buf = request.storage.buffer();
+        for block in &mut request.blocks {
+            match block {
+                kawa::Block::Flags(flags) if flags.end_body => self.in_request_trailers = true,
+                kawa::Block::Header(header) if self.in_request_trailers && !header.is_elided() => {
+                    let key = header.key.data(buf);
+                    let allowed = !FORBIDDEN_TRAILERS
+                        .iter()
+                        .any(|forbidden| compare_no_case(key, forbidden))
+                        && (self.allowed_request_trailers.is_empty()
+                            || self
+                                .allowed_request_trailers
+                                .iter()
+                                .any(|name| compare_no_case(key, name.as_bytes())));
+                    if !allowed {
+                        incr!("http.trailers.dropped");
+                        header.elide();
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Kawa writes the Host and Cookie header names of a request itself,
+    /// replace them in the prepared output with the names used by the client
+    pub fn restore_header_case(&self, out: &mut VecDeque<kawa::OutBlock>) {
+        for block in out {
+            if let kawa::OutBlock::Store(store) = block {
+                let name = match store {
+                    kawa::Store::Static(b"\r\nHost: ") => self
+                        .host_header_name
+                        .as_ref()
+                        .map(|name| format!("\r\n{name}: ")),
+                    kawa::Store::Static(b"Cookie: ") => self
+                        .cookie_header_name
+                        .as_ref()
+                        .map(|name| format!("{name}: ")),
+                    _ => None,
+                };
+                if let Some(name) = name {
+                    *store = kawa::Store::from_string(name);
+                }
+            }
+        }
+    }
+
+    /// true if the request is a CONNECT on a forward proxy listener: Sōzu opens the
+    /// tunnel to the backend of its target itself, instead of forwarding the request
+    pub fn is_connect_tunnel(&self) -> bool {
+        self.forward_proxy && self.method == Some(Method::Connect)
+    }
+
+    /// true if the backend accepted to open a tunnel for a CONNECT request
+    pub fn is_connect_established(&self) -> bool {
+        self.method == Some(Method::Connect) && matches!(self.status, Some(200..=299))
     }
 
     pub fn log_context(&self) -> LogContext {
@@ -366,3 +954,98 @@ impl HttpContext {
         }
     }
 }
+
+/// Name of the header line holding `val` in the buffer, if it is `canonical`
+/// written with another case. `val` must directly follow the "name:" prefix.
+fn original_header_name(buf: &[u8], val: &kawa::Store, canonical: &[u8]) -> Option<String> {
+    let name = raw_header_name(buf, val)?;
+    if name == canonical || !compare_no_case(name, canonical) {
+        return None;
+    }
+    from_utf8(name).ok().map(ToOwned::to_owned)
+}
+
+/// Name of the header line holding `val` in the buffer, even if Kawa elided it.
+/// `val` must directly follow the "name:" prefix.
+fn raw_header_name<'a>(buf: &'a [u8], val: &kawa::Store) -> Option<&'a [u8]> {
+    let kawa::Store::Slice(slice) = val else {
+        return None;
+    };
+    let line = buf.get(..slice.start as usize)?;
+    let colon = line.iter().rposition(|c| *c != b' ' && *c != b'\t')?;
+    if line[colon] != b':' {
+        return None;
+    }
+    let start = line[..colon]
+        .iter()
+        .rposition(|c| *c == b'\n')
+        .map_or(0, |index| index + 1);
+    Some(&line[start..colon])
+}
+
+/// One element of a "Forwarded" header (RFC 7239 section 4). The values that are not
+/// tokens, like the addresses with a port or the IPv6 ones, are quoted
+fn forwarded_element(
+    proto: &str,
+    peer_addr: SocketAddr,
+    public_ip: IpAddr,
+    host: Option<&str>,
+) -> String {
+    let by = match public_ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{ip}]"),
+    };
+    let mut element = format!(
+        "proto={proto};for={};by={}",
+        forwarded_value(&peer_addr.to_string()),
+        forwarded_value(&by)
+    );
+    if let Some(host) = host {
+        element.push_str(";host=");
+        element.push_str(&forwarded_value(host));
+    }
+    element
+}
+
+fn forwarded_value(value: &str) -> String {
+    let is_token = !value.is_empty()
+        && value
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c));
+    if is_token {
+        value.to_owned()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// Size of a header section as counted for SETTINGS_MAX_HEADER_LIST_SIZE: the
+/// length of each name and value, plus 32 bytes per field. Each cookie counts as
+/// a field, as it would in HTTP/2.
+fn header_section_size(stream: &GenericHttpStream) -> usize {
+    let headers = stream.blocks.iter().filter_map(|block| match block {
+        kawa::Block::Header(header) if !header.is_elided() => {
+            Some(header.key.len() + header.val.len() + 32)
+        }
+        _ => None,
+    });
+    let cookies = stream
+        .detached
+        .jar
+        .iter()
+        .map(|cookie| cookie.key.len() + cookie.val.len() + 32);
+    headers.chain(cookies).sum()
+}
+
+/// whether `haystack` contains `needle`, ignoring the ASCII case
+fn contains_no_case(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| compare_no_case(window, needle))
+}
+
+/// whether a client may choose the id of its request: a short token of visible
+/// ASCII characters, that can not break the access logs
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.bytes().all(|c| c.is_ascii_graphic())
+}
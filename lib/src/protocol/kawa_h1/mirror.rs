@@ -0,0 +1,151 @@
+use std::{
+    cell::RefCell,
+    io::{ErrorKind, IoSlice},
+    net::Shutdown,
+    rc::Rc,
+};
+
+use mio::{net::TcpStream, Token};
+
+use crate::{
+    backends::Backend,
+    socket::{SocketHandler, SocketResult},
+    sozu_command::ready::Ready,
+    L7Proxy, Readiness,
+};
+
+/// Shadow connection to a backend of the mirror cluster.
+///
+/// It receives a copy of the bytes of the request written to the main backend,
+/// its response is read and discarded. Any failure closes it without affecting
+/// the client.
+pub struct Mirror {
+    pub backend: Rc<RefCell<Backend>>,
+    pub readiness: Readiness,
+    pub token: Token,
+    socket: TcpStream,
+    /// request bytes not written to the mirror yet
+    pending: Vec<u8>,
+    /// the mirror is dropped if it lags behind the main backend by more than this
+    max_pending: usize,
+    failed: bool,
+}
+
+impl Mirror {
+    pub fn new(
+        backend: Rc<RefCell<Backend>>,
+        socket: TcpStream,
+        token: Token,
+        max_pending: usize,
+    ) -> Self {
+        Mirror {
+            backend,
+            readiness: Readiness {
+                interest: Ready::READABLE | Ready::WRITABLE | Ready::HUP | Ready::ERROR,
+                event: Ready::EMPTY,
+            },
+            token,
+            socket,
+            pending: Vec::new(),
+            max_pending,
+            failed: false,
+        }
+    }
+
+    /// Copy the first `size` bytes of `bufs`, that were just written to the main backend
+    pub fn push(&mut self, bufs: &[IoSlice], mut size: usize) {
+        if self.failed {
+            return;
+        }
+        for buf in bufs {
+            if size == 0 {
+                break;
+            }
+            let len = buf.len().min(size);
+            self.pending.extend_from_slice(&buf[..len]);
+            size -= len;
+        }
+
+        if self.pending.len() > self.max_pending {
+            incr!("http.mirror.dropped");
+            self.failed = true;
+            return;
+        }
+        self.flush();
+    }
+
+    /// Write pending bytes and discard the response.
+    /// Returns false once the mirror should be closed.
+    pub fn ready(&mut self) -> bool {
+        if self.failed {
+            return false;
+        }
+        let events = self.readiness.filter_interest();
+        if events.is_error() || events.is_hup() {
+            incr!("http.mirror.errors");
+            return false;
+        }
+
+        if events.is_writable() {
+            self.flush();
+        }
+
+        if events.is_readable() {
+            let mut discard = [0; 4096];
+            loop {
+                let (size, socket_state) = self.socket.socket_read(&mut discard);
+                count!("http.mirror.bytes_in", size as i64);
+                match socket_state {
+                    SocketResult::Continue => {}
+                    SocketResult::WouldBlock => {
+                        self.readiness.event.remove(Ready::READABLE);
+                        break;
+                    }
+                    SocketResult::Error | SocketResult::Closed => {
+                        self.failed = true;
+                        break;
+                    }
+                }
+            }
+        }
+
+        !self.failed
+    }
+
+    fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let (size, socket_state) = self.socket.socket_write(&self.pending);
+        self.pending.drain(..size);
+        count!("http.mirror.bytes_out", size as i64);
+
+        match socket_state {
+            SocketResult::Continue => {}
+            SocketResult::WouldBlock => self.readiness.event.remove(Ready::WRITABLE),
+            SocketResult::Error | SocketResult::Closed => {
+                incr!("http.mirror.errors");
+                self.failed = true;
+            }
+        }
+    }
+
+    pub fn close(mut self, proxy: &dyn L7Proxy) {
+        if let Err(e) = proxy.deregister_socket(&mut self.socket) {
+            error!(
+                "error deregistering mirror socket({:?}): {:?}",
+                self.socket, e
+            );
+        }
+        if let Err(e) = self.socket.shutdown(Shutdown::Both) {
+            if e.kind() != ErrorKind::NotConnected {
+                error!(
+                    "error shutting down mirror socket({:?}): {:?}",
+                    self.socket, e
+                );
+            }
+        }
+        proxy.remove_session(self.token);
+        self.backend.borrow_mut().dec_connections();
+    }
+}
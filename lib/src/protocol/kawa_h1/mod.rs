@@ -1,7 +1,11 @@
 pub mod answers;
+pub mod buffering;
+pub mod compression;
 pub mod diagnostics;
 pub mod editor;
+pub mod mirror;
 pub mod parser;
+pub mod rewrite;
 
 use std::{
     cell::RefCell,
@@ -12,11 +16,15 @@ use std::{
 };
 
 use mio::{net::TcpStream, Interest, Token};
+use rand::{thread_rng, Rng};
 use rusty_ulid::Ulid;
 use sozu_command::{
     config::MAX_LOOP_ITERATIONS,
     logging::EndpointRecord,
-    proto::command::{Event, EventKind, ListenerType},
+    proto::command::{
+        Event, EventKind, ForwardedHeaders, ListenerType, ProxyProtocolConfig, ResponseBuffering,
+        SessionSnapshot,
+    },
 };
 // use time::{Duration, Instant};
 
@@ -26,17 +34,21 @@ use crate::{
     protocol::{
         http::{
             answers::DefaultAnswerStream,
-            diagnostics::{diagnostic_400_502, diagnostic_413_507},
+            diagnostics::{diagnostic_400_502, diagnostic_413_507, diagnostic_502_h2_backend},
             editor::HttpContext,
-            parser::Method,
+            parser::{compare_no_case, host_matches_server_name, Method},
         },
         pipe::WebSocketContext,
+        proxy_protocol::header::{Command, HeaderV2, ProxyProtocolHeader},
         SessionState,
     },
     retry::RetryPolicy,
     router::Route,
     server::{push_event, CONN_RETRIES},
-    socket::{stats::socket_rtt, SocketHandler, SocketResult, TransportProtocol},
+    socket::{
+        set_tcp_keepalive, stats::socket_rtt, SocketHandler, SocketResult, TlsDetails,
+        TransportProtocol,
+    },
     sozu_command::{logging::LogContext, ready::Ready},
     timer::TimeoutContainer,
     AcceptError, BackendConnectAction, BackendConnectionError, BackendConnectionStatus,
@@ -64,6 +76,15 @@ macro_rules! log_context {
 /// Generic Http representation using the Kawa crate using the Checkout of Sozu as buffer
 type GenericHttpStream = kawa::Kawa<Checkout>;
 
+/// maximum number of request bytes read and discarded after a default answer
+const MAX_DRAINED_BYTES: usize = 1 << 16;
+
+/// answer of Sōzu to a CONNECT request once the tunnel to its target is open
+const CONNECTION_ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+
+/// interval at which a queued request looks for a backend of its cluster again
+const BACKEND_QUEUE_RETRY: Duration = Duration::from_millis(100);
+
 impl kawa::AsBuffer for Checkout {
     fn as_buffer(&self) -> &[u8] {
         self.inner.extra()
@@ -95,6 +116,10 @@ pub enum DefaultAnswer {
         phase: kawa::ParsingPhaseMarker,
         capacity: usize,
     },
+    Answer414 {},
+    Answer421 {},
+    Answer431 {},
+    Answer501 {},
     Answer502 {
         message: String,
         phase: kawa::ParsingPhaseMarker,
@@ -124,6 +149,10 @@ impl From<&DefaultAnswer> for u16 {
             DefaultAnswer::Answer404 { .. } => 404,
             DefaultAnswer::Answer408 { .. } => 408,
             DefaultAnswer::Answer413 { .. } => 413,
+            DefaultAnswer::Answer414 { .. } => 414,
+            DefaultAnswer::Answer421 { .. } => 421,
+            DefaultAnswer::Answer431 { .. } => 431,
+            DefaultAnswer::Answer501 { .. } => 501,
             DefaultAnswer::Answer502 { .. } => 502,
             DefaultAnswer::Answer503 { .. } => 503,
             DefaultAnswer::Answer504 { .. } => 504,
@@ -132,6 +161,17 @@ impl From<&DefaultAnswer> for u16 {
     }
 }
 
+/// What is left of a request answered by Sōzu itself, once drained
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DrainResult {
+    /// its body ended, the connection waits for the next request
+    Complete,
+    /// more of its body is expected
+    Pending,
+    /// the connection is closed
+    Close,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TimeoutStatus {
     Request,
@@ -145,29 +185,61 @@ pub enum ResponseStream {
     DefaultAnswer(u16, DefaultAnswerStream),
 }
 
+/// Request waiting for a backend while all the backends of its cluster are down.
+/// The connection is retried on timeouts, so it keeps what connecting needs. The
+/// session owns this state, it is only referenced weakly
+struct QueuedRequest {
+    cluster_id: String,
+    since: Instant,
+    deadline: Instant,
+    proxy: Rc<RefCell<dyn L7Proxy>>,
+    session: Weak<RefCell<dyn ProxySession>>,
+}
+
 /// Http will be contained in State which itself is contained by Session
 pub struct Http<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> {
     answers: Rc<RefCell<answers::HttpAnswers>>,
     pub backend: Option<Rc<RefCell<Backend>>>,
     backend_connection_status: BackendConnectionStatus,
+    /// PROXY protocol header that must be written to a newly opened backend connection
+    /// before any HTTP byte
+    backend_proxy_header: Option<Vec<u8>>,
+    /// place of the request in the queue of its cluster, while no backend is available
+    backend_queue: Option<QueuedRequest>,
     pub backend_readiness: Readiness,
     pub backend_socket: Option<TcpStream>,
     backend_stop: Option<Instant>,
     pub backend_token: Option<Token>,
     pub container_backend_timeout: TimeoutContainer,
     pub container_frontend_timeout: TimeoutContainer,
+    /// next attempt of a queued request to connect to a backend
+    container_queue_timeout: TimeoutContainer,
     configured_backend_timeout: Duration,
     configured_connect_timeout: Duration,
     configured_frontend_timeout: Duration,
     /// attempts to connect to the backends during the session
     connection_attempts: u8,
+    /// request bytes read and discarded after a default answer
+    drained_bytes: usize,
     pub frontend_readiness: Readiness,
     pub frontend_socket: Front,
     frontend_token: Token,
     keepalive_count: usize,
+    /// reading from the frontend paused at the high-water mark, until the backend
+    /// takes the request body below half of it
+    frontend_read_paused: bool,
     listener: Rc<RefCell<L>>,
+    /// shadow connection receiving a copy of the current request
+    mirror: Option<mirror::Mirror>,
+    /// a response buffer is checked out again for the request following a default answer
+    pool: Weak<RefCell<Pool>>,
     pub request_stream: GenericHttpStream,
     pub response_stream: ResponseStream,
+    /// request body bytes read from the frontend and not yet written to the backend
+    /// over which reading pauses, the buffer size is the only limit if absent
+    read_high_water_mark: Option<usize>,
+    /// longest request target accepted, no limit if absent
+    max_uri_length: Option<usize>,
     /// The HTTP context was separated from the State for borrowing reasons.
     /// Calling a kawa parser mutably borrows the State through request_stream or response_stream,
     /// so Http can't be borrowed again to be used in callbacks. HttContext is an independant
@@ -209,11 +281,36 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                     _ => return Err(AcceptError::BufferCapacityReached),
                 }
             }
-            None => return Err(AcceptError::BufferCapacityReached),
+            None => return Err(AcceptError::PoolDropped),
         };
+        let forward_proxy = listener.borrow().forward_proxy();
+        let request_id_header = listener.borrow().request_id_header().map(ToOwned::to_owned);
+        let trust_client_request_id = listener.borrow().trust_client_request_id();
+        let reject_encoded_slashes = listener.borrow().reject_encoded_slashes();
+        let forward_normalized_path = listener.borrow().forward_normalized_path();
+        let alt_svc = listener.borrow().alt_svc().map(ToOwned::to_owned);
+        let replace_alt_svc = listener.borrow().replace_alt_svc();
+        let max_request_header_size = listener.borrow().max_request_header_size();
+        let max_response_header_size = listener.borrow().max_response_header_size();
+        let forward_tls_headers = listener.borrow().forward_tls_headers();
+        let read_high_water_mark = listener
+            .borrow()
+            .read_buffer_high_water_mark()
+            .map(|mark| mark as usize);
+        let max_uri_length = match listener.borrow().max_uri_length() {
+            0 => None,
+            length => Some(length as usize),
+        };
+        let tls = TlsDetails::new(&frontend_socket);
+        match protocol {
+            Protocol::HTTPS => incr!("https.connections.h1"),
+            _ => incr!("http.connections.h1"),
+        }
         Ok(Http {
             answers,
             backend_connection_status: BackendConnectionStatus::NotConnected,
+            backend_proxy_header: None,
+            backend_queue: None,
             backend_readiness: Readiness::new(),
             backend_socket: None,
             backend_stop: None,
@@ -223,8 +320,10 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             configured_connect_timeout,
             configured_frontend_timeout,
             connection_attempts: 0,
+            drained_bytes: 0,
             container_backend_timeout: TimeoutContainer::new_empty(configured_connect_timeout),
             container_frontend_timeout,
+            container_queue_timeout: TimeoutContainer::new_empty(BACKEND_QUEUE_RETRY),
             frontend_readiness: Readiness {
                 interest: Ready::READABLE | Ready::HUP | Ready::ERROR,
                 event: Ready::EMPTY,
@@ -232,7 +331,10 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             frontend_socket,
             frontend_token,
             keepalive_count: 0,
+            frontend_read_paused: false,
             listener,
+            mirror: None,
+            pool,
             request_stream: GenericHttpStream::new(
                 kawa::Kind::Request,
                 kawa::Buffer::new(front_buffer),
@@ -241,6 +343,8 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                 kawa::Kind::Response,
                 kawa::Buffer::new(back_buffer),
             )),
+            read_high_water_mark,
+            max_uri_length,
             context: HttpContext {
                 id: request_id,
                 backend_id: None,
@@ -252,9 +356,38 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                 protocol,
                 public_address,
                 session_address,
+                tls,
+                forward_tls_headers,
                 sticky_name,
                 sticky_session: None,
                 sticky_session_found: None,
+                send_server_timing: false,
+                server_timing: None,
+                preserve_header_case: false,
+                host_header_name: None,
+                cookie_header_name: None,
+                forward_proxy,
+                request_id_header,
+                trust_client_request_id,
+                reject_encoded_slashes,
+                forward_normalized_path,
+                alt_svc,
+                replace_alt_svc,
+                max_request_header_size,
+                max_response_header_size,
+                compression: None,
+                response_rewrite: None,
+                response_buffering: None,
+                forwarded_headers: ForwardedHeaders::All,
+                forwarded_headers_index: None,
+                allowed_request_trailers: Vec::new(),
+                in_request_trailers: false,
+                unsupported_transfer_encoding: false,
+                conflicting_framing: false,
+                rejected_absolute_form: false,
+                request_headers_too_large: false,
+                http10_client: false,
+                invalid_path: None,
 
                 method: None,
                 authority: None,
@@ -262,10 +395,64 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                 status: None,
                 reason: None,
                 user_agent: None,
+                request_id: None,
+                request_body_size: 0,
+                response_body_size: 0,
+                accept_encoding: None,
+                response_compression: None,
+                response_streamed: false,
             },
         })
     }
 
+    /// Move the body bytes not sent yet to the front of a full request buffer, the
+    /// space of the ones already sent to the backend is read into again. Kawa only
+    /// does it once half of the buffer is sent, a body trickling to a slow backend
+    /// would otherwise stop reads with the buffer partly free. Only the parsed
+    /// blocks not converted yet would be left pointing at the old positions
+    fn reclaim_request_space(&mut self) -> bool {
+        let kawa = &mut self.request_stream;
+        if !kawa.is_main_phase() || kawa.storage.start == 0 || !kawa.blocks.is_empty() {
+            return false;
+        }
+        let amount = kawa.storage.shift() as u32;
+        kawa.push_left(amount);
+        true
+    }
+
+    /// The request bytes waiting for the backend reached the configured high-water mark
+    fn reached_read_high_water_mark(&self) -> bool {
+        self.read_high_water_mark
+            .is_some_and(|mark| self.request_stream.storage.available_data() >= mark)
+    }
+
+    /// The backend took the request bytes below half of the high-water mark, reading
+    /// from the frontend can resume
+    fn below_read_low_water_mark(&self) -> bool {
+        !self
+            .read_high_water_mark
+            .is_some_and(|mark| self.request_stream.storage.available_data() >= mark / 2)
+    }
+
+    /// The request target is longer than the configured limit. It is checked while the
+    /// request line arrives, a long one is refused before it fills the buffer
+    fn request_target_too_long(&self) -> bool {
+        let Some(max) = self.max_uri_length else {
+            return false;
+        };
+        let length = match (
+            &self.request_stream.parsing_phase,
+            &self.request_stream.detached.status_line,
+        ) {
+            (kawa::ParsingPhase::StatusLine, _) => {
+                partial_request_target_length(self.request_stream.storage.unparsed_data())
+            }
+            (_, kawa::StatusLine::Request { uri, .. }) => uri.len(),
+            _ => 0,
+        };
+        length > max
+    }
+
     /// Reset the connection in case of keep-alive to be ready for the next request
     pub fn reset(&mut self) {
         trace!("{} ============== reset", log_context!(self));
@@ -280,6 +467,8 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
         self.request_stream.clear();
         response_stream.clear();
         self.keepalive_count += 1;
+        self.frontend_read_paused = false;
+        self.drained_bytes = 0;
         gauge_add!("http.active_requests", -1);
 
         if let Some(backend) = &mut self.backend {
@@ -324,7 +513,15 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
 
     pub fn readable(&mut self, metrics: &mut SessionMetrics) -> StateResult {
         trace!("{} ============== readable", log_context!(self));
-        if !self.container_frontend_timeout.reset() {
+        // once a request has started, its headers must arrive before the timeout
+        // expires: reads do not push it back, so a client trickling bytes is closed
+        let receiving_headers =
+            !self.request_stream.is_initial() && !self.request_stream.is_main_phase();
+        // a queued request is bounded by the queue timeout instead
+        if !receiving_headers
+            && self.backend_queue.is_none()
+            && !self.container_frontend_timeout.reset()
+        {
             error!(
                 "could not reset front timeout {:?}",
                 self.configured_frontend_timeout
@@ -332,21 +529,22 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             self.print_state(self.protocol_string());
         }
 
-        let response_stream = match &mut self.response_stream {
-            ResponseStream::BackendAnswer(response_stream) => response_stream,
-            ResponseStream::DefaultAnswer(..) => {
-                error!(
-                    "{} Sending default answer, should not read from frontend socket",
-                    log_context!(self)
-                );
-
-                self.frontend_readiness.interest.remove(Ready::READABLE);
-                self.frontend_readiness.interest.insert(Ready::WRITABLE);
-                return StateResult::Continue;
+        if let ResponseStream::DefaultAnswer(_, answer) = &self.response_stream {
+            // the answer is written, the rest of its request is drained
+            if answer.is_completed() {
+                return self.drain_after_default_answer(metrics);
             }
-        };
+            error!(
+                "{} Sending default answer, should not read from frontend socket",
+                log_context!(self)
+            );
+
+            self.frontend_readiness.interest.remove(Ready::READABLE);
+            self.frontend_readiness.interest.insert(Ready::WRITABLE);
+            return StateResult::Continue;
+        }
 
-        if self.request_stream.storage.is_full() {
+        if self.request_stream.storage.is_full() && !self.reclaim_request_space() {
             self.frontend_readiness.interest.remove(Ready::READABLE);
             if self.request_stream.is_main_phase() {
                 self.backend_readiness.interest.insert(Ready::WRITABLE);
@@ -361,9 +559,26 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             return StateResult::Continue;
         }
 
-        let (size, socket_state) = self
-            .frontend_socket
-            .socket_read(self.request_stream.storage.space());
+        // the backend is slower than the client, the body waits in the client's socket
+        // instead of the buffer until the backend catches up
+        if self.request_stream.is_main_phase() && self.reached_read_high_water_mark() {
+            incr!("http.frontend_read_paused");
+            self.frontend_read_paused = true;
+            self.frontend_readiness.interest.remove(Ready::READABLE);
+            self.backend_readiness.interest.insert(Ready::WRITABLE);
+            return StateResult::Continue;
+        }
+
+        // a body read does not go over the high-water mark either
+        let room = match self.read_high_water_mark {
+            Some(mark) if self.request_stream.is_main_phase() => {
+                mark - self.request_stream.storage.available_data()
+            }
+            _ => usize::MAX,
+        };
+        let space = self.request_stream.storage.space();
+        let space_len = space.len().min(room);
+        let (size, socket_state) = self.frontend_socket.socket_read(&mut space[..space_len]);
 
         debug!("{} Read {} bytes", log_context!(self), size);
 
@@ -415,13 +630,16 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
         // kawa::debug_kawa(&self.request_stream);
 
         if was_initial && !self.request_stream.is_initial() {
+            gauge_add!("http.active_requests", 1);
+            incr!("http.requests");
+        }
+
+        if was_not_proxying && self.request_stream.is_main_phase() {
             // if it was the first request, the front timeout duration
-            // was set to request_timeout, which is much lower. For future
-            // requests on this connection, we can wait a bit more
+            // was set to request_timeout, which is much lower. Now that
+            // the headers are in, we can wait a bit more
             self.container_frontend_timeout
                 .set_duration(self.configured_frontend_timeout);
-            gauge_add!("http.active_requests", 1);
-            incr!("http.requests");
         }
 
         if let kawa::ParsingPhase::Error { marker, kind } = self.request_stream.parsing_phase {
@@ -447,7 +665,11 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                     kawa::ParsingErrorKind::Processing { message } => message.to_owned(),
                 }
             );
-            if response_stream.consumed {
+            let response_consumed = matches!(
+                &self.response_stream,
+                ResponseStream::BackendAnswer(response_stream) if response_stream.consumed
+            );
+            if response_consumed {
                 self.log_request_error(metrics, "Parsing error on the request");
                 return StateResult::CloseSession;
             } else {
@@ -464,9 +686,97 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             }
         }
 
+        if was_not_proxying && self.request_target_too_long() {
+            warn!(
+                "{} Request target longer than max_uri_length",
+                log_context!(self)
+            );
+            self.set_answer(DefaultAnswer::Answer414 {});
+            return StateResult::Continue;
+        }
+
         if self.request_stream.is_main_phase() {
             self.backend_readiness.interest.insert(Ready::WRITABLE);
             if was_not_proxying {
+                if self.context.request_headers_too_large {
+                    warn!(
+                        "{} Request headers larger than max_request_header_size",
+                        log_context!(self)
+                    );
+                    self.set_answer(DefaultAnswer::Answer431 {});
+                    return StateResult::Continue;
+                }
+                if self.context.method == Some(Method::Connect) && !self.context.forward_proxy {
+                    warn!(
+                        "{} CONNECT refused on a reverse proxy listener: {:?}",
+                        log_context!(self),
+                        self.get_route()
+                    );
+                    self.set_answer(DefaultAnswer::Answer400 {
+                        message: "CONNECT requests are only accepted by forward proxy listeners."
+                            .into(),
+                        phase: self.request_stream.parsing_phase.marker(),
+                        successfully_parsed: "null".into(),
+                        partially_parsed: "null".into(),
+                        invalid: "null".into(),
+                    });
+                    return StateResult::Continue;
+                }
+                if self.context.rejected_absolute_form {
+                    warn!(
+                        "{} Request target in absolute-form refused: {:?}",
+                        log_context!(self),
+                        self.get_route()
+                    );
+                    self.set_answer(DefaultAnswer::Answer400 {
+                        message: "Requests in absolute-form are only accepted by forward proxy listeners, with the http or https scheme.".into(),
+                        phase: self.request_stream.parsing_phase.marker(),
+                        successfully_parsed: "null".into(),
+                        partially_parsed: "null".into(),
+                        invalid: "null".into(),
+                    });
+                    return StateResult::Continue;
+                }
+                if let Some(error) = self.context.invalid_path.as_ref().map(ToString::to_string) {
+                    incr!("http.invalid_paths");
+                    warn!(
+                        "{} Request path refused, {}: {:?}",
+                        log_context!(self),
+                        error,
+                        self.get_route()
+                    );
+                    self.set_answer(DefaultAnswer::Answer400 {
+                        message: format!("The request path can not be routed safely: {error}."),
+                        phase: self.request_stream.parsing_phase.marker(),
+                        successfully_parsed: "null".into(),
+                        partially_parsed: "null".into(),
+                        invalid: "null".into(),
+                    });
+                    return StateResult::Continue;
+                }
+                if self.context.conflicting_framing {
+                    incr!("http.frontend_framing_conflicts");
+                    warn!(
+                        "{} Request with both Content-Length and Transfer-Encoding",
+                        log_context!(self)
+                    );
+                    self.set_answer(DefaultAnswer::Answer400 {
+                        message: "The request has both a Content-Length and a Transfer-Encoding header, its length is ambiguous.".into(),
+                        phase: self.request_stream.parsing_phase.marker(),
+                        successfully_parsed: "null".into(),
+                        partially_parsed: "null".into(),
+                        invalid: "null".into(),
+                    });
+                    return StateResult::Continue;
+                }
+                if self.context.unsupported_transfer_encoding {
+                    warn!(
+                        "{} Unsupported Transfer-Encoding in the request",
+                        log_context!(self)
+                    );
+                    self.set_answer(DefaultAnswer::Answer501 {});
+                    return StateResult::Continue;
+                }
                 // Sozu tries to connect only once all the headers were gathered and edited
                 // this could be improved
                 trace!("{} ============== HANDLE CONNECTION!", log_context!(self));
@@ -487,7 +797,44 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             _ => return self.writable_default_answer(metrics),
         };
 
+        if self.context.hold_response(response_stream) {
+            // sent once complete, backend_readable asks for WRITABLE again
+            self.frontend_readiness.interest.remove(Ready::WRITABLE);
+            return StateResult::Continue;
+        }
+
+        if self.context.drops_interim_response(response_stream) {
+            trace!(
+                "{} ============== DROP INTERIM RESPONSE!",
+                log_context!(self)
+            );
+            incr!("http.interim_responses_dropped");
+            // consumed as if it was written, the final response may follow in the buffer
+            response_stream.prepare(&mut kawa::h1::BlockConverter);
+            let size = response_stream
+                .as_io_slice()
+                .iter()
+                .map(|buf| buf.len())
+                .sum();
+            response_stream.consume(size);
+            response_stream.clear();
+            self.frontend_readiness.interest.remove(Ready::WRITABLE);
+            // the interest was removed once the interim response was terminated
+            self.backend_readiness.interest.insert(Ready::READABLE);
+            self.backend_readiness.event.insert(Ready::READABLE);
+            return StateResult::Continue;
+        }
+
+        if let Some(compression) = &mut self.context.response_compression {
+            compression.compress_blocks(response_stream);
+        }
+        self.context.response_body_size += body_bytes(response_stream);
         response_stream.prepare(&mut kawa::h1::BlockConverter);
+        if self.context.response_compression.is_some() && response_stream.out.is_empty() {
+            // the compressor holds a copy of the body read so far, the buffer can be emptied
+            response_stream.consume(0);
+            self.backend_readiness.interest.insert(Ready::READABLE);
+        }
 
         let bufs = response_stream.as_io_slice();
         if bufs.is_empty() && !self.frontend_socket.socket_wants_write() {
@@ -504,6 +851,11 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             count!("bytes_out", size as i64);
             metrics.bout += size;
             self.backend_readiness.interest.insert(Ready::READABLE);
+            // the backend timeout only measures inactivity: a response that never ends,
+            // like Server-Sent Events, stays open while it makes progress on either side
+            if !response_stream.is_terminated() {
+                self.container_backend_timeout.reset();
+            }
         }
 
         match socket_state {
@@ -542,6 +894,11 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                     self.log_request_success(metrics);
                     return StateResult::Upgrade;
                 }
+                kawa::StatusLine::Response { .. } if self.context.is_connect_established() => {
+                    trace!("{} ============== HANDLE CONNECT!", log_context!(self));
+                    self.log_request_success(metrics);
+                    return StateResult::Upgrade;
+                }
                 kawa::StatusLine::Response { code: 100, .. } => {
                     trace!("{} ============== HANDLE CONTINUE!", log_context!(self));
                     response_stream.clear();
@@ -634,9 +991,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
         if response_stream.is_completed() {
             save_http_status_metric(self.context.status, self.context.log_context());
             self.log_default_answer_success(metrics);
-            self.frontend_readiness.reset();
-            self.backend_readiness.reset();
-            return StateResult::CloseSession;
+            return self.drain_after_default_answer(metrics);
         }
 
         if socket_state == SocketResult::Error {
@@ -651,6 +1006,142 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
         }
     }
 
+    /// Drain the request of a written default answer. The connection waits for the
+    /// next request once its body ended, if the answer kept it open
+    fn drain_after_default_answer(&mut self, metrics: &mut SessionMetrics) -> StateResult {
+        match self.drain_request_body(metrics, MAX_DRAINED_BYTES) {
+            DrainResult::Complete if self.context.keep_alive_frontend && !self.context.closing => {
+                let back_buffer = match self.pool.upgrade() {
+                    Some(pool) => pool.borrow_mut().checkout(),
+                    None => None,
+                };
+                if let Some(back_buffer) = back_buffer {
+                    debug!(
+                        "{} Keep alive frontend after default answer",
+                        log_context!(self)
+                    );
+                    self.response_stream = ResponseStream::BackendAnswer(GenericHttpStream::new(
+                        kawa::Kind::Response,
+                        kawa::Buffer::new(back_buffer),
+                    ));
+                    metrics.reset();
+                    self.reset();
+                    return StateResult::Continue;
+                }
+            }
+            DrainResult::Pending => {
+                self.frontend_readiness.interest = Ready::READABLE | Ready::HUP | Ready::ERROR;
+                return StateResult::Continue;
+            }
+            _ => {}
+        }
+        self.frontend_readiness.reset();
+        self.backend_readiness.reset();
+        StateResult::CloseSession
+    }
+
+    /// Read and discard what the client still sends after a request answered by
+    /// sozu itself. Closing a socket with unread data resets the connection, and
+    /// the client could lose the answer before reading it.
+    /// If the answer kept the connection open, the request is parsed up to the end
+    /// of its body. Otherwise what the client already sent is discarded.
+    /// Either way, the connection is closed once the client sent more than `limit` bytes.
+    fn drain_request_body(&mut self, metrics: &mut SessionMetrics, limit: usize) -> DrainResult {
+        let framed = self.context.keep_alive_frontend;
+        let mut drained = 0;
+        let mut would_block = false;
+        let result = loop {
+            let request = &mut self.request_stream;
+            if framed {
+                kawa::h1::parse(request, &mut kawa::h1::NoCallbacks);
+                // the parsed bytes are discarded, a pipelined request stays in the buffer
+                request.blocks.clear();
+                request.out.clear();
+                let parsed = request.storage.head - request.storage.start;
+                request.storage.consume(parsed);
+                if request.is_terminated() {
+                    break DrainResult::Complete;
+                }
+                if request.is_error() {
+                    break DrainResult::Close;
+                }
+                if would_block {
+                    self.frontend_readiness.event.remove(Ready::READABLE);
+                    break DrainResult::Pending;
+                }
+            } else if would_block || request.is_terminated() && request.storage.is_empty() {
+                break DrainResult::Close;
+            }
+            if self.drained_bytes + drained >= limit {
+                debug!(
+                    "{} request body too large to drain, closing",
+                    log_context!(self)
+                );
+                break DrainResult::Close;
+            }
+
+            let request = &mut self.request_stream;
+            if framed {
+                request.storage.shift();
+            } else {
+                request.storage.clear();
+            }
+            let (size, socket_state) = self
+                .frontend_socket
+                .socket_read(self.request_stream.storage.space());
+            self.request_stream.storage.fill(size);
+            drained += size;
+            match socket_state {
+                SocketResult::Continue if size > 0 => {}
+                SocketResult::WouldBlock => would_block = true,
+                _ => break DrainResult::Close,
+            }
+        };
+        self.drained_bytes += drained;
+
+        if drained > 0 {
+            count!("bytes_in", drained as i64);
+            count!("http.drained_bytes", drained as i64);
+            metrics.bin += drained;
+            debug!(
+                "{} drained {} bytes of request body",
+                log_context!(self),
+                drained
+            );
+        }
+        if result == DrainResult::Close {
+            self.request_stream.clear();
+        }
+        result
+    }
+
+    /// The backend connection of a CONNECT request on a forward proxy listener is the
+    /// tunnel itself: the request is not forwarded, Sōzu answers it, and the session
+    /// switches to a Pipe once the answer is written, like after a backend accepted it
+    fn establish_tunnel(&mut self) -> SessionResult {
+        self.backend_readiness.interest.remove(Ready::WRITABLE);
+        let ResponseStream::BackendAnswer(response_stream) = &mut self.response_stream else {
+            return SessionResult::Continue;
+        };
+        // answered already, the client sent tunnel data before the answer was written
+        if !response_stream.is_initial() {
+            return SessionResult::Continue;
+        }
+        debug!(
+            "{} Tunnel to {:?} established",
+            log_context!(self),
+            self.context.authority
+        );
+        let answer = CONNECTION_ESTABLISHED;
+        response_stream.storage.space()[..answer.len()].copy_from_slice(answer);
+        response_stream.storage.fill(answer.len());
+        kawa::h1::parse(response_stream, &mut self.context);
+        // the target may speak first, it is read by the Pipe once the answer is sent
+        self.backend_readiness.interest.remove(Ready::READABLE);
+        self.frontend_readiness.interest.insert(Ready::WRITABLE);
+        SessionResult::Continue
+    }
+
     pub fn backend_writable(&mut self, metrics: &mut SessionMetrics) -> SessionResult {
         trace!("{} ============== backend_writable", log_context!(self));
         if let ResponseStream::DefaultAnswer(..) = self.response_stream {
@@ -670,7 +1161,44 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             return SessionResult::Close;
         };
 
+        if let Some(header) = self.backend_proxy_header.as_mut() {
+            let (size, socket_state) = backend_socket.socket_write(header);
+            header.drain(..size);
+            count!("back_bytes_out", size as i64);
+            metrics.backend_bout += size;
+
+            match socket_state {
+                SocketResult::Error | SocketResult::Closed => {
+                    incr!("proxy_protocol.errors");
+                    self.log_request_error(
+                        metrics,
+                        "could not send the proxy protocol header to the backend",
+                    );
+                    return SessionResult::Close;
+                }
+                SocketResult::WouldBlock => {
+                    self.backend_readiness.event.remove(Ready::WRITABLE);
+                    return SessionResult::Continue;
+                }
+                SocketResult::Continue => {}
+            }
+            debug!("{} Proxy protocol header sent", log_context!(self));
+            self.backend_proxy_header = None;
+        }
+
+        if self.context.is_connect_tunnel() {
+            return self.establish_tunnel();
+        }
+
+        self.context.request_body_size += body_bytes(&self.request_stream);
+        self.context.add_forwarded_headers(&mut self.request_stream);
+        self.context
+            .filter_request_trailers(&mut self.request_stream);
         self.request_stream.prepare(&mut kawa::h1::BlockConverter);
+        if self.context.preserve_header_case {
+            self.context
+                .restore_header_case(&mut self.request_stream.out);
+        }
 
         let bufs = self.request_stream.as_io_slice();
         if bufs.is_empty() {
@@ -682,10 +1210,18 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
         debug!("{} Wrote {} bytes", log_context!(self), size);
 
         if size > 0 {
+            if let Some(mirror) = self.mirror.as_mut() {
+                mirror.push(&bufs, size);
+            }
             self.request_stream.consume(size);
             count!("back_bytes_out", size as i64);
             metrics.backend_bout += size;
-            self.frontend_readiness.interest.insert(Ready::READABLE);
+            if self.frontend_read_paused && self.below_read_low_water_mark() {
+                self.frontend_read_paused = false;
+            }
+            if !self.frontend_read_paused {
+                self.frontend_readiness.interest.insert(Ready::READABLE);
+            }
             self.backend_readiness.interest.insert(Ready::READABLE);
         } else {
             self.backend_readiness.event.remove(Ready::WRITABLE);
@@ -819,6 +1355,28 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             "{} ============== backend_readable_parse",
             log_context!(self)
         );
+        if response_stream.is_initial()
+            && parser::is_h2_settings_frame(response_stream.storage.data())
+        {
+            incr!("http.backend_h2_mismatch");
+            warn!(
+                "{} Backend answered with an HTTP/2 SETTINGS frame to an HTTP/1.1 request, it only speaks HTTP/2",
+                log_context!(self)
+            );
+            let (message, successfully_parsed, partially_parsed, invalid) =
+                diagnostic_502_h2_backend(response_stream);
+            self.set_answer(DefaultAnswer::Answer502 {
+                message,
+                phase: kawa::ParsingPhaseMarker::StatusLine,
+                successfully_parsed,
+                partially_parsed,
+                invalid,
+            });
+            return SessionResult::Continue;
+        }
+        if response_stream.is_initial() && self.context.send_server_timing {
+            self.context.server_timing = Some(server_timing(metrics));
+        }
         kawa::h1::parse(response_stream, &mut self.context);
         // kawa::debug_kawa(&self.response_stream);
 
@@ -860,7 +1418,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             }
         }
 
-        if response_stream.is_main_phase() {
+        if response_stream.is_main_phase() && !self.context.hold_response(response_stream) {
             self.frontend_readiness.interest.insert(Ready::WRITABLE);
         }
         if response_stream.is_terminated() {
@@ -940,6 +1498,10 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
 
         let context = self.context.log_context();
         metrics.register_end_of_session(&context);
+        match self.context.protocol {
+            Protocol::HTTPS => incr!("https.requests.h1"),
+            _ => incr!("http.requests.h1"),
+        }
 
         log_access! {
             error,
@@ -959,11 +1521,14 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             bytes_in: metrics.bin,
             bytes_out: metrics.bout,
             user_agent: self.context.user_agent.as_deref(),
+            request_id: self.context.request_id.as_deref(),
+            tls: self.context.tls.as_ref().map(TlsDetails::record),
         };
     }
 
     pub fn log_request_success(&self, metrics: &SessionMetrics) {
         save_http_status_metric(self.context.status, self.context.log_context());
+        save_body_size_metrics(&self.context);
         self.log_request(metrics, false, None);
     }
 
@@ -1012,6 +1577,10 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                     self.context.cluster_id.as_deref(),
                     self.context.backend_id.as_deref()
                 ),
+                DefaultAnswer::Answer421 { .. } => incr!("http.421.errors"),
+                DefaultAnswer::Answer414 { .. } => incr!("http.414.errors"),
+                DefaultAnswer::Answer431 { .. } => incr!("http.431.errors"),
+                DefaultAnswer::Answer501 { .. } => incr!("http.501.errors"),
                 DefaultAnswer::Answer502 { .. } => incr!(
                     "http.502.errors",
                     self.context.cluster_id.as_deref(),
@@ -1042,15 +1611,48 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             self.context.backend_id.as_deref(),
             self.get_route(),
         );
+        // the connection outlives the answer if the rest of its request can be drained
+        let keep_alive_frontend = self.context.keep_alive_frontend
+            && !self.context.closing
+            && self.request_can_be_drained()
+            && answers::keep_connection(&mut kawa);
         kawa.prepare(&mut kawa::h1::BlockConverter);
         self.context.status = Some(status);
         self.context.reason = None;
-        self.context.keep_alive_frontend = false;
+        self.context.keep_alive_frontend = keep_alive_frontend;
         self.response_stream = ResponseStream::DefaultAnswer(status, kawa);
         self.frontend_readiness.interest = Ready::WRITABLE | Ready::HUP | Ready::ERROR;
         self.backend_readiness.interest = Ready::HUP | Ready::ERROR;
     }
 
+    /// The request was not forwarded and the end of its body is known, it can be read
+    /// up to there after a default answer. A client expecting a 100 Continue may
+    /// never send its body once answered, its connection is not kept
+    fn request_can_be_drained(&self) -> bool {
+        let request = &self.request_stream;
+        let buf = request.storage.buffer();
+        request.is_main_phase()
+            && !request.consumed
+            && request.body_size != kawa::BodySize::Empty
+            && !request.blocks.iter().any(|block| {
+                matches!(block, kawa::Block::Header(header)
+                    if !header.is_elided() && compare_no_case(header.key.data(buf), b"expect"))
+            })
+    }
+
+    /// Build a PROXY protocol v2 header carrying the client and public addresses of the session
+    fn proxy_protocol_header(&self) -> Option<Vec<u8>> {
+        let session_address = self.get_session_address()?;
+        Some(
+            ProxyProtocolHeader::V2(HeaderV2::new(
+                Command::Proxy,
+                session_address,
+                self.context.public_address,
+            ))
+            .into_bytes(),
+        )
+    }
+
     pub fn test_backend_socket(&self) -> bool {
         match self.backend_socket {
             Some(ref s) => {
@@ -1121,6 +1723,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
     /// I don't think this is a good idea, but it is a quick fix
     fn close_backend(&mut self, proxy: Rc<RefCell<dyn L7Proxy>>, metrics: &mut SessionMetrics) {
         self.container_backend_timeout.cancel();
+        self.backend_proxy_header = None;
         debug!(
             "{}\tPROXY [{}->{}] CLOSED BACKEND",
             log_context!(self),
@@ -1186,12 +1789,22 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                 self.connection_attempts,
             );
 
-            self.set_answer(DefaultAnswer::Answer503 {
-                message: format!(
-                    "Max connection attempt reached: {}",
-                    self.connection_attempts
-                ),
-            });
+            let message = format!(
+                "Max connection attempt reached: {}",
+                self.connection_attempts
+            );
+            // the target of a tunnel is unreachable, rather than the service unavailable
+            if self.context.is_connect_tunnel() {
+                self.set_answer(DefaultAnswer::Answer502 {
+                    message,
+                    phase: self.request_stream.parsing_phase.marker(),
+                    successfully_parsed: "null".into(),
+                    partially_parsed: "null".into(),
+                    invalid: "null".into(),
+                });
+            } else {
+                self.set_answer(DefaultAnswer::Answer503 { message });
+            }
             return Err(BackendConnectionError::MaxConnectionRetries(None));
         }
         Ok(())
@@ -1221,10 +1834,12 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             .method
             .as_ref()
             .ok_or(RetrieveClusterError::NoMethod)?;
+        // an empty Host header names no virtual host either
         let given_authority = self
             .context
             .authority
             .as_deref()
+            .filter(|authority| !authority.is_empty())
             .ok_or(RetrieveClusterError::NoHost)?;
         let given_path = self
             .context
@@ -1254,6 +1869,37 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
     ) -> Result<String, RetrieveClusterError> {
         let (host, uri, method) = match self.extract_route() {
             Ok(tuple) => tuple,
+            // HTTP/1.0 requests may have no Host header, HTTP/1.1 ones must (RFC 9112
+            // section 3.2): either way there is no virtual host to route them on
+            Err(RetrieveClusterError::NoHost) => {
+                let no_host_cluster_id = self
+                    .listener
+                    .borrow()
+                    .no_host_cluster_id()
+                    .map(ToOwned::to_owned);
+                if let Some(cluster_id) = no_host_cluster_id {
+                    incr!("http.requests_without_host.routed");
+                    // the backend gets an empty Host header
+                    if let kawa::StatusLine::Request { authority, .. } =
+                        &mut self.request_stream.detached.status_line
+                    {
+                        if authority.is_empty() {
+                            *authority = kawa::Store::Static(b"");
+                        }
+                    }
+                    return Ok(cluster_id);
+                }
+                incr!("http.requests_without_host.rejected");
+                self.set_answer(DefaultAnswer::Answer400 {
+                    message: "The request has no host, neither in its target nor in a Host header."
+                        .into(),
+                    phase: self.request_stream.parsing_phase.marker(),
+                    successfully_parsed: "null".into(),
+                    partially_parsed: "null".into(),
+                    invalid: "null".into(),
+                });
+                return Err(RetrieveClusterError::NoHost);
+            }
             Err(cluster_error) => {
                 self.set_answer(DefaultAnswer::Answer400 {
                     message: "Could not extract the route after connection started, this should not happen.".into(),
@@ -1266,6 +1912,19 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             }
         };
 
+        if self.listener.borrow().strict_sni_binding() {
+            if let Some(server_name) = self.frontend_socket.server_name() {
+                if !host_matches_server_name(host, server_name) {
+                    let error = RetrieveClusterError::MisdirectedRequest {
+                        host: host.to_owned(),
+                        server_name: server_name.to_owned(),
+                    };
+                    self.set_answer(DefaultAnswer::Answer421 {});
+                    return Err(error);
+                }
+            }
+        }
+
         let route_result = self
             .listener
             .borrow()
@@ -1319,14 +1978,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                 cluster_id,
                 proxy,
             )
-            .map_err(|backend_error| {
-                // some backend errors are actually retryable
-                // TODO: maybe retry or return a different default answer
-                self.set_answer(DefaultAnswer::Answer503 {
-                    message: backend_error.to_string(),
-                });
-                BackendConnectionError::Backend(backend_error)
-            })?;
+            .map_err(BackendConnectionError::Backend)?;
 
         if frontend_should_stick {
             // update sticky name in case it changed I guess?
@@ -1392,6 +2044,9 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             cluster_id,
             self.backend_connection_status
         );
+
+        // a mirror only receives the request it was opened for
+        self.close_mirror(proxy.clone());
         // check if we can reuse the backend connection
         if (self.context.cluster_id.as_ref()) == Some(&cluster_id)
             && self.backend_connection_status == BackendConnectionStatus::Connected
@@ -1410,6 +2065,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                 .unwrap_or(false);
 
             if has_backend && self.check_backend_connection(metrics) {
+                self.open_mirror(&cluster_id, session_rc, proxy);
                 return Ok(BackendConnectAction::Reuse);
             } else if self.backend_token.take().is_some() {
                 self.close_backend(proxy.clone(), metrics);
@@ -1426,16 +2082,104 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
 
         self.context.cluster_id = Some(cluster_id.clone());
 
-        let frontend_should_stick = proxy
+        let (
+            frontend_should_stick,
+            tcp_nodelay,
+            send_proxy,
+            send_server_timing,
+            preserve_header_case,
+            allowed_request_trailers,
+            tcp_keepalive,
+            compression,
+            response_rewrite,
+            forwarded_headers,
+            response_buffering,
+        ) = proxy
             .borrow()
             .clusters()
             .get(&cluster_id)
-            .map(|cluster| cluster.sticky_session)
-            .unwrap_or(false);
-
-        let mut socket =
-            self.backend_from_request(&cluster_id, frontend_should_stick, proxy.clone(), metrics)?;
-        if let Err(e) = socket.set_nodelay(true) {
+            .map(|cluster| {
+                let send_proxy = matches!(
+                    cluster.proxy_protocol.map(ProxyProtocolConfig::try_from),
+                    Some(Ok(
+                        ProxyProtocolConfig::SendHeader | ProxyProtocolConfig::RelayHeader
+                    ))
+                );
+                (
+                    cluster.sticky_session,
+                    cluster.tcp_nodelay(),
+                    send_proxy,
+                    cluster.server_timing(),
+                    cluster.preserve_header_case(),
+                    cluster.allowed_request_trailers.clone(),
+                    cluster.tcp_keepalive,
+                    cluster.compression.clone(),
+                    cluster.response_rewrite.clone(),
+                    cluster.forwarded_headers(),
+                    (cluster.response_buffering() == ResponseBuffering::BufferComplete).then(
+                        || {
+                            cluster
+                                .response_buffering_max_size
+                                .map_or(usize::MAX, |size| size as usize)
+                        },
+                    ),
+                )
+            })
+            .unwrap_or((
+                false,
+                true,
+                false,
+                false,
+                false,
+                Vec::new(),
+                None,
+                None,
+                None,
+                ForwardedHeaders::All,
+                None,
+            ));
+        self.context.send_server_timing = send_server_timing;
+        self.context.preserve_header_case = preserve_header_case;
+        self.context.allowed_request_trailers = allowed_request_trailers;
+        self.context.compression = compression;
+        self.context.response_rewrite = response_rewrite;
+        self.context.forwarded_headers = forwarded_headers;
+        self.context.response_buffering = response_buffering;
+
+        let backend_connection =
+            self.backend_from_request(&cluster_id, frontend_should_stick, proxy.clone(), metrics);
+        let mut socket = match backend_connection {
+            Ok(socket) => socket,
+            Err(BackendConnectionError::Backend(backend_error)) => {
+                if matches!(backend_error, BackendError::NoBackendForCluster(_))
+                    && self.wait_for_backend(&cluster_id, session_rc, proxy)
+                {
+                    return Ok(BackendConnectAction::Queued);
+                }
+                let message = match (self.leave_backend_queue(), &backend_error) {
+                    (Some(waited), BackendError::NoBackendForCluster(_)) => {
+                        incr!(
+                            "http.backend_queue.timeouts",
+                            Some(cluster_id.as_str()),
+                            None
+                        );
+                        format!("No backend of cluster {cluster_id} came back after {waited:?}")
+                    }
+                    _ => backend_error.to_string(),
+                };
+                // some backend errors are actually retryable
+                // TODO: maybe retry or return a different default answer
+                self.set_answer(DefaultAnswer::Answer503 { message });
+                return Err(BackendConnectionError::Backend(backend_error));
+            }
+            Err(error) => return Err(error),
+        };
+        if self.leave_backend_queue().is_some() {
+            self.container_frontend_timeout.reset();
+        }
+        // opened once a backend is found, not at each retry of a queued request
+        self.open_mirror(&cluster_id, session_rc.clone(), proxy.clone());
+        if let Err(e) = socket.set_nodelay(tcp_nodelay) {
             error!(
                 "{} Error setting nodelay on backend socket({:?}): {:?}",
                 log_context!(self),
@@ -1443,6 +2187,22 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                 e
             );
         }
+        if let Some(keepalive) = &tcp_keepalive {
+            if let Err(e) = set_tcp_keepalive(&socket, keepalive) {
+                error!(
+                    "{} Error setting keepalive on backend socket({:?}): {:?}",
+                    log_context!(self),
+                    socket,
+                    e
+                );
+            }
+        }
+
+        self.backend_proxy_header = if send_proxy {
+            self.proxy_protocol_header()
+        } else {
+            None
+        };
 
         self.backend_readiness.interest = Ready::WRITABLE | Ready::HUP | Ready::ERROR;
         self.backend_connection_status = BackendConnectionStatus::Connecting(Instant::now());
@@ -1493,6 +2253,153 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
         }
     }
 
+    /// Keep the request until a backend of its cluster comes back, if the cluster queues
+    /// requests and its queue has room. A queued request keeps its place until its
+    /// deadline, and looks for a backend again at each tick of the queue timeout
+    fn wait_for_backend(
+        &mut self,
+        cluster_id: &str,
+        session_rc: Rc<RefCell<dyn ProxySession>>,
+        proxy: Rc<RefCell<dyn L7Proxy>>,
+    ) -> bool {
+        let deadline = match &self.backend_queue {
+            Some(queued) if queued.cluster_id == cluster_id => queued.deadline,
+            Some(_) => return false,
+            None => {
+                let (queue_depth, queue_timeout) = match proxy.borrow().clusters().get(cluster_id) {
+                    Some(cluster) => (
+                        cluster.backend_queue_depth(),
+                        cluster.backend_queue_timeout(),
+                    ),
+                    None => return false,
+                };
+                if queue_depth == 0
+                    || !proxy
+                        .borrow()
+                        .backends()
+                        .borrow_mut()
+                        .enqueue_request(cluster_id, queue_depth as usize)
+                {
+                    return false;
+                }
+                incr!("http.backend_queue.queued", Some(cluster_id), None);
+                gauge_add!("http.backend_queue", 1, Some(cluster_id), None);
+                debug!(
+                    "{} No backend available, the request waits for one",
+                    log_context!(self)
+                );
+                // the queue timeout bounds the wait instead
+                self.container_frontend_timeout.cancel();
+                let since = Instant::now();
+                let deadline = since + Duration::from_millis(queue_timeout as u64);
+                self.backend_queue = Some(QueuedRequest {
+                    cluster_id: cluster_id.to_owned(),
+                    since,
+                    deadline,
+                    proxy,
+                    session: Rc::downgrade(&session_rc),
+                });
+                deadline
+            }
+        };
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return false;
+        }
+        self.container_queue_timeout
+            .set_duration(BACKEND_QUEUE_RETRY.min(remaining));
+        self.container_queue_timeout.set(self.frontend_token);
+        true
+    }
+
+    /// Give back the place of the request in the queue of its cluster, returning
+    /// how long it waited there
+    fn leave_backend_queue(&mut self) -> Option<Duration> {
+        let queued = self.backend_queue.take()?;
+        self.container_queue_timeout.cancel();
+        queued
+            .proxy
+            .borrow()
+            .backends()
+            .borrow_mut()
+            .dequeue_request(&queued.cluster_id);
+        gauge_add!(
+            "http.backend_queue",
+            -1,
+            Some(queued.cluster_id.as_str()),
+            None
+        );
+        Some(queued.since.elapsed())
+    }
+
+    /// Open a shadow connection to a backend of the mirror cluster, for a sample of the requests
+    fn open_mirror(
+        &mut self,
+        cluster_id: &str,
+        session_rc: Rc<RefCell<dyn ProxySession>>,
+        proxy: Rc<RefCell<dyn L7Proxy>>,
+    ) {
+        let mirror_cluster_id = match proxy.borrow().clusters().get(cluster_id) {
+            Some(cluster) => match &cluster.mirror_cluster {
+                Some(mirror_cluster_id)
+                    if thread_rng().gen_range(0..100) < cluster.mirror_percentage() =>
+                {
+                    mirror_cluster_id.to_owned()
+                }
+                _ => return,
+            },
+            None => return,
+        };
+
+        let backend_and_socket = proxy
+            .borrow()
+            .backends()
+            .borrow_mut()
+            .backend_from_cluster_id(&mirror_cluster_id);
+        let (backend, mut socket) = match backend_and_socket {
+            Ok(backend_and_socket) => backend_and_socket,
+            Err(e) => {
+                incr!("http.mirror.errors");
+                debug!(
+                    "{} Could not connect to the mirror: {}",
+                    log_context!(self),
+                    e
+                );
+                return;
+            }
+        };
+        let _ = socket.set_nodelay(true);
+
+        let token = proxy.borrow().add_session(session_rc);
+        if let Err(e) = proxy.borrow().register_socket(
+            &mut socket,
+            token,
+            Interest::READABLE | Interest::WRITABLE,
+        ) {
+            error!(
+                "{} Error registering mirror socket({:?}): {:?}",
+                log_context!(self),
+                socket,
+                e
+            );
+        }
+
+        incr!("http.mirror.requests");
+        self.mirror = Some(mirror::Mirror::new(
+            backend,
+            socket,
+            token,
+            self.request_stream.storage.capacity(),
+        ));
+    }
+
+    pub fn close_mirror(&mut self, proxy: Rc<RefCell<dyn L7Proxy>>) {
+        if let Some(mirror) = self.mirror.take() {
+            mirror.close(&*proxy.borrow());
+        }
+    }
+
     fn set_backend_connected(
         &mut self,
         connected: BackendConnectionStatus,
@@ -1673,6 +2580,10 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
     ) -> SessionResult {
         let mut counter = 0;
 
+        if self.mirror.as_mut().is_some_and(|mirror| !mirror.ready()) {
+            self.close_mirror(proxy.clone());
+        }
+
         if self.backend_connection_status.is_connecting()
             && !self.backend_readiness.event.is_empty()
         {
@@ -1855,6 +2766,32 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
         SessionResult::Continue
     }
 
+    /// Look for a backend again for the queued request, it gets a 503 once its
+    /// deadline passed
+    fn retry_queued_request(&mut self, metrics: &mut SessionMetrics) -> StateResult {
+        let Some(queued) = self.backend_queue.as_ref() else {
+            return StateResult::Continue;
+        };
+        let Some(session) = queued.session.upgrade() else {
+            return StateResult::CloseSession;
+        };
+        let proxy = queued.proxy.clone();
+        match self.connect_to_backend(session, proxy, metrics) {
+            Ok(_) => StateResult::Continue,
+            Err(err) => {
+                error!(
+                    "{} Error connecting to backend: {}",
+                    log_context!(self),
+                    err
+                );
+                self.leave_backend_queue();
+                self.container_frontend_timeout.reset();
+                // the default answer is set
+                self.writable(metrics)
+            }
+        }
+    }
+
     pub fn timeout_status(&self) -> TimeoutStatus {
         if self.request_stream.is_main_phase() {
             match &self.response_stream {
@@ -1903,10 +2840,14 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> SessionState
             self.frontend_readiness.event |= events;
         } else if self.backend_token == Some(token) {
             self.backend_readiness.event |= events;
+        } else if let Some(mirror) = self.mirror.as_mut().filter(|mirror| mirror.token == token) {
+            mirror.readiness.event |= events;
         }
     }
 
     fn close(&mut self, proxy: Rc<RefCell<dyn L7Proxy>>, metrics: &mut SessionMetrics) {
+        self.leave_backend_queue();
+        self.close_mirror(proxy.clone());
         self.close_backend(proxy, metrics);
         self.frontend_socket.socket_close();
         let _ = self.frontend_socket.socket_write_vectored(&[]);
@@ -1924,6 +2865,11 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> SessionState
 
     fn timeout(&mut self, token: Token, metrics: &mut SessionMetrics) -> StateResult {
         //info!("got timeout for token: {:?}", token);
+        // the frontend timeout is cancelled while the request is queued
+        if self.frontend_token == token && self.backend_queue.is_some() {
+            self.container_queue_timeout.triggered();
+            return self.retry_queued_request(metrics);
+        }
         if self.frontend_token == token {
             self.container_frontend_timeout.triggered();
             return match self.timeout_status() {
@@ -1989,6 +2935,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> SessionState
     fn cancel_timeouts(&mut self) {
         self.container_backend_timeout.cancel();
         self.container_frontend_timeout.cancel();
+        self.container_queue_timeout.cancel();
     }
 
     fn print_state(&self, context: &str) {
@@ -2010,6 +2957,21 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> SessionState
         );
     }
 
+    fn snapshot(&self) -> SessionSnapshot {
+        let context = self.context.log_context();
+        SessionSnapshot {
+            protocol: self.protocol_string().to_owned(),
+            request_id: Some(context.request_id.to_string()),
+            cluster_id: context.cluster_id.map(String::from),
+            backend_id: context.backend_id.map(String::from),
+            frontend_token: Some(self.frontend_token.0 as u64),
+            frontend_readiness: Some(self.frontend_readiness.to_string()),
+            backend_token: self.backend_token.map(|token| token.0 as u64),
+            backend_readiness: Some(self.backend_readiness.to_string()),
+            ..Default::default()
+        }
+    }
+
     fn shutting_down(&mut self) -> SessionIsToBeClosed {
         if self.request_stream.is_initial() && self.request_stream.storage.is_empty()
         // && self.response_stream.storage.is_empty()
@@ -2028,8 +2990,10 @@ fn handle_connection_result(
     match connection_result {
         // reuse connection or send a default answer, we can continue
         Ok(BackendConnectAction::Reuse) => None,
-        Ok(BackendConnectAction::New) | Ok(BackendConnectAction::Replace) => {
-            // we must wait for an event
+        Ok(BackendConnectAction::New)
+        | Ok(BackendConnectAction::Replace)
+        | Ok(BackendConnectAction::Queued) => {
+            // we must wait for an event, or for a backend to come back
             Some(SessionResult::Continue)
         }
         Err(_) => {
@@ -2045,6 +3009,61 @@ fn handle_connection_result(
     }
 }
 
+/// Value of the Server-Timing header: the time the request waited in sozu's
+/// event loop, the time to connect to the backend, and the time the backend
+/// took to start answering, in milliseconds
+fn server_timing(metrics: &SessionMetrics) -> String {
+    let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    let mut server_timing = format!("sozu-queue;dur={:.3}", millis(metrics.wait_time));
+    if let (Some(start), Some(connected)) = (metrics.backend_start, metrics.backend_connected) {
+        server_timing.push_str(&format!(
+            ", sozu-connect;dur={:.3}",
+            millis(connected.saturating_duration_since(start))
+        ));
+    }
+    if let Some(response_time) = metrics.backend_response_time() {
+        server_timing.push_str(&format!(", sozu-backend;dur={:.3}", millis(response_time)));
+    }
+    server_timing
+}
+
+/// Length of the request target received so far, in a request line that is not complete
+fn partial_request_target_length(line: &[u8]) -> usize {
+    let Some(start) = line.iter().position(|c| *c == b' ') else {
+        return 0;
+    };
+    let target = &line[start + 1..];
+    target
+        .iter()
+        .position(|c| matches!(c, b' ' | b'\r' | b'\n'))
+        .unwrap_or(target.len())
+}
+
+/// number of body bytes in the blocks of a kawa that were not prepared yet
+fn body_bytes(kawa: &GenericHttpStream) -> usize {
+    kawa.blocks
+        .iter()
+        .map(|block| match block {
+            kawa::Block::Chunk(chunk) => chunk.data.len(),
+            _ => 0,
+        })
+        .sum()
+}
+
+fn save_body_size_metrics(context: &HttpContext) {
+    let cluster_id = context.cluster_id.as_deref();
+    histogram!(
+        "http.request.body_size",
+        cluster_id,
+        context.request_body_size
+    );
+    histogram!(
+        "http.response.body_size",
+        cluster_id,
+        context.response_body_size
+    );
+}
+
 /// Save the HTTP status code of the backend response
 fn save_http_status_metric(status: Option<u16>, context: LogContext) {
     if let Some(status) = status {
@@ -2071,3 +3090,22 @@ fn save_http_status_metric(status: Option<u16>, context: LogContext) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn body_bytes_only_count_the_body() {
+        let pool = Rc::new(RefCell::new(Pool::with_capacity(1, 1, 16384)));
+        let buffer = pool.borrow_mut().checkout().unwrap();
+        let mut request = GenericHttpStream::new(kawa::Kind::Request, kawa::Buffer::new(buffer));
+        let bytes = b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+        request.storage.space()[..bytes.len()].copy_from_slice(bytes);
+        request.storage.fill(bytes.len());
+        kawa::h1::parse(&mut request, &mut kawa::h1::NoCallbacks);
+        assert!(request.is_terminated());
+
+        assert_eq!(body_bytes(&request), 11);
+    }
+}
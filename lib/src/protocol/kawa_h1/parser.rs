@@ -133,6 +133,15 @@ pub fn hostname_and_port(i: &[u8]) -> IResult<&[u8], (&[u8], Option<&[u8]>)> {
     Ok((i, (host, port)))
 }
 
+/// Compare the host of a request, without its port, to a TLS server name
+pub fn host_matches_server_name(host: &str, server_name: &str) -> bool {
+    let hostname = match hostname_and_port(host.as_bytes()) {
+        Ok((_, (hostname, _))) => hostname,
+        Err(_) => host.as_bytes(),
+    };
+    hostname.eq_ignore_ascii_case(server_name.as_bytes())
+}
+
 pub fn view(buf: &[u8], size: usize, points: &[usize]) -> String {
     let mut view = format!("{points:?} => ");
     let mut end = 0;
@@ -170,6 +179,21 @@ pub fn view(buf: &[u8], size: usize, points: &[usize]) -> String {
     view
 }
 
+/// Recognize the SETTINGS frame an HTTP/2 server sends first on a connection.
+/// A backend answering this to an HTTP/1.1 request only speaks HTTP/2.
+pub fn is_h2_settings_frame(i: &[u8]) -> bool {
+    if i.len() < 9 {
+        return false;
+    }
+    let length = u32::from_be_bytes([0, i[0], i[1], i[2]]);
+    let frame_type = i[3];
+    let flags = i[4];
+    let stream_id = u32::from_be_bytes([i[5], i[6], i[7], i[8]]) & 0x7FFF_FFFF;
+
+    // SETTINGS frame on stream 0, with the only defined flag being ACK
+    frame_type == 0x04 && flags & !0x01 == 0 && stream_id == 0 && length % 6 == 0
+}
+
 #[test]
 fn test_view_out_of_bound() {
     println!(
@@ -181,3 +205,26 @@ fn test_view_out_of_bound() {
         )
     );
 }
+
+#[test]
+fn test_is_h2_settings_frame() {
+    // empty SETTINGS, then SETTINGS_MAX_CONCURRENT_STREAMS = 100
+    assert!(is_h2_settings_frame(&[0, 0, 0, 4, 0, 0, 0, 0, 0]));
+    assert!(is_h2_settings_frame(&[
+        0, 0, 6, 4, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 100
+    ]));
+    assert!(!is_h2_settings_frame(b"HTTP/1.1 200 OK\r\n"));
+    assert!(!is_h2_settings_frame(&[0, 0, 0, 4, 0]));
+    // SETTINGS on a non zero stream or with an invalid length
+    assert!(!is_h2_settings_frame(&[0, 0, 0, 4, 0, 0, 0, 0, 1]));
+    assert!(!is_h2_settings_frame(&[0, 0, 5, 4, 0, 0, 0, 0, 0]));
+}
+
+#[test]
+fn test_host_matches_server_name() {
+    assert!(host_matches_server_name("example.com", "example.com"));
+    // the port and the case are not compared
+    assert!(host_matches_server_name("Example.COM:8443", "example.com"));
+    assert!(!host_matches_server_name("other.com", "example.com"));
+    assert!(!host_matches_server_name("sub.example.com", "example.com"));
+}
@@ -0,0 +1,166 @@
+//! Rewriting of the response headers that name the backends instead of the public host,
+//! for backends that do not know how the clients reach them
+
+use std::str::from_utf8;
+
+use sozu_command_lib::proto::command::ResponseRewrite;
+
+use crate::protocol::http::parser::hostname_and_port;
+
+/// The new value of an absolute Location pointing to one of the backend authorities:
+/// the same target on the authority of the request, with the scheme of the listener.
+/// None if the header is left alone
+pub fn rewrite_location(
+    rewrite: &ResponseRewrite,
+    location: &[u8],
+    scheme: &str,
+    authority: &str,
+) -> Option<String> {
+    let location = from_utf8(location).ok()?.trim();
+    let (_, rest) = location.split_once("://")?;
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (backend_authority, target) = rest.split_at(end);
+    rewrite
+        .location_authorities
+        .iter()
+        .any(|candidate| candidate.eq_ignore_ascii_case(backend_authority))
+        .then(|| format!("{scheme}://{authority}{target}"))
+}
+
+/// The new value of a Set-Cookie header with the Domain of a backend, replaced by the
+/// host of the request, or with a Path under the backend cookie path, moved under the
+/// public one. None if the header is left alone
+pub fn rewrite_set_cookie(
+    rewrite: &ResponseRewrite,
+    set_cookie: &[u8],
+    authority: &str,
+) -> Option<String> {
+    let set_cookie = from_utf8(set_cookie).ok()?;
+    let host = match hostname_and_port(authority.as_bytes()) {
+        Ok((_, (host, _))) => from_utf8(host).unwrap_or(authority),
+        Err(_) => authority,
+    };
+
+    let mut rewritten = false;
+    let attributes = set_cookie
+        .split(';')
+        .enumerate()
+        .map(|(index, attribute)| {
+            // the first one is the cookie itself
+            if index == 0 {
+                return attribute.to_owned();
+            }
+            let Some((name, value)) = attribute.split_once('=') else {
+                return attribute.to_owned();
+            };
+            let value = value.trim();
+            if name.trim().eq_ignore_ascii_case("domain") && is_cookie_domain(rewrite, value) {
+                rewritten = true;
+                return format!("{name}={host}");
+            }
+            if name.trim().eq_ignore_ascii_case("path") {
+                if let Some(path) = rewrite_cookie_path(rewrite, value) {
+                    rewritten = true;
+                    return format!("{name}={path}");
+                }
+            }
+            attribute.to_owned()
+        })
+        .collect::<Vec<_>>();
+    rewritten.then(|| attributes.join(";"))
+}
+
+/// compare a Domain attribute to the backend domains, ignoring the leading dot
+/// (RFC 6265 section 5.2.3)
+fn is_cookie_domain(rewrite: &ResponseRewrite, domain: &str) -> bool {
+    let domain = domain.strip_prefix('.').unwrap_or(domain);
+    rewrite.cookie_domains.iter().any(|candidate| {
+        candidate
+            .strip_prefix('.')
+            .unwrap_or(candidate)
+            .eq_ignore_ascii_case(domain)
+    })
+}
+
+/// move a Path attribute under the backend cookie path to the public one,
+/// "/app" covers "/app" and "/app/static" but not "/application"
+fn rewrite_cookie_path(rewrite: &ResponseRewrite, path: &str) -> Option<String> {
+    let (Some(from), Some(to)) = (&rewrite.cookie_path, &rewrite.public_cookie_path) else {
+        return None;
+    };
+    let rest = path.strip_prefix(from.as_str())?;
+    if !(rest.is_empty() || rest.starts_with('/') || from.ends_with('/')) {
+        return None;
+    }
+    let to = if rest.starts_with('/') {
+        to.trim_end_matches('/')
+    } else {
+        to
+    };
+    Some(format!("{to}{rest}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite() -> ResponseRewrite {
+        ResponseRewrite {
+            location_authorities: vec!["backend:8080".to_owned()],
+            cookie_domains: vec!["backend.internal".to_owned()],
+            cookie_path: Some("/app".to_owned()),
+            public_cookie_path: Some("/".to_owned()),
+        }
+    }
+
+    #[test]
+    fn locations_of_the_backends_are_rewritten() {
+        let rewrite = rewrite();
+        assert_eq!(
+            rewrite_location(
+                &rewrite,
+                b"http://backend:8080/login?next=/",
+                "https",
+                "example.com"
+            ),
+            Some("https://example.com/login?next=/".to_owned())
+        );
+        assert_eq!(
+            rewrite_location(&rewrite, b"http://BACKEND:8080", "http", "example.com:8443"),
+            Some("http://example.com:8443".to_owned())
+        );
+        assert_eq!(
+            rewrite_location(&rewrite, b"http://backend/login", "https", "example.com"),
+            None
+        );
+        assert_eq!(
+            rewrite_location(&rewrite, b"/login", "https", "example.com"),
+            None
+        );
+    }
+
+    #[test]
+    fn cookie_domains_and_paths_of_the_backends_are_rewritten() {
+        let rewrite = rewrite();
+        assert_eq!(
+            rewrite_set_cookie(
+                &rewrite,
+                b"id=1; Domain=.backend.internal; Path=/app/static; Secure",
+                "example.com:8443"
+            ),
+            Some("id=1; Domain=example.com; Path=/static; Secure".to_owned())
+        );
+        assert_eq!(
+            rewrite_set_cookie(&rewrite, b"id=1; Path=/app", "example.com"),
+            Some("id=1; Path=/".to_owned())
+        );
+        assert_eq!(
+            rewrite_set_cookie(
+                &rewrite,
+                b"id=1; Domain=other.internal; Path=/application",
+                "example.com"
+            ),
+            None
+        );
+    }
+}
@@ -7,7 +7,7 @@ pub mod rustls;
 use std::{cell::RefCell, rc::Rc};
 
 use mio::Token;
-use sozu_command::ready::Ready;
+use sozu_command::{proto::command::SessionSnapshot, ready::Ready};
 
 use crate::{
     L7Proxy, ProxySession, SessionIsToBeClosed, SessionMetrics, SessionResult, StateResult,
@@ -49,6 +49,11 @@ pub trait SessionState {
     ///         - Token(...) Readiness(...)
     /// ```
     fn print_state(&self, context: &str);
+    /// structured version of `print_state`, to inspect the session from the
+    /// command layer. The session completes it with its metrics.
+    fn snapshot(&self) -> SessionSnapshot {
+        SessionSnapshot::default()
+    }
     /// tell the session it has to shut down if possible
     ///
     /// if the session handles HTTP requests, it will not close until the response
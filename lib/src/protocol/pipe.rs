@@ -1,17 +1,23 @@
-use std::{cell::RefCell, net::SocketAddr, rc::Rc};
+use std::{
+    cell::RefCell,
+    net::SocketAddr,
+    rc::Rc,
+    time::{Duration, Instant},
+};
 
 use mio::{net::TcpStream, Token};
 use rusty_ulid::Ulid;
 use sozu_command::{
     config::MAX_LOOP_ITERATIONS,
     logging::{EndpointRecord, LogContext},
+    proto::command::SessionSnapshot,
 };
 
 use crate::{
     backends::Backend,
     pool::Checkout,
     protocol::{http::parser::Method, SessionState},
-    socket::{stats::socket_rtt, SocketHandler, SocketResult, TransportProtocol},
+    socket::{stats::socket_rtt, SocketHandler, SocketResult, TlsDetails, TransportProtocol},
     sozu_command::ready::Ready,
     timer::TimeoutContainer,
     L7Proxy, ListenerHandler, Protocol, Readiness, SessionMetrics, SessionResult, StateResult,
@@ -59,6 +65,14 @@ pub enum WebSocketContext {
     Tcp,
 }
 
+/// Bounds of a connection upgraded from HTTP (WebSocket tunnel)
+struct TunnelTimeouts {
+    /// maximum time of inactivity, replaces the frontend and backend timeouts
+    idle: Duration,
+    /// the tunnel is closed at this instant, whatever its activity
+    deadline: Option<Instant>,
+}
+
 pub struct Pipe<Front: SocketHandler, L: ListenerHandler> {
     backend_buffer: Checkout,
     backend_id: Option<String>,
@@ -79,6 +93,7 @@ pub struct Pipe<Front: SocketHandler, L: ListenerHandler> {
     protocol: Protocol,
     request_id: Ulid,
     session_address: Option<SocketAddr>,
+    tunnel_timeouts: Option<TunnelTimeouts>,
     websocket_context: WebSocketContext,
 }
 
@@ -142,6 +157,7 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
             protocol,
             request_id,
             session_address,
+            tunnel_timeouts: None,
             websocket_context,
         };
 
@@ -175,22 +191,80 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
     }
 
     fn reset_timeouts(&mut self) {
+        // near the end of a tunnel lifetime, the timeouts are shortened to fire at its deadline
+        let tunnel_duration = self.tunnel_timeout_duration();
+
         if let Some(t) = self.container_frontend_timeout.as_mut() {
-            if !t.reset() {
-                error!(
-                    "{} Could not reset front timeout (pipe)",
-                    log_context!(self)
-                );
+            match tunnel_duration {
+                Some(duration) if duration < t.duration() => t.set_duration(duration),
+                _ => {
+                    if !t.reset() {
+                        error!(
+                            "{} Could not reset front timeout (pipe)",
+                            log_context!(self)
+                        );
+                    }
+                }
             }
         }
 
         if let Some(t) = self.container_backend_timeout.as_mut() {
-            if !t.reset() {
-                error!("{} Could not reset back timeout (pipe)", log_context!(self));
+            match tunnel_duration {
+                Some(duration) if duration < t.duration() => t.set_duration(duration),
+                _ => {
+                    if !t.reset() {
+                        error!("{} Could not reset back timeout (pipe)", log_context!(self));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Bound a connection upgraded from HTTP: the frontend and backend timeouts
+    /// are replaced by the tunnel idle timeout, and the session is closed once
+    /// `max_lifetime` is elapsed, even if data is still flowing.
+    pub fn set_tunnel_timeouts(&mut self, idle: Duration, max_lifetime: Option<Duration>) {
+        self.tunnel_timeouts = Some(TunnelTimeouts {
+            idle,
+            deadline: max_lifetime.map(|lifetime| Instant::now() + lifetime),
+        });
+
+        if let Some(duration) = self.tunnel_timeout_duration() {
+            if let Some(t) = self.container_frontend_timeout.as_mut() {
+                t.set_duration(duration);
+            }
+            if let Some(t) = self.container_backend_timeout.as_mut() {
+                t.set_duration(duration);
+            }
+        }
+    }
+
+    /// the idle timeout of the tunnel, capped by the time left before its deadline
+    fn tunnel_timeout_duration(&self) -> Option<Duration> {
+        self.tunnel_timeouts
+            .as_ref()
+            .map(|tunnel| match tunnel.deadline {
+                Some(deadline) => tunnel
+                    .idle
+                    .min(deadline.saturating_duration_since(Instant::now())),
+                None => tunnel.idle,
+            })
+    }
+
+    /// a timeout shorter than the idle timeout was set to match the tunnel deadline
+    fn tunnel_lifetime_reached(&self, timeout: Option<&TimeoutContainer>) -> bool {
+        match (self.tunnel_timeouts.as_ref(), timeout) {
+            (Some(tunnel), Some(timeout)) => {
+                tunnel.deadline.is_some() && timeout.duration() < tunnel.idle
             }
+            _ => false,
         }
     }
 
+    fn is_tunnel(&self) -> bool {
+        matches!(self.websocket_context, WebSocketContext::Http { .. })
+    }
+
     pub fn set_cluster_id(&mut self, cluster_id: Option<String>) {
         self.cluster_id = cluster_id;
     }
@@ -235,6 +309,7 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
         let listener = self.listener.borrow();
         let context = self.log_context();
         let endpoint = self.log_endpoint();
+        let tls = TlsDetails::new(&self.frontend);
         metrics.register_end_of_session(&context);
         log_access!(
             error,
@@ -253,7 +328,9 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
             request_time: metrics.request_time(),
             bytes_in: metrics.bin,
             bytes_out: metrics.bout,
-            user_agent: None
+            user_agent: None,
+            request_id: None,
+            tls: tls.as_ref().map(TlsDetails::record)
         );
     }
 
@@ -360,6 +437,9 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
             self.frontend_buffer.fill(sz);
 
             count!("bytes_in", sz as i64);
+            if self.is_tunnel() {
+                count!("tunnel.bytes_in", sz as i64);
+            }
             metrics.bin += sz;
 
             if self.frontend_buffer.available_space() == 0 {
@@ -586,6 +666,9 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
             }
             if size > 0 {
                 self.frontend_readiness.interest.insert(Ready::WRITABLE);
+                if self.is_tunnel() {
+                    count!("tunnel.back_bytes_in", size as i64);
+                }
                 metrics.backend_bin += size;
             }
 
@@ -763,8 +846,21 @@ impl<Front: SocketHandler, L: ListenerHandler> SessionState for Pipe<Front, L> {
 
     fn timeout(&mut self, token: Token, metrics: &mut SessionMetrics) -> StateResult {
         //info!("got timeout for token: {:?}", token);
+        let lifetime_reached = if self.frontend_token == token {
+            self.tunnel_lifetime_reached(self.container_frontend_timeout.as_ref())
+        } else {
+            self.tunnel_lifetime_reached(self.container_backend_timeout.as_ref())
+        };
+        if lifetime_reached {
+            incr!("tunnel.max_lifetime_reached");
+        }
+
         if self.frontend_token == token {
-            self.log_request_error(metrics, "frontend socket timeout");
+            if lifetime_reached {
+                self.log_request_error(metrics, "tunnel max lifetime reached");
+            } else {
+                self.log_request_error(metrics, "frontend socket timeout");
+            }
             if let Some(timeout) = self.container_frontend_timeout.as_mut() {
                 timeout.triggered()
             }
@@ -777,7 +873,11 @@ impl<Front: SocketHandler, L: ListenerHandler> SessionState for Pipe<Front, L> {
                 timeout.triggered()
             }
 
-            self.log_request_error(metrics, "backend socket timeout");
+            if lifetime_reached {
+                self.log_request_error(metrics, "tunnel max lifetime reached");
+            } else {
+                self.log_request_error(metrics, "backend socket timeout");
+            }
             return StateResult::CloseSession;
         }
 
@@ -814,4 +914,19 @@ impl<Front: SocketHandler, L: ListenerHandler> SessionState for Pipe<Front, L> {
             self.backend_readiness
         );
     }
+
+    fn snapshot(&self) -> SessionSnapshot {
+        let context = self.log_context();
+        SessionSnapshot {
+            protocol: self.protocol_string().to_owned(),
+            request_id: Some(context.request_id.to_string()),
+            cluster_id: context.cluster_id.map(String::from),
+            backend_id: context.backend_id.map(String::from),
+            frontend_token: Some(self.frontend_token.0 as u64),
+            frontend_readiness: Some(self.frontend_readiness.to_string()),
+            backend_token: self.backend_token.map(|token| token.0 as u64),
+            backend_readiness: Some(self.backend_readiness.to_string()),
+            ..Default::default()
+        }
+    }
 }
@@ -1,4 +1,4 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, io::ErrorKind, rc::Rc};
 
 use mio::{net::TcpStream, *};
 use nom::{Err, HexDisplay};
@@ -18,10 +18,18 @@ use crate::{
     Protocol, Readiness, SessionMetrics, StateResult,
 };
 
-use super::{header::ProxyAddr, parser::parse_v2_header};
+use super::{
+    header::ProxyAddr,
+    parser::{parse_v1_header, parse_v2_header},
+};
+
+/// maximum length of a PROXY protocol v1 header, CRLF included
+const PROXY_V1_MAX_LEN: usize = 107;
 
 #[derive(Clone, Copy)]
 pub enum HeaderLen {
+    /// text header, read up to the CRLF
+    V1,
     V4,
     V6,
     Unix,
@@ -67,7 +75,22 @@ impl<Front: SocketHandler> ExpectProxyProtocol<Front> {
     }
 
     pub fn readable(&mut self, metrics: &mut SessionMetrics) -> SessionResult {
+        // the version is not known yet, a v1 header starts with "PROXY",
+        // a v2 header with its binary signature
+        if self.index == 0 {
+            match self.peek_v1_header_len() {
+                Ok(Some(_)) => self.header_len = HeaderLen::V1,
+                Ok(None) => {}
+                Err(session_result) => return session_result,
+            }
+        }
+
         let total_len = match self.header_len {
+            HeaderLen::V1 => match self.peek_v1_header_len() {
+                Ok(Some(len)) => self.index + len,
+                Ok(None) => PROXY_V1_MAX_LEN,
+                Err(session_result) => return session_result,
+            },
             HeaderLen::V4 => 28,
             HeaderLen::V6 => 52,
             HeaderLen::Unix => 232,
@@ -111,18 +134,35 @@ impl<Front: SocketHandler> ExpectProxyProtocol<Front> {
             SocketResult::Closed | SocketResult::Continue => {}
         }
 
-        match parse_v2_header(&self.frontend_buffer[..self.index]) {
-            Ok((rest, header)) => {
+        let parsed = match self.header_len {
+            HeaderLen::V1 => parse_v1_header(&self.frontend_buffer[..self.index]),
+            _ => parse_v2_header(&self.frontend_buffer[..self.index])
+                .map(|(rest, header)| (rest, header.addr)),
+        };
+
+        match parsed {
+            Ok((rest, addresses)) => {
                 trace!(
                     "got expect header: {:?}, rest.len() = {}",
-                    header,
+                    addresses,
                     rest.len()
                 );
-                self.addresses = Some(header.addr);
+                self.addresses = Some(addresses);
                 SessionResult::Upgrade
             }
             Err(Err::Incomplete(_)) => {
                 match self.header_len {
+                    HeaderLen::V1 => {
+                        if self.index >= PROXY_V1_MAX_LEN {
+                            error!(
+                                "[{:?}] PROXY protocol v1 header too long, closing the connection",
+                                self.frontend_token
+                            );
+                            incr!("proxy_protocol.errors");
+                            self.frontend_readiness.reset();
+                            return SessionResult::Close;
+                        }
+                    }
                     HeaderLen::V4 => {
                         if self.index == 28 {
                             self.header_len = HeaderLen::V6;
@@ -156,6 +196,55 @@ impl<Front: SocketHandler> ExpectProxyProtocol<Front> {
         }
     }
 
+    /// Look at the pending bytes without consuming them, to find how many of them
+    /// belong to a v1 header: we must not read past its CRLF, the following bytes
+    /// are for the next protocol.
+    ///
+    /// Returns `Ok(None)` if the pending bytes do not start a v1 header.
+    fn peek_v1_header_len(&mut self) -> Result<Option<usize>, SessionResult> {
+        let mut peeked = [0; PROXY_V1_MAX_LEN];
+        let available = PROXY_V1_MAX_LEN.saturating_sub(self.index);
+
+        let size = match self.frontend.socket_ref().peek(&mut peeked[..available]) {
+            Ok(0) => {
+                self.frontend_readiness.reset();
+                return Err(SessionResult::Close);
+            }
+            Ok(size) => size,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                self.frontend_readiness.event.remove(Ready::READABLE);
+                return Err(SessionResult::Continue);
+            }
+            Err(e) => {
+                error!(
+                    "[{:?}] (expect proxy) front socket error while peeking: {}",
+                    self.frontend_token, e
+                );
+                incr!("proxy_protocol.errors");
+                self.frontend_readiness.reset();
+                return Err(SessionResult::Close);
+            }
+        };
+        let peeked = &peeked[..size];
+
+        if self.index == 0 && peeked[0] != b'P' {
+            return Ok(None);
+        }
+
+        // the CR may already be in the buffer
+        if self.index > 0 && self.frontend_buffer[self.index - 1] == b'\r' && peeked[0] == b'\n' {
+            return Ok(Some(1));
+        }
+
+        Ok(Some(
+            peeked
+                .windows(2)
+                .position(|window| window == b"\r\n")
+                .map(|position| position + 2)
+                .unwrap_or(size),
+        ))
+    }
+
     pub fn front_socket(&self) -> &TcpStream {
         self.frontend.socket_ref()
     }
@@ -326,14 +415,39 @@ mod expect_test {
         let middleware_addr: SocketAddr = "127.0.0.1:3500".parse().expect("parse address error");
         let barrier = Arc::new(Barrier::new(2));
 
-        let upfront = start_upfront_middleware(middleware_addr, barrier.clone());
+        let upfront = start_upfront_middleware(middleware_addr, barrier.clone(), None);
         start_middleware(middleware_addr, barrier);
 
         upfront.join().expect("should join");
     }
 
+    #[test]
+    fn middleware_should_receive_proxy_protocol_v1_header_and_leave_the_request_unread() {
+        setup_test_logger!();
+        let middleware_addr: SocketAddr = "127.0.0.1:3501".parse().expect("parse address error");
+        let barrier = Arc::new(Barrier::new(2));
+
+        let header = b"PROXY TCP4 125.25.10.1 10.4.5.8 8080 4200\r\nGET / HTTP/1.1\r\n".to_vec();
+        let upfront = start_upfront_middleware(middleware_addr, barrier.clone(), Some(header));
+        let mut expect_pp = start_middleware(middleware_addr, barrier);
+
+        assert_eq!(
+            expect_pp.addresses.and_then(|addresses| addresses.source()),
+            Some("125.25.10.1:8080".parse().unwrap())
+        );
+
+        let mut rest = [0; 32];
+        let (size, _) = expect_pp.frontend.socket_read(&mut rest);
+        assert_eq!(&rest[..size], b"GET / HTTP/1.1\r\n");
+
+        upfront.join().expect("should join");
+    }
+
     // Accept connection from an upfront proxy and expect to read a proxy protocol header in this stream.
-    fn start_middleware(middleware_addr: SocketAddr, barrier: Arc<Barrier>) {
+    fn start_middleware(
+        middleware_addr: SocketAddr,
+        barrier: Arc<Barrier>,
+    ) -> ExpectProxyProtocol<TcpStream> {
         let upfront_middleware_conn_listener = TcpListener::bind(middleware_addr)
             .expect("could not accept upfront middleware connection");
         let session_stream;
@@ -364,22 +478,26 @@ mod expect_test {
         if res != SessionResult::Upgrade {
             panic!("Should receive a complete proxy protocol header, res = {res:?}");
         };
+
+        expect_pp
     }
 
-    // Connect to the next middleware and send a proxy protocol header
+    // Connect to the next middleware and send a proxy protocol header (v2 if none is given)
     fn start_upfront_middleware(
         next_middleware_addr: SocketAddr,
         barrier: Arc<Barrier>,
+        header: Option<Vec<u8>>,
     ) -> JoinHandle<()> {
         thread::spawn(move || {
             let src_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(125, 25, 10, 1)), 8080);
             let dst_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 4, 5, 8)), 4200);
-            let proxy_protocol = HeaderV2::new(Command::Local, src_addr, dst_addr).into_bytes();
+            let proxy_protocol = header
+                .unwrap_or_else(|| HeaderV2::new(Command::Local, src_addr, dst_addr).into_bytes());
 
             barrier.wait();
             match StdTcpStream::connect(next_middleware_addr) {
                 Ok(mut stream) => {
-                    stream.write(&proxy_protocol).unwrap();
+                    stream.write_all(&proxy_protocol).unwrap();
                 }
                 Err(e) => panic!("could not connect to the next middleware: {e}"),
             };
@@ -37,8 +37,9 @@ impl fmt::Display for ProtocolSupportedV1 {
     }
 }
 
-/// WARNING: proxy protocol v1 is never used in Sōzu, only v2.
-/// The tests have been commented out, the whole code may be meant for deletion.
+/// WARNING: Sōzu only sends proxy protocol v2, v1 headers are parsed
+/// (see `parse_v1_header`) but never written.
+/// The tests have been commented out.
 ///
 /// Proxy Protocol header for version 1 (text version)
 /// Example:
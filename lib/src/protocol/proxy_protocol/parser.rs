@@ -1,7 +1,10 @@
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    str::{from_utf8, FromStr},
+};
 
 use nom::{
-    bytes::streaming::{tag, take},
+    bytes::streaming::{tag, take, take_until, take_while1},
     error::{Error, ErrorKind, ParseError},
     number::streaming::{be_u16, be_u8},
     Err, IResult,
@@ -86,6 +89,54 @@ fn parse_ipv6_on_v2(i: &[u8]) -> IResult<&[u8], ProxyAddr> {
     ))
 }
 
+/// Parse a text PROXY protocol header (version 1), like
+/// `PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n`
+pub fn parse_v1_header(i: &[u8]) -> IResult<&[u8], ProxyAddr> {
+    let (i, _) = tag("PROXY ")(i)?;
+    let (i, protocol) = take_while1(|c| c != b' ' && c != b'\r')(i)?;
+
+    match protocol {
+        b"UNKNOWN" => {
+            // the rest of the line must be ignored by the receiver
+            let (i, _) = take_until("\r\n")(i)?;
+            let (i, _) = tag("\r\n")(i)?;
+            Ok((i, ProxyAddr::AfUnspec))
+        }
+        b"TCP4" | b"TCP6" => {
+            let (i, src_ip) = parse_v1_field::<IpAddr>(i)?;
+            let (i, dst_ip) = parse_v1_field::<IpAddr>(i)?;
+            let (i, src_port) = parse_v1_field::<u16>(i)?;
+            let (i, dst_port) = parse_v1_field::<u16>(i)?;
+            let (i, _) = tag("\r\n")(i)?;
+
+            let addr = match (protocol, src_ip, dst_ip) {
+                (b"TCP4", IpAddr::V4(_), IpAddr::V4(_))
+                | (b"TCP6", IpAddr::V6(_), IpAddr::V6(_)) => ProxyAddr::from(
+                    SocketAddr::new(src_ip, src_port),
+                    SocketAddr::new(dst_ip, dst_port),
+                ),
+                _ => return Err(Err::Failure(Error::from_error_kind(i, ErrorKind::Verify))),
+            };
+            Ok((i, addr))
+        }
+        _ => Err(Err::Failure(Error::from_error_kind(i, ErrorKind::Switch))),
+    }
+}
+
+/// parse a space prefixed field of a PROXY protocol v1 header
+fn parse_v1_field<T: FromStr>(i: &[u8]) -> IResult<&[u8], T> {
+    let (i, _) = tag(" ")(i)?;
+    let (i, field) = take_while1(|c| c != b' ' && c != b'\r')(i)?;
+
+    match from_utf8(field).ok().and_then(|field| field.parse().ok()) {
+        Some(value) => Ok((i, value)),
+        None => Err(Err::Failure(Error::from_error_kind(
+            field,
+            ErrorKind::Verify,
+        ))),
+    }
+}
+
 // assumes the slice has 16 bytes
 pub fn slice_to_ipv6(sl: &[u8]) -> Ipv6Addr {
     let mut arr: [u8; 16] = [0; 16];
@@ -300,4 +351,69 @@ mod test {
             parse_v2_header(input)
         );
     }
+
+    #[test]
+    fn it_should_parse_proxy_protocol_v1_ipv4_header() {
+        let input = b"PROXY TCP4 125.25.10.1 10.4.5.8 8080 4200\r\nGET / HTTP/1.1\r\n";
+
+        let src_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(125, 25, 10, 1)), 8080);
+        let dst_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 4, 5, 8)), 4200);
+
+        assert_eq!(
+            Ok((
+                &b"GET / HTTP/1.1\r\n"[..],
+                ProxyAddr::from(src_addr, dst_addr)
+            )),
+            parse_v1_header(input)
+        );
+    }
+
+    #[test]
+    fn it_should_parse_proxy_protocol_v1_ipv6_header() {
+        let input = b"PROXY TCP6 ::1 ::2 8080 4200\r\n";
+
+        let src_addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8080);
+        let dst_addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 2)), 4200);
+
+        assert_eq!(
+            Ok((&[][..], ProxyAddr::from(src_addr, dst_addr))),
+            parse_v1_header(input)
+        );
+    }
+
+    #[test]
+    fn it_should_parse_proxy_protocol_v1_unknown_header() {
+        assert_eq!(
+            Ok((&[][..], ProxyAddr::AfUnspec)),
+            parse_v1_header(b"PROXY UNKNOWN\r\n")
+        );
+        assert_eq!(
+            Ok((&[][..], ProxyAddr::AfUnspec)),
+            parse_v1_header(b"PROXY UNKNOWN ::1 ::2 8080 4200\r\n")
+        );
+    }
+
+    #[test]
+    fn it_should_not_parse_incomplete_proxy_protocol_v1_header() {
+        assert!(matches!(
+            parse_v1_header(b"PROXY TCP4 125.25.10.1 10.4.5"),
+            Err(Err::Incomplete(_))
+        ));
+    }
+
+    #[test]
+    fn it_should_not_parse_proxy_protocol_v1_with_mismatched_family() {
+        assert!(matches!(
+            parse_v1_header(b"PROXY TCP4 ::1 ::2 8080 4200\r\n"),
+            Err(Err::Failure(_))
+        ));
+        assert!(matches!(
+            parse_v1_header(b"PROXY TCP4 125.25.10.1 10.4.5.8 99999 4200\r\n"),
+            Err(Err::Failure(_))
+        ));
+        assert!(matches!(
+            parse_v1_header(b"PROXY UDP4 125.25.10.1 10.4.5.8 8080 4200\r\n"),
+            Err(Err::Failure(_))
+        ));
+    }
 }
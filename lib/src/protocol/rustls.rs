@@ -1,9 +1,17 @@
-use std::{cell::RefCell, io::ErrorKind, net::SocketAddr, rc::Rc};
+use std::{
+    cell::RefCell,
+    io::{ErrorKind, Read},
+    net::SocketAddr,
+    rc::Rc,
+    sync::Arc,
+};
 
 use mio::{net::TcpStream, Token};
-use rustls::ServerConnection;
+use rustls::{ServerConfig, ServerConnection};
 use rusty_ulid::Ulid;
-use sozu_command::{config::MAX_LOOP_ITERATIONS, logging::LogContext};
+use sozu_command::{
+    config::MAX_LOOP_ITERATIONS, logging::LogContext, proto::command::SessionSnapshot,
+};
 
 use crate::{
     protocol::SessionState, timer::TimeoutContainer, Readiness, Ready, SessionMetrics,
@@ -32,6 +40,24 @@ macro_rules! log_context {
     };
 }
 
+/// bytes of the first flight of a client kept for a fallback without ALPN, a
+/// ClientHello over it is not replayed
+const MAX_CLIENT_HELLO_SIZE: usize = 65536;
+
+/// Reads from the socket, keeping a copy of the bytes read
+struct Recording<'a> {
+    stream: &'a mut TcpStream,
+    record: &'a mut Vec<u8>,
+}
+
+impl Read for Recording<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.stream.read(buf)?;
+        self.record.extend_from_slice(&buf[..size]);
+        Ok(size)
+    }
+}
+
 pub enum TlsState {
     Initial,
     Handshake,
@@ -47,6 +73,12 @@ pub struct TlsHandshake {
     pub request_id: Ulid,
     pub session: ServerConnection,
     pub stream: TcpStream,
+    /// configuration without ALPN, for the clients offering only protocols we
+    /// do not support. They fail the handshake with no_application_protocol if unset
+    alpn_fallback: Option<Arc<ServerConfig>>,
+    /// bytes received until the ClientHello is handled, replayed on a session
+    /// of `alpn_fallback`
+    client_hello: Option<Vec<u8>>,
 }
 
 impl TlsHandshake {
@@ -75,9 +107,46 @@ impl TlsHandshake {
             request_id,
             session,
             stream,
+            alpn_fallback: None,
+            client_hello: None,
         }
     }
 
+    /// Serve the clients whose ALPN protocols are all unsupported without ALPN, in
+    /// HTTP/1.1, with a session of this configuration instead of failing their handshake
+    pub fn set_alpn_fallback(&mut self, config: Option<Arc<ServerConfig>>) {
+        self.client_hello = config.as_ref().map(|_| Vec::new());
+        self.alpn_fallback = config;
+    }
+
+    /// Replay the ClientHello on a session of the fallback configuration, which
+    /// negotiates no ALPN. False if there is none or the ClientHello was not kept
+    fn fall_back_without_alpn(&mut self) -> bool {
+        let (Some(config), Some(client_hello)) =
+            (self.alpn_fallback.take(), self.client_hello.take())
+        else {
+            return false;
+        };
+        let Ok(mut session) = ServerConnection::new(config) else {
+            return false;
+        };
+        let mut input = client_hello.as_slice();
+        while !input.is_empty() {
+            if !matches!(session.read_tls(&mut input), Ok(size) if size > 0)
+                || session.process_new_packets().is_err()
+            {
+                return false;
+            }
+        }
+        incr!("tls.alpn_fallback");
+        info!(
+            "{} no ALPN protocol in common with the client, falling back to HTTP/1.1",
+            log_context!(self)
+        );
+        self.session = session;
+        true
+    }
+
     pub fn readable(&mut self) -> SessionResult {
         let mut can_read = true;
 
@@ -87,7 +156,14 @@ impl TlsHandshake {
             if self.session.wants_read() && can_read {
                 can_work = true;
 
-                match self.session.read_tls(&mut self.stream) {
+                let read = match self.client_hello.as_mut() {
+                    Some(record) => self.session.read_tls(&mut Recording {
+                        stream: &mut self.stream,
+                        record,
+                    }),
+                    None => self.session.read_tls(&mut self.stream),
+                };
+                match read {
                     Ok(0) => {
                         error!("{} Connection closed during handshake", log_context!(self));
                         return SessionResult::Close;
@@ -109,13 +185,30 @@ impl TlsHandshake {
                     },
                 }
 
-                if let Err(e) = self.session.process_new_packets() {
-                    error!(
-                        "{} Could not perform handshake: {:?}",
-                        log_context!(self),
-                        e
-                    );
-                    return SessionResult::Close;
+                match self.session.process_new_packets() {
+                    Err(rustls::Error::NoApplicationProtocol) if self.fall_back_without_alpn() => {}
+                    Err(e) => {
+                        error!(
+                            "{} Could not perform handshake: {:?}",
+                            log_context!(self),
+                            e
+                        );
+                        // the alert telling why, like no_application_protocol, is sent
+                        // before closing
+                        let _ = self.session.write_tls(&mut self.stream);
+                        return SessionResult::Close;
+                    }
+                    // the ClientHello is handled once the server answers it
+                    Ok(_) if self.session.wants_write() => self.client_hello = None,
+                    Ok(_) => {
+                        if self
+                            .client_hello
+                            .as_ref()
+                            .is_some_and(|record| record.len() > MAX_CLIENT_HELLO_SIZE)
+                        {
+                            self.client_hello = None;
+                        }
+                    }
                 }
             }
 
@@ -312,4 +405,14 @@ impl SessionState for TlsHandshake {
             context, self.frontend_token, self.frontend_readiness
         );
     }
+
+    fn snapshot(&self) -> SessionSnapshot {
+        SessionSnapshot {
+            protocol: String::from("Handshake"),
+            request_id: Some(self.request_id.to_string()),
+            frontend_token: Some(self.frontend_token.0 as u64),
+            frontend_readiness: Some(self.frontend_readiness.to_string()),
+            ..Default::default()
+        }
+    }
 }
@@ -1,3 +1,4 @@
+pub mod path_normalization;
 pub mod pattern_trie;
 
 use std::{str::from_utf8, time::Instant};
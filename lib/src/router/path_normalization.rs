@@ -0,0 +1,195 @@
+//! Normalization of the request paths before routing.
+//!
+//! A prefix rule like `/static` must not match `/static/../admin`, nor must
+//! `/admin` be reachable through `/%61dmin` or `//admin` when the backend reads
+//! them as `/admin`. Routing works on the normalized path, the backend receives
+//! the original one unless the listener forwards the normalized path.
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum PathNormalizationError {
+    #[error("the path does not start with a slash")]
+    NotOriginForm,
+    #[error("invalid percent-encoding")]
+    InvalidPercentEncoding,
+    #[error("encoded slash or backslash")]
+    EncodedSlash,
+    #[error("dot segments going above the root")]
+    AboveRoot,
+}
+
+/// Unreserved characters of RFC 3986 section 2.3, their percent-encoding is decoded
+fn is_unreserved(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, b'-' | b'.' | b'_' | b'~')
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Normalize the path of a request target in origin-form, its query is kept as is:
+///
+/// - the percent-encoded unreserved characters are decoded (RFC 3986 section 6.2.2.2),
+///   the hexadecimal digits of the other encodings are uppercased
+/// - a truncated or non hexadecimal percent-encoding is rejected
+/// - an encoded slash or backslash (`%2F`, `%5C`) is rejected with `reject_encoded_slashes`,
+///   otherwise it stays encoded and does not separate segments
+/// - empty segments are removed, `//` becomes `/`
+/// - the `.` and `..` segments are removed (RFC 3986 section 5.2.4), a `..` going
+///   above the root is rejected
+///
+/// The asterisk-form of `OPTIONS *` is left alone.
+pub fn normalize_path(
+    target: &str,
+    reject_encoded_slashes: bool,
+) -> Result<String, PathNormalizationError> {
+    if target == "*" {
+        return Ok(target.to_owned());
+    }
+    let (path, query) = match target.find('?') {
+        Some(index) => target.split_at(index),
+        None => (target, ""),
+    };
+    if !path.starts_with('/') {
+        return Err(PathNormalizationError::NotOriginForm);
+    }
+
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] != b'%' {
+            decoded.push(bytes[index]);
+            index += 1;
+            continue;
+        }
+        let (Some(high), Some(low)) = (
+            bytes.get(index + 1).copied().and_then(hex_value),
+            bytes.get(index + 2).copied().and_then(hex_value),
+        ) else {
+            return Err(PathNormalizationError::InvalidPercentEncoding);
+        };
+        let value = (high << 4) | low;
+        if is_unreserved(value) {
+            decoded.push(value);
+        } else if matches!(value, b'/' | b'\\') && reject_encoded_slashes {
+            return Err(PathNormalizationError::EncodedSlash);
+        } else {
+            decoded.extend_from_slice(format!("%{value:02X}").as_bytes());
+        }
+        index += 3;
+    }
+
+    let mut segments: Vec<&[u8]> = Vec::new();
+    let mut trailing_slash = false;
+    // the path starts with a slash, the first segment is empty
+    for segment in decoded.split(|c| *c == b'/').skip(1) {
+        trailing_slash = matches!(segment, b"" | b"." | b"..");
+        match segment {
+            b"" | b"." => {}
+            b".." => {
+                if segments.pop().is_none() {
+                    return Err(PathNormalizationError::AboveRoot);
+                }
+            }
+            _ => segments.push(segment),
+        }
+    }
+
+    let mut normalized = Vec::with_capacity(decoded.len() + query.len());
+    for segment in &segments {
+        normalized.push(b'/');
+        normalized.extend_from_slice(segment);
+    }
+    if segments.is_empty() || trailing_slash {
+        normalized.push(b'/');
+    }
+    normalized.extend_from_slice(query.as_bytes());
+    // only ASCII characters were decoded, the rest of the input is copied as is
+    String::from_utf8(normalized).map_err(|_| PathNormalizationError::InvalidPercentEncoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_are_normalized() {
+        for (path, normalized) in [
+            ("/", "/"),
+            ("/api/users", "/api/users"),
+            ("/api/users/", "/api/users/"),
+            ("/a/./b/", "/a/b/"),
+            ("/a/b/..", "/a/"),
+            ("/a/b/../c", "/a/c"),
+            ("//admin", "/admin"),
+            ("/api//users", "/api/users"),
+            ("/%61pi/%7Euser", "/api/~user"),
+            ("/caf%c3%a9", "/caf%C3%A9"),
+            ("/search?q=../x", "/search?q=../x"),
+            ("*", "*"),
+        ] {
+            assert_eq!(
+                normalize_path(path, true).as_deref(),
+                Ok(normalized),
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn path_confusion_payloads_can_not_bypass_prefixes() {
+        for (path, normalized) in [
+            ("/static/../admin", "/admin"),
+            ("/static/%2e%2e/admin", "/admin"),
+            ("/static/.%2E/admin", "/admin"),
+            ("/static/%2e/../admin", "/admin"),
+            ("/static//../admin", "/admin"),
+            ("/%61dmin", "/admin"),
+        ] {
+            assert_eq!(
+                normalize_path(path, false).as_deref(),
+                Ok(normalized),
+                "{path}"
+            );
+        }
+
+        // above the root
+        for path in ["/../etc/passwd", "/static/../../admin", "/%2e%2e/admin"] {
+            assert_eq!(
+                normalize_path(path, false),
+                Err(PathNormalizationError::AboveRoot),
+                "{path}"
+            );
+        }
+
+        // an encoded slash does not separate segments, or is refused
+        assert_eq!(
+            normalize_path("/static%2f..%2fadmin", false).as_deref(),
+            Ok("/static%2F..%2Fadmin")
+        );
+        for path in ["/static%2f..%2fadmin", "/static/..%5cadmin"] {
+            assert_eq!(
+                normalize_path(path, true),
+                Err(PathNormalizationError::EncodedSlash),
+                "{path}"
+            );
+        }
+
+        for path in ["/admin%", "/admin%2", "/admin%zz", "/%%32%65"] {
+            assert_eq!(
+                normalize_path(path, false),
+                Err(PathNormalizationError::InvalidPercentEncoding),
+                "{path}"
+            );
+        }
+        assert_eq!(
+            normalize_path("admin", false),
+            Err(PathNormalizationError::NotOriginForm)
+        );
+    }
+}
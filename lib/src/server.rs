@@ -22,8 +22,8 @@ use sozu_command::{
         CertificatesWithFingerprints, Cluster, ClusterHashes, ClusterInformations,
         DeactivateListener, Event, HttpListenerConfig, HttpsListenerConfig, InitialState,
         ListenerType, LoadBalancingAlgorithms, LoadMetric, MetricsConfiguration, RemoveBackend,
-        Request, ResponseStatus, ServerConfig, TcpListenerConfig as CommandTcpListener,
-        WorkerRequest, WorkerResponse,
+        Request, ResponseStatus, ServerConfig, SessionSnapshot, SessionSnapshots,
+        TcpListenerConfig as CommandTcpListener, WorkerRequest, WorkerResponse,
     },
     ready::Ready,
     scm_socket::{Listeners, ScmSocket, ScmSocketError},
@@ -730,6 +730,27 @@ impl Server {
         );
     }
 
+    /// Snapshots of the client sessions. A session is in the slab under its frontend
+    /// and backend tokens, it is only taken once
+    fn session_snapshots(&self) -> Vec<SessionSnapshot> {
+        let mut frontend_tokens = HashSet::new();
+        self.sessions
+            .borrow()
+            .slab
+            .iter()
+            .filter_map(|(_index, session)| {
+                let session = session.borrow();
+                match session.protocol() {
+                    Protocol::HTTP | Protocol::HTTPS | Protocol::TCP => {}
+                    _ => return None,
+                }
+                frontend_tokens
+                    .insert(session.frontend_token())
+                    .then(|| session.snapshot())
+            })
+            .collect()
+    }
+
     /// Calls close on targeted sessions, yields the number of entries in the slab
     /// that were not properly removed
     fn shut_down_sessions_by_frontend_tokens(&self, tokens: HashSet<Token>) -> usize {
@@ -945,6 +966,16 @@ impl Server {
                 ));
                 return;
             }
+            Some(RequestType::QuerySessions(_)) => {
+                push_queue(WorkerResponse::ok_with_content(
+                    message.id.clone(),
+                    ContentType::SessionSnapshots(SessionSnapshots {
+                        sessions: self.session_snapshots(),
+                    })
+                    .into(),
+                ));
+                return;
+            }
             Some(RequestType::QueryCertificatesFromWorkers(filters)) => {
                 if filters.fingerprint.is_some() {
                     let certs = self.config_state.get_certificates(filters.clone());
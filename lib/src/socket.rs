@@ -1,12 +1,13 @@
 use std::{
     io::{ErrorKind, Read, Write},
     net::SocketAddr,
+    time::Duration,
 };
 
 use mio::net::{TcpListener, TcpStream};
 use rustls::{ProtocolVersion, ServerConnection};
-use socket2::{Domain, Protocol, Socket, Type};
-use sozu_command::config::MAX_LOOP_ITERATIONS;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use sozu_command::{config::MAX_LOOP_ITERATIONS, logging::TlsRecord, proto::command::TcpKeepalive};
 
 #[derive(thiserror::Error, Debug)]
 pub enum ServerBindError {
@@ -45,6 +46,55 @@ pub enum TransportProtocol {
     Tls1_3,
 }
 
+/// What the TLS handshake of a client connection negotiated, read once the
+/// session starts for the access logs and the X-Forwarded-TLS-* headers
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsDetails {
+    pub version: &'static str,
+    pub cipher: Option<&'static str>,
+    pub server_name: Option<String>,
+}
+
+impl TlsDetails {
+    /// None for a plain text socket
+    pub fn new<S: SocketHandler>(socket: &S) -> Option<TlsDetails> {
+        let version = match socket.protocol() {
+            TransportProtocol::Tcp => return None,
+            TransportProtocol::Ssl2 => "SSLv2",
+            TransportProtocol::Ssl3 => "SSLv3",
+            TransportProtocol::Tls1_0 => "TLSv1.0",
+            TransportProtocol::Tls1_1 => "TLSv1.1",
+            TransportProtocol::Tls1_2 => "TLSv1.2",
+            TransportProtocol::Tls1_3 => "TLSv1.3",
+        };
+        Some(TlsDetails {
+            version,
+            cipher: socket.cipher_suite(),
+            server_name: socket.server_name().map(ToOwned::to_owned),
+        })
+    }
+
+    pub fn record(&self) -> TlsRecord<'_> {
+        TlsRecord {
+            version: self.version,
+            cipher: self.cipher,
+            server_name: self.server_name.as_deref(),
+        }
+    }
+
+    /// the X-Forwarded-TLS-* headers describing the handshake to the backend
+    pub fn forwarded_headers(&self) -> Vec<(&'static [u8], String)> {
+        let mut headers = vec![(&b"X-Forwarded-TLS-Version"[..], self.version.to_owned())];
+        if let Some(cipher) = self.cipher {
+            headers.push((b"X-Forwarded-TLS-Cipher", cipher.to_owned()));
+        }
+        if let Some(server_name) = &self.server_name {
+            headers.push((b"X-Forwarded-TLS-SNI", server_name.to_owned()));
+        }
+        headers
+    }
+}
+
 pub trait SocketHandler {
     fn socket_read(&mut self, buf: &mut [u8]) -> (usize, SocketResult);
     fn socket_write(&mut self, buf: &[u8]) -> (usize, SocketResult);
@@ -56,6 +106,14 @@ pub trait SocketHandler {
     fn socket_ref(&self) -> &TcpStream;
     fn socket_mut(&mut self) -> &mut TcpStream;
     fn protocol(&self) -> TransportProtocol;
+    /// the server name sent by the client in the TLS handshake, if any
+    fn server_name(&self) -> Option<&str> {
+        None
+    }
+    /// the cipher suite negotiated in the TLS handshake, if any
+    fn cipher_suite(&self) -> Option<&'static str> {
+        None
+    }
     fn read_error(&self);
     fn write_error(&self);
 }
@@ -447,6 +505,16 @@ impl SocketHandler for FrontRustls {
         &mut self.stream
     }
 
+    fn server_name(&self) -> Option<&str> {
+        self.session.server_name()
+    }
+
+    fn cipher_suite(&self) -> Option<&'static str> {
+        self.session
+            .negotiated_cipher_suite()
+            .and_then(|suite| suite.suite().as_str())
+    }
+
     fn protocol(&self) -> TransportProtocol {
         self.session
             .protocol_version()
@@ -497,6 +565,16 @@ pub fn server_bind(addr: SocketAddr) -> Result<TcpListener, ServerBindError> {
     Ok(TcpListener::from_std(sock.into()))
 }
 
+/// enable TCP keepalive on a connected socket, with the probe timings of the configuration
+pub fn set_tcp_keepalive(stream: &TcpStream, config: &TcpKeepalive) -> std::io::Result<()> {
+    let keepalive = socket2::TcpKeepalive::new()
+        .with_time(Duration::from_secs(config.idle() as u64))
+        .with_interval(Duration::from_secs(config.interval() as u64))
+        .with_retries(config.count());
+
+    SockRef::from(stream).set_tcp_keepalive(&keepalive)
+}
+
 /// Socket statistics
 pub mod stats {
     use std::{os::fd::AsRawFd, time::Duration};
@@ -644,3 +722,85 @@ pub mod stats {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keepalive_is_set_with_the_configured_timings() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::from_std(
+            std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap(),
+        );
+        let config = TcpKeepalive {
+            idle: Some(30),
+            interval: Some(5),
+            count: Some(3),
+        };
+        set_tcp_keepalive(&stream, &config).unwrap();
+
+        let socket = SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+        assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(5));
+        assert_eq!(socket.keepalive_retries().unwrap(), 3);
+    }
+
+    #[test]
+    fn tls_details_go_in_the_headers_and_the_log_tags() {
+        let details = TlsDetails {
+            version: "TLSv1.3",
+            cipher: Some("TLS13_AES_256_GCM_SHA384"),
+            server_name: Some(String::from("example.com")),
+        };
+        assert_eq!(
+            details.forwarded_headers(),
+            vec![
+                (&b"X-Forwarded-TLS-Version"[..], String::from("TLSv1.3")),
+                (
+                    &b"X-Forwarded-TLS-Cipher"[..],
+                    String::from("TLS13_AES_256_GCM_SHA384")
+                ),
+                (&b"X-Forwarded-TLS-SNI"[..], String::from("example.com")),
+            ]
+        );
+
+        let record = details.record();
+        let tags = sozu_command::logging::FullTags {
+            concatenated: None,
+            user_agent: Some("curl/8.0"),
+            tls_cipher: record.cipher,
+            tls_server_name: record.server_name,
+            request_id: None,
+        };
+        assert_eq!(
+            tags.to_string(),
+            "user-agent=curl/8.0, tls-cipher=TLS13_AES_256_GCM_SHA384, sni=example.com"
+        );
+
+        // a client sending no SNI
+        let details = TlsDetails {
+            server_name: None,
+            ..details
+        };
+        assert_eq!(details.forwarded_headers().len(), 2);
+    }
+
+    #[test]
+    fn nodelay_follows_the_cluster_option() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        for (tcp_nodelay, expected) in [(None, true), (Some(true), true), (Some(false), false)] {
+            let cluster = sozu_command::proto::command::Cluster {
+                tcp_nodelay,
+                ..Default::default()
+            };
+            let stream = TcpStream::from_std(
+                std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap(),
+            );
+            stream.set_nodelay(cluster.tcp_nodelay()).unwrap();
+
+            assert_eq!(SockRef::from(&stream).nodelay().unwrap(), expected);
+        }
+    }
+}
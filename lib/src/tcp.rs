@@ -17,7 +17,7 @@ use rusty_ulid::Ulid;
 use sozu_command::{
     config::MAX_LOOP_ITERATIONS,
     logging::{EndpointRecord, LogContext},
-    proto::command::request::RequestType,
+    proto::command::{request::RequestType, TcpKeepalive},
     ObjectKind,
 };
 
@@ -29,15 +29,15 @@ use crate::{
         proxy_protocol::{
             expect::ExpectProxyProtocol, relay::RelayProxyProtocol, send::SendProxyProtocol,
         },
-        Pipe,
+        Pipe, SessionState,
     },
     retry::RetryPolicy,
     server::{push_event, ListenToken, SessionManager, CONN_RETRIES},
-    socket::{server_bind, stats::socket_rtt},
+    socket::{server_bind, set_tcp_keepalive, stats::socket_rtt},
     sozu_command::{
         proto::command::{
-            Event, EventKind, ProxyProtocolConfig, RequestTcpFrontend, TcpListenerConfig,
-            WorkerRequest, WorkerResponse,
+            Event, EventKind, ProxyProtocolConfig, RequestTcpFrontend, SessionSnapshot,
+            TcpListenerConfig, WorkerRequest, WorkerResponse,
         },
         ready::Ready,
         state::ClusterId,
@@ -228,6 +228,8 @@ impl TcpSession {
             client_rtt: socket_rtt(self.state.front_socket()),
             server_rtt: None,
             user_agent: None,
+            request_id: None,
+            tls: None,
             service_time: self.metrics.service_time(),
             response_time: self.metrics.backend_response_time(),
             request_time: self.metrics.request_time(),
@@ -865,7 +867,15 @@ impl TcpSession {
             .backend_from_cluster_id(&cluster_id)
             .map_err(BackendConnectionError::Backend)?;
 
-        if let Err(e) = stream.set_nodelay(true) {
+        let (tcp_nodelay, tcp_keepalive) = self
+            .proxy
+            .borrow()
+            .configs
+            .get(&cluster_id)
+            .map(|config| (config.tcp_nodelay, config.tcp_keepalive))
+            .unwrap_or((true, None));
+
+        if let Err(e) = stream.set_nodelay(tcp_nodelay) {
             error!(
                 "{} Error setting nodelay on back socket({:?}): {:?}",
                 log_context!(self),
@@ -873,6 +883,16 @@ impl TcpSession {
                 e
             );
         }
+        if let Some(keepalive) = &tcp_keepalive {
+            if let Err(e) = set_tcp_keepalive(&stream, keepalive) {
+                error!(
+                    "{} Error setting keepalive on back socket({:?}): {:?}",
+                    log_context!(self),
+                    stream,
+                    e
+                );
+            }
+        }
         self.backend_connected = BackendConnectionStatus::Connecting(Instant::now());
 
         let back_token = {
@@ -1083,6 +1103,41 @@ impl ProxySession for TcpSession {
         error!("Metrics: {:?}", self.metrics);
     }
 
+    fn snapshot(&self) -> SessionSnapshot {
+        let mut snapshot = match &self.state {
+            TcpStateMachine::Pipe(pipe) => pipe.snapshot(),
+            TcpStateMachine::ExpectProxyProtocol(expect) => SessionSnapshot {
+                protocol: String::from("Expect"),
+                frontend_readiness: Some(expect.frontend_readiness.to_string()),
+                ..Default::default()
+            },
+            TcpStateMachine::SendProxyProtocol(send) => SessionSnapshot {
+                protocol: String::from("Send"),
+                frontend_readiness: Some(send.frontend_readiness.to_string()),
+                backend_readiness: Some(send.backend_readiness.to_string()),
+                ..Default::default()
+            },
+            TcpStateMachine::RelayProxyProtocol(relay) => SessionSnapshot {
+                protocol: String::from("Relay"),
+                frontend_readiness: Some(relay.frontend_readiness.to_string()),
+                backend_readiness: Some(relay.backend_readiness.to_string()),
+                ..Default::default()
+            },
+            TcpStateMachine::FailedUpgrade(marker) => SessionSnapshot {
+                protocol: format!("FailedUpgrade({marker:?})"),
+                ..Default::default()
+            },
+        };
+        let context = self.log_context();
+        snapshot.request_id = Some(context.request_id.to_string());
+        snapshot.cluster_id = context.cluster_id.map(String::from);
+        snapshot.backend_id = context.backend_id.map(String::from);
+        snapshot.frontend_token = Some(self.frontend_token.0 as u64);
+        snapshot.backend_token = self.backend_token.map(|token| token.0 as u64);
+        self.metrics.fill_snapshot(&mut snapshot);
+        snapshot
+    }
+
     fn frontend_token(&self) -> Token {
         self.frontend_token
     }
@@ -1161,7 +1216,9 @@ fn handle_connection_result(
     match connection_result {
         // reuse connection or send a default answer, we can continue
         Ok(BackendConnectAction::Reuse) => None,
-        Ok(BackendConnectAction::New) | Ok(BackendConnectAction::Replace) => {
+        Ok(BackendConnectAction::New)
+        | Ok(BackendConnectAction::Replace)
+        | Ok(BackendConnectAction::Queued) => {
             // we must wait for an event
             Some(SessionResult::Continue)
         }
@@ -1176,6 +1233,8 @@ fn handle_connection_result(
 #[derive(Debug)]
 pub struct ClusterConfiguration {
     proxy_protocol: Option<ProxyProtocolConfig>,
+    tcp_nodelay: bool,
+    tcp_keepalive: Option<TcpKeepalive>,
     // Uncomment this when implementing new load balancing algorithms
     // load_balancing: LoadBalancingAlgorithms,
 }
@@ -1368,6 +1427,8 @@ impl ProxyConfiguration for TcpProxy {
                     proxy_protocol: cluster
                         .proxy_protocol
                         .and_then(|n| ProxyProtocolConfig::try_from(n).ok()),
+                    tcp_nodelay: cluster.tcp_nodelay(),
+                    tcp_keepalive: cluster.tcp_keepalive,
                     //load_balancing: cluster.load_balancing,
                 };
                 self.configs.insert(cluster.cluster_id, config);